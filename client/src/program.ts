@@ -60,11 +60,12 @@ export async function registerHomeserverOnchain(
 }
 
 /// Look up a wallet's homeserver delegation onchain.
-/// Returns the homeserver URL, or null if no delegation exists.
-export async function lookupHomeserver(
+/// Returns the homeservers in priority order, or an empty list if no delegation exists
+/// or it has expired.
+export async function lookupHomeservers(
   walletAddress: string,
   rpcUrl: string = "https://api.devnet.solana.com"
-): Promise<string | null> {
+): Promise<Array<string>> {
   const ownerAddress = address(walletAddress);
 
   const { pda: delegationPda } = await getPDAAndBump(
@@ -77,15 +78,42 @@ export async function lookupHomeserver(
     encoding: "base64" as never,
   }).send();
 
-  if (!accountInfo.value) return null;
+  if (!accountInfo.value) return [];
 
   // Decode account data:
-  // 8 bytes Anchor discriminator + 32 bytes owner pubkey + borsh string (homeserver)
+  // 8 bytes Anchor discriminator + 32 bytes owner pubkey + borsh vec of strings (homeservers)
   const raw = accountInfo.value.data;
   const data = Uint8Array.from(atob(raw[0] as string), (character) => character.charCodeAt(0));
   const DISCRIMINATOR_LENGTH = 8;
   const PUBKEY_LENGTH = 32;
-  const [homeserver] = borshDecodeString(data, DISCRIMINATOR_LENGTH + PUBKEY_LENGTH);
+  let offset = DISCRIMINATOR_LENGTH + PUBKEY_LENGTH;
+  const count = new DataView(data.buffer).getUint32(offset, true);
+  offset += 4;
+
+  const homeservers: Array<string> = [];
+  for (let index = 0; index < count; index++) {
+    const [homeserver, consumed] = borshDecodeString(data, offset);
+    homeservers.push(homeserver);
+    offset += consumed;
+  }
+
+  // updated_at (i64) + bump (u8) + version (u8), then expires_at (Option<i64>),
+  // which accounts written before it existed don't have.
+  offset += 8 + 1 + 1;
+  if (offset + 9 <= data.length && data[offset] === 1) {
+    const expiresAt = new DataView(data.buffer).getBigInt64(offset + 1, true);
+    if (expiresAt <= BigInt(Math.floor(Date.now() / 1000))) return [];
+  }
+
+  return homeservers;
+}
 
-  return homeserver;
+/// Look up a wallet's primary homeserver onchain.
+/// Returns the homeserver URL, or null if no delegation exists.
+export async function lookupHomeserver(
+  walletAddress: string,
+  rpcUrl: string = "https://api.devnet.solana.com"
+): Promise<string | null> {
+  const [primary] = await lookupHomeservers(walletAddress, rpcUrl);
+  return primary ?? null;
 }
@@ -0,0 +1,116 @@
+//! Builders for the registry's instructions, for Rust apps that want to call the program
+//! without `anchor-client`. Enabled with the `client` feature.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::InstructionData;
+
+use crate::state::HomeserverStats;
+use crate::{accounts, instruction};
+
+/// The delegation PDA of `owner` and its bump, from the seeds `["delegation", owner]`.
+pub fn derive_delegation_pda(owner: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"delegation", owner.as_ref()], &crate::ID)
+}
+
+/// The stats PDA counting delegations to `homeserver` and its bump, from the seeds
+/// `["homeserver_stats", sha256(homeserver)]`.
+pub fn derive_homeserver_stats_pda(homeserver: &str) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"homeserver_stats", &HomeserverStats::seed(homeserver)], &crate::ID)
+}
+
+/// A `register` instruction that creates or updates `owner`'s delegation with a single
+/// homeserver. `owner` signs and pays.
+pub fn build_register_ix(owner: &Pubkey, homeserver: &str) -> Instruction {
+    let accounts = accounts::RegisterAccountConstraints {
+        delegation: derive_delegation_pda(owner).0,
+        owner: *owner,
+        system_program: anchor_lang::system_program::ID,
+        homeserver_stats: None,
+        counted_stats: None,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::Register { homeserver: homeserver.to_owned() }.data(),
+    }
+}
+
+/// An `unregister` instruction that closes `owner`'s delegation and returns its rent to
+/// `owner`, who signs. Only for delegations `owner` still holds under its own seed, not
+/// ones transferred to it.
+pub fn build_unregister_ix(owner: &Pubkey) -> Instruction {
+    let accounts = accounts::UnregisterAccountConstraints {
+        delegation: derive_delegation_pda(owner).0,
+        owner: *owner,
+        homeserver_stats: None,
+    };
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::Unregister.data(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: Pubkey = Pubkey::new_from_array([1; 32]);
+
+    #[test]
+    fn derives_the_delegation_seeded_by_the_owner() {
+        // The same reference address the server derives for this owner.
+        let (address, bump) = derive_delegation_pda(&OWNER);
+        assert_eq!(address.to_string(), "Yvk5xziYQZp2mBsBdcKbpQpYBR1A4GR4a2ZQBoixRJj");
+        assert_eq!(bump, 252);
+        assert_eq!(Pubkey::create_program_address(&[b"delegation", OWNER.as_ref(), &[bump]], &crate::ID), Ok(address));
+    }
+
+    #[test]
+    fn derives_homeserver_stats_seeded_by_the_hostname_hash() {
+        let (address, bump) = derive_homeserver_stats_pda("chat.example.com");
+        assert_eq!(Pubkey::create_program_address(&[b"homeserver_stats", &HomeserverStats::seed("chat.example.com"), &[bump]], &crate::ID), Ok(address));
+        assert_ne!(address, derive_homeserver_stats_pda("chat.example.org").0);
+
+        // Names longer than a 32-byte seed still get one.
+        let long = format!("{}.example.com", "a".repeat(63));
+        assert_ne!(derive_homeserver_stats_pda(&long).0, address);
+    }
+
+    #[test]
+    fn builds_register_with_the_programs_discriminator() {
+        let register = build_register_ix(&OWNER, "chat.example.com");
+
+        // The first 8 bytes of sha256("global:register"), then the borsh string.
+        assert_eq!(register.data[..8], [211, 124, 67, 15, 211, 194, 178, 240]);
+        assert_eq!(register.data[..8], *instruction::Register::DISCRIMINATOR);
+        assert_eq!(register.data[8..12], 16u32.to_le_bytes());
+        assert_eq!(&register.data[12..], b"chat.example.com");
+
+        assert_eq!(register.program_id, crate::ID);
+        assert_eq!(
+            register.accounts,
+            [
+                AccountMeta::new(derive_delegation_pda(&OWNER).0, false),
+                AccountMeta::new(OWNER, true),
+                AccountMeta::new_readonly(anchor_lang::system_program::ID, false),
+                // No homeserver stats: the program's own ID stands in for the optional accounts.
+                AccountMeta::new_readonly(crate::ID, false),
+                AccountMeta::new_readonly(crate::ID, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_unregister_with_the_programs_discriminator() {
+        let unregister = build_unregister_ix(&OWNER);
+
+        assert_eq!(unregister.data, [143, 28, 144, 79, 140, 220, 228, 248]);
+        assert_eq!(unregister.data, instruction::Unregister::DISCRIMINATOR);
+        assert_eq!(
+            unregister.accounts,
+            [AccountMeta::new(derive_delegation_pda(&OWNER).0, false), AccountMeta::new(OWNER, true), AccountMeta::new_readonly(crate::ID, false)]
+        );
+    }
+}
@@ -5,9 +5,63 @@ pub enum RegistryError {
     #[msg("Homeserver URL cannot be empty")]
     EmptyHomeserver,
 
-    #[msg("Homeserver URL exceeds 253 characters (max DNS name length)")]
+    #[msg("Homeserver URL exceeds 253 bytes (max DNS name length)")]
     HomeserverTooLong,
 
-    #[msg("Homeserver URL is not a valid hostname (must contain a dot, no spaces or protocol prefix)")]
+    #[msg("Homeserver URL is not a valid hostname (dot-separated DNS labels, optional :port, no protocol prefix)")]
     InvalidHomeserver,
+
+    #[msg("A delegation must list at least one homeserver")]
+    NoHomeservers,
+
+    #[msg("A delegation can list at most 3 homeservers")]
+    TooManyHomeservers,
+
+    #[msg("The same homeserver is listed more than once")]
+    DuplicateHomeserver,
+
+    #[msg("No homeserver at that position in the delegation")]
+    HomeserverIndexOutOfRange,
+
+    #[msg("Cannot remove the only homeserver; unregister instead")]
+    LastHomeserver,
+
+    #[msg("The delegation already points at that homeserver")]
+    SameHomeserver,
+
+    #[msg("A delegation's expiry must be in the future")]
+    ExpiryInPast,
+
+    #[msg("A label exceeds 64 bytes")]
+    LabelTooLong,
+
+    #[msg("A label cannot be empty or contain control characters")]
+    InvalidLabel,
+
+    #[msg("This delegation belongs to another wallet")]
+    NotDelegationOwner,
+
+    #[msg("Ownership can't be transferred to the default public key")]
+    InvalidNewOwner,
+
+    #[msg("An auth key must be a key other than the owner and the default public key")]
+    InvalidAuthKey,
+
+    #[msg("register_for must directly follow an ed25519 signature check of the owner's authorization")]
+    MissingAuthorization,
+
+    #[msg("The ed25519 signature check does not cover the owner's authorization for this homeserver")]
+    InvalidAuthorization,
+
+    #[msg("The owner's authorization has expired")]
+    AuthorizationExpired,
+
+    #[msg("The homeserver stats account is not for the delegation's primary homeserver")]
+    HomeserverStatsMismatch,
+
+    #[msg("The delegation is counted for another homeserver; pass that homeserver's stats to take it off their count")]
+    CountedElsewhere,
+
+    #[msg("The delegation is counted for a homeserver whose stats account was not given")]
+    MissingHomeserverStats,
 }
@@ -0,0 +1,78 @@
+use anchor_lang::prelude::*;
+
+/// Emitted when a wallet's delegation is created or its homeserver list changes, so
+/// indexers can follow the registry from program logs instead of polling every PDA.
+#[event]
+pub struct DelegationRegistered {
+    pub owner: Pubkey,
+    /// The full homeserver list after the change, in priority order.
+    pub homeservers: Vec<String>,
+    pub updated_at: i64,
+    pub expires_at: Option<i64>,
+    pub label: Option<String>,
+    /// True if the delegation account was created by this instruction, false if an
+    /// existing delegation was changed.
+    pub created: bool,
+}
+
+/// Emitted when a wallet deactivates its delegation, keeping the account.
+#[event]
+pub struct DelegationDeactivated {
+    pub owner: Pubkey,
+    pub updated_at: i64,
+}
+
+/// Emitted when a delegation is handed to another wallet. The account stays at the
+/// PDA seeded by the wallet that first registered it.
+#[event]
+pub struct DelegationTransferred {
+    pub previous_owner: Pubkey,
+    pub new_owner: Pubkey,
+    pub updated_at: i64,
+}
+
+/// Emitted when a delegation's primary homeserver moves to another host, so clients
+/// that cached the old one can follow the move.
+#[event]
+pub struct DelegationMigrated {
+    pub owner: Pubkey,
+    /// The primary homeserver before the move.
+    pub previous_homeserver: String,
+    /// The primary homeserver after the move.
+    pub homeserver: String,
+    pub updated_at: i64,
+}
+
+/// Emitted when the owner sets or clears the auth key of its delegation.
+#[event]
+pub struct AuthKeyChanged {
+    pub owner: Pubkey,
+    /// The key now allowed to sign logins for the owner, or `None` once cleared.
+    pub auth_pubkey: Option<Pubkey>,
+    pub updated_at: i64,
+}
+
+/// Emitted when the owner freezes or unfreezes its delegation.
+#[event]
+pub struct DelegationFrozen {
+    pub owner: Pubkey,
+    /// Whether logins through the registry are now refused.
+    pub frozen: bool,
+    pub updated_at: i64,
+}
+
+/// Emitted when a wallet closes its delegation.
+#[event]
+pub struct DelegationRemoved {
+    pub owner: Pubkey,
+}
+
+/// Emitted when `migrate_account` rewrites a delegation written by an older version of the
+/// program in the current layout.
+#[event]
+pub struct DelegationUpgraded {
+    pub owner: Pubkey,
+    /// The layout version the account had before, 0 if it predates versioning.
+    pub previous_version: u8,
+    pub version: u8,
+}
@@ -0,0 +1,45 @@
+use anchor_lang::prelude::*;
+
+use crate::state::{Delegation, HomeserverStats};
+use crate::events::DelegationDeactivated;
+use crate::instructions::init_homeserver_stats::uncount_delegation;
+
+/// Deactivate a homeserver delegation but keep the account.
+///
+/// Clears the homeserver list and stamps `updated_at`, so anyone reading the PDA can
+/// tell "removed at T" from "never registered". The rent stays in the account; a later
+/// `register` reactivates it, and `unregister` still closes it. As with `unregister`,
+/// `has_one = owner` means only the current owner can deactivate, and a delegation counted
+/// for a homeserver needs that homeserver's `homeserver_stats` to be taken off its count.
+pub fn handle_deactivate(context: Context<DeactivateAccountConstraints>) -> Result<()> {
+    let accounts = context.accounts;
+    let delegation = &mut accounts.delegation;
+    Delegation::begin_write(delegation)?;
+    delegation.homeservers.clear();
+    delegation.updated_at = Clock::get()?.unix_timestamp;
+    uncount_delegation(delegation, accounts.homeserver_stats.as_mut())?;
+
+    emit!(DelegationDeactivated {
+        owner: delegation.owner,
+        updated_at: delegation.updated_at,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct DeactivateAccountConstraints<'info> {
+    #[account(
+        mut,
+        // Not seeded by the signer: a transferred delegation keeps its original seed.
+        has_one = owner,
+    )]
+    pub delegation: Account<'info, Delegation>,
+
+    pub owner: Signer<'info>,
+
+    /// The stats the delegation is counted in, if it is. Optional, and last so callers
+    /// that never counted can leave it out.
+    #[account(mut)]
+    pub homeserver_stats: Option<Account<'info, HomeserverStats>>,
+}
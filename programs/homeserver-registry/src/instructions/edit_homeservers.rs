@@ -0,0 +1,100 @@
+use anchor_lang::prelude::*;
+
+use crate::state::{Delegation, HomeserverStats};
+use crate::errors::RegistryError;
+use crate::events::DelegationRegistered;
+use crate::instructions::init_homeserver_stats::recount_delegation;
+use crate::instructions::register::validate_homeservers;
+
+/// Replace the homeserver list of an existing delegation.
+///
+/// Unlike `register`, the delegation is found by address rather than derived from the
+/// signer, so this is how the owner of a transferred delegation updates it. Like the other
+/// edits, it keeps homeserver stats as `register` does when the primary homeserver changes.
+pub fn handle_update_homeservers(context: Context<EditHomeserversAccountConstraints>, homeservers: Vec<String>) -> Result<()> {
+    validate_homeservers(&homeservers)?;
+
+    let accounts = context.accounts;
+    let previous_primary = accounts.delegation.homeservers.first().cloned();
+    accounts.delegation.homeservers = homeservers;
+
+    touch(accounts, previous_primary)
+}
+
+/// Move the homeserver at `from` to position `to`, shifting the entries in between.
+///
+/// Lets the owner promote a fallback without resending the whole list.
+pub fn handle_move_homeserver(context: Context<EditHomeserversAccountConstraints>, from: u8, to: u8) -> Result<()> {
+    let accounts = context.accounts;
+    let delegation = &mut accounts.delegation;
+    let (from, to) = (usize::from(from), usize::from(to));
+    require!(from < delegation.homeservers.len() && to < delegation.homeservers.len(), RegistryError::HomeserverIndexOutOfRange);
+
+    let previous_primary = delegation.homeservers.first().cloned();
+    let homeserver = delegation.homeservers.remove(from);
+    delegation.homeservers.insert(to, homeserver);
+
+    touch(accounts, previous_primary)
+}
+
+/// Remove the homeserver at `index`. The last homeserver can't be removed, since a
+/// delegation must point somewhere; `unregister` closes it instead.
+pub fn handle_remove_homeserver(context: Context<EditHomeserversAccountConstraints>, index: u8) -> Result<()> {
+    let accounts = context.accounts;
+    let delegation = &mut accounts.delegation;
+    let index = usize::from(index);
+    require!(index < delegation.homeservers.len(), RegistryError::HomeserverIndexOutOfRange);
+    require!(delegation.homeservers.len() > 1, RegistryError::LastHomeserver);
+
+    let previous_primary = delegation.homeservers.first().cloned();
+    delegation.homeservers.remove(index);
+
+    touch(accounts, previous_primary)
+}
+
+/// Bump `updated_at` after an edit, recount the delegation if its primary homeserver was
+/// `previous_primary` before, and announce the new list.
+fn touch(accounts: &mut EditHomeserversAccountConstraints, previous_primary: Option<String>) -> Result<()> {
+    let delegation = &mut accounts.delegation;
+    Delegation::begin_write(delegation)?;
+    delegation.updated_at = Clock::get()?.unix_timestamp;
+
+    emit!(DelegationRegistered {
+        owner: delegation.owner,
+        homeservers: delegation.homeservers.clone(),
+        updated_at: delegation.updated_at,
+        expires_at: delegation.expires_at,
+        label: delegation.label.clone(),
+        created: false,
+    });
+
+    recount_delegation(delegation, previous_primary, accounts.counted_stats.as_mut(), accounts.homeserver_stats.as_mut())
+}
+
+#[derive(Accounts)]
+pub struct EditHomeserversAccountConstraints<'info> {
+    #[account(
+        mut,
+        // Not seeded by the signer: a transferred delegation keeps its original seed.
+        has_one = owner,
+        realloc = Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE,
+        realloc::payer = owner,
+        realloc::zero = false,
+    )]
+    pub delegation: Account<'info, Delegation>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    /// The new primary homeserver's stats, to count the delegation in. Optional, and last
+    /// so callers that don't count can leave it out.
+    #[account(mut)]
+    pub homeserver_stats: Option<Account<'info, HomeserverStats>>,
+
+    /// The stats the delegation is counted in, if it is and its primary homeserver
+    /// changes. Optional, and last for the same reason.
+    #[account(mut)]
+    pub counted_stats: Option<Account<'info, HomeserverStats>>,
+}
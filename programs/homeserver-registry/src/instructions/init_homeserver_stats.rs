@@ -0,0 +1,88 @@
+use anchor_lang::prelude::*;
+
+use crate::errors::RegistryError;
+use crate::instructions::register::validate_homeserver;
+use crate::state::{Delegation, HomeserverStats};
+
+/// Create the stats account that counts delegations to `homeserver`, paid for by anyone,
+/// typically its operator. Delegations are only counted once their owners pass it to
+/// `register`.
+pub fn handle_init_homeserver_stats(context: Context<InitHomeserverStatsAccountConstraints>, homeserver: String) -> Result<()> {
+    validate_homeserver(&homeserver)?;
+
+    let stats = &mut context.accounts.homeserver_stats;
+    stats.homeserver = homeserver;
+    stats.delegations = 0;
+    stats.updated_at = Clock::get()?.unix_timestamp;
+    stats.bump = context.bumps.homeserver_stats;
+
+    Ok(())
+}
+
+/// Count `delegation` for the homeserver `stats` belongs to, which has to be its primary
+/// homeserver. A delegation already counted there isn't counted again, and one counted for
+/// another homeserver has to be taken off that count first.
+pub(crate) fn count_delegation(delegation: &mut Delegation, stats: &mut Account<HomeserverStats>) -> Result<()> {
+    require!(delegation.homeservers.first() == Some(&stats.homeserver), RegistryError::HomeserverStatsMismatch);
+    match delegation.counted_in {
+        Some(counted_in) if counted_in == stats.key() => return Ok(()),
+        Some(_) => return err!(RegistryError::CountedElsewhere),
+        None => {}
+    }
+
+    stats.delegations += 1;
+    stats.updated_at = Clock::get()?.unix_timestamp;
+    delegation.counted_in = Some(stats.key());
+    Ok(())
+}
+
+/// Take `delegation` off the count of the homeserver it was counted for, if any, which
+/// `stats` has to be.
+pub(crate) fn uncount_delegation(delegation: &mut Delegation, stats: Option<&mut Account<HomeserverStats>>) -> Result<()> {
+    let Some(counted_in) = delegation.counted_in else {
+        return Ok(());
+    };
+    let stats = stats.filter(|stats| stats.key() == counted_in).ok_or(RegistryError::MissingHomeserverStats)?;
+
+    stats.delegations = stats.delegations.saturating_sub(1);
+    stats.updated_at = Clock::get()?.unix_timestamp;
+    delegation.counted_in = None;
+    Ok(())
+}
+
+/// Keep the counts right after an instruction wrote `delegation`, whose primary homeserver
+/// was `previous_primary`. A delegation counted for a homeserver that is no longer its
+/// primary is taken off that count, which needs the `counted_stats` it is counted in, and
+/// given the new primary's `homeserver_stats` it is counted there instead.
+pub(crate) fn recount_delegation(delegation: &mut Delegation, previous_primary: Option<String>, counted_stats: Option<&mut Account<HomeserverStats>>, homeserver_stats: Option<&mut Account<HomeserverStats>>) -> Result<()> {
+    let primary = delegation.homeservers.first();
+    // Stats given for a homeserver that isn't the primary any more are also taken to mean
+    // the delegation moved, for delegations whose primary changed before it was recounted.
+    let moved = primary != previous_primary.as_ref() || counted_stats.as_ref().is_some_and(|stats| primary != Some(&stats.homeserver));
+    if moved {
+        uncount_delegation(delegation, counted_stats)?;
+    }
+    if let Some(stats) = homeserver_stats {
+        count_delegation(delegation, stats)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+#[instruction(homeserver: String)]
+pub struct InitHomeserverStatsAccountConstraints<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = HomeserverStats::DISCRIMINATOR.len() + HomeserverStats::INIT_SPACE,
+        seeds = [b"homeserver_stats", HomeserverStats::seed(&homeserver).as_ref()],
+        bump,
+    )]
+    pub homeserver_stats: Account<'info, HomeserverStats>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
@@ -0,0 +1,68 @@
+use anchor_lang::prelude::*;
+
+use crate::state::{Delegation, HomeserverStats};
+use crate::errors::RegistryError;
+use crate::events::DelegationMigrated;
+use crate::instructions::init_homeserver_stats::recount_delegation;
+use crate::instructions::register::validate_homeserver;
+
+/// Move the delegation's primary homeserver to `new_homeserver`, remembering the one it
+/// replaces as `previous_homeserver`.
+///
+/// Fallbacks are kept, except `new_homeserver` itself if it was one. Resolvers and
+/// clients that cached the old homeserver can then tell the wallet moved instead of
+/// finding it gone. Accounts created before `previous_homeserver` existed are grown to
+/// make room for it, paid for by the owner. A counted delegation moves to the new
+/// homeserver's count as with `register`.
+pub fn handle_migrate(context: Context<MigrateAccountConstraints>, new_homeserver: String) -> Result<()> {
+    validate_homeserver(&new_homeserver)?;
+
+    let accounts = context.accounts;
+    let delegation = &mut accounts.delegation;
+    // A deactivated delegation has nothing to move from; `register` reactivates it.
+    require!(!delegation.homeservers.is_empty(), RegistryError::NoHomeservers);
+    require!(delegation.homeservers[0] != new_homeserver, RegistryError::SameHomeserver);
+
+    Delegation::begin_write(delegation)?;
+    delegation.homeservers.retain(|homeserver| *homeserver != new_homeserver);
+    let previous_homeserver = std::mem::replace(&mut delegation.homeservers[0], new_homeserver.clone());
+    delegation.previous_homeserver = Some(previous_homeserver.clone());
+    delegation.updated_at = Clock::get()?.unix_timestamp;
+
+    emit!(DelegationMigrated {
+        owner: delegation.owner,
+        previous_homeserver: previous_homeserver.clone(),
+        homeserver: new_homeserver,
+        updated_at: delegation.updated_at,
+    });
+
+    recount_delegation(delegation, Some(previous_homeserver), accounts.counted_stats.as_mut(), accounts.homeserver_stats.as_mut())
+}
+
+#[derive(Accounts)]
+pub struct MigrateAccountConstraints<'info> {
+    #[account(
+        mut,
+        // Not seeded by the signer: a transferred delegation keeps its original seed.
+        has_one = owner,
+        realloc = Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE,
+        realloc::payer = owner,
+        realloc::zero = false,
+    )]
+    pub delegation: Account<'info, Delegation>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    /// The new primary homeserver's stats, to count the delegation in. Optional, and last
+    /// so callers that don't count can leave it out.
+    #[account(mut)]
+    pub homeserver_stats: Option<Account<'info, HomeserverStats>>,
+
+    /// The stats the delegation is counted in, if it is and its primary homeserver
+    /// changes. Optional, and last for the same reason.
+    #[account(mut)]
+    pub counted_stats: Option<Account<'info, HomeserverStats>>,
+}
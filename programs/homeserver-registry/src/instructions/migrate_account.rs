@@ -0,0 +1,72 @@
+use anchor_lang::prelude::*;
+use anchor_lang::system_program::{transfer, Transfer};
+
+use crate::events::DelegationUpgraded;
+use crate::state::Delegation;
+
+/// Rewrite a delegation in the layout this version of the program writes, after an
+/// upgrade. Not to be confused with `migrate`, which moves the delegation to another
+/// homeserver.
+///
+/// Every instruction that changes a delegation upgrades it anyway, so this is for
+/// delegations nobody is about to change, and for the ones [`Account`] can't load at all:
+/// accounts written by the first version of the program, which held a single homeserver
+/// string where the list now goes, and accounts from before versioning with a full list,
+/// which end before the newer fields. The account is read by hand, grown to the current
+/// size and written back with every field kept. Only the layout changes, so anyone can
+/// crank it, paying for the extra rent. A delegation that is already up to date is left
+/// alone.
+pub fn handle_migrate_account(context: Context<MigrateLayoutAccountConstraints>) -> Result<()> {
+    let account = context.accounts.delegation.to_account_info();
+    let mut delegation = Delegation::read_any_version(&account.try_borrow_data()?)?;
+    let previous_version = delegation.version;
+    if previous_version >= Delegation::VERSION {
+        return Ok(());
+    }
+
+    // Grow the account to the current size, topping up its rent from the payer. `realloc`
+    // can only be asked of accounts Anchor loads.
+    let space = Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE;
+    let rent = Rent::get()?.minimum_balance(space);
+    if rent > account.lamports() {
+        transfer(
+            CpiContext::new(
+                context.accounts.system_program.to_account_info(),
+                Transfer {
+                    from: context.accounts.payer.to_account_info(),
+                    to: account.clone(),
+                },
+            ),
+            rent - account.lamports(),
+        )?;
+    }
+    if account.data_len() < space {
+        account.resize(space)?;
+    }
+
+    delegation.migrate();
+    let mut data = account.try_borrow_mut_data()?;
+    data.fill(0);
+    delegation.try_serialize(&mut &mut data[..])?;
+
+    emit!(DelegationUpgraded {
+        owner: delegation.owner,
+        previous_version,
+        version: delegation.version,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct MigrateLayoutAccountConstraints<'info> {
+    /// CHECK: a delegation of this program in any layout, which `Account` can't load if it
+    /// predates the newer fields. The handler checks the discriminator.
+    #[account(mut, owner = crate::ID)]
+    pub delegation: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
@@ -1,5 +1,25 @@
+pub mod deactivate;
+pub mod edit_homeservers;
+pub mod init_homeserver_stats;
+pub mod migrate;
+pub mod migrate_account;
 pub mod register;
+pub mod register_delegated;
+pub mod register_for;
+pub mod set_auth_key;
+pub mod set_frozen;
+pub mod transfer_ownership;
 pub mod unregister;
 
+pub use deactivate::*;
+pub use edit_homeservers::*;
+pub use init_homeserver_stats::*;
+pub use migrate::*;
+pub use migrate_account::*;
 pub use register::*;
+pub use register_delegated::*;
+pub use register_for::*;
+pub use set_auth_key::*;
+pub use set_frozen::*;
+pub use transfer_ownership::*;
 pub use unregister::*;
@@ -1,22 +1,57 @@
 use anchor_lang::prelude::*;
 
-use crate::state::Delegation;
+use crate::state::{Delegation, HomeserverStats, MAX_HOMESERVERS, MAX_HOMESERVER_LENGTH, MAX_LABEL_LENGTH};
 use crate::errors::RegistryError;
+use crate::events::DelegationRegistered;
+use crate::instructions::init_homeserver_stats::recount_delegation;
 
 /// Register or update a homeserver delegation.
 ///
-/// The owner signs once to designate their homeserver. Calling again with a
-/// different homeserver overwrites the previous delegation.
-pub fn handle_register(context: Context<RegisterAccountConstraints>, homeserver: String) -> Result<()> {
-    require!(!homeserver.is_empty(), RegistryError::EmptyHomeserver);
-    require!(homeserver.len() <= 253, RegistryError::HomeserverTooLong);
-    require!(is_valid_hostname(&homeserver), RegistryError::InvalidHomeserver);
+/// The owner signs once to designate their homeservers, in priority order, optionally
+/// until `expires_at` and with a `label` for directories. Calling again overwrites the
+/// previous list, expiry and label. `register` with a single homeserver, no expiry and no
+/// label is the one-element case.
+///
+/// Passing the primary homeserver's `homeserver_stats` opts the delegation into its count,
+/// once however often it is registered again. A counted delegation whose primary changes
+/// needs the `counted_stats` it is counted in, to be taken off that count.
+pub fn handle_register(context: Context<RegisterAccountConstraints>, homeservers: Vec<String>, expires_at: Option<i64>, label: Option<String>) -> Result<()> {
+    let owner = context.accounts.owner.key();
+    let accounts = context.accounts;
+    let previous_primary = accounts.delegation.homeservers.first().cloned();
+    write_delegation(&mut accounts.delegation, owner, context.bumps.delegation, homeservers, expires_at, label)?;
+    recount_delegation(&mut accounts.delegation, previous_primary, accounts.counted_stats.as_mut(), accounts.homeserver_stats.as_mut())
+}
+
+/// Validate a registration and write it to `owner`'s delegation, emitting
+/// `DelegationRegistered`. Shared by the instructions that register on the owner's
+/// signature.
+pub(crate) fn write_delegation(delegation: &mut Account<Delegation>, owner: Pubkey, bump: u8, homeservers: Vec<String>, expires_at: Option<i64>, label: Option<String>) -> Result<()> {
+    validate_homeservers(&homeservers)?;
+    if let Some(label) = &label {
+        validate_label(label)?;
+    }
+    let now = Clock::get()?.unix_timestamp;
+    require!(expires_at.is_none_or(|expires_at| expires_at > now), RegistryError::ExpiryInPast);
+
+    // A freshly initialized account is still zeroed.
+    let created = delegation.owner == Pubkey::default();
+    Delegation::begin_write(delegation)?;
+    delegation.owner = owner;
+    delegation.homeservers = homeservers;
+    delegation.updated_at = now;
+    delegation.expires_at = expires_at;
+    delegation.label = label;
+    delegation.bump = bump;
 
-    let delegation = &mut context.accounts.delegation;
-    delegation.owner = context.accounts.owner.key();
-    delegation.homeserver = homeserver;
-    delegation.updated_at = Clock::get()?.unix_timestamp;
-    delegation.bump = context.bumps.delegation;
+    emit!(DelegationRegistered {
+        owner: delegation.owner,
+        homeservers: delegation.homeservers.clone(),
+        updated_at: now,
+        expires_at,
+        label: delegation.label.clone(),
+        created,
+    });
 
     Ok(())
 }
@@ -28,7 +63,9 @@ pub struct RegisterAccountConstraints<'info> {
         payer = owner,
         space = Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE,
         seeds = [b"delegation", owner.key().as_ref()],
-        bump
+        bump,
+        // An existing delegation may have been transferred to another wallet.
+        constraint = delegation.owner == Pubkey::default() || delegation.owner == owner.key() @ RegistryError::NotDelegationOwner
     )]
     pub delegation: Account<'info, Delegation>,
 
@@ -36,18 +73,157 @@ pub struct RegisterAccountConstraints<'info> {
     pub owner: Signer<'info>,
 
     pub system_program: Program<'info, System>,
+
+    /// The primary homeserver's stats, to count the delegation in. Optional, and last so
+    /// callers that don't count can leave it out.
+    #[account(mut)]
+    pub homeserver_stats: Option<Account<'info, HomeserverStats>>,
+
+    /// The stats the delegation is counted in, if it is and its primary homeserver
+    /// changes. Optional, and last for the same reason.
+    #[account(mut)]
+    pub counted_stats: Option<Account<'info, HomeserverStats>>,
 }
 
-/// Basic hostname validation: must contain at least one dot, no spaces,
-/// no protocol prefix, and only valid hostname characters.
+/// Check a homeserver list is non-empty, fits in the account, has no repeats and
+/// holds only valid homeservers.
+pub(crate) fn validate_homeservers(homeservers: &[String]) -> Result<()> {
+    require!(!homeservers.is_empty(), RegistryError::NoHomeservers);
+    require!(homeservers.len() <= MAX_HOMESERVERS, RegistryError::TooManyHomeservers);
+    for (index, homeserver) in homeservers.iter().enumerate() {
+        validate_homeserver(homeserver)?;
+        require!(!homeservers[..index].contains(homeserver), RegistryError::DuplicateHomeserver);
+    }
+
+    Ok(())
+}
+
+/// Check a homeserver is non-empty, fits in the account and looks like a hostname.
+pub(crate) fn validate_homeserver(homeserver: &str) -> Result<()> {
+    require!(!homeserver.is_empty(), RegistryError::EmptyHomeserver);
+    require!(homeserver.len() <= MAX_HOMESERVER_LENGTH, RegistryError::HomeserverTooLong);
+    require!(is_valid_hostname(homeserver), RegistryError::InvalidHomeserver);
+
+    Ok(())
+}
+
+/// Check a label is non-empty, fits in the account and has no control characters, which
+/// could break the line it is shown on.
+pub(crate) fn validate_label(label: &str) -> Result<()> {
+    require!(label.len() <= MAX_LABEL_LENGTH, RegistryError::LabelTooLong);
+    require!(!label.is_empty() && !label.chars().any(char::is_control), RegistryError::InvalidLabel);
+
+    Ok(())
+}
+
+/// Hostname validation, with an optional `:port`. The host must have at least two
+/// dot-separated labels of 1-63 letters, digits or hyphens, none starting or ending with
+/// a hyphen. Internationalized names must be given in their punycode (`xn--`) form. The
+/// port, if present, must be a number from 1 to 65535.
 fn is_valid_hostname(hostname: &str) -> bool {
-    if !hostname.contains('.') {
+    let (host, port) = match hostname.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (hostname, None),
+    };
+    if port.is_some_and(|port| !is_valid_port(port)) {
         return false;
     }
-    if hostname.contains(' ') || hostname.contains("://") {
-        return false;
+
+    host.len() <= MAX_HOMESERVER_LENGTH && host.contains('.') && host.split('.').all(is_valid_label)
+}
+
+/// One DNS label: 1-63 characters, alphanumerics and hyphens, no hyphen at either end.
+fn is_valid_label(label: &str) -> bool {
+    (1..=63).contains(&label.len())
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.chars().all(|character| character.is_ascii_alphanumeric() || character == '-')
+}
+
+/// A port number from 1 to 65535, written as plain digits.
+fn is_valid_port(port: &str) -> bool {
+    !port.is_empty() && port.chars().all(|character| character.is_ascii_digit()) && port.parse::<u16>().is_ok_and(|port| port > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_hostnames() {
+        for hostname in ["chat.example.com", "good.example.com:8448", "a.b", "xn--bcher-kva.example", "matrix-1.example.org", "chat.example.com:1", "chat.example.com:65535"] {
+            assert!(is_valid_hostname(hostname), "{hostname} should be valid");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_labels() {
+        let too_long_label = format!("{}.com", "a".repeat(64));
+        for hostname in ["a..b", "-bad.com", "bad-.com", ".example.com", "example.com.", "-.-", "localhost", "bücher.example", "chat_room.example.com", too_long_label.as_str()] {
+            assert!(!is_valid_hostname(hostname), "{hostname} should be invalid");
+        }
+        assert!(is_valid_hostname(&format!("{}.com", "a".repeat(63))));
+    }
+
+    #[test]
+    fn rejects_ambiguous_ports() {
+        for hostname in ["example.com:", "foo:bar:baz.com", "chat.example.com:8448:1", "chat.example.com::8448", ":8448", "chat.example.com:0", "chat.example.com:65536", "chat.example.com:+8448", "chat.example.com:84a8", "chat.example.com: 8448"] {
+            assert!(!is_valid_hostname(hostname), "{hostname} should be invalid");
+        }
+    }
+
+    #[test]
+    fn rejects_names_longer_than_dns_allows() {
+        let label = "a".repeat(63);
+        let too_long = [label.as_str(); 4].join(".") + ".example";
+        assert!(too_long.len() > MAX_HOMESERVER_LENGTH);
+        assert!(!is_valid_hostname(&too_long));
+        assert!(validate_homeserver(&too_long).is_err());
+    }
+
+    #[test]
+    fn length_limit_agrees_with_the_hostname_rules() {
+        // Three full labels and one of 61 characters make a hostname of exactly the limit.
+        let longest = format!("{0}.{0}.{0}.{1}", "a".repeat(63), "b".repeat(61));
+        assert_eq!(longest.len(), MAX_HOMESERVER_LENGTH);
+        let too_long = format!("{longest}b");
+        let longest_with_port = format!("{0}.{0}.{0}.{1}:8448", "a".repeat(63), "b".repeat(56));
+        assert_eq!(longest_with_port.len(), MAX_HOMESERVER_LENGTH);
+        // Characters outside ASCII take several bytes and the limit counts bytes, as the
+        // account stores them. "é" is two, so this name of 127 characters is 253 bytes and
+        // only the hostname rules refuse it, while one more "é" goes over the limit.
+        let multibyte = format!("{}.{}", "é".repeat(63), "é".repeat(63));
+        let multibyte_too_long = format!("{multibyte}é");
+
+        for (homeserver, expected) in [
+            (longest.as_str(), Ok(())),
+            (longest_with_port.as_str(), Ok(())),
+            (too_long.as_str(), Err(RegistryError::HomeserverTooLong.into())),
+            (&format!("{longest}:8448"), Err(RegistryError::HomeserverTooLong.into())),
+            (&multibyte, Err(RegistryError::InvalidHomeserver.into())),
+            (&multibyte_too_long, Err(RegistryError::HomeserverTooLong.into())),
+            ("bücher.example", Err(RegistryError::InvalidHomeserver.into())),
+        ] {
+            assert_eq!(validate_homeserver(homeserver), expected, "{homeserver}");
+            // Whatever passes the byte limit is ASCII, so its bytes are its characters.
+            if homeserver.len() <= MAX_HOMESERVER_LENGTH && is_valid_hostname(homeserver) {
+                assert_eq!(homeserver.chars().count(), homeserver.len());
+            }
+        }
+        assert_eq!((multibyte.chars().count(), multibyte.len()), (127, MAX_HOMESERVER_LENGTH));
+    }
+
+    #[test]
+    fn validates_labels() {
+        for label in ["Alice's DAO server", "Café ☕", &"a".repeat(MAX_LABEL_LENGTH)] {
+            assert!(validate_label(label).is_ok(), "{label:?} should be valid");
+        }
+
+        assert_eq!(validate_label(&"a".repeat(MAX_LABEL_LENGTH + 1)), Err(RegistryError::LabelTooLong.into()));
+        // 22 three-byte characters are 66 bytes, over the limit though only 22 characters.
+        assert_eq!(validate_label(&"☕".repeat(22)), Err(RegistryError::LabelTooLong.into()));
+        for label in ["", "Alice\nBob", "tab\there", "bell\u{7}", "\u{85}next line"] {
+            assert_eq!(validate_label(label), Err(RegistryError::InvalidLabel.into()), "{label:?} should be invalid");
+        }
     }
-    hostname
-        .chars()
-        .all(|character| character.is_ascii_alphanumeric() || character == '.' || character == '-' || character == ':')
 }
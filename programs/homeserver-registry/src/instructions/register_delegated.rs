@@ -0,0 +1,52 @@
+use anchor_lang::prelude::*;
+
+use crate::state::{Delegation, HomeserverStats};
+use crate::errors::RegistryError;
+use crate::instructions::init_homeserver_stats::recount_delegation;
+use crate::instructions::register::write_delegation;
+
+/// Register or update a delegation for an owner whose transaction someone else pays for,
+/// e.g. a DAO treasury onboarding its members in a batch.
+///
+/// Both wallets sign: the payer covers the rent and the owner authorizes the delegation,
+/// which is seeded by and belongs to the owner exactly as if it had used
+/// `register_homeservers`, counted in homeserver stats the same way too.
+pub fn handle_register_delegated(context: Context<RegisterDelegatedAccountConstraints>, homeservers: Vec<String>, expires_at: Option<i64>, label: Option<String>) -> Result<()> {
+    let owner = context.accounts.owner.key();
+    let accounts = context.accounts;
+    let previous_primary = accounts.delegation.homeservers.first().cloned();
+    write_delegation(&mut accounts.delegation, owner, context.bumps.delegation, homeservers, expires_at, label)?;
+    recount_delegation(&mut accounts.delegation, previous_primary, accounts.counted_stats.as_mut(), accounts.homeserver_stats.as_mut())
+}
+
+#[derive(Accounts)]
+pub struct RegisterDelegatedAccountConstraints<'info> {
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE,
+        seeds = [b"delegation", owner.key().as_ref()],
+        bump,
+        // An existing delegation may have been transferred to another wallet.
+        constraint = delegation.owner == Pubkey::default() || delegation.owner == owner.key() @ RegistryError::NotDelegationOwner
+    )]
+    pub delegation: Account<'info, Delegation>,
+
+    /// The wallet the delegation is for. Signs to authorize it but pays nothing.
+    pub owner: Signer<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    /// The new primary homeserver's stats, to count the delegation in. Optional, and last
+    /// so callers that don't count can leave it out.
+    #[account(mut)]
+    pub homeserver_stats: Option<Account<'info, HomeserverStats>>,
+
+    /// The stats the delegation is counted in, if it is and its primary homeserver
+    /// changes. Optional, and last for the same reason.
+    #[account(mut)]
+    pub counted_stats: Option<Account<'info, HomeserverStats>>,
+}
@@ -0,0 +1,127 @@
+use anchor_lang::prelude::*;
+use solana_instructions_sysvar::{load_current_index_checked, load_instruction_at_checked};
+use solana_sdk_ids::{ed25519_program, sysvar};
+
+use crate::state::{Delegation, HomeserverStats};
+use crate::errors::RegistryError;
+use crate::events::DelegationRegistered;
+use crate::instructions::init_homeserver_stats::recount_delegation;
+use crate::instructions::register::validate_homeserver;
+
+/// Size of one set of signature offsets in ed25519 precompile instruction data.
+const SIGNATURE_OFFSETS_LEN: usize = 14;
+
+/// Offset index meaning "this instruction" in ed25519 precompile instruction data.
+const CURRENT_INSTRUCTION: u16 = u16::MAX;
+
+/// Register or update a homeserver delegation on behalf of another wallet. The
+/// delegation is replaced with a one-element list holding `homeserver`, with no expiry
+/// or label.
+///
+/// A service signs and pays for the transaction. The owner authorizes the exact
+/// `(owner, homeserver)` binding by signing `authorization_message` off-chain, and the
+/// transaction checks that signature with an ed25519 precompile instruction placed
+/// directly before this one. The delegation is still seeded by the owner, so it is the
+/// same account the owner would create with `register`.
+///
+/// An authorization can be replayed until `expires_at`, so services should keep it short.
+/// Homeserver stats are kept as by `register`.
+pub fn handle_register_for(context: Context<RegisterForAccountConstraints>, owner: Pubkey, homeserver: String, expires_at: i64) -> Result<()> {
+    validate_homeserver(&homeserver)?;
+
+    let now = Clock::get()?.unix_timestamp;
+    require!(now <= expires_at, RegistryError::AuthorizationExpired);
+
+    let instructions = context.accounts.instructions.to_account_info();
+    let current_index = load_current_index_checked(&instructions)?;
+    require!(current_index > 0, RegistryError::MissingAuthorization);
+    let signature_check = load_instruction_at_checked(usize::from(current_index - 1), &instructions)?;
+    require_keys_eq!(signature_check.program_id, ed25519_program::ID, RegistryError::MissingAuthorization);
+
+    let message = authorization_message(&crate::ID, &owner, &homeserver, expires_at);
+    require!(checks_signature(&signature_check.data, &owner, &message), RegistryError::InvalidAuthorization);
+
+    let accounts = context.accounts;
+    let delegation = &mut accounts.delegation;
+    let created = delegation.owner == Pubkey::default();
+    let previous_primary = delegation.homeservers.first().cloned();
+    Delegation::begin_write(delegation)?;
+    delegation.owner = owner;
+    delegation.homeservers = vec![homeserver];
+    delegation.updated_at = now;
+    delegation.expires_at = None;
+    delegation.label = None;
+    delegation.bump = context.bumps.delegation;
+
+    emit!(DelegationRegistered {
+        owner,
+        homeservers: delegation.homeservers.clone(),
+        updated_at: now,
+        expires_at: None,
+        label: None,
+        created,
+    });
+
+    recount_delegation(delegation, previous_primary, accounts.counted_stats.as_mut(), accounts.homeserver_stats.as_mut())
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct RegisterForAccountConstraints<'info> {
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE,
+        seeds = [b"delegation", owner.as_ref()],
+        bump,
+        constraint = delegation.owner == Pubkey::default() || delegation.owner == owner @ RegistryError::NotDelegationOwner
+    )]
+    pub delegation: Account<'info, Delegation>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: the instructions sysvar, pinned by address.
+    #[account(address = sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    /// The new primary homeserver's stats, to count the delegation in. Optional, and last
+    /// so callers that don't count can leave it out.
+    #[account(mut)]
+    pub homeserver_stats: Option<Account<'info, HomeserverStats>>,
+
+    /// The stats the delegation is counted in, if it is and its primary homeserver
+    /// changes. Optional, and last for the same reason.
+    #[account(mut)]
+    pub counted_stats: Option<Account<'info, HomeserverStats>>,
+}
+
+/// The message an owner signs to let someone else register `homeserver` for them.
+/// It names the program so an authorization can't be used against another deployment.
+pub fn authorization_message(program_id: &Pubkey, owner: &Pubkey, homeserver: &str, expires_at: i64) -> Vec<u8> {
+    format!("Delegate {owner} to homeserver {homeserver}\nProgram: {program_id}\nExpires: {expires_at}").into_bytes()
+}
+
+/// Whether ed25519 precompile instruction data checks exactly one signature, by `owner`
+/// over `message`. The runtime has already verified the signature itself; this makes
+/// sure it is the signature we need. The key, signature and message must all be carried
+/// in the precompile instruction, so they can't be pointed at data elsewhere.
+fn checks_signature(data: &[u8], owner: &Pubkey, message: &[u8]) -> bool {
+    if data.len() < 2 + SIGNATURE_OFFSETS_LEN || data[0] != 1 {
+        return false;
+    }
+
+    let offset = |field: usize| u16::from_le_bytes([data[2 + field * 2], data[3 + field * 2]]);
+    let [_, signature_instruction, public_key_offset, public_key_instruction, message_offset, message_size, message_instruction] = [0, 1, 2, 3, 4, 5, 6].map(offset);
+
+    if [signature_instruction, public_key_instruction, message_instruction].iter().any(|&index| index != CURRENT_INSTRUCTION) {
+        return false;
+    }
+
+    let slice = |start: u16, len: usize| data.get(usize::from(start)..usize::from(start) + len);
+    slice(public_key_offset, 32) == Some(owner.as_ref())
+        && usize::from(message_size) == message.len()
+        && slice(message_offset, message.len()) == Some(message)
+}
@@ -0,0 +1,48 @@
+use anchor_lang::prelude::*;
+
+use crate::state::Delegation;
+use crate::errors::RegistryError;
+use crate::events::AuthKeyChanged;
+
+/// Allow `auth_pubkey` to sign logins for the delegation's owner, or with `None` stop
+/// allowing any key but the owner's own.
+///
+/// Homeservers that read the registry accept a login challenge signed by the auth key
+/// as a login of the owner, so the owner's spending key never has to sign for a website.
+/// Accounts created before the auth key existed are grown to make room for it, paid for
+/// by the owner.
+pub fn handle_set_auth_key(context: Context<SetAuthKeyAccountConstraints>, auth_pubkey: Option<Pubkey>) -> Result<()> {
+    let owner = context.accounts.owner.key();
+    require!(auth_pubkey.is_none_or(|auth_pubkey| auth_pubkey != owner && auth_pubkey != Pubkey::default()), RegistryError::InvalidAuthKey);
+
+    let delegation = &mut context.accounts.delegation;
+    Delegation::begin_write(delegation)?;
+    delegation.auth_pubkey = auth_pubkey;
+    delegation.updated_at = Clock::get()?.unix_timestamp;
+
+    emit!(AuthKeyChanged {
+        owner,
+        auth_pubkey,
+        updated_at: delegation.updated_at,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct SetAuthKeyAccountConstraints<'info> {
+    #[account(
+        mut,
+        // Not seeded by the signer: a transferred delegation keeps its original seed.
+        has_one = owner,
+        realloc = Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE,
+        realloc::payer = owner,
+        realloc::zero = false,
+    )]
+    pub delegation: Account<'info, Delegation>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
@@ -0,0 +1,43 @@
+use anchor_lang::prelude::*;
+
+use crate::state::Delegation;
+use crate::events::DelegationFrozen;
+
+/// Freeze the delegation, or with `false` unfreeze it.
+///
+/// Homeservers that read the registry refuse to log a frozen wallet in, wherever it is
+/// delegated, so an owner who fears a key was leaked can stop logins at once and keep its
+/// homeservers for when it unfreezes. Accounts created before freezing existed are grown
+/// to make room for it, paid for by the owner.
+pub fn handle_set_frozen(context: Context<SetFrozenAccountConstraints>, frozen: bool) -> Result<()> {
+    let delegation = &mut context.accounts.delegation;
+    Delegation::begin_write(delegation)?;
+    delegation.frozen = frozen;
+    delegation.updated_at = Clock::get()?.unix_timestamp;
+
+    emit!(DelegationFrozen {
+        owner: delegation.owner,
+        frozen,
+        updated_at: delegation.updated_at,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct SetFrozenAccountConstraints<'info> {
+    #[account(
+        mut,
+        // Not seeded by the signer: a transferred delegation keeps its original seed.
+        has_one = owner,
+        realloc = Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE,
+        realloc::payer = owner,
+        realloc::zero = false,
+    )]
+    pub delegation: Account<'info, Delegation>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
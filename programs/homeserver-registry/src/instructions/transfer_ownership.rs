@@ -0,0 +1,44 @@
+use anchor_lang::prelude::*;
+
+use crate::state::Delegation;
+use crate::errors::RegistryError;
+use crate::events::DelegationTransferred;
+
+/// Hand a delegation to `new_owner`, for users rotating to a new wallet.
+///
+/// The PDA stays seeded by the wallet that first registered it: seeds can't change
+/// without moving the account, and keeping it in place means anyone who derives the
+/// old address finds who it belongs to now rather than stale data. The tradeoff is that
+/// the new owner's own PDA stays empty, so it has to be looked up by this address, and
+/// updated with `update_homeservers` rather than `register`. The old wallet loses all
+/// control once the transfer lands.
+pub fn handle_transfer_ownership(context: Context<TransferOwnershipAccountConstraints>, new_owner: Pubkey) -> Result<()> {
+    // A zeroed owner is how `register` recognises a fresh account.
+    require_keys_neq!(new_owner, Pubkey::default(), RegistryError::InvalidNewOwner);
+
+    let delegation = &mut context.accounts.delegation;
+    Delegation::begin_write(delegation)?;
+    let previous_owner = delegation.owner;
+    delegation.owner = new_owner;
+    delegation.updated_at = Clock::get()?.unix_timestamp;
+
+    emit!(DelegationTransferred {
+        previous_owner,
+        new_owner,
+        updated_at: delegation.updated_at,
+    });
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct TransferOwnershipAccountConstraints<'info> {
+    #[account(
+        mut,
+        // Not seeded by the signer: a transferred delegation keeps its original seed.
+        has_one = owner,
+    )]
+    pub delegation: Account<'info, Delegation>,
+
+    pub owner: Signer<'info>,
+}
@@ -1,11 +1,24 @@
 use anchor_lang::prelude::*;
 
-use crate::state::Delegation;
+use crate::state::{Delegation, HomeserverStats};
+use crate::events::DelegationRemoved;
+use crate::instructions::init_homeserver_stats::uncount_delegation;
 
 /// Remove a homeserver delegation and reclaim the rent.
 ///
-/// Only the original owner can close their delegation account.
-pub fn handle_unregister(_context: Context<UnregisterAccountConstraints>) -> Result<()> {
+/// Only the current owner can close their delegation account (`has_one = owner`).
+/// `deactivate` is the alternative that keeps the account as a record. A delegation
+/// counted for a homeserver needs that homeserver's `homeserver_stats` to be taken off its
+/// count.
+pub fn handle_unregister(context: Context<UnregisterAccountConstraints>) -> Result<()> {
+    let accounts = context.accounts;
+    accounts.delegation.migrate();
+    uncount_delegation(&mut accounts.delegation, accounts.homeserver_stats.as_mut())?;
+
+    emit!(DelegationRemoved {
+        owner: accounts.owner.key(),
+    });
+
     Ok(())
 }
 
@@ -14,12 +27,16 @@ pub struct UnregisterAccountConstraints<'info> {
     #[account(
         mut,
         close = owner,
-        seeds = [b"delegation", owner.key().as_ref()],
-        bump = delegation.bump,
+        // Not seeded by the signer: a transferred delegation keeps its original seed.
         has_one = owner,
     )]
     pub delegation: Account<'info, Delegation>,
 
     #[account(mut)]
     pub owner: Signer<'info>,
+
+    /// The stats the delegation is counted in, if it is. Optional, and last so callers
+    /// that never counted can leave it out.
+    #[account(mut)]
+    pub homeserver_stats: Option<Account<'info, HomeserverStats>>,
 }
@@ -1,6 +1,9 @@
 use anchor_lang::prelude::*;
 
+#[cfg(any(feature = "client", test))]
+pub mod client;
 pub mod errors;
+pub mod events;
 pub mod instructions;
 pub mod state;
 
@@ -15,7 +18,80 @@ pub mod homeserver_registry {
     /// Register or update a homeserver delegation for the signing wallet.
     /// The PDA is derived from the wallet address, so each wallet gets one delegation.
     pub fn register(context: Context<RegisterAccountConstraints>, homeserver: String) -> Result<()> {
-        instructions::register::handle_register(context, homeserver)
+        instructions::register::handle_register(context, vec![homeserver], None, None)
+    }
+
+    /// Register or update a delegation to up to three homeservers, in priority order,
+    /// optionally expiring at the unix timestamp `expires_at` and with a short `label`
+    /// for directories to show.
+    pub fn register_homeservers(context: Context<RegisterAccountConstraints>, homeservers: Vec<String>, expires_at: Option<i64>, label: Option<String>) -> Result<()> {
+        instructions::register::handle_register(context, homeservers, expires_at, label)
+    }
+
+    /// Replace the homeserver list of an existing delegation, which may have been
+    /// transferred from the wallet that created it.
+    pub fn update_homeservers(context: Context<EditHomeserversAccountConstraints>, homeservers: Vec<String>) -> Result<()> {
+        instructions::edit_homeservers::handle_update_homeservers(context, homeservers)
+    }
+
+    /// Move one homeserver in the delegation to a new position in the priority order.
+    pub fn move_homeserver(context: Context<EditHomeserversAccountConstraints>, from: u8, to: u8) -> Result<()> {
+        instructions::edit_homeservers::handle_move_homeserver(context, from, to)
+    }
+
+    /// Remove one homeserver from the delegation, keeping the order of the rest.
+    pub fn remove_homeserver(context: Context<EditHomeserversAccountConstraints>, index: u8) -> Result<()> {
+        instructions::edit_homeservers::handle_remove_homeserver(context, index)
+    }
+
+    /// Move the delegation's primary homeserver to `new_homeserver`, recording the one it
+    /// replaces so resolvers can tell the wallet moved.
+    pub fn migrate(context: Context<MigrateAccountConstraints>, new_homeserver: String) -> Result<()> {
+        instructions::migrate::handle_migrate(context, new_homeserver)
+    }
+
+    /// Rewrite a delegation left by an older version of the program in the current layout.
+    /// Anyone can call it, paying for any extra rent.
+    pub fn migrate_account(context: Context<MigrateLayoutAccountConstraints>) -> Result<()> {
+        instructions::migrate_account::handle_migrate_account(context)
+    }
+
+    /// Register or update a homeserver delegation for another wallet, paid for by the
+    /// signer and authorized by the owner's ed25519 signature in the preceding instruction.
+    pub fn register_for(context: Context<RegisterForAccountConstraints>, owner: Pubkey, homeserver: String, expires_at: i64) -> Result<()> {
+        instructions::register_for::handle_register_for(context, owner, homeserver, expires_at)
+    }
+
+    /// Register or update a delegation like `register_homeservers`, with the rent paid by
+    /// a second signer. The owner still signs, and the delegation is still seeded by it.
+    pub fn register_delegated(context: Context<RegisterDelegatedAccountConstraints>, homeservers: Vec<String>, expires_at: Option<i64>, label: Option<String>) -> Result<()> {
+        instructions::register_delegated::handle_register_delegated(context, homeservers, expires_at, label)
+    }
+
+    /// Set or clear the separate key allowed to sign logins for the delegation's owner.
+    pub fn set_auth_key(context: Context<SetAuthKeyAccountConstraints>, auth_pubkey: Option<Pubkey>) -> Result<()> {
+        instructions::set_auth_key::handle_set_auth_key(context, auth_pubkey)
+    }
+
+    /// Freeze a delegation so homeservers refuse logins for its owner, or unfreeze it.
+    pub fn set_frozen(context: Context<SetFrozenAccountConstraints>, frozen: bool) -> Result<()> {
+        instructions::set_frozen::handle_set_frozen(context, frozen)
+    }
+
+    /// Hand a delegation to another wallet. The account keeps its original PDA.
+    pub fn transfer_ownership(context: Context<TransferOwnershipAccountConstraints>, new_owner: Pubkey) -> Result<()> {
+        instructions::transfer_ownership::handle_transfer_ownership(context, new_owner)
+    }
+
+    /// Create the account counting the delegations to `homeserver` that opt in to it.
+    pub fn init_homeserver_stats(context: Context<InitHomeserverStatsAccountConstraints>, homeserver: String) -> Result<()> {
+        instructions::init_homeserver_stats::handle_init_homeserver_stats(context, homeserver)
+    }
+
+    /// Clear a homeserver delegation but keep the account, recording when it was removed.
+    /// A later `register` reactivates it.
+    pub fn deactivate(context: Context<DeactivateAccountConstraints>) -> Result<()> {
+        instructions::deactivate::handle_deactivate(context)
     }
 
     /// Remove a homeserver delegation and reclaim rent.
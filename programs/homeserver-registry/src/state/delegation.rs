@@ -1,5 +1,21 @@
 use anchor_lang::prelude::*;
 
+/// Most homeservers a wallet can delegate to.
+pub const MAX_HOMESERVERS: usize = 3;
+
+/// Longest homeserver a delegation can list, in bytes, port included: the longest name
+/// DNS allows. Hostnames are ASCII, so this is also their length in characters.
+pub const MAX_HOMESERVER_LENGTH: usize = 253;
+
+/// Longest label a delegation can carry, in bytes of UTF-8.
+pub const MAX_LABEL_LENGTH: usize = 64;
+
+/// Space, after the discriminator, of a delegation written by the first version of the
+/// program, which held a single `homeserver` string where the list now goes and nothing
+/// after the bump: owner + string length + MAX_HOMESERVER_LENGTH bytes + updated_at + bump.
+/// Every later layout takes more, so an account of exactly this size is in the first one.
+pub const SINGLE_HOMESERVER_SPACE: usize = 32 + 4 + MAX_HOMESERVER_LENGTH + 8 + 1;
+
 /// Stores a wallet's homeserver delegation.
 ///
 /// PDA seeds: ["delegation", owner.key()]
@@ -10,13 +26,334 @@ pub struct Delegation {
     /// The wallet that owns this delegation.
     pub owner: Pubkey,
 
-    /// The homeserver URL (e.g. "chat.example.com").
-    #[max_len(253)]
-    pub homeserver: String,
+    /// The homeservers to reach the wallet on (e.g. "chat.example.com"), in priority
+    /// order. Clients try the first and fall back to the rest. Empty once the owner has
+    /// deactivated the delegation.
+    #[max_len(MAX_HOMESERVERS, MAX_HOMESERVER_LENGTH)]
+    pub homeservers: Vec<String>,
 
     /// Unix timestamp when the delegation was created or last updated.
     pub updated_at: i64,
 
     /// PDA bump seed for re-derivation.
     pub bump: u8,
+
+    /// Layout version, [`Delegation::VERSION`] once written by this program. New fields
+    /// go after this one, so older readers can keep decoding the prefix they know.
+    /// Accounts written before versioning read 0 here from the zeroed space the list
+    /// didn't use. Only an old account with a completely full list has no byte to spare,
+    /// and it has to be upgraded with `migrate_account` first, as does any account still
+    /// in the single-homeserver layout of [`SINGLE_HOMESERVER_SPACE`].
+    pub version: u8,
+
+    /// Unix timestamp after which the delegation no longer counts, if the owner gave one
+    /// (e.g. a temporary server for an event). Added in version 2.
+    pub expires_at: Option<i64>,
+
+    /// A short name for directory UIs to show instead of the hostname (e.g. "Alice's DAO
+    /// server"), if the owner gave one. Added in version 3.
+    #[max_len(MAX_LABEL_LENGTH)]
+    pub label: Option<String>,
+
+    /// A separate key the owner allows to sign logins for this wallet, so its spending
+    /// key never has to sign for a website. Logins signed by it are still logins of the
+    /// owner. Added in version 4.
+    pub auth_pubkey: Option<Pubkey>,
+
+    /// The primary homeserver the delegation was last migrated away from, so resolvers
+    /// can tell clients that cached it where the wallet went. Set by `migrate`. Added in
+    /// version 5.
+    #[max_len(MAX_HOMESERVER_LENGTH)]
+    pub previous_homeserver: Option<String>,
+
+    /// Set by the owner with `set_frozen` to stop homeservers that read the registry from
+    /// logging the wallet in, e.g. while it might be compromised, without giving up the
+    /// delegation. Added in version 6.
+    pub frozen: bool,
+
+    /// The [`HomeserverStats`](super::HomeserverStats) account the delegation is counted
+    /// in, if its owner opted in by passing one to `register`. It is taken off that count
+    /// again when the primary homeserver changes, or by `deactivate` or `unregister`.
+    /// Added in version 7.
+    pub counted_in: Option<Pubkey>,
+}
+
+impl Delegation {
+    /// The layout this program writes.
+    pub const VERSION: u8 = 7;
+
+    /// Bring an account written by an older version of the program up to date in place.
+    /// Called through [`Delegation::begin_write`].
+    pub fn migrate(&mut self) {
+        // Version 0 had no `version`, version 1 no `expires_at`, version 2 no `label` and
+        // version 3 no `auth_pubkey`, version 4 no `previous_homeserver`, version 5 no
+        // `frozen` and version 6 no `counted_in`. They read as zero from the unused space,
+        // which is already the right default: no expiry, no label, no auth key, no previous
+        // homeserver, not frozen and not counted.
+        if self.version < 4 {
+            // A label that shrank under version 3 can leave bytes behind that read as a key.
+            self.auth_pubkey = None;
+        }
+        if self.version < 5 {
+            // Accounts written by version 4 before stale bytes were cleared can hold some here.
+            self.previous_homeserver = None;
+        }
+        if self.version < 6 {
+            // Version 4 accounts can leave stale bytes here too, past the previous homeserver.
+            self.frozen = false;
+        }
+        if self.version < 7 {
+            // And here, past `frozen`.
+            self.counted_in = None;
+        }
+        if self.version < Self::VERSION {
+            self.version = Self::VERSION;
+        }
+    }
+
+    /// Read a delegation written by any version of the program, including one [`Account`]
+    /// can't load: an account in the first, single-homeserver layout, which is read as a
+    /// one-element list, or one from before versioning with a completely full list, which
+    /// ends before the fields added since. Fields the account has no bytes for read as their
+    /// defaults, and the caller still has to [`Delegation::migrate`] it.
+    pub fn read_any_version(data: &[u8]) -> Result<Self> {
+        let mut rest = data
+            .strip_prefix(Self::DISCRIMINATOR)
+            .ok_or(ErrorCode::AccountDiscriminatorMismatch)?;
+        let rest = &mut rest;
+        if rest.len() == SINGLE_HOMESERVER_SPACE {
+            return Ok(Self {
+                owner: read(rest)?,
+                homeservers: vec![read(rest)?],
+                updated_at: read(rest)?,
+                bump: read(rest)?,
+                version: 0,
+                expires_at: None,
+                label: None,
+                auth_pubkey: None,
+                previous_homeserver: None,
+                frozen: false,
+                counted_in: None,
+            });
+        }
+        Ok(Self {
+            owner: read(rest)?,
+            homeservers: read(rest)?,
+            updated_at: read(rest)?,
+            bump: read(rest)?,
+            version: read_or_default(rest)?,
+            expires_at: read_or_default(rest)?,
+            label: read_or_default(rest)?,
+            auth_pubkey: read_or_default(rest)?,
+            previous_homeserver: read_or_default(rest)?,
+            frozen: read_or_default(rest)?,
+            counted_in: read_or_default(rest)?,
+        })
+    }
+
+    /// Ready a delegation an instruction is about to change: [`Delegation::migrate`] it
+    /// and zero the account's data, which is written again in full when the instruction
+    /// ends. Otherwise a field that got shorter, like a cleared label, would leave bytes
+    /// behind the end of the delegation, where a field added by a later version would be
+    /// read from before `migrate` gets a chance to clear it. Called by every instruction
+    /// that writes a delegation.
+    pub fn begin_write(delegation: &mut Account<Self>) -> Result<()> {
+        delegation.migrate();
+        delegation.to_account_info().try_borrow_mut_data()?[Self::DISCRIMINATOR.len()..].fill(0);
+        Ok(())
+    }
+}
+
+fn read<T: AnchorDeserialize>(rest: &mut &[u8]) -> Result<T> {
+    T::deserialize(rest).map_err(|_| ErrorCode::AccountDidNotDeserialize.into())
+}
+
+/// A field added after the account was written reads as its default if the account ends
+/// before it.
+fn read_or_default<T: AnchorDeserialize + Default>(rest: &mut &[u8]) -> Result<T> {
+    if rest.is_empty() {
+        return Ok(T::default());
+    }
+    read(rest)
+}
+
+// owner + list length + MAX_HOMESERVERS × (string length + MAX_HOMESERVER_LENGTH bytes)
+// + updated_at + bump
+// + version + expires_at (option tag + i64) + label (option tag + string length +
+// MAX_LABEL_LENGTH bytes) + auth_pubkey (option tag + pubkey) + previous_homeserver
+// (option tag + string length + MAX_HOMESERVER_LENGTH bytes) + frozen + counted_in
+// (option tag + pubkey).
+// Rent is paid for the full list and label up front, so adding a fallback or a label later
+// never reallocates.
+const _: () = assert!(Delegation::INIT_SPACE == 32 + 4 + MAX_HOMESERVERS * (4 + MAX_HOMESERVER_LENGTH) + 8 + 1 + 1 + 1 + 8 + 1 + 4 + MAX_LABEL_LENGTH + 1 + 32 + 1 + 4 + MAX_HOMESERVER_LENGTH + 1 + 1 + 32);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_an_unversioned_account_as_version_zero() {
+        // Laid out as before `version` existed, in an account of the old size.
+        let mut data = Delegation::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(Pubkey::new_unique().as_ref());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(b"chat.example.com");
+        data.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+        data.push(254);
+        data.resize(Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE - 1 - 9 - 5 - MAX_LABEL_LENGTH - 33 - 5 - MAX_HOMESERVER_LENGTH - 1 - 33, 0);
+
+        let mut delegation = Delegation::try_deserialize(&mut data.as_slice()).unwrap();
+        assert_eq!(delegation.version, 0);
+        assert_eq!(delegation.expires_at, None);
+        assert_eq!(delegation.label, None);
+        assert_eq!(delegation.auth_pubkey, None);
+        assert_eq!(delegation.previous_homeserver, None);
+        assert!(!delegation.frozen);
+        assert_eq!(delegation.counted_in, None);
+        assert_eq!(delegation.homeservers, ["chat.example.com"]);
+        assert_eq!(delegation.bump, 254);
+
+        delegation.migrate();
+        assert_eq!(delegation.version, Delegation::VERSION);
+    }
+
+    #[test]
+    fn reads_an_unversioned_account_with_a_full_list() {
+        // Laid out as before `version` existed, with no byte to spare after the bump.
+        let owner = Pubkey::new_unique();
+        let homeservers = ["a", "b", "c"].map(|host| format!("{}.example.com", host.repeat(241)));
+        let mut data = Delegation::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(owner.as_ref());
+        data.extend_from_slice(&3u32.to_le_bytes());
+        for homeserver in &homeservers {
+            data.extend_from_slice(&(homeserver.len() as u32).to_le_bytes());
+            data.extend_from_slice(homeserver.as_bytes());
+        }
+        data.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+        data.push(254);
+        assert_eq!(data.len(), Delegation::DISCRIMINATOR.len() + 32 + 4 + MAX_HOMESERVERS * (4 + MAX_HOMESERVER_LENGTH) + 8 + 1);
+        assert!(Delegation::try_deserialize(&mut data.as_slice()).is_err());
+
+        let delegation = Delegation::read_any_version(&data).unwrap();
+        assert_eq!(delegation.owner, owner);
+        assert_eq!(delegation.homeservers, homeservers);
+        assert_eq!(delegation.updated_at, 1_700_000_000);
+        assert_eq!(delegation.bump, 254);
+        assert_eq!(delegation.version, 0);
+        assert_eq!(delegation.expires_at, None);
+        assert_eq!(delegation.label, None);
+        assert_eq!(delegation.auth_pubkey, None);
+        assert_eq!(delegation.previous_homeserver, None);
+        assert!(!delegation.frozen);
+        assert_eq!(delegation.counted_in, None);
+
+        let mut wrong_account = data.clone();
+        wrong_account[0] ^= 1;
+        assert!(Delegation::read_any_version(&wrong_account).is_err());
+    }
+
+    #[test]
+    fn reads_a_single_homeserver_account() {
+        // Laid out by the first version of the program, with one homeserver string where
+        // the list now goes, in an account of that version's size.
+        let owner = Pubkey::new_unique();
+        let mut data = Delegation::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(owner.as_ref());
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(b"chat.example.com");
+        data.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+        data.push(254);
+        data.resize(Delegation::DISCRIMINATOR.len() + SINGLE_HOMESERVER_SPACE, 0);
+        // The string's length would be read as the length of the list.
+        assert!(Delegation::try_deserialize(&mut data.as_slice()).is_err());
+
+        let mut delegation = Delegation::read_any_version(&data).unwrap();
+        assert_eq!(delegation.owner, owner);
+        assert_eq!(delegation.homeservers, ["chat.example.com"]);
+        assert_eq!(delegation.updated_at, 1_700_000_000);
+        assert_eq!(delegation.bump, 254);
+        assert_eq!(delegation.version, 0);
+        assert_eq!(delegation.expires_at, None);
+        assert_eq!(delegation.label, None);
+        assert_eq!(delegation.auth_pubkey, None);
+        assert_eq!(delegation.previous_homeserver, None);
+        assert!(!delegation.frozen);
+        assert_eq!(delegation.counted_in, None);
+
+        delegation.migrate();
+        assert_eq!(delegation.version, Delegation::VERSION);
+    }
+
+    #[test]
+    fn reads_a_current_account_the_same_as_anchor() {
+        let delegation = Delegation {
+            owner: Pubkey::new_unique(),
+            homeservers: vec!["chat.example.com".to_owned(), "backup.example.com".to_owned()],
+            updated_at: 1_700_000_000,
+            bump: 254,
+            version: Delegation::VERSION,
+            expires_at: Some(1_800_000_000),
+            label: Some("Alice's DAO server".to_owned()),
+            auth_pubkey: Some(Pubkey::new_unique()),
+            previous_homeserver: Some("old.example.com".to_owned()),
+            frozen: true,
+            counted_in: Some(Pubkey::new_unique()),
+        };
+        let mut data = Vec::new();
+        delegation.try_serialize(&mut data).unwrap();
+        data.resize(Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE, 0);
+
+        let read = Delegation::read_any_version(&data).unwrap();
+        assert_eq!(read.try_to_vec().unwrap(), delegation.try_to_vec().unwrap());
+    }
+
+    #[test]
+    fn migrating_drops_what_a_version_3_account_left_where_the_auth_key_goes() {
+        // A version 3 account whose label was cleared, leaving the start of the old label
+        // behind: a length of 1 followed by its text reads as a present key.
+        let mut data = Delegation::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(Pubkey::new_unique().as_ref());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(b"chat.example.com");
+        data.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+        data.extend_from_slice(&[254, 3, 0, 0]);
+        data.extend_from_slice(&[1, 0, 0, 0]);
+        data.extend_from_slice(&[b'a'; 29]);
+        data.resize(Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE, 0);
+
+        let mut delegation = Delegation::try_deserialize(&mut data.as_slice()).unwrap();
+        assert!(delegation.auth_pubkey.is_some());
+
+        delegation.migrate();
+        assert_eq!(delegation.auth_pubkey, None);
+        assert_eq!(delegation.version, Delegation::VERSION);
+    }
+
+    #[test]
+    fn migrating_drops_what_a_version_4_account_left_where_the_previous_homeserver_goes() {
+        // A version 4 account whose label was cleared, leaving the start of the old label
+        // behind, past the auth key, where it reads as a one-byte previous homeserver.
+        let mut data = Delegation::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(Pubkey::new_unique().as_ref());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(b"chat.example.com");
+        data.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+        data.extend_from_slice(&[254, 4, 0, 0, 0]);
+        data.extend_from_slice(&[1, 1, 0, 0, 0, b'a', 1, 1]);
+        data.resize(Delegation::DISCRIMINATOR.len() + Delegation::INIT_SPACE, 0);
+
+        let mut delegation = Delegation::try_deserialize(&mut data.as_slice()).unwrap();
+        assert_eq!(delegation.previous_homeserver.as_deref(), Some("a"));
+        assert!(delegation.frozen);
+        assert!(delegation.counted_in.is_some());
+
+        delegation.migrate();
+        assert_eq!(delegation.previous_homeserver, None);
+        assert!(!delegation.frozen);
+        assert_eq!(delegation.counted_in, None);
+        assert_eq!(delegation.version, Delegation::VERSION);
+    }
 }
@@ -0,0 +1,36 @@
+use anchor_lang::prelude::*;
+use solana_sha256_hasher::hash;
+
+use super::MAX_HOMESERVER_LENGTH;
+
+/// Counts the delegations whose owners chose to be counted for a homeserver, so its
+/// operator can tell how many wallets delegated to it without scanning every account.
+///
+/// PDA seeds: ["homeserver_stats", sha256(homeserver)]
+/// The hostname is hashed because it can be longer than a seed may be.
+#[derive(InitSpace)]
+#[account]
+pub struct HomeserverStats {
+    /// The homeserver counted, as delegations list it (e.g. "chat.example.com").
+    #[max_len(MAX_HOMESERVER_LENGTH)]
+    pub homeserver: String,
+
+    /// How many delegations are counted here.
+    pub delegations: u64,
+
+    /// Unix timestamp when the count last changed, or when the account was created.
+    pub updated_at: i64,
+
+    /// PDA bump seed for re-derivation.
+    pub bump: u8,
+}
+
+impl HomeserverStats {
+    /// The seed identifying `homeserver`'s stats: the SHA-256 of its name.
+    pub fn seed(homeserver: &str) -> [u8; 32] {
+        hash(homeserver.as_bytes()).to_bytes()
+    }
+}
+
+// homeserver (string length + MAX_HOMESERVER_LENGTH bytes) + delegations + updated_at + bump
+const _: () = assert!(HomeserverStats::INIT_SPACE == 4 + MAX_HOMESERVER_LENGTH + 8 + 8 + 1);
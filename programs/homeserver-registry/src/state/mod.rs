@@ -1,2 +1,4 @@
 pub mod delegation;
+pub mod homeserver_stats;
 pub use delegation::*;
+pub use homeserver_stats::*;
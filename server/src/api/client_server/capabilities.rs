@@ -25,5 +25,14 @@ pub async fn get_capabilities_route(
         available,
     };
 
+    if let Some(display_name) = services().globals.server_display_name() {
+        capabilities
+            .set(
+                "org.solana.server_display_name",
+                serde_json::json!({ "name": display_name }),
+            )
+            .expect("serialization can't fail");
+    }
+
     Ok(get_capabilities::v3::Response { capabilities })
 }
@@ -31,6 +31,7 @@ use std::{
 use tokio::sync::RwLock;
 use tracing::{error, info, warn};
 
+use super::solana_auth::{self, PowerAction};
 use crate::{
     service::pdu::{gen_event_id_canonical_json, PduBuilder},
     services, utils, Error, PduEvent, Result, Ruma,
@@ -336,6 +337,13 @@ pub async fn kick_user_route(
 ) -> Result<kick_user::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    solana_auth::require_power_action_signature(
+        sender_user,
+        body.sender_device.as_deref(),
+        &body.room_id,
+        PowerAction::Kick,
+    )?;
+
     let event: RoomMemberEventContent = serde_json::from_str(
         services()
             .rooms
@@ -408,6 +416,13 @@ pub async fn kick_user_route(
 pub async fn ban_user_route(body: Ruma<ban_user::v3::Request>) -> Result<ban_user::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    solana_auth::require_power_action_signature(
+        sender_user,
+        body.sender_device.as_deref(),
+        &body.room_id,
+        PowerAction::Ban,
+    )?;
+
     let event = if let Some(event) = services()
         .rooms
         .state_accessor
@@ -1,3 +1,4 @@
+use super::solana_auth;
 use crate::{service::pdu::PduBuilder, services, utils, Error, Result, Ruma};
 use ruma::{
     api::{
@@ -10,6 +11,7 @@ use ruma::{
         federation::{self, query::get_profile_information::v1::ProfileField},
     },
     events::{room::member::RoomMemberEventContent, StateEventType, TimelineEventType},
+    UserId,
 };
 use serde_json::value::to_raw_value;
 use std::sync::Arc;
@@ -24,6 +26,8 @@ pub async fn set_displayname_route(
 ) -> Result<set_display_name::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    solana_auth::check_displayname(sender_user, body.displayname.as_deref())?;
+
     services()
         .users
         .set_displayname(sender_user, body.displayname.clone())?;
@@ -325,3 +329,45 @@ pub async fn get_profile_route(
         displayname: services().users.displayname(&body.user_id)?,
     })
 }
+
+/// # `GET /_matrix/client/unstable/org.solana.auth/profile/{userId}`
+///
+/// The profile of a wallet account with the base58 address of its wallet, derived from
+/// the user ID. Clients that only know the user ID can show the address even after the
+/// display name was changed to something else. Remote users only get the address.
+pub async fn get_wallet_profile_route(user_id: &str) -> Result<serde_json::Value> {
+    if !services().globals.allow_solana_auth() {
+        return Err(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "Wallet login is not enabled on this server.",
+        ));
+    }
+
+    let user_id = UserId::parse(user_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid user ID."))?;
+    let address = solana_auth::localpart_to_base58(
+        user_id.localpart(),
+        services().globals.solana_localpart_namespace(),
+    )
+    .ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "This user is not a wallet account.",
+    ))?;
+
+    let mut profile = serde_json::json!({ "user_id": user_id, "address": address });
+    if user_id.server_name() == services().globals.server_name() {
+        if !services().users.exists(&user_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Profile was not found.",
+            ));
+        }
+        if let Some(displayname) = services().users.displayname(&user_id)? {
+            profile["displayname"] = displayname.into();
+        }
+        if let Some(avatar_url) = services().users.avatar_url(&user_id)? {
+            profile["avatar_url"] = avatar_url.to_string().into();
+        }
+    }
+    Ok(profile)
+}
@@ -1,16 +1,18 @@
 use super::{solana_auth, DEVICE_ID_LENGTH, TOKEN_LENGTH};
-use crate::{services, utils, Error, Result, Ruma};
+use crate::{service::solana::LoginFailure, services, utils, Error, Result, Ruma};
+use base64::{engine::general_purpose, Engine as _};
 use ruma::{
     api::client::{
         error::ErrorKind,
-        session::{get_login_types, login, logout, logout_all},
+        session::{get_login_types, login, logout, logout_all, refresh_token},
         uiaa::UserIdentifier,
     },
     events::room::message::RoomMessageEventContent,
-    UserId,
+    OwnedDeviceId, UserId,
 };
 use serde::Deserialize;
-use tracing::{info, warn};
+use std::net::IpAddr;
+use tracing::{info, warn, Instrument};
 
 #[derive(Debug, Deserialize)]
 struct Claims {
@@ -25,27 +27,42 @@ struct Claims {
 pub async fn get_login_types_route(
     _body: Ruma<get_login_types::v3::Request>,
 ) -> Result<get_login_types::v3::Response> {
+    Ok(get_login_types::v3::Response::new(login_types(
+        services().globals.solana_auth_ready(),
+        &services().globals.well_known_client(),
+        services().globals.server_name().as_str(),
+        services().globals.solana_challenge_statement(),
+    )))
+}
+
+fn login_types(
+    solana_auth_ready: bool,
+    client_url: &str,
+    server_name: &str,
+    statement: &str,
+) -> Vec<get_login_types::v3::LoginType> {
     let mut types = vec![
         get_login_types::v3::LoginType::Password(Default::default()),
         get_login_types::v3::LoginType::ApplicationService(Default::default()),
     ];
 
-    // Advertise Solana wallet authentication if enabled.
+    // Advertise Solana wallet authentication once it is fully wired up.
     // Clients see this in the login types response and know they can use
     // m.login.solana.signature. The nonce endpoint URL is included so clients
-    // know where to request challenges.
-    if services().globals.allow_solana_auth() {
+    // know where to request challenges, and a template of the message they'll sign
+    // so they can preview it.
+    if solana_auth_ready {
         // We use _unknown for custom login types since ruma doesn't have a variant for ours.
         // Matrix clients that understand Solana auth will look for this type string.
         types.push(get_login_types::v3::LoginType::_Custom(Box::new(
             get_login_types::v3::CustomLoginType {
                 type_: "m.login.solana.signature".to_owned(),
-                data: Default::default(),
+                data: solana_auth::login_type_data(client_url, server_name, statement),
             },
         )));
     }
 
-    Ok(get_login_types::v3::Response::new(types))
+    types
 }
 
 /// # `POST /_matrix/client/r0/login`
@@ -316,95 +333,211 @@ async fn handle_solana_login(
     body: &Ruma<login::v3::Request>,
     map: &std::collections::BTreeMap<String, ruma::CanonicalJsonValue>,
 ) -> Result<login::v3::Response> {
-    if !services().globals.allow_solana_auth() {
+    if !services().globals.solana_auth_ready() {
         return Err(Error::BadRequest(
             ErrorKind::Unknown,
             "Solana authentication is not enabled on this server.",
         ));
     }
 
-    // Extract the Solana-specific fields from the raw JSON body
-    let get_string = |key: &str| -> Result<String> {
-        match map.get(key) {
-            Some(ruma::CanonicalJsonValue::String(s)) => Ok(s.clone()),
-            _ => Err(Error::BadRequest(
-                ErrorKind::MissingParam,
-                "Missing required Solana auth field.",
-            )),
-        }
-    };
-
-    let solana_request = solana_auth::SolanaLoginRequest {
-        address: get_string("address")?,
-        signature: get_string("signature")?,
-        nonce: get_string("nonce")?,
-    };
+    // Read the Solana-specific fields from the raw JSON body
+    let raw_body = serde_json::to_value(map).expect("canonical JSON is valid JSON");
+    let mut solana_request = solana_auth::SolanaLoginRequest::from_login_body(
+        &raw_body,
+        body.device_id
+            .as_ref()
+            .map(|device_id| device_id.to_string()),
+        body.initial_device_display_name.clone(),
+    )
+    .inspect_err(|_| {
+        services()
+            .solana
+            .record_login_failure(LoginFailure::BadEncoding)
+    })?;
+    solana_request.client_ip = body.client_ip;
+    solana_request.refresh_token = body.refresh_token;
 
-    // Verify the wallet signature and get the hex localpart + base58 display name
-    let (hex_localpart, base58_address) = solana_auth::verify_solana_login(&solana_request)?;
+    complete_solana_login(solana_request).await
+}
 
-    // Build the Matrix user ID: @<64-char-hex>:server
-    let user_id = UserId::parse_with_server_name(
-        hex_localpart,
-        services().globals.server_name(),
-    )
-    .map_err(|_| Error::BadRequest(ErrorKind::InvalidUsername, "Generated username is invalid."))?;
+/// Verify a Solana login request and sign the wallet in, creating its account on first
+/// login. Shared by `m.login.solana.signature` and the combined challenge endpoint.
+///
+/// Everything logged along the way is in the login's [`solana_auth::login_span`].
+pub async fn complete_solana_login(
+    solana_request: solana_auth::SolanaLoginRequest,
+) -> Result<login::v3::Response> {
+    traced_sign_in(solana_request, false).await
+}
 
-    let is_new_user = !services().users.exists(&user_id)?;
+/// Verify a Solana login request for a device the wallet's account already has, and
+/// give it a new access token, e.g. after the old one was lost. Unlike a login it never
+/// creates an account or a device, see [`solana_auth::check_rotation`].
+pub async fn rotate_solana_device_token(
+    solana_request: solana_auth::SolanaLoginRequest,
+) -> Result<login::v3::Response> {
+    traced_sign_in(solana_request, true).await
+}
 
-    if is_new_user {
-        // Create the account with no password (wallet-only auth)
-        services().users.create(&user_id, None)?;
+async fn traced_sign_in(
+    solana_request: solana_auth::SolanaLoginRequest,
+    existing_device_only: bool,
+) -> Result<login::v3::Response> {
+    let span = solana_auth::login_span(&solana_request.address, &solana_request.nonce);
+    let result = sign_in_wallet(solana_request, existing_device_only)
+        .instrument(span.clone())
+        .await;
+    solana_auth::record_login_outcome(&span, &result);
+    result
+}
 
-        // Set display name to the human-readable base58 address
+async fn sign_in_wallet(
+    solana_request: solana_auth::SolanaLoginRequest,
+    existing_device_only: bool,
+) -> Result<login::v3::Response> {
+    // Verify the wallet signature and get its public key + base58 display name
+    let verified = match solana_auth::verify_solana_login(&solana_request).await {
+        Ok(verified) => verified,
+        Err(error) => {
+            // Only wallets that exist are counted, by key, so junk addresses can't fill the map
+            if let Ok(pubkey) = solana_auth::decode_address(&solana_request.address) {
+                services().solana.record_failure(&pubkey);
+            }
+            services()
+                .solana
+                .record_login_failure(solana_auth::failure_category(&error));
+            return Err(solana_auth::with_password_fallback(
+                &solana_request.address,
+                error,
+            ));
+        }
+    };
+    services().solana.clear_failures(&verified.pubkey);
+
+    // A blocked wallet is refused before its account is looked up or created
+    services()
+        .solana
+        .blocked_wallets
+        .check(&verified.pubkey)
+        .inspect_err(|_| {
+            services()
+                .solana
+                .record_login_failure(LoginFailure::GatingDenied)
+        })?;
+
+    // Build the Matrix user ID: @solana_<64-char-hex>:server, or the account the wallet
+    // was linked to
+    let (user_id, user_exists) = services().solana.resolve_user(&verified)?;
+    let solana_auth::VerifiedSolanaLogin {
+        pubkey,
+        base58_address,
+        scopes,
+    } = verified;
+
+    let requested_device_id: Option<OwnedDeviceId> = solana_request.device_id.map(Into::into);
+    let device_exists = requested_device_id.as_ref().is_some_and(|device_id| {
         services()
             .users
-            .set_displayname(&user_id, Some(base58_address.clone()))?;
+            .all_device_ids(&user_id)
+            .any(|x| x.as_ref().is_ok_and(|v| v == device_id))
+    });
+    if existing_device_only {
+        solana_auth::check_rotation(requested_device_id.as_deref(), device_exists)?;
+    }
 
-        // Set up default push rules
-        services().account_data.update(
-            None,
-            &user_id,
-            ruma::events::GlobalAccountDataEventType::PushRules
-                .to_string()
-                .into(),
-            &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
-                content: ruma::events::push_rules::PushRulesEventContent {
-                    global: ruma::push::Ruleset::server_default(&user_id),
-                },
+    // If registry enforcement is on, the on-chain delegation of the wallet or one of the
+    // account's linked wallets must point here. A trusted edge proxy may pass the
+    // signing wallet's already-fetched account as base64 `delegation`.
+    let supplied_delegation = solana_request
+        .delegation
+        .as_deref()
+        .map(|encoded| {
+            general_purpose::STANDARD.decode(encoded).map_err(|_| {
+                Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Invalid base64 delegation account.",
+                )
             })
-            .expect("serialization can't fail"),
-        )?;
-
-        info!("New Solana user registered: {} ({})", base58_address, user_id);
-
-        services()
-            .admin
-            .send_message(RoomMessageEventContent::notice_plain(format!(
-                "New Solana user {} registered ({}).",
-                base58_address, user_id
-            )));
-
-        // TODO: Auto-join lobby room. For MVP, create a public room manually
-        // and users will find it via the room directory. The solana_auto_join_room
-        // config is reserved for future use once we wire up proper room joining.
+        })
+        .transpose()?;
+    // Policy refusals count towards the failure summary; RPC and database errors don't.
+    let denied = |error: Error| {
+        if matches!(error, Error::BadRequest(..)) {
+            services()
+                .solana
+                .record_login_failure(LoginFailure::GatingDenied);
+        }
+        error
+    };
+    // On a private server only allowlisted wallets get in, however they signed.
+    services()
+        .solana
+        .check_allowed_address(&pubkey)
+        .map_err(denied)?;
+    services()
+        .solana_registry
+        .enforce(
+            &pubkey,
+            &services().users.linked_wallets(&user_id)?,
+            supplied_delegation.as_deref(),
+        )
+        .await
+        .map_err(denied)?;
+    services()
+        .solana_registry
+        .require_recent_activity(&pubkey)
+        .await
+        .map_err(denied)?;
+    services()
+        .solana_registry
+        .require_collection(&pubkey)
+        .await
+        .map_err(denied)?;
+    services()
+        .solana_registry
+        .require_balance(&pubkey, !user_exists)
+        .await
+        .map_err(denied)?;
+
+    solana_auth::check_recreation_cooldown(&user_id).map_err(denied)?;
+
+    // Two first logins of the same wallet can both have found no account. Whichever gets
+    // the lock creates it, and the other finds it once it gets the lock and just logs in.
+    let is_new_user = !user_exists
+        && services()
+            .solana
+            .signup_locks
+            .create_once(
+                &user_id,
+                || services().users.exists(&user_id),
+                || {
+                    create_wallet_account(
+                        &user_id,
+                        &pubkey,
+                        &base58_address,
+                        solana_request.client_ip,
+                    )
+                },
+            )
+            .await?;
+
+    // Set display name to the human-readable base58 address, or the configured template,
+    // unless the user has picked their own. Accounts the wallet was linked to keep theirs.
+    if !is_new_user
+        && solana_auth::localpart_to_pubkey(
+            user_id.localpart(),
+            services().globals.solana_localpart_namespace(),
+        ) == Some(pubkey)
+    {
+        solana_auth::apply_default_displayname(&user_id, &base58_address, None)?;
     }
 
     // Generate device and token (same as standard login)
-    let device_id = body
-        .device_id
-        .clone()
-        .unwrap_or_else(|| utils::random_string(DEVICE_ID_LENGTH).into());
+    let device_id =
+        requested_device_id.unwrap_or_else(|| utils::random_string(DEVICE_ID_LENGTH).into());
 
     let token = utils::random_string(TOKEN_LENGTH);
 
-    let device_exists = body.device_id.as_ref().is_some_and(|device_id| {
-        services()
-            .users
-            .all_device_ids(&user_id)
-            .any(|x| x.as_ref().is_ok_and(|v| v == device_id))
-    });
-
     if device_exists {
         services().users.set_token(&user_id, &device_id, &token)?;
     } else {
@@ -412,11 +545,30 @@ async fn handle_solana_login(
             &user_id,
             &device_id,
             &token,
-            body.initial_device_display_name.clone(),
+            solana_request.initial_device_display_name,
         )?;
     }
+    services().users.start_session(&user_id, &device_id)?;
+    services()
+        .solana
+        .sessions
+        .set_scopes(&user_id, &device_id, &scopes)?;
+    let (refresh_token, expires_in) = solana_auth::start_expiring_session(
+        &user_id,
+        &device_id,
+        &token,
+        solana_request.refresh_token,
+    )?;
 
     info!("{} logged in via Solana wallet", user_id);
+    services().solana.metrics.login_succeeded(is_new_user);
+    services().admin.send_login_notice(
+        services().globals.solana_notify_on_login(),
+        &user_id,
+        &base58_address,
+        &device_id,
+        solana_request.client_ip,
+    );
 
     #[allow(deprecated)]
     Ok(login::v3::Response {
@@ -424,10 +576,116 @@ async fn handle_solana_login(
         access_token: token,
         home_server: Some(services().globals.server_name().to_owned()),
         device_id,
-        well_known: None,
-        refresh_token: None,
-        expires_in: None,
+        well_known: solana_auth::login_discovery(),
+        refresh_token,
+        expires_in,
+    })
+}
+
+/// Create the account of a wallet's first login: default push rules, a display name from
+/// its SNS domain, the base58 address or the configured template, the signup notice, and
+/// the configured rooms. Runs under the account's signup lock, see
+/// [`SignupLocks::create_once`](crate::service::solana::SignupLocks::create_once).
+async fn create_wallet_account(
+    user_id: &UserId,
+    pubkey: &[u8; 32],
+    base58_address: &str,
+    client_ip: Option<IpAddr>,
+) -> Result<()> {
+    // Create the account with no password (wallet-only auth)
+    services().users.create(user_id, None)?;
+
+    // Set up default push rules
+    services().account_data.update(
+        None,
+        user_id,
+        ruma::events::GlobalAccountDataEventType::PushRules
+            .to_string()
+            .into(),
+        &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+            content: ruma::events::push_rules::PushRulesEventContent {
+                global: ruma::push::Ruleset::server_default(user_id),
+            },
+        })
+        .expect("serialization can't fail"),
+    )?;
+
+    info!(
+        "New Solana user registered: {} ({})",
+        base58_address, user_id
+    );
+
+    // Name the account after the wallet's SNS domain rather than its address, if
+    // `solana_resolve_sns` is on and it has one.
+    let sns_name = services().solana_registry.sns_name(pubkey).await;
+
+    services()
+        .admin
+        .send_signup_notice(RoomMessageEventContent::notice_plain(
+            solana_auth::signup_notice(
+                base58_address,
+                user_id,
+                utils::millis_since_unix_epoch(),
+                client_ip
+                    .and_then(|ip| services().solana.geo.country(ip))
+                    .as_deref(),
+            ),
+        ));
+
+    solana_auth::apply_default_displayname(user_id, base58_address, sns_name.as_deref())?;
+
+    // Join after the display name is set, so the membership event carries it
+    solana_auth::auto_join(user_id).await;
+    Ok(())
+}
+
+/// # `POST /_matrix/client/v3/refresh`
+///
+/// Swap a refresh token for a new access token and refresh token.
+///
+/// - Only wallet logins get refresh tokens, when `solana_session_ttl` is set
+/// - Invalidates the old access token and refresh token
+pub async fn refresh_token_route(
+    body: Ruma<refresh_token::v3::Request>,
+) -> Result<refresh_token::v3::Response> {
+    let (user_id, device_id) = solana_auth::redeem_refresh_token(&body.refresh_token)?;
+
+    let token = utils::random_string(TOKEN_LENGTH);
+    services().users.set_token(&user_id, &device_id, &token)?;
+    let (refresh_token, expires_in) =
+        solana_auth::start_expiring_session(&user_id, &device_id, &token, true)?;
+
+    Ok(refresh_token::v3::Response {
+        access_token: token,
+        refresh_token,
+        expires_in,
     })
 }
 
-// Auto-join will be implemented in v2 using the membership service.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advertises_solana(types: &[get_login_types::v3::LoginType]) -> bool {
+        types.iter().any(|login_type| {
+            matches!(
+                login_type,
+                get_login_types::v3::LoginType::_Custom(custom)
+                    if custom.type_ == "m.login.solana.signature"
+                        && custom.data.contains_key("nonce_url")
+            )
+        })
+    }
+
+    #[test]
+    fn advertises_solana_login_only_when_ready() {
+        let types = login_types(true, "https://matrix.example.com", "example.com", "");
+        assert!(advertises_solana(&types));
+
+        // `solana_auth_ready` is false when auth is enabled but the nonce route was never
+        // registered, and then clients aren't offered a login they can't complete.
+        let types = login_types(false, "https://matrix.example.com", "example.com", "");
+        assert!(!advertises_solana(&types));
+        assert_eq!(types.len(), 2);
+    }
+}
@@ -1,37 +1,169 @@
 //! Solana wallet authentication for Matrix.
 //!
 //! Users log in by signing a challenge message with their Solana wallet's ed25519 key.
-//! The Matrix localpart is the hex-encoded 32-byte public key (always 64 lowercase hex chars).
+//! The Matrix localpart is "solana_" followed by the hex-encoded 32-byte public key.
 //! The display name is set to the base58 address so users see the familiar Solana format.
 
 use std::{
-    collections::HashMap,
-    sync::Mutex,
-    time::{Duration, Instant},
+    collections::HashSet,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
 };
 
-use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, SecondsFormat};
+use ed25519_dalek::{Signature, VerifyingKey};
+use ruma::{
+    api::client::session::login,
+    events::{
+        room::member::{MembershipState, RoomMemberEventContent},
+        StateEventType,
+    },
+    DeviceId, OwnedDeviceId, OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName, OwnedUserId, RoomId,
+    ServerName, UserId,
+};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use sha2::{Digest, Sha256, Sha512};
+use tracing::{info, warn};
+
+use crate::{
+    config::DisplaynameGuard,
+    service::solana::{
+        login_fingerprint, ChallengeFormat, LoginFailure, Redemption, StoredNonce,
+        REISSUE_GRACE_MILLIS,
+    },
+    services, utils, Error, Result,
+};
+
+// Errors from `verify_solana_login` that `failure_category` tells apart.
+const UNKNOWN_NONCE: &str = "Nonce not found or already used.";
+pub const EXPIRED_NONCE: &str = "Nonce has expired.";
+const WRONG_ADDRESS: &str = "Nonce was issued for a different address.";
+const WRONG_DEVICE: &str = "Nonce was issued for a different device.";
+const WRONG_DEVICE_NAME: &str = "Device display name does not match the signed challenge.";
+const WRONG_SCOPES: &str = "Scopes do not match the signed challenge.";
+const WRONG_ORIGIN: &str = "Nonce was issued for a different origin.";
+const BAD_SIGNATURE: &str = "Signature verification failed.";
+const WRONG_DOMAIN: &str = "Sign In With Solana message is for a different domain.";
+const WRONG_SERVER: &str = "Challenge was shown for a different server.";
+const STALE_SIGNATURE: &str = "Signature is too old; sign the challenge again.";
+const FUTURE_SIGNATURE: &str = "Signature is dated in the future; check the device clock.";
+const UNSIGNED_SIGNED_AT: &str = "signed_at can only be used when signing the plain challenge.";
 
-use crate::{services, Error, Result};
+// Errcodes clients can act on, e.g. by requesting a new nonce when theirs expired.
+const ERRCODE_UNKNOWN_NONCE: &str = "M_SOLANA_UNKNOWN_NONCE";
+pub const ERRCODE_NONCE_EXPIRED: &str = "M_SOLANA_NONCE_EXPIRED";
+const ERRCODE_CHALLENGE_MISMATCH: &str = "M_SOLANA_CHALLENGE_MISMATCH";
+const ERRCODE_BAD_SIGNATURE: &str = "M_SOLANA_BAD_SIGNATURE";
 
-/// How long a nonce is valid after creation.
-const NONCE_TTL: Duration = Duration::from_secs(300); // 5 minutes
+/// How far ahead of the server's clock a login's `signed_at` may be, for devices whose
+/// clock runs a little fast.
+const SIGNED_AT_TOLERANCE_MILLIS: u64 = 5 * 1000;
 
-/// Maximum number of stored nonces before we prune expired ones.
-const MAX_NONCES: usize = 10_000;
+/// Where the nonce challenge endpoint is mounted. Advertised in the login types response.
+pub const NONCE_PATH: &str = "/_matrix/client/unstable/org.solana.auth/nonce";
 
-/// In-memory nonce store. Each nonce can only be used once.
-/// In production you'd want this in the database, but for MVP this is fine.
-static NONCES: std::sync::LazyLock<Mutex<HashMap<String, Instant>>> =
-    std::sync::LazyLock::new(|| Mutex::new(HashMap::new()));
+/// State event (empty state key) that makes a room require a fresh wallet signature for
+/// each [`PowerAction`], with content `{"required": true}`.
+pub const POWER_ACTION_SIGNATURES: &str = "org.solana.power_action_signatures";
 
 /// Request body for the nonce challenge endpoint.
 #[derive(Debug, Deserialize)]
 pub struct NonceRequest {
     /// The Solana public key (base58-encoded) requesting a challenge.
     pub address: String,
+    /// Optional device the challenge is for. When set, only a login for the
+    /// same device can consume the nonce, so concurrent logins from several
+    /// devices of one wallet each get an independent challenge.
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// Optional device display name to include in the signed message. A login using
+    /// this nonce must then ask for exactly this name, so it can't be changed in transit.
+    #[serde(default)]
+    pub initial_device_display_name: Option<String>,
+    /// Which message to sign: `"legacy"` (the default) for our plaintext challenge, or
+    /// `"siws"` for a Sign In With Solana message that wallets display as a sign-in.
+    #[serde(default)]
+    pub format: ChallengeFormat,
+    /// Optional rooms or other scopes the session is being granted. They are listed in
+    /// the signed message, and a device that signs in with any can only make requests
+    /// whose path names a room if it is one of them, see [`check_scoped_request`].
+    /// Requests that name no room, like `/sync`, aren't limited.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Optional web origin the client runs on, e.g. "https://chat.example.com". Only a
+    /// login reporting the same origin can use the nonce, and `solana_allowed_origins`
+    /// limits which origins may ask for one.
+    #[serde(default)]
+    pub origin: Option<String>,
+}
+
+/// What `solana_allowed_origins` lists to allow clients that report no origin, like
+/// native apps and scripts.
+pub const NATIVE_ORIGIN: &str = "native";
+
+/// Most scopes one challenge can list.
+const MAX_SCOPES: usize = 32;
+
+/// Request body for the combined challenge endpoint, for scripts that want one URL.
+/// Send `address` (and optionally `device_id`) to get a challenge, then `session` and
+/// `signature` to log in.
+#[derive(Debug, Deserialize)]
+pub struct ChallengeLoginRequest {
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// The `session` returned with the challenge.
+    #[serde(default)]
+    pub session: Option<String>,
+    /// Base58 signature over the challenge `message`.
+    #[serde(default)]
+    pub signature: Option<String>,
+    #[serde(default)]
+    pub initial_device_display_name: Option<String>,
+    #[serde(default)]
+    pub format: ChallengeFormat,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+/// Request body for the step-up endpoint: a signature over a challenge that was issued
+/// for the signing device.
+#[derive(Debug, Deserialize)]
+pub struct StepUpRequest {
+    pub nonce: String,
+    /// Base58 signature over the challenge `message`.
+    pub signature: String,
+}
+
+/// Request body for the `link_solana_wallet` endpoint: a signature over a challenge that
+/// was issued to the linking device for the wallet being linked.
+#[derive(Debug, Deserialize)]
+pub struct LinkWalletRequest {
+    pub nonce: String,
+    /// Base58 signature over the challenge `message`.
+    pub signature: String,
+}
+
+/// Request body for the `rotate_solana_device_token` endpoint: a signature over a
+/// challenge that was issued for the device getting a new access token.
+#[derive(Debug, Deserialize)]
+pub struct RotateDeviceTokenRequest {
+    pub nonce: String,
+    /// Base58 signature over the challenge `message`.
+    pub signature: String,
+    /// Whether the client supports refresh tokens, as in a login body.
+    #[serde(default)]
+    pub refresh_token: bool,
+}
+
+/// Content of the [`POWER_ACTION_SIGNATURES`] state event.
+#[derive(Deserialize)]
+struct PowerActionSignatures {
+    #[serde(default)]
+    required: bool,
 }
 
 /// Response body for the nonce challenge endpoint.
@@ -49,135 +181,4300 @@ pub struct SolanaLoginRequest {
     pub address: String,
     /// Base58-encoded ed25519 signature (64 bytes).
     pub signature: String,
+    /// How `signature` was produced. Only ever what the client asked for.
+    #[serde(default)]
+    pub signature_scheme: SignatureScheme,
     /// The nonce that was signed.
     pub nonce: String,
+    /// The device being logged in, if the client supplied one.
+    pub device_id: Option<String>,
+    /// Base64 `Delegation` account supplied by a trusted proxy, if any.
+    pub delegation: Option<String>,
+    /// Display name for a newly created device.
+    pub initial_device_display_name: Option<String>,
+    /// The exact Sign In With Solana message the wallet signed, when it logged in with the
+    /// wallet-standard `signIn` feature instead of signing our challenge text.
+    pub signed_message: Option<Vec<u8>>,
+    /// The server name the client showed the user in the challenge, if it reports one.
+    pub domain: Option<String>,
+    /// The web origin the client runs on, if it reports one. Has to be the origin the
+    /// nonce was requested from.
+    pub origin: Option<String>,
+    /// The IP the login request came from, when known. Only used for notices and to rate
+    /// limit challenges issued in place of an expired nonce.
+    pub client_ip: Option<IpAddr>,
+    /// Whether the client supports refresh tokens, from the login body's `refresh_token`.
+    pub refresh_token: bool,
+    /// When the wallet signed, in milliseconds since the unix epoch, from the login body's
+    /// `signed_at`. The challenge then states it, and the login is refused once it is
+    /// older than `solana_signature_max_age`.
+    pub signed_at: Option<u64>,
+}
+
+/// How a login signature covers the challenge, from the login body's `signature_scheme`.
+/// Each scheme is checked by its own [`WalletVerifier`]; unknown names are refused.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum SignatureScheme {
+    /// Ed25519 over the message itself, as software wallets sign (`ed25519`).
+    #[default]
+    #[serde(rename = "ed25519")]
+    Raw,
+    /// Ed25519ph from RFC 8032, over the SHA-512 of the message (`ed25519ph`). Some
+    /// hardware wallets sign this way because they can't hold the whole message.
+    #[serde(rename = "ed25519ph")]
+    Prehashed,
+}
+
+impl SignatureScheme {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ed25519" => Some(Self::Raw),
+            "ed25519ph" => Some(Self::Prehashed),
+            _ => None,
+        }
+    }
+
+    /// The verifier for signatures under this scheme only. A signature made under
+    /// another scheme never verifies, so clients have to say which one they used.
+    pub fn verifier(self) -> &'static dyn WalletVerifier {
+        match self {
+            Self::Raw => &Ed25519Verifier { prehashed: false },
+            Self::Prehashed => &Ed25519Verifier { prehashed: true },
+        }
+    }
+}
+
+const INVALID_KEY: &str = "Public key is not valid for the signature scheme.";
+const WRONG_SIGNATURE_LENGTH: &str = "Signature has the wrong length for the signature scheme.";
+
+/// Checks wallet signatures on one curve, so wallets of other chains can be supported by
+/// adding a [`SignatureScheme`] and an implementation without touching the login checks.
+pub trait WalletVerifier: Send + Sync {
+    /// Whether `public_key` is a valid key on this verifier's curve.
+    fn accepts_key(&self, public_key: &[u8]) -> bool;
+
+    /// The length of a signature, in bytes.
+    fn signature_length(&self) -> usize;
+
+    /// Check `signature` over `message` by `public_key`. Malformed keys and signatures
+    /// don't verify.
+    fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// Ed25519 as Solana wallets sign it, over the message itself or, `prehashed`, as
+/// Ed25519ph.
+///
+/// Signatures are checked strictly: small-order keys, which a signature with a
+/// small-order `R` and zero `S` satisfies for any message, are refused, as are
+/// small-order `R`s.
+struct Ed25519Verifier {
+    prehashed: bool,
+}
+
+impl WalletVerifier for Ed25519Verifier {
+    fn accepts_key(&self, public_key: &[u8]) -> bool {
+        <[u8; 32]>::try_from(public_key)
+            .is_ok_and(|public_key| VerifyingKey::from_bytes(&public_key).is_ok())
+    }
+
+    fn signature_length(&self) -> usize {
+        Signature::BYTE_SIZE
+    }
+
+    fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        let Some(verifying_key) = <[u8; 32]>::try_from(public_key)
+            .ok()
+            .and_then(|public_key| VerifyingKey::from_bytes(&public_key).ok())
+        else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(signature) else {
+            return false;
+        };
+
+        if self.prehashed {
+            verifying_key
+                .verify_prehashed_strict(Sha512::new().chain_update(message), None, &signature)
+                .is_ok()
+        } else {
+            verifying_key.verify_strict(message, &signature).is_ok()
+        }
+    }
+}
+
+/// Output of the wallet-standard `signIn` feature (`SolanaSignInOutput`), passed through
+/// by the client as `sign_in_output`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignInOutput {
+    account: SignInAccount,
+    signed_message: WalletBytes,
+    signature: WalletBytes,
+}
+
+#[derive(Deserialize)]
+struct SignInAccount {
+    address: String,
+}
+
+/// Bytes from a wallet: base64, or the array a serialized `Uint8Array` becomes.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WalletBytes {
+    Base64(String),
+    Array(Vec<u8>),
+}
+
+impl WalletBytes {
+    fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            WalletBytes::Base64(encoded) => general_purpose::STANDARD.decode(encoded).ok(),
+            WalletBytes::Array(bytes) => Some(bytes),
+        }
+    }
+}
+
+/// The fields we check in a Sign In With Solana message.
+#[derive(Debug, PartialEq, Eq)]
+struct SignInMessage<'a> {
+    domain: &'a str,
+    address: &'a str,
+    nonce: &'a str,
+    /// The `Resources` list, in order.
+    resources: Vec<&'a str>,
+}
+
+impl<'a> SignInMessage<'a> {
+    /// Parse the SIWS text format: a `<domain> wants you to sign in with your Solana
+    /// account:` header, the address on the next line, then an optional statement and
+    /// `Key: value` fields, exactly one of which must be the `Nonce`, and optionally a
+    /// closing `Resources:` line followed by `- <resource>` lines.
+    fn parse(message: &'a str) -> Option<Self> {
+        let mut lines = message.lines();
+        let domain = lines
+            .next()?
+            .strip_suffix(" wants you to sign in with your Solana account:")?;
+        let address = lines.next()?;
+
+        let mut nonces = lines
+            .clone()
+            .filter_map(|line| line.strip_prefix("Nonce: "));
+        let nonce = nonces.next()?;
+        if nonces.next().is_some() {
+            return None;
+        }
+
+        let resources = lines
+            .skip_while(|line| *line != "Resources:")
+            .skip(1)
+            .map(|line| line.strip_prefix("- "))
+            .collect::<Option<_>>()?;
+
+        Some(Self {
+            domain,
+            address,
+            nonce,
+            resources,
+        })
+    }
+}
+
+/// A malformed field in an `m.login.solana.signature` login body.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoginBodyError {
+    /// A required field is absent.
+    Missing(&'static str),
+    /// A field is present but has the wrong type or is empty.
+    Invalid(&'static str),
+}
+
+impl From<LoginBodyError> for Error {
+    fn from(error: LoginBodyError) -> Self {
+        use ruma::api::client::error::ErrorKind;
+        match error {
+            LoginBodyError::Missing(message) => Error::BadRequest(ErrorKind::MissingParam, message),
+            LoginBodyError::Invalid(message) => Error::BadRequest(ErrorKind::InvalidParam, message),
+        }
+    }
+}
+
+/// A login body field, with the errors reported when it is missing or malformed.
+struct LoginField {
+    name: &'static str,
+    missing: &'static str,
+    invalid: &'static str,
+}
+
+const ADDRESS: LoginField = LoginField {
+    name: "address",
+    missing: "Missing address.",
+    invalid: "address must be a non-empty string.",
+};
+const SIGNATURE: LoginField = LoginField {
+    name: "signature",
+    missing: "Missing signature.",
+    invalid: "signature must be a non-empty string.",
+};
+const NONCE: LoginField = LoginField {
+    name: "nonce",
+    missing: "Missing nonce.",
+    invalid: "nonce must be a non-empty string.",
+};
+const DOMAIN: LoginField = LoginField {
+    name: "domain",
+    missing: "Missing domain.",
+    invalid: "domain must be a non-empty string.",
+};
+const ORIGIN: LoginField = LoginField {
+    name: "origin",
+    missing: "Missing origin.",
+    invalid: "origin must be a non-empty string.",
+};
+const SIGNATURE_SCHEME: LoginField = LoginField {
+    name: "signature_scheme",
+    missing: "Missing signature_scheme.",
+    invalid: "signature_scheme must be \"ed25519\" or \"ed25519ph\".",
+};
+const DELEGATION: LoginField = LoginField {
+    name: "delegation",
+    missing: "Missing delegation.",
+    invalid: "delegation must be a base64 string.",
+};
+const SIGNED_AT: LoginField = LoginField {
+    name: "signed_at",
+    missing: "Missing signed_at.",
+    invalid: "signed_at must be a timestamp in milliseconds since the unix epoch.",
+};
+
+impl LoginField {
+    fn optional<'a>(&self, body: &'a serde_json::Value) -> Result<Option<&'a str>, LoginBodyError> {
+        match body.get(self.name) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(serde_json::Value::String(value)) if !value.is_empty() => Ok(Some(value)),
+            Some(_) => Err(LoginBodyError::Invalid(self.invalid)),
+        }
+    }
+
+    fn optional_timestamp(&self, body: &serde_json::Value) -> Result<Option<u64>, LoginBodyError> {
+        match body.get(self.name) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => value
+                .as_u64()
+                .map(Some)
+                .ok_or(LoginBodyError::Invalid(self.invalid)),
+        }
+    }
+
+    fn required<'a>(&self, body: &'a serde_json::Value) -> Result<&'a str, LoginBodyError> {
+        self.optional(body)?
+            .ok_or(LoginBodyError::Missing(self.missing))
+    }
+}
+
+impl SolanaLoginRequest {
+    /// Read the Solana fields from a raw login body, reporting exactly which field is
+    /// missing or malformed. Unknown fields (the standard `type`, `device_id`, ...) are
+    /// left to the generic login handling.
+    pub fn from_login_body(
+        body: &serde_json::Value,
+        device_id: Option<String>,
+        initial_device_display_name: Option<String>,
+    ) -> Result<Self, LoginBodyError> {
+        if !body.is_object() {
+            return Err(LoginBodyError::Invalid("Login body must be a JSON object."));
+        }
+
+        let delegation = DELEGATION.optional(body)?.map(str::to_owned);
+        let origin = ORIGIN.optional(body)?.map(str::to_owned);
+        let signature_scheme = match SIGNATURE_SCHEME.optional(body)? {
+            Some(name) => SignatureScheme::from_name(name)
+                .ok_or(LoginBodyError::Invalid(SIGNATURE_SCHEME.invalid))?,
+            None => SignatureScheme::Raw,
+        };
+        let signed_at = SIGNED_AT.optional_timestamp(body)?;
+        if let Some(output) = body.get("sign_in_output") {
+            let mut request = Self::from_sign_in_output(
+                output,
+                device_id,
+                delegation,
+                initial_device_display_name,
+            )?;
+            request.signature_scheme = signature_scheme;
+            request.origin = origin;
+            request.signed_at = signed_at;
+            return Ok(request);
+        }
+
+        Ok(Self {
+            address: ADDRESS.required(body)?.to_owned(),
+            signature: SIGNATURE.required(body)?.to_owned(),
+            signature_scheme,
+            nonce: NONCE.required(body)?.to_owned(),
+            device_id,
+            delegation,
+            initial_device_display_name,
+            signed_message: None,
+            domain: DOMAIN.optional(body)?.map(str::to_owned),
+            origin,
+            client_ip: None,
+            refresh_token: false,
+            signed_at,
+        })
+    }
+
+    /// Read a wallet-standard `signIn` output. The nonce comes from the signed message,
+    /// and the message is kept so the signature is checked over exactly what was signed.
+    fn from_sign_in_output(
+        output: &serde_json::Value,
+        device_id: Option<String>,
+        delegation: Option<String>,
+        initial_device_display_name: Option<String>,
+    ) -> Result<Self, LoginBodyError> {
+        let output = SignInOutput::deserialize(output).map_err(|_| {
+            LoginBodyError::Invalid(
+                "sign_in_output must have account.address, signedMessage and signature.",
+            )
+        })?;
+        let signed_message = output
+            .signed_message
+            .into_bytes()
+            .ok_or(LoginBodyError::Invalid(
+                "signedMessage must be base64 or a byte array.",
+            ))?;
+        let signature = output
+            .signature
+            .into_bytes()
+            .ok_or(LoginBodyError::Invalid(
+                "signature must be base64 or a byte array.",
+            ))?;
+
+        let message = std::str::from_utf8(&signed_message)
+            .ok()
+            .and_then(SignInMessage::parse)
+            .ok_or(LoginBodyError::Invalid(
+                "signedMessage is not a Sign In With Solana message.",
+            ))?;
+        if message.address != output.account.address {
+            return Err(LoginBodyError::Invalid(
+                "signedMessage is for a different account.",
+            ));
+        }
+        let nonce = message.nonce.to_owned();
+
+        Ok(Self {
+            address: output.account.address,
+            signature: bs58::encode(signature).into_string(),
+            signature_scheme: SignatureScheme::Raw,
+            nonce,
+            device_id,
+            delegation,
+            initial_device_display_name,
+            signed_message: Some(signed_message),
+            domain: None,
+            origin: None,
+            client_ip: None,
+            refresh_token: false,
+            signed_at: None,
+        })
+    }
+}
+
+/// The IP a request came from, for rate limiting and login notices: the peer address, or
+/// the right-most `X-Forwarded-For` entry when `solana_trust_forwarded_for` is on.
+pub fn client_ip(remote: SocketAddr, headers: &http::HeaderMap) -> IpAddr {
+    forwarded_client_ip(
+        remote,
+        headers,
+        services().globals.solana_trust_forwarded_for(),
+    )
+}
+
+/// The right-most `X-Forwarded-For` entry is the one the trusted proxy appended; entries
+/// left of it come from the client, who could pick a fresh one per request. Falls back to
+/// the peer address if the header is missing or unreadable.
+fn forwarded_client_ip(remote: SocketAddr, headers: &http::HeaderMap, trust: bool) -> IpAddr {
+    trust
+        .then(|| headers.get("x-forwarded-for"))
+        .flatten()
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.rsplit(',').next())
+        .and_then(|entry| entry.trim().parse().ok())
+        .unwrap_or_else(|| remote.ip())
 }
 
 /// Generate a nonce challenge for a Solana address.
 /// The client must sign the returned `message` field with their wallet.
-pub fn generate_nonce(address: &str) -> Result<NonceResponse> {
-    // Validate that the address is valid base58-encoded ed25519 pubkey
-    let pubkey_bytes = bs58::decode(address)
-        .into_vec()
-        .map_err(|_| Error::BadRequest(ruma::api::client::error::ErrorKind::InvalidParam, "Invalid base58 address."))?;
+///
+/// Requests are rate limited per `client_ip`, when known, and per address.
+pub fn generate_nonce(request: &NonceRequest, client_ip: Option<IpAddr>) -> Result<NonceResponse> {
+    let address = normalize_address(&request.address)?;
+    decode_address(address)?;
 
-    if pubkey_bytes.len() != 32 {
+    // The name ends up on its own line of the signed message, so it can't be allowed
+    // to add lines of its own.
+    if request
+        .initial_device_display_name
+        .as_deref()
+        .is_some_and(|name| name.chars().any(char::is_control))
+    {
         return Err(Error::BadRequest(
             ruma::api::client::error::ErrorKind::InvalidParam,
-            "Solana address must decode to exactly 32 bytes.",
+            "Device display name may not contain control characters.",
         ));
     }
+    check_scopes(&request.scopes)?;
+    check_allowed_origin(
+        request.origin.as_deref(),
+        services().globals.solana_allowed_origins(),
+    )?;
 
-    let nonce = generate_random_nonce();
-    let server_name = services().globals.server_name();
-    let message = format_sign_message(server_name.as_str(), &nonce);
+    services().solana.check_nonce_rate(client_ip, address)?;
 
-    // Store nonce with timestamp
-    let mut nonces = NONCES.lock().expect("nonce lock poisoned");
+    issue_challenge(
+        address,
+        request.device_id.clone(),
+        request.initial_device_display_name.clone(),
+        request.format,
+        request.scopes.clone(),
+        request.origin.clone(),
+    )
+}
 
-    // Prune expired nonces if we're getting too many
-    if nonces.len() > MAX_NONCES {
-        let now = Instant::now();
-        nonces.retain(|_, created| now.duration_since(*created) < NONCE_TTL);
+/// Refuse a challenge for an origin `allowed` doesn't list. No origin counts as
+/// [`NATIVE_ORIGIN`]. An empty list allows every origin.
+fn check_allowed_origin(origin: Option<&str>, allowed: &[String]) -> Result<()> {
+    let origin = origin.unwrap_or(NATIVE_ORIGIN);
+    if allowed.is_empty() || allowed.iter().any(|allowed| allowed == origin) {
+        return Ok(());
     }
+    Err(Error::BadRequest(
+        ruma::api::client::error::ErrorKind::forbidden(),
+        "This origin may not request login challenges.",
+    ))
+}
+
+/// Refuse scopes that can't each be listed on a line of their own in the signed
+/// message, or more than [`MAX_SCOPES`] of them.
+fn check_scopes(scopes: &[String]) -> Result<()> {
+    let message = if scopes.len() > MAX_SCOPES {
+        "Too many scopes."
+    } else if scopes
+        .iter()
+        .any(|scope| scope.trim().is_empty() || scope.chars().any(char::is_control))
+    {
+        "Scopes must be non-empty and may not contain control characters."
+    } else {
+        return Ok(());
+    };
+    Err(Error::BadRequest(
+        ruma::api::client::error::ErrorKind::InvalidParam,
+        message,
+    ))
+}
+
+/// Store a new challenge for `address` and return the message its wallet has to sign.
+fn issue_challenge(
+    address: &str,
+    device_id: Option<String>,
+    initial_device_display_name: Option<String>,
+    format: ChallengeFormat,
+    scopes: Vec<String>,
+    origin: Option<String>,
+) -> Result<NonceResponse> {
+    let nonce = generate_random_nonce(services().globals.solana_nonce_bytes());
+    let issued_at = utils::millis_since_unix_epoch();
+    let stored = StoredNonce {
+        issued_at,
+        expires_at: expiry(issued_at, acceptance_window()),
+        address: address.to_owned(),
+        device_id,
+        initial_device_display_name,
+        format,
+        statement: services().globals.solana_challenge_statement().to_owned(),
+        scopes,
+        origin,
+    };
+    let message = challenge_message(
+        services().globals.server_name().as_str(),
+        &nonce,
+        &stored,
+        None,
+    );
+
+    // Store the nonce with its expiry. Expired nonces are swept by the periodic cleanup.
+    services().solana.nonces.issue(&nonce, &stored)?;
+    services().solana.metrics.nonce_issued();
 
-    nonces.insert(nonce.clone(), Instant::now());
+    let span = login_span(address, &nonce);
+    span.record("outcome", "challenge issued");
+    span.in_scope(|| info!("Issued Solana login challenge"));
 
     Ok(NonceResponse {
         nonce,
         message,
-        expires_in_seconds: NONCE_TTL.as_secs(),
+        expires_in_seconds: services().globals.solana_nonce_ttl(),
     })
 }
 
-/// Verify a Solana wallet signature and return the user's hex-encoded public key
-/// (for use as Matrix localpart) and base58 address (for display name).
-pub fn verify_solana_login(request: &SolanaLoginRequest) -> Result<(String, String)> {
-    let error_kind = ruma::api::client::error::ErrorKind::forbidden();
+/// A span for one step of a wallet login: issuing its challenge, or signing in with it.
+///
+/// Both steps share a `login_id` derived from the nonce, so their logs can be tied
+/// together without logging the nonce itself. The address is abbreviated and only a
+/// prefix of the nonce is kept; signatures are never recorded. Fill in `outcome` with
+/// [`record_login_outcome`].
+pub fn login_span(address: &str, nonce: &str) -> tracing::Span {
+    tracing::info_span!(
+        "solana_login",
+        login_id = %login_id(nonce),
+        address = %abbreviate_address(address),
+        nonce = %nonce.chars().take(8).collect::<String>(),
+        outcome = tracing::field::Empty,
+        reason = tracing::field::Empty,
+    )
+}
 
-    // Decode the public key from base58
-    let pubkey_bytes = bs58::decode(&request.address)
-        .into_vec()
-        .map_err(|_| Error::BadRequest(error_kind.clone(), "Invalid base58 address."))?;
+/// Record how a sign-in went on its [`login_span`]: "ok", "refused" with the reason the
+/// client was given, or "error" for server-side failures.
+pub fn record_login_outcome<T>(span: &tracing::Span, result: &Result<T>) {
+    match result {
+        Ok(_) => {
+            span.record("outcome", "ok");
+        }
+        Err(Error::BadRequest(_, reason) | Error::SolanaAuth(_, reason)) => {
+            span.record("outcome", "refused");
+            span.record("reason", *reason);
+        }
+        Err(Error::SolanaNonceReissued(_)) => {
+            span.record("outcome", "refused");
+            span.record("reason", EXPIRED_NONCE);
+        }
+        Err(_) => {
+            span.record("outcome", "error");
+        }
+    }
+}
+
+/// Short correlation ID for the login a nonce belongs to: the start of its SHA-256.
+fn login_id(nonce: &str) -> String {
+    hex::encode(&Sha256::digest(nonce.as_bytes())[..4])
+}
+
+/// Invisible characters that get copied along with addresses but that
+/// `char::is_whitespace` doesn't cover.
+const ZERO_WIDTH: [char; 5] = ['\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}'];
 
-    if pubkey_bytes.len() != 32 {
+/// Strip the whitespace and zero-width characters clients paste around a wallet address.
+/// Inside an address they, and control characters anywhere, are refused rather than
+/// guessed around.
+pub fn normalize_address(address: &str) -> Result<&str> {
+    let invisible = |c: char| c.is_whitespace() || ZERO_WIDTH.contains(&c);
+    let address = address.trim_matches(invisible);
+    if address.chars().any(|c| invisible(c) || c.is_control()) {
         return Err(Error::BadRequest(
-            error_kind.clone(),
-            "Solana address must decode to exactly 32 bytes.",
+            ruma::api::client::error::ErrorKind::InvalidParam,
+            "Solana address may not contain whitespace or control characters.",
         ));
     }
+    Ok(address)
+}
+
+/// Decode a base58 wallet address a client sent, after [`normalize_address`], rejecting
+/// anything that isn't 32 bytes.
+pub fn decode_address(address: &str) -> Result<[u8; 32]> {
+    let pubkey_bytes = bs58::decode(normalize_address(address)?)
+        .into_vec()
+        .map_err(|_| {
+            Error::BadRequest(
+                ruma::api::client::error::ErrorKind::InvalidParam,
+                "Solana address contains characters that aren't base58.",
+            )
+        })?;
+
+    pubkey_bytes.try_into().map_err(|_| {
+        Error::BadRequest(
+            ruma::api::client::error::ErrorKind::InvalidParam,
+            "Solana address must decode to exactly 32 bytes.",
+        )
+    })
+}
+
+/// Turn a signature for a challenge issued by [`generate_nonce`] into a login request,
+/// using the address, device and device name the challenge was issued for. The nonce is
+/// left in the store for [`verify_solana_login`] to consume.
+pub fn challenge_login_request(nonce: &str, signature: String) -> Result<SolanaLoginRequest> {
+    let stored = services()
+        .solana
+        .nonces
+        .get(nonce)?
+        .ok_or_else(|| login_error(UNKNOWN_NONCE))?;
+
+    Ok(SolanaLoginRequest {
+        address: stored.address,
+        signature,
+        signature_scheme: SignatureScheme::Raw,
+        nonce: nonce.to_owned(),
+        device_id: stored.device_id,
+        delegation: None,
+        initial_device_display_name: stored.initial_device_display_name,
+        signed_message: None,
+        domain: None,
+        origin: stored.origin,
+        client_ip: None,
+        refresh_token: false,
+        signed_at: None,
+    })
+}
+
+/// A wallet that signed its login challenge, in each form callers need.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifiedSolanaLogin {
+    /// The wallet's ed25519 public key.
+    pub pubkey: [u8; 32],
+    /// The Matrix localpart of the wallet's own account under `solana_localpart_namespace`,
+    /// see [`pubkey_to_localpart`].
+    pub hex_localpart: String,
+    /// The base58 address wallets show, used as the display name.
+    pub base58_address: String,
+    /// The scopes the signed challenge granted. Empty for an unscoped login.
+    pub scopes: Vec<String>,
+}
+
+impl VerifiedSolanaLogin {
+    pub fn new(pubkey: [u8; 32], namespace: Option<&str>) -> Self {
+        Self {
+            hex_localpart: pubkey_to_localpart(&pubkey, namespace),
+            base58_address: bs58::encode(pubkey).into_string(),
+            pubkey,
+            scopes: Vec::new(),
+        }
+    }
+}
 
-    let pubkey_array: [u8; 32] = pubkey_bytes
-        .try_into()
-        .map_err(|_| Error::BadRequest(error_kind.clone(), "Invalid public key length."))?;
+/// Verify a Solana wallet signature and return the wallet that signed in.
+pub async fn verify_solana_login(request: &SolanaLoginRequest) -> Result<VerifiedSolanaLogin> {
+    let error_kind = ruma::api::client::error::ErrorKind::forbidden();
+
+    // Decode the public key from base58
+    let pubkey_array = decode_address(&request.address)?;
 
-    let verifying_key = VerifyingKey::from_bytes(&pubkey_array)
-        .map_err(|_| Error::BadRequest(error_kind.clone(), "Invalid ed25519 public key."))?;
+    let verifier = request.signature_scheme.verifier();
+    if !verifier.accepts_key(&pubkey_array) {
+        return Err(Error::BadRequest(error_kind.clone(), INVALID_KEY));
+    }
 
     // Decode the signature from base58
-    let sig_bytes = bs58::decode(&request.signature)
+    let signature = bs58::decode(&request.signature)
         .into_vec()
         .map_err(|_| Error::BadRequest(error_kind.clone(), "Invalid base58 signature."))?;
 
-    if sig_bytes.len() != 64 {
+    if signature.len() != verifier.signature_length() {
         return Err(Error::BadRequest(
             error_kind.clone(),
-            "Signature must be exactly 64 bytes.",
+            WRONG_SIGNATURE_LENGTH,
         ));
     }
 
-    let sig_array: [u8; 64] = sig_bytes
-        .try_into()
-        .map_err(|_| Error::BadRequest(error_kind.clone(), "Invalid signature length."))?;
-
-    let signature = Signature::from_bytes(&sig_array);
-
-    // Verify the nonce exists and hasn't expired, then consume it (one-time use)
+    // Redeem the nonce: no other login can use it while we check the signature, and it
+    // is only used up once the signature checks out. Any failure puts it back.
     let server_name = services().globals.server_name();
-    let message = format_sign_message(server_name.as_str(), &request.nonce);
+    let now = utils::millis_since_unix_epoch();
+    let fingerprint = login_fingerprint(&pubkey_array, &request.nonce, &signature);
+    let check = |stored: StoredNonce| async move {
+        let message = expected_message(
+            request,
+            &stored,
+            server_name.as_str(),
+            services().globals.solana_sign_in_domains(),
+            now,
+            Duration::from_secs(services().globals.solana_signature_max_age()),
+        )
+        .map_err(login_error)?;
 
+        // Verify the signature over the challenge message
+        let verify = |key| {
+            services().solana.verify_signature(
+                key,
+                message.clone(),
+                signature.clone(),
+                request.signature_scheme,
+            )
+        };
+        let auth_key = || async {
+            registry_auth_key(&pubkey_array)
+                .await
+                .filter(|key| verifier.accepts_key(key))
+        };
+        let wallet = VerifiedSolanaLogin::new(
+            pubkey_array,
+            services().globals.solana_localpart_namespace(),
+        );
+        let Some(mut verified) = check_login_signature(wallet, verify, auth_key).await else {
+            return Err(login_error(BAD_SIGNATURE));
+        };
+        verified.scopes = stored.scopes;
+        Ok(verified)
+    };
+    let verified = match services()
+        .solana
+        .nonces
+        .redeem(
+            &request.nonce,
+            &services().solana.consumed_logins,
+            &fingerprint,
+            now,
+            check,
+        )
+        .await?
     {
-        let mut nonces = NONCES.lock().expect("nonce lock poisoned");
-        let created = nonces.remove(&request.nonce).ok_or_else(|| {
-            Error::BadRequest(error_kind.clone(), "Nonce not found or already used.")
-        })?;
-
-        if Instant::now().duration_since(created) > NONCE_TTL {
-            return Err(Error::BadRequest(error_kind.clone(), "Nonce has expired."));
+        Redemption::Redeemed(verified) => verified,
+        Redemption::Unknown => return Err(login_error(UNKNOWN_NONCE)),
+        // Refused however its address was padded, since the fingerprint is over the key
+        Redemption::Replayed => {
+            warn!(
+                "Refused a replayed Solana login for {}",
+                bs58::encode(pubkey_array).into_string()
+            );
+            return Err(login_error(UNKNOWN_NONCE));
         }
-    }
+        Redemption::Expired(expired) => {
+            let error = expired_nonce_error(&expired, now, |expired| {
+                services()
+                    .solana
+                    .check_nonce_rate(request.client_ip, &expired.address)?;
+                issue_challenge(
+                    &expired.address,
+                    expired.device_id.clone(),
+                    expired.initial_device_display_name.clone(),
+                    expired.format,
+                    expired.scopes.clone(),
+                    expired.origin.clone(),
+                )
+            })?;
+            return Err(error);
+        }
+    };
+
+    info!(
+        "Solana auth verified: {} (localpart: {})",
+        verified.base58_address, verified.hex_localpart
+    );
 
-    // Verify the signature over the challenge message
-    verifying_key
-        .verify(message.as_bytes(), &signature)
-        .map_err(|_| Error::BadRequest(error_kind, "Signature verification failed."))?;
+    Ok(verified)
+}
 
-    // Prefix + hex-encode the public key for the Matrix localpart.
-    // "solana_" prefix identifies this as a Solana wallet account and
-    // distinguishes it from regular Matrix accounts or other chains.
-    let hex_localpart = format!("solana_{}", hex::encode(pubkey_array));
-    let base58_address = request.address.clone();
+/// Check a login signature against the wallet's own key and then, if `auth_key` finds
+/// one, the auth key its registry delegation designates. Either way the login is the
+/// wallet's, never the auth key's.
+async fn check_login_signature<V, VerifyFut, A, AuthKeyFut>(
+    verified: VerifiedSolanaLogin,
+    mut verify: V,
+    auth_key: A,
+) -> Option<VerifiedSolanaLogin>
+where
+    V: FnMut([u8; 32]) -> VerifyFut,
+    VerifyFut: Future<Output = bool>,
+    A: FnOnce() -> AuthKeyFut,
+    AuthKeyFut: Future<Output = Option<[u8; 32]>>,
+{
+    if verify(verified.pubkey).await {
+        return Some(verified);
+    }
 
+    let auth_key = auth_key().await?;
+    if !verify(auth_key).await {
+        return None;
+    }
     info!(
-        "Solana auth verified: {} (localpart: {})",
-        base58_address, hex_localpart
+        "Solana login for {} was signed by its registered auth key {}",
+        verified.base58_address,
+        bs58::encode(auth_key).into_string()
     );
+    Some(verified)
+}
+
+/// The auth key `wallet`'s active registry delegation designates, when
+/// `solana_registry_auth_keys` is on. A delegation that can't be read counts as having
+/// none.
+async fn registry_auth_key(wallet: &[u8; 32]) -> Option<[u8; 32]> {
+    if !services().globals.solana_registry_auth_keys() {
+        return None;
+    }
 
-    Ok((hex_localpart, base58_address))
+    let delegations = services()
+        .solana_registry
+        .fetch_delegations(&[*wallet])
+        .await
+        .inspect_err(|error| warn!("Could not read the registry auth key of a wallet: {error}"))
+        .ok()?;
+    let now = utils::secs_since_unix_epoch() as i64;
+    delegations
+        .into_iter()
+        .next()
+        .flatten()
+        .filter(|delegation| delegation.is_active(now))?
+        .auth_pubkey
 }
 
-/// Format the challenge message that the wallet must sign.
-/// This is human-readable so users can verify what they're signing in their wallet popup.
-fn format_sign_message(server_name: &str, nonce: &str) -> String {
-    format!(
-        "Sign in to {server_name}\n\nNonce: {nonce}\n\nThis signature will not trigger a blockchain transaction or cost any fees."
+/// The error for a login whose nonce `expired`. Within [`REISSUE_GRACE_MILLIS`] of expiry
+/// it carries a new challenge from `reissue`, for the same wallet and device, so the client
+/// can have it signed straight away; after that the login just fails.
+fn expired_nonce_error(
+    expired: &StoredNonce,
+    now: u64,
+    reissue: impl FnOnce(&StoredNonce) -> Result<NonceResponse>,
+) -> Result<Error> {
+    if !expired.can_be_reissued(now) {
+        return Ok(login_error(EXPIRED_NONCE));
+    }
+    Ok(Error::SolanaNonceReissued(Box::new(reissue(expired)?)))
+}
+
+/// The message a login's signature has to cover, after checking the login against the
+/// nonce it uses. Returns the error message if the nonce has expired or doesn't match, or
+/// the login says it was signed longer than `max_signature_age` ago.
+fn expected_message(
+    request: &SolanaLoginRequest,
+    stored: &StoredNonce,
+    server_name: &str,
+    sign_in_domains: &[String],
+    now: u64,
+    max_signature_age: Duration,
+) -> std::result::Result<Vec<u8>, &'static str> {
+    if stored.is_expired(now) {
+        return Err(EXPIRED_NONCE);
+    }
+    check_challenge_binding(stored, request)?;
+    check_displayed_domain(request.domain.as_deref(), server_name)?;
+    if let Some(signed_at) = request.signed_at {
+        check_signed_at(signed_at, now, max_signature_age)?;
+    }
+
+    match &request.signed_message {
+        // The wallet built and signed its own Sign In With Solana message around our
+        // nonce. It has no line for a bound device name, so those nonces can't be used.
+        Some(signed_message) => {
+            if stored.initial_device_display_name.is_some() {
+                return Err(WRONG_DEVICE_NAME);
+            }
+            if request.signed_at.is_some() {
+                return Err(UNSIGNED_SIGNED_AT);
+            }
+
+            let message = std::str::from_utf8(signed_message)
+                .ok()
+                .and_then(SignInMessage::parse);
+            let allowed = message.as_ref().is_some_and(|message| {
+                message.domain == server_name
+                    || sign_in_domains
+                        .iter()
+                        .any(|allowed| allowed == message.domain)
+            });
+            if !allowed {
+                return Err(WRONG_DOMAIN);
+            }
+            // The wallet must have listed exactly the scopes the challenge was issued for.
+            if message.is_some_and(|message| message.resources != stored.scopes) {
+                return Err(WRONG_SCOPES);
+            }
+
+            Ok(signed_message.clone())
+        }
+        // Otherwise the wallet signed the challenge we issued, in the format the nonce
+        // was requested in, so rebuild it from what was stored. Only the plain challenge
+        // has a line for the time it was signed.
+        None => {
+            if request.signed_at.is_some() && stored.format != ChallengeFormat::Legacy {
+                return Err(UNSIGNED_SIGNED_AT);
+            }
+            let message = challenge_message(server_name, &request.nonce, stored, request.signed_at);
+
+            // The message carries its own expiry, which is checked as well in case the
+            // stored one is ever wrong.
+            if message_expired(&message, now) {
+                return Err(EXPIRED_NONCE);
+            }
+
+            Ok(message.into_bytes())
+        }
+    }
+}
+
+/// The step of a login verification a [`LoginDiagnosis`] stopped at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyStage {
+    AddressDecode,
+    AddressLength,
+    KeyParse,
+    SignatureDecode,
+    SignatureLength,
+    /// The nonce is unknown, expired, or was issued for a different login.
+    NonceLookup,
+    SignatureVerify,
+}
+
+/// What a dry run of [`verify_solana_login`] found, for client developers getting the
+/// message format and signature encoding right.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct LoginDiagnosis {
+    pub ok: bool,
+    /// The step that failed, if one did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<VerifyStage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+    /// The message the signature has to cover, once the nonce was found.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl LoginDiagnosis {
+    fn failed(stage: VerifyStage, error: &'static str, message: Option<String>) -> Self {
+        Self {
+            ok: false,
+            stage: Some(stage),
+            error: Some(error),
+            message,
+        }
+    }
+}
+
+/// Run a login through the checks of [`verify_solana_login`] without logging in. The
+/// nonce is only looked up, so it can still be used for a real login afterwards.
+pub fn dry_run_solana_login(request: &SolanaLoginRequest) -> Result<LoginDiagnosis> {
+    let stored = services().solana.nonces.get(&request.nonce)?;
+    Ok(diagnose_login(
+        request,
+        stored.as_ref(),
+        services().globals.server_name().as_str(),
+        services().globals.solana_sign_in_domains(),
+        utils::millis_since_unix_epoch(),
+        Duration::from_secs(services().globals.solana_signature_max_age()),
+    ))
+}
+
+/// Check a login an admin pasted into the admin room with `verify-solana-login`, for
+/// following up user reports. It goes through [`verify_solana_login`] like a real login,
+/// so a good signature uses up its nonce, but no account is created or logged in.
+pub async fn verify_login_for_admin(address: &str, nonce: &str, signature: &str) -> Result<String> {
+    let request = SolanaLoginRequest::from_login_body(
+        &serde_json::json!({ "address": address, "nonce": nonce, "signature": signature }),
+        None,
+        None,
+    )?;
+    admin_verification_reply(
+        services().globals.allow_solana_auth(),
+        &request,
+        |request| async move {
+            let wallet = verify_solana_login(request).await?;
+            Ok(services().solana.resolve_user(&wallet)?.0)
+        },
     )
+    .await
 }
 
-/// Generate a cryptographically random nonce string.
-fn generate_random_nonce() -> String {
-    use rand::Rng;
-    let mut rng = rand::rng();
-    let bytes: [u8; 32] = rng.random();
-    hex::encode(bytes)
+/// The admin room's answer to a pasted login: the account `verify` would sign it in to,
+/// or the exact reason it was refused. Errors that aren't about the login are returned.
+async fn admin_verification_reply<'a, V, Fut>(
+    enabled: bool,
+    request: &'a SolanaLoginRequest,
+    verify: V,
+) -> Result<String>
+where
+    V: FnOnce(&'a SolanaLoginRequest) -> Fut,
+    Fut: Future<Output = Result<OwnedUserId>>,
+{
+    if !enabled {
+        return Ok("Solana wallet login is not enabled on this server".to_owned());
+    }
+
+    let address = &request.address;
+    let reason = match verify(request).await {
+        Ok(user_id) => {
+            return Ok(format!(
+                "Login for {address} is valid and signs in as {user_id}"
+            ))
+        }
+        Err(Error::BadRequest(_, message) | Error::SolanaAuth(_, message)) => message,
+        Err(Error::SolanaNonceReissued(_)) => EXPIRED_NONCE,
+        Err(error) => return Err(error),
+    };
+    Ok(format!("Login for {address} was refused: {reason}"))
+}
+
+fn diagnose_login(
+    request: &SolanaLoginRequest,
+    stored: Option<&StoredNonce>,
+    server_name: &str,
+    sign_in_domains: &[String],
+    now: u64,
+    max_signature_age: Duration,
+) -> LoginDiagnosis {
+    use VerifyStage::*;
+
+    let Some(pubkey_bytes) = normalize_address(&request.address)
+        .ok()
+        .and_then(|address| bs58::decode(address).into_vec().ok())
+    else {
+        return LoginDiagnosis::failed(
+            AddressDecode,
+            "Solana address is not base58 or contains whitespace.",
+            None,
+        );
+    };
+    let Ok(pubkey) = <[u8; 32]>::try_from(pubkey_bytes) else {
+        return LoginDiagnosis::failed(
+            AddressLength,
+            "Solana address must decode to exactly 32 bytes.",
+            None,
+        );
+    };
+    let verifier = request.signature_scheme.verifier();
+    if !verifier.accepts_key(&pubkey) {
+        return LoginDiagnosis::failed(KeyParse, INVALID_KEY, None);
+    }
+
+    let Ok(signature) = bs58::decode(&request.signature).into_vec() else {
+        return LoginDiagnosis::failed(SignatureDecode, "Invalid base58 signature.", None);
+    };
+    if signature.len() != verifier.signature_length() {
+        return LoginDiagnosis::failed(SignatureLength, WRONG_SIGNATURE_LENGTH, None);
+    }
+
+    let Some(stored) = stored else {
+        return LoginDiagnosis::failed(NonceLookup, UNKNOWN_NONCE, None);
+    };
+    let message = match expected_message(
+        request,
+        stored,
+        server_name,
+        sign_in_domains,
+        now,
+        max_signature_age,
+    ) {
+        Ok(message) => message,
+        Err(error) => return LoginDiagnosis::failed(NonceLookup, error, None),
+    };
+    let shown = String::from_utf8_lossy(&message).into_owned();
+
+    if !verifier.verify(&pubkey, &message, &signature) {
+        return LoginDiagnosis::failed(SignatureVerify, BAD_SIGNATURE, Some(shown));
+    }
+
+    LoginDiagnosis {
+        ok: true,
+        stage: None,
+        error: None,
+        message: Some(shown),
+    }
+}
+
+/// Check the time a login says it was signed at, in milliseconds since the unix epoch, is
+/// at most `max_age` ago and no more than [`SIGNED_AT_TOLERANCE_MILLIS`] ahead of `now`.
+fn check_signed_at(
+    signed_at: u64,
+    now: u64,
+    max_age: Duration,
+) -> std::result::Result<(), &'static str> {
+    if signed_at > now.saturating_add(SIGNED_AT_TOLERANCE_MILLIS) {
+        return Err(FUTURE_SIGNATURE);
+    }
+    if now.saturating_sub(signed_at) > max_age.as_millis() as u64 {
+        return Err(STALE_SIGNATURE);
+    }
+    Ok(())
+}
+
+/// Check a login against what its nonce was issued for, returning the error message if
+/// it doesn't match.
+fn check_challenge_binding(
+    stored: &StoredNonce,
+    request: &SolanaLoginRequest,
+) -> std::result::Result<(), &'static str> {
+    // A nonce is only good for the wallet that requested it, so a leaked nonce can't be
+    // signed by someone else.
+    if normalize_address(&request.address).ok() != Some(stored.address.as_str()) {
+        return Err(WRONG_ADDRESS);
+    }
+
+    // A device-scoped nonce can only be consumed by a login for that device.
+    // Unscoped nonces keep working for any device.
+    if stored.device_id.is_some() && stored.device_id != request.device_id {
+        return Err(WRONG_DEVICE);
+    }
+
+    // Likewise a device name bound into the challenge must be the one requested,
+    // since that's what the wallet signed. Unbound nonces accept any name.
+    if stored.initial_device_display_name.is_some()
+        && stored.initial_device_display_name != request.initial_device_display_name
+    {
+        return Err(WRONG_DEVICE_NAME);
+    }
+
+    // A challenge requested from a web origin can only be used from that origin, so a
+    // site relaying another's challenges has to report the origin it isn't.
+    if stored.origin != request.origin {
+        return Err(WRONG_ORIGIN);
+    }
+
+    Ok(())
+}
+
+/// Check the server name the client says it showed the user against ours, so a relay
+/// can't pass one homeserver's challenge off as another's. Clients that don't report
+/// one aren't checked.
+fn check_displayed_domain(
+    domain: Option<&str>,
+    server_name: &str,
+) -> std::result::Result<(), &'static str> {
+    match domain {
+        Some(domain) if domain != server_name => Err(WRONG_SERVER),
+        _ => Ok(()),
+    }
+}
+
+/// Log out every device of the account `address` logs in as, e.g. when the wallet has
+/// been compromised. Returns the account and how many devices were removed, or `None`
+/// if the wallet has no account here.
+pub fn logout_wallet(address: &str) -> Result<Option<(OwnedUserId, usize)>> {
+    let pubkey = decode_address(address)?;
+    let (user_id, exists) = services().solana.resolve_user(&VerifiedSolanaLogin::new(
+        pubkey,
+        services().globals.solana_localpart_namespace(),
+    ))?;
+    if !exists {
+        return Ok(None);
+    }
+
+    let device_ids = services()
+        .users
+        .all_device_ids(&user_id)
+        .collect::<Result<Vec<_>>>()?;
+    for device_id in &device_ids {
+        services().users.remove_device(&user_id, device_id)?;
+    }
+    Ok(Some((user_id, device_ids.len())))
+}
+
+/// Prefix of wallet account localparts. It identifies a Solana wallet account and
+/// distinguishes it from regular Matrix accounts or other chains.
+const LOCALPART_PREFIX: &str = "solana_";
+
+/// Derive the Matrix localpart for a wallet's public key: the prefix and the key in
+/// lowercase hex, masked with the SHA-256 of `namespace` (`solana_localpart_namespace`)
+/// if there is one.
+///
+/// This is the only place a wallet's localpart is made, so every way of naming the key
+/// (padded addresses, `signIn` outputs, linked wallets) ends at the same account.
+/// Localparts are case-sensitive, so an uppercase or otherwise different encoding would
+/// silently give the wallet a second account.
+///
+/// A namespace gives the wallet a different localpart on each server sharing a database,
+/// and the key can still be read back from it by [`localpart_to_pubkey`] with the same
+/// namespace. Without one the localpart is the raw key.
+pub fn pubkey_to_localpart(pubkey: &[u8; 32], namespace: Option<&str>) -> String {
+    let localpart = format!(
+        "{LOCALPART_PREFIX}{}",
+        hex::encode(mask_pubkey(pubkey, namespace))
+    );
+    debug_assert!(localpart
+        .strip_prefix(LOCALPART_PREFIX)
+        .is_some_and(is_canonical_hex));
+    localpart
+}
+
+/// `pubkey` XORed with the SHA-256 of `namespace`, or unchanged without one. Masking
+/// twice with the same namespace gives the key back.
+///
+/// A mask rather than a hash of namespace and key, because the server has to read wallets
+/// back from localparts: the `list-solana-users` and `link-solana-wallet` admin commands,
+/// step-up checks and the display name guard all go through [`localpart_to_pubkey`]. It
+/// only keeps servers' accounts apart and hides nothing: anyone who knows the namespace
+/// can read the wallet off a localpart.
+fn mask_pubkey(pubkey: &[u8; 32], namespace: Option<&str>) -> [u8; 32] {
+    let Some(namespace) = namespace else {
+        return *pubkey;
+    };
+    let mask = Sha256::digest(namespace.as_bytes());
+    std::array::from_fn(|index| pubkey[index] ^ mask[index])
+}
+
+/// Whether `hex` is exactly 64 lowercase hex characters, as [`pubkey_to_localpart`]
+/// writes a key.
+fn is_canonical_hex(hex: &str) -> bool {
+    hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The wallet an account belongs to, if its localpart is one [`pubkey_to_localpart`]
+/// produces under `namespace`: "solana_" followed by exactly 64 lowercase hex characters
+/// that decode to a valid ed25519 public key.
+///
+/// Usernames that only look similar (uppercase hex, wrong length, or bytes that aren't a
+/// curve point) were not created by a wallet login and return `None`.
+pub fn localpart_to_pubkey(localpart: &str, namespace: Option<&str>) -> Option<[u8; 32]> {
+    let hex_part = localpart.strip_prefix(LOCALPART_PREFIX)?;
+    if !is_canonical_hex(hex_part) {
+        return None;
+    }
+
+    let masked: [u8; 32] = hex::decode(hex_part).ok()?.try_into().ok()?;
+    let pubkey = mask_pubkey(&masked, namespace);
+    VerifyingKey::from_bytes(&pubkey).ok()?;
+    Some(pubkey)
+}
+
+/// The base58 address of the wallet an account belongs to, for showing next to a user
+/// ID whose display name may say anything. See [`localpart_to_pubkey`] for which
+/// localparts count.
+pub fn localpart_to_base58(localpart: &str, namespace: Option<&str>) -> Option<String> {
+    localpart_to_pubkey(localpart, namespace).map(|pubkey| bs58::encode(pubkey).into_string())
+}
+
+/// The localpart a wallet address signs in as, or `None` if it isn't one, the inverse of
+/// [`localpart_to_base58`].
+pub fn base58_to_localpart(address: &str, namespace: Option<&str>) -> Option<String> {
+    decode_address(address)
+        .ok()
+        .map(|pubkey| pubkey_to_localpart(&pubkey, namespace))
+}
+
+/// Turn a failed wallet login into a hint that password login is available, when the
+/// account the wallet maps to also has a password set: the account it is linked to with
+/// [`link_wallet`], otherwise its own. Other errors pass through unchanged.
+///
+/// Only says whether a password exists; the hash never leaves the users service.
+pub fn with_password_fallback(address: &str, error: Error) -> Error {
+    let Ok(pubkey) = decode_address(address) else {
+        return error;
+    };
+
+    let linked = services().users.wallet_login_user(&pubkey).ok().flatten();
+    let has_password = login_user_id(
+        &VerifiedSolanaLogin::new(pubkey, services().globals.solana_localpart_namespace()),
+        linked,
+        services().globals.server_name(),
+        services().globals.solana_localpart_namespace(),
+    )
+    .ok()
+    .and_then(|user_id| services().users.has_password(&user_id).ok())
+    .unwrap_or(false);
+
+    if !has_password {
+        return error;
+    }
+
+    Error::BadRequest(
+        ruma::api::client::error::ErrorKind::forbidden(),
+        "Wallet authentication failed. This account also has a password: log in with m.login.password instead.",
+    )
+}
+
+/// The error a login refused for `message` gets: nonce, challenge and signature
+/// failures carry their own errcode, see [`Error::SolanaAuth`], and anything else is
+/// `M_FORBIDDEN`.
+fn login_error(message: &'static str) -> Error {
+    let errcode = match message {
+        UNKNOWN_NONCE => ERRCODE_UNKNOWN_NONCE,
+        EXPIRED_NONCE => ERRCODE_NONCE_EXPIRED,
+        WRONG_ADDRESS | WRONG_DEVICE | WRONG_DEVICE_NAME | WRONG_SCOPES | WRONG_ORIGIN
+        | WRONG_DOMAIN | WRONG_SERVER | STALE_SIGNATURE | FUTURE_SIGNATURE | UNSIGNED_SIGNED_AT => {
+            ERRCODE_CHALLENGE_MISMATCH
+        }
+        BAD_SIGNATURE => ERRCODE_BAD_SIGNATURE,
+        _ => return Error::BadRequest(ruma::api::client::error::ErrorKind::forbidden(), message),
+    };
+    Error::SolanaAuth(errcode, message)
+}
+
+/// Categorize an error from [`verify_solana_login`] for the admin room failure summary.
+/// Anything other than a nonce, challenge or signature problem means the request
+/// couldn't be decoded.
+pub fn failure_category(error: &Error) -> LoginFailure {
+    let message = match error {
+        Error::BadRequest(_, message) | Error::SolanaAuth(_, message) => *message,
+        Error::SolanaNonceReissued(_) => EXPIRED_NONCE,
+        _ => return LoginFailure::BadEncoding,
+    };
+    match message {
+        UNKNOWN_NONCE => LoginFailure::UnknownNonce,
+        EXPIRED_NONCE => LoginFailure::NonceExpired,
+        WRONG_ADDRESS | WRONG_DEVICE | WRONG_DEVICE_NAME | WRONG_SCOPES | WRONG_ORIGIN
+        | WRONG_DOMAIN | WRONG_SERVER | STALE_SIGNATURE | FUTURE_SIGNATURE | UNSIGNED_SIGNED_AT => {
+            LoginFailure::ChallengeMismatch
+        }
+        BAD_SIGNATURE => LoginFailure::SignatureMismatch,
+        _ => LoginFailure::BadEncoding,
+    }
+}
+
+/// Room actions that need a fresh wallet signature in rooms with [`POWER_ACTION_SIGNATURES`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerAction {
+    /// Removing another member from the room.
+    Kick,
+    /// Banning a user.
+    Ban,
+    /// Changing the room's power levels.
+    PowerLevels,
+    /// Changing whether the room requires signatures for these actions.
+    SignatureMode,
+}
+
+/// The power action a state event performs, if it is one. Membership changes only count
+/// when they target someone other than the sender, so leaving a room is never gated.
+/// Member content that doesn't parse counts as a ban, so it can't slip through ungated.
+pub fn power_action(
+    event_type: &StateEventType,
+    state_key: &str,
+    sender: &UserId,
+    content: &str,
+) -> Option<PowerAction> {
+    match event_type {
+        StateEventType::RoomPowerLevels => Some(PowerAction::PowerLevels),
+        StateEventType::RoomMember if state_key != sender.as_str() => {
+            let Ok(content) = serde_json::from_str::<RoomMemberEventContent>(content) else {
+                return Some(PowerAction::Ban);
+            };
+            match content.membership {
+                MembershipState::Leave => Some(PowerAction::Kick),
+                MembershipState::Ban => Some(PowerAction::Ban),
+                _ => None,
+            }
+        }
+        _ if event_type.to_string() == POWER_ACTION_SIGNATURES => Some(PowerAction::SignatureMode),
+        _ => None,
+    }
+}
+
+/// Refuse a power action in a room that requires a wallet signature for it, unless the
+/// acting device signed a step-up challenge within `solana_step_up_window`. Each
+/// signature covers one action. Accounts without a wallet, neither their own nor a linked
+/// one, can't sign, so they can't perform these actions in such rooms at all.
+pub fn require_power_action_signature(
+    user_id: &UserId,
+    device_id: Option<&DeviceId>,
+    room_id: &RoomId,
+    action: PowerAction,
+) -> Result<()> {
+    let required = services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &POWER_ACTION_SIGNATURES.into(), "")?
+        .and_then(|event| serde_json::from_str::<PowerActionSignatures>(event.content.get()).ok())
+        .is_some_and(|mode| mode.required);
+    if !required {
+        return Ok(());
+    }
+
+    if !has_wallet(
+        user_id,
+        services().globals.server_name(),
+        services().globals.solana_localpart_namespace(),
+        &services().users.linked_wallets(user_id)?,
+    ) {
+        return Err(Error::BadRequest(
+            ruma::api::client::error::ErrorKind::forbidden(),
+            "This room only lets wallet accounts perform this action.",
+        ));
+    }
+
+    if !device_id.is_some_and(|device_id| services().solana.take_step_up(user_id, device_id)) {
+        info!("Refused {action:?} by {user_id} in {room_id}: no fresh wallet signature");
+        return Err(Error::BadRequest(
+            ruma::api::client::error::ErrorKind::forbidden(),
+            "This room requires a fresh wallet signature for this action.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether `user_id` has a wallet to sign step-up challenges with: it is a local wallet
+/// account, or has wallets `linked` to it, the same wallets [`complete_step_up`] accepts.
+fn has_wallet(
+    user_id: &UserId,
+    server_name: &ServerName,
+    namespace: Option<&str>,
+    linked: &[[u8; 32]],
+) -> bool {
+    !linked.is_empty()
+        || (user_id.server_name() == server_name
+            && localpart_to_pubkey(user_id.localpart(), namespace).is_some())
+}
+
+/// Check a step-up signature from a logged-in device and record it for
+/// [`require_power_action_signature`]. The challenge must have been issued for this
+/// device and signed by a wallet that signs in to the account: its own or a linked one.
+pub async fn complete_step_up(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    request: StepUpRequest,
+) -> Result<()> {
+    let error_kind = ruma::api::client::error::ErrorKind::forbidden();
+
+    let request = challenge_login_request(&request.nonce, request.signature)?;
+    if request.device_id.as_deref() != Some(device_id.as_str()) {
+        return Err(login_error(WRONG_DEVICE));
+    }
+
+    let verified = verify_solana_login(&request).await?;
+    if services().solana.resolve_user(&verified)?.0 != user_id {
+        return Err(Error::BadRequest(
+            error_kind,
+            "Challenge was signed by a different wallet.",
+        ));
+    }
+
+    services().solana.record_step_up(user_id, device_id);
+    Ok(())
+}
+
+/// Make a wallet sign in to an existing account, e.g. one with a password, once the
+/// account's device has signed a challenge with it. Returns the wallet's base58 address.
+pub async fn link_solana_wallet(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    request: LinkWalletRequest,
+) -> Result<String> {
+    let request = challenge_login_request(&request.nonce, request.signature)?;
+    if request.device_id.as_deref() != Some(device_id.as_str()) {
+        return Err(login_error(WRONG_DEVICE));
+    }
+
+    let verified = verify_solana_login(&request).await?;
+    services().solana.blocked_wallets.check(&verified.pubkey)?;
+    link_wallet(user_id, &verified)?;
+    info!(
+        "Linked Solana wallet {} to {}",
+        verified.base58_address, user_id
+    );
+    Ok(verified.base58_address)
+}
+
+/// Link a wallet to `user_id` if [`check_link`] allows it, so the wallet signs in to
+/// the account and its registry delegation counts for it. Used by `link_solana_wallet`
+/// and the `link-solana-wallet` admin command.
+pub fn link_wallet(user_id: &UserId, wallet: &VerifiedSolanaLogin) -> Result<()> {
+    let own_user_id = login_user_id(
+        wallet,
+        None,
+        services().globals.server_name(),
+        services().globals.solana_localpart_namespace(),
+    )?;
+    check_link(
+        user_id,
+        &own_user_id,
+        services().users.exists(&own_user_id)?,
+        services()
+            .users
+            .wallet_login_user(&wallet.pubkey)?
+            .as_deref(),
+    )?;
+
+    services().users.link_wallet(user_id, &wallet.pubkey)
+}
+
+/// The account a verified wallet signs in to: the one `linked` to it with
+/// [`link_wallet`], otherwise its own account, see [`pubkey_to_localpart`].
+pub fn login_user_id(
+    wallet: &VerifiedSolanaLogin,
+    linked: Option<OwnedUserId>,
+    server_name: &ServerName,
+    namespace: Option<&str>,
+) -> Result<OwnedUserId> {
+    if let Some(user_id) = linked {
+        return Ok(user_id);
+    }
+    UserId::parse_with_server_name(pubkey_to_localpart(&wallet.pubkey, namespace), server_name)
+        .map_err(|_| {
+            Error::BadRequest(
+                ruma::api::client::error::ErrorKind::InvalidUsername,
+                "Generated username is invalid.",
+            )
+        })
+}
+
+/// Whether a wallet whose own account is `own_user_id` can be linked to `user_id`.
+///
+/// A wallet signs in to one account: linking fails if it is already linked elsewhere or
+/// has already signed in to its own account, which would otherwise become unreachable.
+/// Linking it to the same account again succeeds.
+fn check_link(
+    user_id: &UserId,
+    own_user_id: &UserId,
+    own_account_exists: bool,
+    linked: Option<&UserId>,
+) -> Result<()> {
+    let error_kind = ruma::api::client::error::ErrorKind::forbidden();
+    match linked {
+        Some(linked) if linked == user_id => Ok(()),
+        Some(_) => Err(Error::BadRequest(
+            ruma::api::client::error::ErrorKind::UserInUse,
+            "Wallet is already linked to another account.",
+        )),
+        None if own_user_id == user_id => Err(Error::BadRequest(
+            error_kind,
+            "This account already signs in with that wallet.",
+        )),
+        None if own_account_exists => Err(Error::BadRequest(
+            ruma::api::client::error::ErrorKind::UserInUse,
+            "Wallet already has its own account on this server.",
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Whether a signed challenge can rotate the access token of `device_id`: only devices
+/// the wallet's account already has get a new token, so rotating never creates an
+/// account or a device. The challenge has to name the device.
+pub fn check_rotation(device_id: Option<&DeviceId>, device_exists: bool) -> Result<()> {
+    match device_id {
+        None => Err(Error::BadRequest(
+            ruma::api::client::error::ErrorKind::MissingParam,
+            "Request the challenge with the device_id of the device to rotate.",
+        )),
+        Some(_) if !device_exists => Err(Error::BadRequest(
+            ruma::api::client::error::ErrorKind::NotFound,
+            "This wallet's account has no such device. Log in to create it.",
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+/// The admin room notice for a successful wallet login, new or returning.
+pub fn login_notice(
+    user_id: &UserId,
+    address: &str,
+    device_id: &DeviceId,
+    client_ip: Option<IpAddr>,
+) -> String {
+    let from = client_ip.map_or_else(|| "an unknown IP".to_owned(), |ip| ip.to_string());
+    format!(
+        "Solana user {user_id} logged in with wallet {address} on device {device_id} from {from}."
+    )
+}
+
+/// The notice announcing that `address` registered as `user_id` at `registered_at`
+/// (milliseconds since the unix epoch), from `country` if its IP could be located.
+pub fn signup_notice(
+    address: &str,
+    user_id: &UserId,
+    registered_at: u64,
+    country: Option<&str>,
+) -> String {
+    let from = country.map_or_else(String::new, |country| format!(" from {country}"));
+    format!(
+        "New Solana user {address} registered as {user_id} at {}{from}.",
+        format_timestamp(registered_at)
+    )
+}
+
+/// `data` for the `m.login.solana.signature` login type: where to request a challenge,
+/// as a path and as a URL under `client_url`, the features a client can rely on, and a
+/// [template](sign_message_template) of the message it will be asked to sign, so it can
+/// show the user a preview before requesting a nonce.
+pub fn login_type_data(
+    client_url: &str,
+    server_name: &str,
+    statement: &str,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut data = serde_json::Map::new();
+    data.insert("nonce_endpoint".to_owned(), NONCE_PATH.into());
+    data.insert(
+        "nonce_url".to_owned(),
+        format!("{}{NONCE_PATH}", client_url.trim_end_matches('/')).into(),
+    );
+    data.insert(
+        "message_template".to_owned(),
+        sign_message_template(server_name, statement).into(),
+    );
+    data.insert(
+        "unstable_features".to_owned(),
+        serde_json::json!({ "org.solana.auth.nonce": true }),
+    );
+    data
+}
+
+/// Whether a wallet account's session has outlived `solana_session_lifetime`, counted
+/// from the wallet signature that started it. Sessions that predate the setting start
+/// their clock the first time they are checked.
+pub fn session_expired(user_id: &UserId, device_id: &DeviceId) -> Result<bool> {
+    let Some(lifetime) = services().globals.solana_session_lifetime() else {
+        return Ok(false);
+    };
+    if user_id.server_name() != services().globals.server_name()
+        || localpart_to_pubkey(
+            user_id.localpart(),
+            services().globals.solana_localpart_namespace(),
+        )
+        .is_none()
+    {
+        return Ok(false);
+    }
+
+    let Some(started) = services().users.session_started(user_id, device_id)? else {
+        services().users.start_session(user_id, device_id)?;
+        return Ok(false);
+    };
+    Ok(utils::millis_since_unix_epoch().saturating_sub(started) / 1000 >= lifetime)
+}
+
+/// Refuse a request by a device that signed in with scopes if its path names a room they
+/// don't list.
+pub fn check_scoped_request(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    path_params: &[String],
+) -> Result<()> {
+    let scopes = services().solana.sessions.scopes(user_id, device_id)?;
+    if scopes_allow(&scopes, path_params) {
+        return Ok(());
+    }
+    Err(Error::BadRequest(
+        ruma::api::client::error::ErrorKind::forbidden(),
+        "This wallet session is not scoped to that room.",
+    ))
+}
+
+/// Whether a device limited to `scopes` may make a request with `path_params`: every room
+/// ID or alias among them has to be listed. A device without scopes isn't limited, and one
+/// whose scopes name no room can't reach any.
+fn scopes_allow(scopes: &[String], path_params: &[String]) -> bool {
+    scopes.is_empty()
+        || path_params
+            .iter()
+            .filter(|param| param.starts_with(['!', '#']))
+            .all(|room| scopes.contains(room))
+}
+
+/// How long a wallet session's access token lasts: `solana_session_ttl`, but only for
+/// clients that can refresh it. Tokens given to other clients don't expire.
+fn expiring_session_ttl(session_ttl: Option<u64>, refresh_supported: bool) -> Option<u64> {
+    session_ttl.filter(|_| refresh_supported)
+}
+
+/// Make a wallet session's new `access_token` expire after `solana_session_ttl`, and
+/// return the refresh token that renews it and how long it lasts for the login or
+/// refresh response. Both are `None` when the token doesn't expire.
+pub fn start_expiring_session(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    access_token: &str,
+    refresh_supported: bool,
+) -> Result<(Option<String>, Option<Duration>)> {
+    let Some(ttl) =
+        expiring_session_ttl(services().globals.solana_session_ttl(), refresh_supported)
+    else {
+        return Ok((None, None));
+    };
+
+    let expires_at = utils::millis_since_unix_epoch().saturating_add(ttl.saturating_mul(1000));
+    let refresh_token =
+        services()
+            .solana
+            .sessions
+            .issue(user_id, device_id, access_token, expires_at)?;
+    Ok((Some(refresh_token), Some(Duration::from_secs(ttl))))
+}
+
+/// Whether `access_token` came from [`start_expiring_session`] and has expired. Clients
+/// should use their refresh token then, so the error is a soft logout.
+pub fn access_token_expired(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    access_token: &str,
+) -> Result<bool> {
+    if services().globals.solana_session_ttl().is_none() {
+        return Ok(false);
+    }
+    services().solana.sessions.is_expired(
+        user_id,
+        device_id,
+        access_token,
+        utils::millis_since_unix_epoch(),
+    )
+}
+
+/// Use up a refresh token, returning the device to issue a new access token to.
+///
+/// A refresh token stops working once its device gets another access token some other
+/// way, e.g. a password login, and a session past `solana_session_lifetime` is ended
+/// like it would be on any other request.
+pub fn redeem_refresh_token(refresh_token: &str) -> Result<(OwnedUserId, OwnedDeviceId)> {
+    let unknown = || {
+        Error::BadRequest(
+            ruma::api::client::error::ErrorKind::UnknownToken { soft_logout: false },
+            "Unknown refresh token.",
+        )
+    };
+
+    let (user_id, device_id, access_token) = services()
+        .solana
+        .sessions
+        .redeem(refresh_token)?
+        .ok_or_else(unknown)?;
+    if services().users.find_from_token(&access_token)?.as_ref()
+        != Some(&(user_id.clone(), device_id.clone()))
+    {
+        return Err(unknown());
+    }
+
+    if session_expired(&user_id, &device_id)? {
+        services().users.remove_device(&user_id, &device_id)?;
+        return Err(Error::BadRequest(
+            ruma::api::client::error::ErrorKind::UnknownToken { soft_logout: false },
+            "Wallet session expired. Sign in with your wallet again.",
+        ));
+    }
+    Ok((user_id, device_id))
+}
+
+/// Discovery info for a Solana login response when `solana_login_discovery` is on, so
+/// wallet clients that bootstrapped from a delegated name learn the real base URLs.
+pub fn login_discovery() -> Option<login::v3::DiscoveryInfo> {
+    if !services().globals.solana_login_discovery() {
+        return None;
+    }
+
+    let mut discovery = login::v3::DiscoveryInfo::new(login::v3::HomeserverInfo::new(
+        services().globals.well_known_client(),
+    ));
+    discovery.identity_server = services()
+        .globals
+        .well_known_identity_server()
+        .map(login::v3::IdentityServerInfo::new);
+    Some(discovery)
+}
+
+/// Refuse a wallet login that would recreate a recently deactivated account, so a wallet
+/// can't cycle through deactivation and signup. The wait is `solana_recreation_cooldown`.
+pub fn check_recreation_cooldown(user_id: &UserId) -> Result<()> {
+    let cooldown = services().globals.solana_recreation_cooldown();
+    if cooldown == 0 {
+        return Ok(());
+    }
+
+    let Some(deactivated_at) = services().users.last_deactivation(user_id.localpart())? else {
+        return Ok(());
+    };
+    let elapsed = utils::millis_since_unix_epoch().saturating_sub(deactivated_at) / 1000;
+    if elapsed < cooldown {
+        return Err(Error::BadRequest(
+            ruma::api::client::error::ErrorKind::forbidden(),
+            "This wallet's account was deactivated recently. Try again later.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Decode the wallets in `solana_allowed_addresses` and those listed one per line in
+/// `file`, the contents of `solana_allowed_addresses_file`, where blank lines and `#`
+/// comments are skipped. They are kept as keys, so any spelling of an address matches.
+pub fn parse_allowed_addresses(inline: &[String], file: Option<&str>) -> Result<HashSet<[u8; 32]>> {
+    let listed = file
+        .into_iter()
+        .flat_map(str::lines)
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    inline
+        .iter()
+        .map(String::as_str)
+        .chain(listed)
+        .map(|address| {
+            decode_address(address).map_err(|_| {
+                Error::bad_config("solana_allowed_addresses lists an invalid wallet address.")
+            })
+        })
+        .collect()
+}
+
+/// Refuse a wallet that isn't on a non-empty allowlist, whatever it signed. An empty
+/// allowlist lets every wallet sign in.
+pub fn check_allowed_address(pubkey: &[u8; 32], allowed: &HashSet<[u8; 32]>) -> Result<()> {
+    if allowed.is_empty() || allowed.contains(pubkey) {
+        return Ok(());
+    }
+    Err(Error::BadRequest(
+        ruma::api::client::error::ErrorKind::forbidden(),
+        "This wallet is not allowed to sign in to this server.",
+    ))
+}
+
+/// Account data event type naming the room a wallet account was auto-joined to first,
+/// for clients that open a room after login: `{ "room_id": "!…" }`.
+pub const DEFAULT_ROOM_EVENT_TYPE: &str = "org.solana.default_room";
+
+/// Join a new wallet account to `solana_auto_join_room` and the rooms in
+/// `solana_auto_join_rooms`, so it doesn't start out with an empty room list. A room that
+/// can't be resolved or joined is logged and skipped: the login still succeeds. The first
+/// room the account ends up in is stored as its [default room](DEFAULT_ROOM_EVENT_TYPE).
+pub async fn auto_join(user_id: &UserId) {
+    let server_name = services().globals.server_name();
+    let configured = services()
+        .globals
+        .solana_auto_join_room()
+        .into_iter()
+        .chain(
+            services()
+                .globals
+                .solana_auto_join_rooms()
+                .iter()
+                .map(String::as_str),
+        );
+    let mut targets = Vec::new();
+    for configured in configured {
+        match configured_room(configured, server_name) {
+            Some(target) => targets.push(target),
+            None => warn!("Auto-join room {configured:?} is not a room ID or alias"),
+        }
+    }
+
+    let default_room = join_rooms(
+        &targets,
+        |target| {
+            services()
+                .rooms
+                .state_cache
+                .get_room_id_and_via_servers(user_id, target, Vec::new())
+        },
+        // An account recreated after deactivation may still be in the room.
+        |room_id| {
+            services()
+                .rooms
+                .state_cache
+                .is_joined(user_id, room_id)
+                .unwrap_or(false)
+        },
+        |room_id, servers| async move {
+            services()
+                .rooms
+                .helpers
+                .join_room_by_id(user_id, &room_id, None, &servers, None)
+                .await
+                .map(|_| ())
+        },
+    )
+    .await;
+
+    let Some(room_id) = default_room else {
+        return;
+    };
+    if let Err(error) = services().account_data.update(
+        None,
+        user_id,
+        DEFAULT_ROOM_EVENT_TYPE.into(),
+        &serde_json::json!({
+            "type": DEFAULT_ROOM_EVENT_TYPE,
+            "content": { "room_id": room_id },
+        }),
+    ) {
+        warn!("Could not store the default room of {user_id}: {error}");
+    }
+}
+
+/// Join each of `targets` in turn: `resolve` it to a room and the servers to join
+/// through, skip it if `is_joined` already, and otherwise `join` it. A room that fails is
+/// logged and the rest are still joined. Returns the first room the user ends up in,
+/// whether just joined or already there.
+async fn join_rooms<R, RFut, J, JFut>(
+    targets: &[OwnedRoomOrAliasId],
+    mut resolve: R,
+    is_joined: impl Fn(&RoomId) -> bool,
+    mut join: J,
+) -> Option<OwnedRoomId>
+where
+    R: FnMut(OwnedRoomOrAliasId) -> RFut,
+    RFut: Future<Output = Result<(Vec<OwnedServerName>, OwnedRoomId)>>,
+    J: FnMut(OwnedRoomId, Vec<OwnedServerName>) -> JFut,
+    JFut: Future<Output = Result<()>>,
+{
+    let mut first = None;
+    for target in targets {
+        let (servers, room_id) = match resolve(target.clone()).await {
+            Ok(resolved) => resolved,
+            Err(error) => {
+                warn!("Could not resolve auto-join room {target}: {error}");
+                continue;
+            }
+        };
+
+        if !is_joined(&room_id) {
+            if let Err(error) = join(room_id.clone(), servers).await {
+                warn!("Could not auto-join {room_id}: {error}");
+                continue;
+            }
+        }
+        first.get_or_insert(room_id);
+    }
+    first
+}
+
+/// The room a room setting like `solana_auto_join_room` names. A room ID or full alias is
+/// used as is, and a bare name like "lobby" means the local alias `#lobby:<server_name>`.
+pub fn configured_room(configured: &str, server_name: &ServerName) -> Option<OwnedRoomOrAliasId> {
+    let configured = configured.trim();
+    if configured.starts_with('!') || configured.starts_with('#') {
+        return configured.try_into().ok();
+    }
+
+    format!("#{configured}:{server_name}").try_into().ok()
+}
+
+/// Reject a display name that impersonates another user, according to
+/// `solana_displayname_guard`. Covers both Matrix user IDs and Solana wallet addresses,
+/// since clients show either prominently.
+pub fn check_displayname(user_id: &UserId, displayname: Option<&str>) -> Result<()> {
+    let guard = services().globals.solana_displayname_guard();
+    let Some(displayname) = displayname.filter(|_| guard != DisplaynameGuard::Off) else {
+        return Ok(());
+    };
+
+    let server_name = services().globals.server_name();
+    let strict = guard == DisplaynameGuard::Strict;
+    let Some(target) = impersonation_target(
+        displayname,
+        user_id.as_str(),
+        server_name.as_str(),
+        services().globals.solana_localpart_namespace(),
+        strict,
+    ) else {
+        return Ok(());
+    };
+
+    // Outside strict mode only names that point at a real local account are refused.
+    let collides = strict
+        || UserId::parse(&target)
+            .ok()
+            .filter(|target| target.server_name() == server_name)
+            .map(|target| services().users.exists(&target))
+            .transpose()?
+            .unwrap_or(false);
+
+    if collides {
+        return Err(Error::BadRequest(
+            ruma::api::client::error::ErrorKind::InvalidParam,
+            "Display name may not be another user's ID or wallet address.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// The user ID `displayname` names, if it is a Matrix user ID or a Solana wallet address
+/// that doesn't belong to `own_user_id`. Wallet addresses are mapped to the local user ID
+/// they log in as.
+///
+/// `strict` also ignores surrounding whitespace and compares user IDs case-insensitively,
+/// catching near-identical names that clients render the same way.
+fn impersonation_target(
+    displayname: &str,
+    own_user_id: &str,
+    server_name: &str,
+    namespace: Option<&str>,
+    strict: bool,
+) -> Option<String> {
+    let candidate = if strict {
+        displayname.trim()
+    } else {
+        displayname
+    };
+
+    if candidate.starts_with('@') && candidate.contains(':') {
+        let same = if strict {
+            candidate.eq_ignore_ascii_case(own_user_id)
+        } else {
+            candidate == own_user_id
+        };
+        return (!same).then(|| candidate.to_owned());
+    }
+
+    let pubkey: [u8; 32] = bs58::decode(candidate).into_vec().ok()?.try_into().ok()?;
+    let target = format!("@{}:{server_name}", pubkey_to_localpart(&pubkey, namespace));
+    (target != own_user_id).then_some(target)
+}
+
+/// Display name for a new wallet account: its SNS domain if `solana_resolve_sns` found
+/// one, otherwise `solana_displayname_template` with `{abbrev}` filled in, or the full
+/// base58 address when no template is set.
+pub fn initial_displayname(address: &str, sns_name: Option<&str>) -> String {
+    wallet_displayname(
+        address,
+        sns_name,
+        services().globals.solana_displayname_template(),
+    )
+}
+
+fn wallet_displayname(address: &str, sns_name: Option<&str>, template: Option<&str>) -> String {
+    match (sns_name, template) {
+        (Some(sns_name), _) => sns_name.to_owned(),
+        (None, Some(template)) => render_displayname_template(template, address),
+        (None, None) => address.to_owned(),
+    }
+}
+
+/// Give a wallet account its default display name if it has none, or if it still has
+/// the bare address while a template or SNS domain gives it a better one. A name the
+/// user picked is left alone.
+pub fn apply_default_displayname(
+    user_id: &UserId,
+    address: &str,
+    sns_name: Option<&str>,
+) -> Result<()> {
+    let current = services().users.displayname(user_id)?;
+    let initial = initial_displayname(address, sns_name);
+    let Some(displayname) = default_displayname_update(current.as_deref(), address, &initial)
+    else {
+        return Ok(());
+    };
+
+    check_displayname(user_id, Some(&displayname))?;
+    services().users.set_displayname(user_id, Some(displayname))
+}
+
+/// The display name to set, if any, given the `current` one. Only a missing name or the
+/// bare address count as defaults that may be replaced with `initial`.
+fn default_displayname_update(
+    current: Option<&str>,
+    address: &str,
+    initial: &str,
+) -> Option<String> {
+    match current {
+        Some(current) if current == initial || current != address => None,
+        _ => Some(initial.to_owned()),
+    }
+}
+
+fn render_displayname_template(template: &str, address: &str) -> String {
+    template.replace("{abbrev}", &abbreviate_address(address))
+}
+
+/// Shorten a base58 address to its first and last four characters, the way the web
+/// client shows addresses. Counts characters rather than bytes, since it is also given
+/// addresses straight from a request, before they are checked to be base58.
+pub fn abbreviate_address(address: &str) -> String {
+    let characters = address.chars().collect::<Vec<_>>();
+    if characters.len() <= 12 {
+        return address.to_owned();
+    }
+    let start = characters[..4].iter().collect::<String>();
+    let end = characters[characters.len() - 4..]
+        .iter()
+        .collect::<String>();
+    format!("{start}...{end}")
+}
+
+/// How long after issuance a signature over a nonce is accepted.
+///
+/// `solana_nonce_ttl` is what clients are told they have to start signing, and
+/// `solana_challenge_replay_window` lets the server keep accepting the signature for a
+/// little longer, e.g. for wallets that are slow to confirm. The window never ends before
+/// the TTL.
+fn acceptance_window() -> Duration {
+    let globals = &services().globals;
+    effective_window(
+        globals.solana_nonce_ttl(),
+        globals.solana_challenge_replay_window(),
+    )
+}
+
+fn effective_window(nonce_ttl: u64, replay_window: Option<u64>) -> Duration {
+    Duration::from_secs(replay_window.map_or(nonce_ttl, |window| window.max(nonce_ttl)))
+}
+
+/// When a nonce issued at `issued_at` (milliseconds since the unix epoch) expires.
+fn expiry(issued_at: u64, window: Duration) -> u64 {
+    issued_at + window.as_millis() as u64
+}
+
+/// The challenge message issued for `nonce`, in the format it was requested in, ending
+/// with the scopes it grants. `signed_at` is only stated in the plain challenge.
+fn challenge_message(
+    server_name: &str,
+    nonce: &str,
+    stored: &StoredNonce,
+    signed_at: Option<u64>,
+) -> String {
+    let device_name = stored.initial_device_display_name.as_deref();
+    let message = match stored.format {
+        ChallengeFormat::Legacy => format_sign_message(
+            server_name,
+            nonce,
+            stored.issued_at,
+            stored.expires_at,
+            device_name,
+            &stored.statement,
+            signed_at,
+        ),
+        ChallengeFormat::Siws => format_sign_in_message(
+            server_name,
+            &stored.address,
+            nonce,
+            stored.issued_at,
+            stored.expires_at,
+            device_name,
+            &stored.statement,
+        ),
+    };
+    message + &format_resources(&stored.scopes)
+}
+
+/// The scopes a challenge grants, as the `Resources` list that EIP-4361 and SIWS
+/// messages end with. Empty when there are none.
+fn format_resources(scopes: &[String]) -> String {
+    if scopes.is_empty() {
+        return String::new();
+    }
+    let mut resources = "\nResources:".to_owned();
+    for scope in scopes {
+        resources.push_str("\n- ");
+        resources.push_str(scope);
+    }
+    resources
+}
+
+/// Format the challenge message that the wallet must sign.
+/// This is human-readable so users can verify what they're signing in their wallet popup.
+///
+/// It states when it was issued and when the server stops accepting it, in ISO-8601 UTC,
+/// so the user can see how long they have. A client that sends `signed_at` states it on a
+/// line of its own, in the same format. A bound device name gets its own line so the
+/// wallet shows which device is being signed in. The `statement` closes the message.
+fn format_sign_message(
+    server_name: &str,
+    nonce: &str,
+    issued_at: u64,
+    expires_at: u64,
+    device_name: Option<&str>,
+    statement: &str,
+    signed_at: Option<u64>,
+) -> String {
+    sign_message(
+        server_name,
+        nonce,
+        &format_timestamp(issued_at),
+        &format_timestamp(expires_at),
+        signed_at.map(format_timestamp).as_deref(),
+        device_name,
+        statement,
+    )
+}
+
+/// The message [`format_sign_message`] formats for a challenge without a device name or
+/// scopes, with `{nonce}`, `{issued_at}` and `{expires_at}` standing in for the values
+/// only known once the nonce is issued. The nonce response still carries the message to
+/// sign; this is only for previews.
+pub fn sign_message_template(server_name: &str, statement: &str) -> String {
+    sign_message(
+        server_name,
+        "{nonce}",
+        "{issued_at}",
+        "{expires_at}",
+        None,
+        None,
+        statement,
+    )
+}
+
+fn sign_message(
+    server_name: &str,
+    nonce: &str,
+    issued_at: &str,
+    expires_at: &str,
+    signed_at: Option<&str>,
+    device_name: Option<&str>,
+    statement: &str,
+) -> String {
+    let signed_at = signed_at
+        .map(|signed_at| format!("Signed At: {signed_at}\n"))
+        .unwrap_or_default();
+    let device = device_name
+        .map(|name| format!("Device: {name}\n"))
+        .unwrap_or_default();
+    format!(
+        "Sign in to {server_name}\n\nNonce: {nonce}\nIssued At: {issued_at}\nExpires At: {expires_at}\n{signed_at}{device}\n{statement}"
+    )
+}
+
+/// Format the challenge as a Sign In With Solana message, laid out the way the
+/// wallet-standard `createSignInMessageText` does so wallets recognise it and check its
+/// domain against the site asking. A bound device name goes in the SIWS statement, since
+/// SIWS has no field for it, followed by our `statement`.
+fn format_sign_in_message(
+    server_name: &str,
+    address: &str,
+    nonce: &str,
+    issued_at: u64,
+    expires_at: u64,
+    device_name: Option<&str>,
+    statement: &str,
+) -> String {
+    let issued_at = format_timestamp(issued_at);
+    let expires_at = format_timestamp(expires_at);
+    let device = device_name
+        .map(|name| format!(" as device \"{name}\""))
+        .unwrap_or_default();
+    format!(
+        "{server_name} wants you to sign in with your Solana account:\n{address}\n\nSign in to {server_name}{device}. {statement}\n\nVersion: 1\nNonce: {nonce}\nIssued At: {issued_at}\nExpiration Time: {expires_at}"
+    )
+}
+
+/// Format milliseconds since the unix epoch as an ISO-8601 UTC timestamp, to the second.
+fn format_timestamp(millis: u64) -> String {
+    DateTime::from_timestamp(i64::try_from(millis / 1000).unwrap_or(i64::MAX), 0)
+        .expect("Timestamp is within range")
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Whether the `Expires At` (or, in a SIWS message, `Expiration Time`) in a challenge
+/// message has passed. A message without a readable expiry counts as expired.
+fn message_expired(message: &str, now: u64) -> bool {
+    let expires_at = message
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix("Expires At: ")
+                .or_else(|| line.strip_prefix("Expiration Time: "))
+        })
+        .and_then(|value| DateTime::parse_from_rfc3339(value).ok());
+    expires_at.is_none_or(|expires_at| i128::from(now) > i128::from(expires_at.timestamp_millis()))
+}
+
+/// Generate a cryptographically random nonce string: `bytes` random bytes, hex-encoded.
+fn generate_random_nonce(bytes: usize) -> String {
+    use rand::Rng;
+    let mut nonce = vec![0; bytes];
+    rand::rng().fill(&mut nonce[..]);
+    hex::encode(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "chat.example.com";
+    const OWN_ADDRESS: &str = "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi";
+    const OTHER_ADDRESS: &str = "8qbHbw2BbbTHBW1sbeqakYXVKRQM8Ne7pLK7m6CVfeR";
+    /// 2026-10-16T12:00:00Z and five minutes later, in milliseconds.
+    const ISSUED_AT: u64 = 1_792_152_000_000;
+    const EXPIRES_AT: u64 = ISSUED_AT + 5 * 60 * 1000;
+    /// The default `solana_signature_max_age`.
+    const MAX_AGE: Duration = Duration::from_secs(60);
+    /// The default `solana_challenge_statement`.
+    const STATEMENT: &str =
+        "This signature will not trigger a blockchain transaction or cost any fees.";
+
+    fn own_user_id() -> String {
+        let pubkey: [u8; 32] = bs58::decode(OWN_ADDRESS)
+            .into_vec()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        format!("@{}:{SERVER}", pubkey_to_localpart(&pubkey, None))
+    }
+
+    #[test]
+    fn allows_own_identity_and_ordinary_names() {
+        let own = own_user_id();
+        for strict in [false, true] {
+            assert_eq!(
+                impersonation_target(OWN_ADDRESS, &own, SERVER, None, strict),
+                None
+            );
+            assert_eq!(impersonation_target(&own, &own, SERVER, None, strict), None);
+            assert_eq!(
+                impersonation_target("Alice", &own, SERVER, None, strict),
+                None
+            );
+            assert_eq!(
+                impersonation_target("@alice", &own, SERVER, None, strict),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_wallet_addresses_and_user_ids() {
+        let own = own_user_id();
+        let other = impersonation_target(OTHER_ADDRESS, &own, SERVER, None, false).unwrap();
+        assert!(other.starts_with("@solana_"));
+        assert!(other.ends_with(":chat.example.com"));
+
+        assert_eq!(
+            impersonation_target("@admin:chat.example.com", &own, SERVER, None, false).as_deref(),
+            Some("@admin:chat.example.com")
+        );
+    }
+
+    #[test]
+    fn strict_mode_catches_padded_and_recased_names() {
+        let own = own_user_id();
+        let padded = format!(" {OTHER_ADDRESS} ");
+        assert_eq!(
+            impersonation_target(&padded, &own, SERVER, None, false),
+            None
+        );
+        assert!(impersonation_target(&padded, &own, SERVER, None, true).is_some());
+
+        let own_upper = own.to_uppercase();
+        assert_eq!(
+            impersonation_target(&own_upper, &own, SERVER, None, true),
+            None
+        );
+        assert!(impersonation_target(&own_upper, &own, SERVER, None, false).is_some());
+    }
+
+    #[test]
+    fn reads_a_well_formed_login_body() {
+        let body = serde_json::json!({
+            "type": "m.login.solana.signature",
+            "address": OWN_ADDRESS,
+            "signature": "sig",
+            "nonce": "abc",
+        });
+        let request =
+            SolanaLoginRequest::from_login_body(&body, Some("DEVICE".to_owned()), None).unwrap();
+        assert_eq!(request.address, OWN_ADDRESS);
+        assert_eq!(request.signature, "sig");
+        assert_eq!(request.nonce, "abc");
+        assert_eq!(request.device_id.as_deref(), Some("DEVICE"));
+        assert_eq!(request.delegation, None);
+        assert_eq!(request.signature_scheme, SignatureScheme::Raw);
+    }
+
+    #[test]
+    fn names_the_malformed_login_field() {
+        let valid = serde_json::json!({ "address": "a", "signature": "s", "nonce": "n" });
+        let cases = [
+            ("address", None, LoginBodyError::Missing("Missing address.")),
+            (
+                "signature",
+                None,
+                LoginBodyError::Missing("Missing signature."),
+            ),
+            ("nonce", None, LoginBodyError::Missing("Missing nonce.")),
+            (
+                "address",
+                Some(serde_json::json!(42)),
+                LoginBodyError::Invalid("address must be a non-empty string."),
+            ),
+            (
+                "signature",
+                Some(serde_json::json!(["s"])),
+                LoginBodyError::Invalid("signature must be a non-empty string."),
+            ),
+            (
+                "nonce",
+                Some(serde_json::json!("")),
+                LoginBodyError::Invalid("nonce must be a non-empty string."),
+            ),
+            (
+                "delegation",
+                Some(serde_json::json!({})),
+                LoginBodyError::Invalid("delegation must be a base64 string."),
+            ),
+            (
+                "signature_scheme",
+                Some(serde_json::json!("sha512")),
+                LoginBodyError::Invalid("signature_scheme must be \"ed25519\" or \"ed25519ph\"."),
+            ),
+            (
+                "signed_at",
+                Some(serde_json::json!("2026-10-16T12:00:00Z")),
+                LoginBodyError::Invalid(
+                    "signed_at must be a timestamp in milliseconds since the unix epoch.",
+                ),
+            ),
+        ];
+
+        for (field, value, expected) in cases {
+            let mut body = valid.clone();
+            match value {
+                Some(value) => body[field] = value,
+                None => {
+                    body.as_object_mut().unwrap().remove(field);
+                }
+            }
+            assert_eq!(
+                SolanaLoginRequest::from_login_body(&body, None, None).err(),
+                Some(expected),
+                "{field}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_non_object_login_body() {
+        assert_eq!(
+            SolanaLoginRequest::from_login_body(&serde_json::json!("address"), None, None).err(),
+            Some(LoginBodyError::Invalid("Login body must be a JSON object."))
+        );
+    }
+
+    #[test]
+    fn verifies_signatures_only_under_the_requested_scheme() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let wallet = SigningKey::from_bytes(&[3; 32]);
+        let message =
+            format_sign_message(SERVER, "abc", ISSUED_AT, EXPIRES_AT, None, STATEMENT, None);
+        let raw = wallet.sign(message.as_bytes());
+        let prehashed = wallet
+            .sign_prehashed(Sha512::new().chain_update(message.as_bytes()), None)
+            .unwrap();
+
+        let body = serde_json::json!({
+            "address": OWN_ADDRESS,
+            "signature": "sig",
+            "nonce": "abc",
+            "signature_scheme": "ed25519ph",
+        });
+        let scheme = SolanaLoginRequest::from_login_body(&body, None, None)
+            .unwrap()
+            .signature_scheme;
+        assert_eq!(scheme, SignatureScheme::Prehashed);
+
+        let key = wallet.verifying_key().to_bytes();
+        let bytes = message.as_bytes();
+        let (raw, prehashed) = (raw.to_bytes(), prehashed.to_bytes());
+        let verify = |scheme: SignatureScheme, message: &[u8], signature: &[u8]| {
+            scheme.verifier().verify(&key, message, signature)
+        };
+        assert!(verify(SignatureScheme::Raw, bytes, &raw));
+        assert!(verify(SignatureScheme::Prehashed, bytes, &prehashed));
+        assert!(!verify(SignatureScheme::Raw, bytes, &prehashed));
+        assert!(!verify(SignatureScheme::Prehashed, bytes, &raw));
+        assert!(!verify(
+            SignatureScheme::Prehashed,
+            b"another message",
+            &prehashed
+        ));
+    }
+
+    #[test]
+    fn the_ed25519_verifier_agrees_with_ed25519_dalek() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let verifier = SignatureScheme::default().verifier();
+        let message =
+            format_sign_message(SERVER, "abc", ISSUED_AT, EXPIRES_AT, None, STATEMENT, None);
+        let message = message.as_bytes();
+        let other_message = b"another message".as_slice();
+        for seed in 0..8 {
+            let wallet = SigningKey::from_bytes(&[seed; 32]);
+            let key = wallet.verifying_key();
+            let signature = wallet.sign(message);
+            let forged = SigningKey::from_bytes(&[seed + 100; 32]).sign(message);
+
+            for (message, signature) in [
+                (message, signature),
+                (message, forged),
+                (other_message, signature),
+            ] {
+                assert_eq!(
+                    verifier.verify(key.as_bytes(), message, &signature.to_bytes()),
+                    key.verify_strict(message, &signature).is_ok()
+                );
+            }
+            assert!(verifier.accepts_key(key.as_bytes()));
+        }
+
+        // y = 2 isn't the coordinate of any point on the curve.
+        let mut not_a_point = [0; 32];
+        not_a_point[0] = 2;
+        assert!(!verifier.accepts_key(&not_a_point));
+        assert!(!verifier.accepts_key(&[1; 31]));
+        assert_eq!(verifier.signature_length(), 64);
+
+        // Malformed input is refused rather than panicking.
+        let wallet = SigningKey::from_bytes(&[1; 32]);
+        let signature = wallet.sign(message).to_bytes();
+        assert!(!verifier.verify(&not_a_point, message, &signature));
+        assert!(!verifier.verify(wallet.verifying_key().as_bytes(), message, &signature[..63]));
+
+        // The localpart still comes from the ed25519 key itself.
+        let pubkey = bs58::decode(OWN_ADDRESS).into_vec().unwrap();
+        assert!(verifier.accepts_key(&pubkey));
+        assert_eq!(
+            pubkey_to_localpart(&pubkey.try_into().unwrap(), None),
+            format!("solana_{}", "01".repeat(32))
+        );
+    }
+
+    #[test]
+    fn refuses_signatures_that_only_pass_lax_verification() {
+        use ed25519_dalek::Verifier;
+
+        // The identity point has small order, so with `R` the identity and `S` zero the
+        // lax equation holds for every message.
+        let mut identity = [0; 32];
+        identity[0] = 1;
+        let key = VerifyingKey::from_bytes(&identity).unwrap();
+        let mut forged = [0; 64];
+        forged[..32].copy_from_slice(&identity);
+
+        let message =
+            format_sign_message(SERVER, "abc", ISSUED_AT, EXPIRES_AT, None, STATEMENT, None);
+        let message = message.as_bytes();
+        assert!(key.verify(message, &Signature::from_bytes(&forged)).is_ok());
+        for scheme in [SignatureScheme::Raw, SignatureScheme::Prehashed] {
+            assert!(!scheme.verifier().verify(&identity, message, &forged));
+        }
+    }
+
+    #[test]
+    fn an_auth_key_signature_logs_into_the_main_wallet() {
+        use ed25519_dalek::{Signer, SigningKey};
+        use futures_util::FutureExt;
+
+        let wallet = SigningKey::from_bytes(&[7; 32]);
+        let auth = SigningKey::from_bytes(&[8; 32]);
+        let stranger = SigningKey::from_bytes(&[9; 32]);
+        let [wallet_key, auth_key] = [&wallet, &auth].map(|key| key.verifying_key().to_bytes());
+
+        let message =
+            format_sign_message(SERVER, "abc", ISSUED_AT, EXPIRES_AT, None, STATEMENT, None);
+        let check = |signer: &SigningKey, designated: Option<[u8; 32]>| {
+            let signature = signer.sign(message.as_bytes()).to_bytes();
+            let verify = |key: [u8; 32]| {
+                let verifier = SignatureScheme::Raw.verifier();
+                std::future::ready(verifier.verify(&key, message.as_bytes(), &signature))
+            };
+            let wallet = VerifiedSolanaLogin::new(wallet_key, None);
+            check_login_signature(wallet, verify, || std::future::ready(designated))
+                .now_or_never()
+                .unwrap()
+        };
+
+        // Signed by the designated auth key, the login is the main wallet's.
+        let verified = check(&auth, Some(auth_key)).unwrap();
+        assert_eq!(verified, VerifiedSolanaLogin::new(wallet_key, None));
+        assert_ne!(verified.pubkey, auth_key);
+
+        // The wallet's own signature still works, with or without an auth key.
+        assert_eq!(
+            check(&wallet, Some(auth_key)),
+            Some(VerifiedSolanaLogin::new(wallet_key, None))
+        );
+        assert_eq!(
+            check(&wallet, None),
+            Some(VerifiedSolanaLogin::new(wallet_key, None))
+        );
+
+        // The auth key counts only while designated, and no other key does.
+        assert_eq!(check(&auth, None), None);
+        assert_eq!(check(&stranger, Some(auth_key)), None);
+    }
+
+    #[test]
+    fn refuses_unknown_signature_schemes() {
+        for scheme in ["secp256k1", "ED25519", ""] {
+            let body = serde_json::json!({
+                "address": OWN_ADDRESS,
+                "signature": "sig",
+                "nonce": "abc",
+                "signature_scheme": scheme,
+            });
+            assert_eq!(
+                SolanaLoginRequest::from_login_body(&body, None, None).unwrap_err(),
+                LoginBodyError::Invalid(SIGNATURE_SCHEME.invalid)
+            );
+        }
+        assert!(serde_json::from_value::<SignatureScheme>(serde_json::json!("secp256k1")).is_err());
+        assert_eq!(
+            serde_json::from_value::<SignatureScheme>(serde_json::json!("ed25519")).unwrap(),
+            SignatureScheme::Raw
+        );
+    }
+
+    #[test]
+    fn binds_the_device_name_into_the_signed_message() {
+        use ed25519_dalek::{Signer, SigningKey, Verifier};
+
+        let unbound =
+            format_sign_message(SERVER, "abc", ISSUED_AT, EXPIRES_AT, None, STATEMENT, None);
+        assert_eq!(
+            unbound,
+            "Sign in to chat.example.com\n\nNonce: abc\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees."
+        );
+
+        let wallet = SigningKey::from_bytes(&[1; 32]);
+        let signed = format_sign_message(
+            SERVER,
+            "abc",
+            ISSUED_AT,
+            EXPIRES_AT,
+            Some("Alice's phone"),
+            STATEMENT,
+            None,
+        );
+        assert!(signed.contains("\nDevice: Alice's phone\n"));
+        let signature = wallet.sign(signed.as_bytes());
+
+        let tampered = format_sign_message(
+            SERVER,
+            "abc",
+            ISSUED_AT,
+            EXPIRES_AT,
+            Some("Mallory's laptop"),
+            STATEMENT,
+            None,
+        );
+        assert!(wallet
+            .verifying_key()
+            .verify_strict(tampered.as_bytes(), &signature)
+            .is_err());
+        assert!(wallet
+            .verifying_key()
+            .verify_strict(signed.as_bytes(), &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn nonce_ttl_decides_when_a_signature_is_too_late() {
+        use ed25519_dalek::{Signer, SigningKey, Verifier};
+
+        let wallet = SigningKey::from_bytes(&[2; 32]);
+        let two_minutes_later = ISSUED_AT + 2 * 60 * 1000;
+
+        for (ttl, accepted) in [(60, false), (600, true)] {
+            let expires_at = expiry(ISSUED_AT, effective_window(ttl, None));
+            let stored = StoredNonce {
+                issued_at: ISSUED_AT,
+                expires_at,
+                address: OWN_ADDRESS.to_owned(),
+                device_id: None,
+                initial_device_display_name: None,
+                format: ChallengeFormat::Legacy,
+                statement: STATEMENT.to_owned(),
+                scopes: Vec::new(),
+                origin: None,
+            };
+            let message =
+                format_sign_message(SERVER, "abc", ISSUED_AT, expires_at, None, STATEMENT, None);
+            let signature = wallet.sign(message.as_bytes());
+
+            assert!(wallet
+                .verifying_key()
+                .verify_strict(message.as_bytes(), &signature)
+                .is_ok());
+            assert_eq!(stored.is_expired(two_minutes_later), !accepted, "ttl {ttl}");
+            assert_eq!(
+                message_expired(&message, two_minutes_later),
+                !accepted,
+                "ttl {ttl}"
+            );
+        }
+    }
+
+    #[test]
+    fn enforces_the_expiry_in_the_message() {
+        let message =
+            format_sign_message(SERVER, "abc", ISSUED_AT, EXPIRES_AT, None, STATEMENT, None);
+        assert!(!message_expired(&message, ISSUED_AT));
+        assert!(!message_expired(&message, EXPIRES_AT));
+        assert!(message_expired(&message, EXPIRES_AT + 1000));
+
+        let without_expiry = message.replace("Expires At", "Valid Until");
+        assert!(message_expired(&without_expiry, ISSUED_AT));
+    }
+
+    fn stored_challenge(address: &str, format: ChallengeFormat) -> StoredNonce {
+        StoredNonce {
+            issued_at: ISSUED_AT,
+            expires_at: EXPIRES_AT,
+            address: address.to_owned(),
+            device_id: None,
+            initial_device_display_name: None,
+            format,
+            statement: STATEMENT.to_owned(),
+            scopes: Vec::new(),
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn signs_and_verifies_a_sign_in_with_solana_challenge() {
+        use ed25519_dalek::{Signer, SigningKey, Verifier};
+
+        let wallet = SigningKey::from_bytes(&[3; 32]);
+        let address = bs58::encode(wallet.verifying_key().as_bytes()).into_string();
+        let stored = stored_challenge(&address, ChallengeFormat::Siws);
+
+        let message = challenge_message(SERVER, "abc", &stored, None);
+        assert_eq!(
+            message,
+            format!("chat.example.com wants you to sign in with your Solana account:\n{address}\n\nSign in to chat.example.com. This signature will not trigger a blockchain transaction or cost any fees.\n\nVersion: 1\nNonce: abc\nIssued At: 2026-10-16T12:00:00Z\nExpiration Time: 2026-10-16T12:05:00Z")
+        );
+        assert_eq!(
+            SignInMessage::parse(&message),
+            Some(SignInMessage {
+                domain: SERVER,
+                address: &address,
+                nonce: "abc",
+                resources: Vec::new(),
+            })
+        );
+        assert!(!message_expired(&message, EXPIRES_AT));
+        assert!(message_expired(&message, EXPIRES_AT + 1000));
+
+        // The server rebuilds the message from the stored nonce, so the signature only
+        // holds for the wallet and domain it was issued to.
+        let signature = wallet.sign(message.as_bytes());
+        assert!(wallet
+            .verifying_key()
+            .verify_strict(
+                challenge_message(SERVER, "abc", &stored, None).as_bytes(),
+                &signature
+            )
+            .is_ok());
+        assert!(wallet
+            .verifying_key()
+            .verify_strict(
+                challenge_message("evil.example.com", "abc", &stored, None).as_bytes(),
+                &signature
+            )
+            .is_err());
+
+        let bound = StoredNonce {
+            initial_device_display_name: Some("Alice's phone".to_owned()),
+            ..stored
+        };
+        assert!(challenge_message(SERVER, "abc", &bound, None)
+            .contains("\n\nSign in to chat.example.com as device \"Alice's phone\". "));
+    }
+
+    #[test]
+    fn legacy_challenges_stay_the_default() {
+        use ed25519_dalek::{Signer, SigningKey, Verifier};
+
+        let request: NonceRequest =
+            serde_json::from_value(serde_json::json!({ "address": OWN_ADDRESS })).unwrap();
+        assert_eq!(request.format, ChallengeFormat::Legacy);
+        let request: NonceRequest =
+            serde_json::from_value(serde_json::json!({ "address": OWN_ADDRESS, "format": "siws" }))
+                .unwrap();
+        assert_eq!(request.format, ChallengeFormat::Siws);
+
+        let wallet = SigningKey::from_bytes(&[4; 32]);
+        let stored = stored_challenge(OWN_ADDRESS, ChallengeFormat::Legacy);
+        let message = challenge_message(SERVER, "abc", &stored, None);
+        assert_eq!(
+            message,
+            format_sign_message(SERVER, "abc", ISSUED_AT, EXPIRES_AT, None, STATEMENT, None)
+        );
+        assert_eq!(SignInMessage::parse(&message), None);
+
+        let signature = wallet.sign(message.as_bytes());
+        assert!(wallet
+            .verifying_key()
+            .verify_strict(
+                challenge_message(SERVER, "abc", &stored, None).as_bytes(),
+                &signature
+            )
+            .is_ok());
+        let siws = stored_challenge(OWN_ADDRESS, ChallengeFormat::Siws);
+        assert!(wallet
+            .verifying_key()
+            .verify_strict(
+                challenge_message(SERVER, "abc", &siws, None).as_bytes(),
+                &signature
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn custom_statements_survive_the_round_trip_through_the_store() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let wallet = SigningKey::from_bytes(&[5; 32]);
+        for format in [ChallengeFormat::Legacy, ChallengeFormat::Siws] {
+            let stored = StoredNonce {
+                statement:
+                    "Aucune transaction, aucuns frais. Conditions : https://chat.example.com/cgu"
+                        .to_owned(),
+                ..stored_challenge(OWN_ADDRESS, format)
+            };
+            let issued = challenge_message(SERVER, "abc", &stored, None);
+            assert!(issued.contains("Aucune transaction, aucuns frais."));
+            assert!(!issued.contains(STATEMENT));
+            let signature = wallet.sign(issued.as_bytes());
+
+            let reloaded: StoredNonce =
+                serde_json::from_str(&serde_json::to_string(&stored).unwrap()).unwrap();
+            let rebuilt = challenge_message(SERVER, "abc", &reloaded, None);
+            assert_eq!(rebuilt, issued);
+            assert!(SignatureScheme::Raw.verifier().verify(
+                wallet.verifying_key().as_bytes(),
+                rebuilt.as_bytes(),
+                &signature.to_bytes()
+            ));
+
+            let default = stored_challenge(OWN_ADDRESS, format);
+            assert!(!SignatureScheme::Raw.verifier().verify(
+                wallet.verifying_key().as_bytes(),
+                challenge_message(SERVER, "abc", &default, None).as_bytes(),
+                &signature.to_bytes()
+            ));
+            assert!(!message_expired(&rebuilt, ISSUED_AT));
+        }
+    }
+
+    #[test]
+    fn reads_a_sign_in_with_solana_output() {
+        use ed25519_dalek::{Signer, SigningKey, Verifier};
+
+        let wallet = SigningKey::from_bytes(&[1; 32]);
+        let address = bs58::encode(wallet.verifying_key().as_bytes()).into_string();
+        let message = format!(
+            "{SERVER} wants you to sign in with your Solana account:\n{address}\n\nSign in to chat\n\nURI: https://{SERVER}\nVersion: 1\nChain ID: mainnet\nNonce: abc123\nIssued At: 2026-01-01T00:00:00.000Z"
+        );
+        let signature = wallet.sign(message.as_bytes());
+
+        let body = serde_json::json!({
+            "type": "m.login.solana.signature",
+            "sign_in_output": {
+                "account": { "address": address, "publicKey": wallet.verifying_key().as_bytes() },
+                "signedMessage": general_purpose::STANDARD.encode(&message),
+                "signature": signature.to_bytes().to_vec(),
+                "signatureType": "ed25519",
+            },
+        });
+        let request = SolanaLoginRequest::from_login_body(&body, None, None).unwrap();
+        assert_eq!(request.address, address);
+        assert_eq!(request.nonce, "abc123");
+        assert_eq!(request.signed_message.as_deref(), Some(message.as_bytes()));
+
+        let decoded: [u8; 64] = bs58::decode(&request.signature)
+            .into_vec()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert!(wallet
+            .verifying_key()
+            .verify_strict(message.as_bytes(), &Signature::from_bytes(&decoded))
+            .is_ok());
+        assert_eq!(
+            SignInMessage::parse(&message).map(|message| message.domain),
+            Some(SERVER)
+        );
+
+        let mut other_account = body.clone();
+        other_account["sign_in_output"]["account"]["address"] = OWN_ADDRESS.into();
+        assert_eq!(
+            SolanaLoginRequest::from_login_body(&other_account, None, None).err(),
+            Some(LoginBodyError::Invalid(
+                "signedMessage is for a different account."
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_sign_in_messages() {
+        let header = "chat.example.com wants you to sign in with your Solana account:";
+        assert_eq!(
+            SignInMessage::parse(&format!("{header}\n{OWN_ADDRESS}\n\nNonce: abc")),
+            Some(SignInMessage {
+                domain: SERVER,
+                address: OWN_ADDRESS,
+                nonce: "abc",
+                resources: Vec::new(),
+            })
+        );
+        assert_eq!(
+            SignInMessage::parse(&format!(
+                "{header}\n{OWN_ADDRESS}\n\nNonce: abc\nResources:\n- !a:x\n- !b:x"
+            ))
+            .map(|message| message.resources),
+            Some(vec!["!a:x", "!b:x"])
+        );
+        assert_eq!(
+            SignInMessage::parse(&format!(
+                "{header}\n{OWN_ADDRESS}\n\nNonce: abc\nResources:\nChain ID: mainnet"
+            )),
+            None
+        );
+        assert_eq!(
+            SignInMessage::parse(&format!("{header}\n{OWN_ADDRESS}\n\nVersion: 1")),
+            None
+        );
+        assert_eq!(
+            SignInMessage::parse(&format!("{header}\n{OWN_ADDRESS}\nNonce: a\nNonce: b")),
+            None
+        );
+        assert_eq!(
+            SignInMessage::parse(&format_sign_message(
+                SERVER, "abc", ISSUED_AT, EXPIRES_AT, None, STATEMENT, None
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn replay_window_only_extends_the_nonce_ttl() {
+        assert_eq!(effective_window(300, None), Duration::from_secs(300));
+        assert_eq!(effective_window(30, Some(120)), Duration::from_secs(120));
+        assert_eq!(effective_window(300, Some(60)), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn renders_the_displayname_template() {
+        assert_eq!(
+            render_displayname_template("Wallet {abbrev}", OWN_ADDRESS),
+            "Wallet 4vJ9...kLKi"
+        );
+        assert_eq!(
+            render_displayname_template("{abbrev} ({abbrev})", "short"),
+            "short (short)"
+        );
+        assert_eq!(render_displayname_template("Anon", OWN_ADDRESS), "Anon");
+    }
+
+    #[test]
+    fn abbreviates_unvalidated_addresses_by_character() {
+        assert_eq!(abbreviate_address("aéééééééééééé"), "aééé...éééé");
+        assert_eq!(
+            abbreviate_address("☕".repeat(12).as_str()),
+            "☕".repeat(12)
+        );
+        assert_eq!(
+            abbreviate_address(&format!("{OWN_ADDRESS}\u{200B}")),
+            "4vJ9...LKi\u{200B}"
+        );
+    }
+
+    #[test]
+    fn new_accounts_are_named_after_their_sns_domain_when_they_have_one() {
+        for template in [None, Some("Wallet {abbrev}")] {
+            assert_eq!(
+                wallet_displayname(OWN_ADDRESS, Some("alice.sol"), template),
+                "alice.sol"
+            );
+        }
+        assert_eq!(wallet_displayname(OWN_ADDRESS, None, None), OWN_ADDRESS);
+        assert_eq!(
+            wallet_displayname(OWN_ADDRESS, None, Some("Wallet {abbrev}")),
+            "Wallet 4vJ9...kLKi"
+        );
+    }
+
+    #[test]
+    fn only_replaces_default_displaynames() {
+        // A new account gets the default name.
+        assert_eq!(
+            default_displayname_update(None, OWN_ADDRESS, OWN_ADDRESS),
+            Some(OWN_ADDRESS.to_owned())
+        );
+        // A returning account that kept it is left as it is.
+        assert_eq!(
+            default_displayname_update(Some(OWN_ADDRESS), OWN_ADDRESS, OWN_ADDRESS),
+            None
+        );
+        // A bare address is moved onto a newly configured template...
+        assert_eq!(
+            default_displayname_update(Some(OWN_ADDRESS), OWN_ADDRESS, "Wallet 4vJ9...kLKi"),
+            Some("Wallet 4vJ9...kLKi".to_owned())
+        );
+        // ...but a custom name is never touched.
+        assert_eq!(
+            default_displayname_update(Some("Alice"), OWN_ADDRESS, OWN_ADDRESS),
+            None
+        );
+        assert_eq!(
+            default_displayname_update(Some("Alice"), OWN_ADDRESS, "Wallet 4vJ9...kLKi"),
+            None
+        );
+    }
+
+    #[test]
+    fn categorizes_login_failures() {
+        let forbidden = ruma::api::client::error::ErrorKind::forbidden;
+
+        assert_eq!(
+            failure_category(&Error::BadRequest(forbidden(), EXPIRED_NONCE)),
+            LoginFailure::NonceExpired
+        );
+        assert_eq!(
+            failure_category(&Error::BadRequest(forbidden(), WRONG_DEVICE_NAME)),
+            LoginFailure::ChallengeMismatch
+        );
+        assert_eq!(
+            failure_category(&Error::BadRequest(forbidden(), BAD_SIGNATURE)),
+            LoginFailure::SignatureMismatch
+        );
+        assert_eq!(
+            failure_category(&Error::BadRequest(forbidden(), "Invalid base58 signature.")),
+            LoginFailure::BadEncoding
+        );
+        assert_eq!(
+            failure_category(&login_error(UNKNOWN_NONCE)),
+            LoginFailure::UnknownNonce
+        );
+        assert_eq!(
+            failure_category(&login_error(WRONG_DOMAIN)),
+            LoginFailure::ChallengeMismatch
+        );
+    }
+
+    #[test]
+    fn login_failures_carry_their_own_errcode() {
+        let (stored, request) = login_with_nonce_for(OWN_ADDRESS, OTHER_ADDRESS);
+        let mismatch = check_challenge_binding(&stored, &request)
+            .map_err(login_error)
+            .unwrap_err();
+
+        let failures = [
+            (login_error(UNKNOWN_NONCE), ERRCODE_UNKNOWN_NONCE),
+            (login_error(EXPIRED_NONCE), ERRCODE_NONCE_EXPIRED),
+            (mismatch, ERRCODE_CHALLENGE_MISMATCH),
+            (login_error(BAD_SIGNATURE), ERRCODE_BAD_SIGNATURE),
+        ];
+        for (error, expected) in &failures {
+            assert!(
+                matches!(error, Error::SolanaAuth(errcode, _) if errcode == expected),
+                "{error:?}"
+            );
+        }
+        let errcodes: HashSet<_> = failures.iter().map(|(_, errcode)| errcode).collect();
+        assert_eq!(errcodes.len(), failures.len());
+
+        // The human message is kept alongside the errcode.
+        assert_eq!(
+            login_error(EXPIRED_NONCE).to_string(),
+            format!("{ERRCODE_NONCE_EXPIRED}: {EXPIRED_NONCE}")
+        );
+        // Failures clients can't act on stay M_FORBIDDEN.
+        assert!(matches!(
+            login_error(INVALID_KEY),
+            Error::BadRequest(_, INVALID_KEY)
+        ));
+    }
+
+    #[test]
+    fn reissues_nonces_that_only_just_expired() {
+        let stored = stored_challenge(OWN_ADDRESS, ChallengeFormat::Legacy);
+        let reissue = |expired: &StoredNonce| {
+            Ok(NonceResponse {
+                nonce: "fresh".to_owned(),
+                message: format!("Sign in as {}", expired.address),
+                expires_in_seconds: 300,
+            })
+        };
+
+        // Just past expiry, and at the very end of the grace, the login gets a new challenge.
+        for late_by in [1, REISSUE_GRACE_MILLIS] {
+            let error = expired_nonce_error(&stored, EXPIRES_AT + late_by, reissue).unwrap();
+            let Error::SolanaNonceReissued(challenge) = &error else {
+                panic!("expected a new challenge, got {error:?}");
+            };
+            assert_eq!(challenge.nonce, "fresh");
+            assert_eq!(challenge.message, format!("Sign in as {OWN_ADDRESS}"));
+            assert_eq!(failure_category(&error), LoginFailure::NonceExpired);
+            assert_eq!(
+                error.to_string(),
+                format!("{ERRCODE_NONCE_EXPIRED}: {EXPIRED_NONCE}")
+            );
+        }
+
+        // Any later and it just fails, without issuing anything.
+        let error = expired_nonce_error(&stored, EXPIRES_AT + REISSUE_GRACE_MILLIS + 1, |_| {
+            unreachable!("stale nonces aren't reissued")
+        })
+        .unwrap();
+        assert!(matches!(
+            error,
+            Error::SolanaAuth(ERRCODE_NONCE_EXPIRED, EXPIRED_NONCE)
+        ));
+    }
+
+    fn login_with_nonce_for(address: &str, claimed: &str) -> (StoredNonce, SolanaLoginRequest) {
+        let stored = StoredNonce {
+            issued_at: 0,
+            expires_at: u64::MAX,
+            address: address.to_owned(),
+            device_id: None,
+            initial_device_display_name: None,
+            format: ChallengeFormat::Legacy,
+            statement: STATEMENT.to_owned(),
+            scopes: Vec::new(),
+            origin: None,
+        };
+        let request = SolanaLoginRequest {
+            address: claimed.to_owned(),
+            signature: String::new(),
+            signature_scheme: SignatureScheme::Raw,
+            nonce: "abc".to_owned(),
+            device_id: None,
+            delegation: None,
+            initial_device_display_name: None,
+            signed_message: None,
+            domain: None,
+            origin: None,
+            client_ip: None,
+            refresh_token: false,
+            signed_at: None,
+        };
+        (stored, request)
+    }
+
+    #[test]
+    fn nonces_are_as_long_as_configured() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let wallet = SigningKey::from_bytes(&[7; 32]);
+        let address = bs58::encode(wallet.verifying_key().as_bytes()).into_string();
+        for bytes in [16, 32, 64] {
+            let nonce = generate_random_nonce(bytes);
+            assert_eq!(nonce.len(), 2 * bytes);
+            assert!(nonce.bytes().all(|byte| byte.is_ascii_hexdigit()));
+
+            // The message is rebuilt around the nonce the login names, whatever its length.
+            for format in [ChallengeFormat::Legacy, ChallengeFormat::Siws] {
+                let stored = stored_challenge(&address, format);
+                let issued = challenge_message(SERVER, &nonce, &stored, None);
+                let signature = wallet.sign(issued.as_bytes());
+
+                let (_, mut request) = login_with_nonce_for(&address, &address);
+                request.nonce = nonce.clone();
+                let message =
+                    expected_message(&request, &stored, SERVER, &[], ISSUED_AT, MAX_AGE).unwrap();
+                assert!(wallet
+                    .verifying_key()
+                    .verify_strict(&message, &signature)
+                    .is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn scopes_are_part_of_the_signed_message() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let wallet = SigningKey::from_bytes(&[6; 32]);
+        let address = bs58::encode(wallet.verifying_key().as_bytes()).into_string();
+        let scopes = vec!["!lobby:chat.example.com".to_owned(), "read-only".to_owned()];
+        for format in [ChallengeFormat::Legacy, ChallengeFormat::Siws] {
+            let stored = StoredNonce {
+                scopes: scopes.clone(),
+                ..stored_challenge(&address, format)
+            };
+            let issued = challenge_message(SERVER, "abc", &stored, None);
+            assert!(issued.ends_with("\nResources:\n- !lobby:chat.example.com\n- read-only"));
+            let signature = wallet.sign(issued.as_bytes());
+
+            // The server rebuilds the message from the stored scopes, so a signature over
+            // them doesn't hold for any others.
+            let (_, request) = login_with_nonce_for(&address, &address);
+            let verify = |stored: &StoredNonce| {
+                let message =
+                    expected_message(&request, stored, SERVER, &[], ISSUED_AT, MAX_AGE).unwrap();
+                wallet
+                    .verifying_key()
+                    .verify_strict(&message, &signature)
+                    .is_ok()
+            };
+            assert!(verify(&stored), "{format:?}");
+            let widened = StoredNonce {
+                scopes: vec!["!lobby:chat.example.com".to_owned()],
+                ..stored.clone()
+            };
+            assert!(!verify(&widened), "{format:?}");
+            let unscoped = StoredNonce {
+                scopes: Vec::new(),
+                ..stored.clone()
+            };
+            assert!(!verify(&unscoped), "{format:?}");
+        }
+
+        // A wallet building its own sign-in message has to list the same scopes.
+        let stored = StoredNonce {
+            scopes,
+            ..stored_challenge(&address, ChallengeFormat::Siws)
+        };
+        let (_, mut request) = login_with_nonce_for(&address, &address);
+        let signed = challenge_message(SERVER, "abc", &stored, None);
+        request.signed_message = Some(signed.clone().into_bytes());
+        assert_eq!(
+            expected_message(&request, &stored, SERVER, &[], ISSUED_AT, MAX_AGE),
+            Ok(signed.clone().into_bytes())
+        );
+        request.signed_message = Some(signed.replace("\n- read-only", "").into_bytes());
+        assert_eq!(
+            expected_message(&request, &stored, SERVER, &[], ISSUED_AT, MAX_AGE),
+            Err(WRONG_SCOPES)
+        );
+        assert_eq!(
+            failure_category(&login_error(WRONG_SCOPES)),
+            LoginFailure::ChallengeMismatch
+        );
+    }
+
+    #[test]
+    fn padded_addresses_fingerprint_as_the_same_login() {
+        let fingerprint = |address: &str| {
+            login_fingerprint(&decode_address(address).unwrap(), "abc", b"signature")
+        };
+        let plain = fingerprint(OWN_ADDRESS);
+        assert_eq!(fingerprint(&format!(" {OWN_ADDRESS}\n")), plain);
+        assert_eq!(
+            fingerprint(&format!("\u{200B}{OWN_ADDRESS}\u{FEFF}")),
+            plain
+        );
+    }
+
+    #[test]
+    fn scoped_devices_only_reach_their_rooms() {
+        let scopes = ["!lobby:chat.example.com".to_owned(), "read-only".to_owned()];
+        let path = |params: &[&str]| {
+            params
+                .iter()
+                .map(|&param| param.to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(scopes_allow(
+            &scopes,
+            &path(&["!lobby:chat.example.com", "m.room.message", "txn1"])
+        ));
+        assert!(!scopes_allow(
+            &scopes,
+            &path(&["!other:chat.example.com", "m.room.message", "txn1"])
+        ));
+        assert!(!scopes_allow(&scopes, &path(&["#other:chat.example.com"])));
+        // Requests that name no room, like `/sync` or a profile lookup, aren't limited.
+        assert!(scopes_allow(&scopes, &[]));
+        assert!(scopes_allow(
+            &scopes,
+            &path(&["@alice:chat.example.com", "displayname"])
+        ));
+
+        // Scopes that name no room leave the device no room to reach.
+        assert!(!scopes_allow(
+            &scopes[1..],
+            &path(&["!lobby:chat.example.com"])
+        ));
+        // Unscoped devices reach every room.
+        assert!(scopes_allow(&[], &path(&["!other:chat.example.com"])));
+    }
+
+    #[test]
+    fn signatures_older_than_the_max_age_or_from_the_future_are_refused() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let wallet = SigningKey::from_bytes(&[3; 32]);
+        let address = bs58::encode(wallet.verifying_key().as_bytes()).into_string();
+        let stored = stored_challenge(&address, ChallengeFormat::Legacy);
+        let (_, mut request) = login_with_nonce_for(&address, &address);
+        let now = ISSUED_AT + 2 * 60 * 1000;
+
+        // A fresh signature. The message states when it was signed, so the time can't be
+        // moved forward afterwards to keep the login acceptable.
+        let signed_at = now - 30 * 1000;
+        let issued = challenge_message(SERVER, "abc", &stored, Some(signed_at));
+        assert!(issued
+            .contains("\nExpires At: 2026-10-16T12:05:00Z\nSigned At: 2026-10-16T12:01:30Z\n"));
+        let signature = wallet.sign(issued.as_bytes());
+        let verify = |request: &SolanaLoginRequest| {
+            let message = expected_message(request, &stored, SERVER, &[], now, MAX_AGE).unwrap();
+            wallet
+                .verifying_key()
+                .verify_strict(&message, &signature)
+                .is_ok()
+        };
+        request.signed_at = Some(signed_at);
+        assert!(verify(&request));
+        request.signed_at = Some(now);
+        assert!(!verify(&request));
+
+        // Too old.
+        request.signed_at = Some(now - MAX_AGE.as_millis() as u64 - 1);
+        assert_eq!(
+            expected_message(&request, &stored, SERVER, &[], now, MAX_AGE),
+            Err(STALE_SIGNATURE)
+        );
+
+        // Dated in the future, past what a slightly fast clock explains.
+        request.signed_at = Some(now + SIGNED_AT_TOLERANCE_MILLIS);
+        assert!(expected_message(&request, &stored, SERVER, &[], now, MAX_AGE).is_ok());
+        request.signed_at = Some(now + SIGNED_AT_TOLERANCE_MILLIS + 1);
+        assert_eq!(
+            expected_message(&request, &stored, SERVER, &[], now, MAX_AGE),
+            Err(FUTURE_SIGNATURE)
+        );
+
+        // A SIWS message has no line to state it in, so it wouldn't be signed.
+        request.signed_at = Some(now);
+        let siws = stored_challenge(&address, ChallengeFormat::Siws);
+        assert_eq!(
+            expected_message(&request, &siws, SERVER, &[], now, MAX_AGE),
+            Err(UNSIGNED_SIGNED_AT)
+        );
+        assert_eq!(
+            failure_category(&login_error(STALE_SIGNATURE)),
+            LoginFailure::ChallengeMismatch
+        );
+    }
+
+    #[test]
+    fn dry_runs_report_the_stage_that_failed() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let wallet = SigningKey::from_bytes(&[4; 32]);
+        let address = bs58::encode(wallet.verifying_key().as_bytes()).into_string();
+        let stored = stored_challenge(&address, ChallengeFormat::Legacy);
+        let message = challenge_message(SERVER, "abc", &stored, None);
+        let signature = bs58::encode(wallet.sign(message.as_bytes()).to_bytes()).into_string();
+        let (_, valid) = login_with_nonce_for(&address, &address);
+        let valid = SolanaLoginRequest { signature, ..valid };
+
+        let diagnose = |request: &SolanaLoginRequest, stored: Option<&StoredNonce>| {
+            diagnose_login(request, stored, SERVER, &[], ISSUED_AT, MAX_AGE)
+        };
+        let stage = |address: &str, signature: &str, stored: Option<&StoredNonce>| {
+            let request = SolanaLoginRequest {
+                address: address.to_owned(),
+                signature: signature.to_owned(),
+                ..login_with_nonce_for(address, address).1
+            };
+            diagnose(&request, stored).stage
+        };
+
+        // y = 2 isn't the coordinate of any point on the curve.
+        let mut not_a_point = [0; 32];
+        not_a_point[0] = 2;
+        let not_a_point = bs58::encode(not_a_point).into_string();
+        let short = bs58::encode([1; 63]).into_string();
+        for (address, signature, expected) in [
+            ("0OIl", valid.signature.as_str(), VerifyStage::AddressDecode),
+            (
+                "3yZe7d",
+                valid.signature.as_str(),
+                VerifyStage::AddressLength,
+            ),
+            (
+                &not_a_point,
+                valid.signature.as_str(),
+                VerifyStage::KeyParse,
+            ),
+            (&address, "not base58!", VerifyStage::SignatureDecode),
+            (&address, &short, VerifyStage::SignatureLength),
+        ] {
+            assert_eq!(stage(address, signature, Some(&stored)), Some(expected));
+        }
+
+        assert_eq!(
+            diagnose(&valid, None),
+            LoginDiagnosis::failed(VerifyStage::NonceLookup, UNKNOWN_NONCE, None)
+        );
+        let other = stored_challenge(OTHER_ADDRESS, ChallengeFormat::Legacy);
+        assert_eq!(
+            diagnose(&valid, Some(&other)),
+            LoginDiagnosis::failed(VerifyStage::NonceLookup, WRONG_ADDRESS, None)
+        );
+        assert_eq!(
+            diagnose_login(&valid, Some(&stored), SERVER, &[], EXPIRES_AT + 1, MAX_AGE),
+            LoginDiagnosis::failed(VerifyStage::NonceLookup, EXPIRED_NONCE, None)
+        );
+
+        // The message to sign is shown once the nonce is found, to compare against.
+        let forged = SolanaLoginRequest {
+            signature: bs58::encode(
+                SigningKey::from_bytes(&[5; 32])
+                    .sign(message.as_bytes())
+                    .to_bytes(),
+            )
+            .into_string(),
+            ..login_with_nonce_for(&address, &address).1
+        };
+        assert_eq!(
+            diagnose(&forged, Some(&stored)),
+            LoginDiagnosis::failed(
+                VerifyStage::SignatureVerify,
+                BAD_SIGNATURE,
+                Some(message.clone())
+            )
+        );
+
+        assert_eq!(
+            diagnose(&valid, Some(&stored)),
+            LoginDiagnosis {
+                ok: true,
+                stage: None,
+                error: None,
+                message: Some(message),
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(diagnose(&forged, Some(&stored))).unwrap()["stage"],
+            "signature_verify"
+        );
+    }
+
+    #[test]
+    fn admin_verification_replies_with_the_account_or_the_reason() {
+        use ed25519_dalek::{Signer, SigningKey};
+        use futures_util::FutureExt;
+
+        let wallet = SigningKey::from_bytes(&[4; 32]);
+        let address = bs58::encode(wallet.verifying_key().as_bytes()).into_string();
+        let stored = stored_challenge(&address, ChallengeFormat::Legacy);
+        let sign = |signer: &SigningKey| {
+            let message = challenge_message(SERVER, "abc", &stored, None);
+            bs58::encode(signer.sign(message.as_bytes()).to_bytes()).into_string()
+        };
+        let server_name = <&ServerName>::try_from(SERVER).unwrap();
+
+        // The checks `verify_solana_login` makes, against the stored nonce.
+        let reply = |enabled: bool, signature: String| {
+            let request = SolanaLoginRequest {
+                signature,
+                ..login_with_nonce_for(&address, &address).1
+            };
+            admin_verification_reply(enabled, &request, |request| async {
+                let message = expected_message(request, &stored, SERVER, &[], ISSUED_AT, MAX_AGE)
+                    .map_err(login_error)?;
+                let wallet = decode_address(&request.address)?;
+                let signature = bs58::decode(&request.signature).into_vec().unwrap();
+                if !SignatureScheme::Raw
+                    .verifier()
+                    .verify(&wallet, &message, &signature)
+                {
+                    return Err(login_error(BAD_SIGNATURE));
+                }
+                login_user_id(
+                    &VerifiedSolanaLogin::new(wallet, None),
+                    None,
+                    server_name,
+                    None,
+                )
+            })
+            .now_or_never()
+            .unwrap()
+            .unwrap()
+        };
+
+        let user_id = format!(
+            "@{}:{SERVER}",
+            pubkey_to_localpart(&wallet.verifying_key().to_bytes(), None)
+        );
+        assert_eq!(
+            reply(true, sign(&wallet)),
+            format!("Login for {address} is valid and signs in as {user_id}")
+        );
+        assert_eq!(
+            reply(true, sign(&SigningKey::from_bytes(&[5; 32]))),
+            format!("Login for {address} was refused: {BAD_SIGNATURE}")
+        );
+        assert_eq!(
+            reply(false, sign(&wallet)),
+            "Solana wallet login is not enabled on this server"
+        );
+    }
+
+    #[test]
+    fn binds_the_nonce_to_the_requesting_address() {
+        let (stored, request) = login_with_nonce_for(OWN_ADDRESS, OWN_ADDRESS);
+        assert_eq!(check_challenge_binding(&stored, &request), Ok(()));
+
+        let (stored, request) = login_with_nonce_for(OWN_ADDRESS, OTHER_ADDRESS);
+        assert_eq!(
+            check_challenge_binding(&stored, &request),
+            Err(WRONG_ADDRESS)
+        );
+
+        let (stored, request) = login_with_nonce_for(OTHER_ADDRESS, OWN_ADDRESS);
+        assert_eq!(
+            check_challenge_binding(&stored, &request),
+            Err(WRONG_ADDRESS)
+        );
+
+        assert_eq!(
+            failure_category(&Error::BadRequest(
+                ruma::api::client::error::ErrorKind::forbidden(),
+                WRONG_ADDRESS
+            )),
+            LoginFailure::ChallengeMismatch
+        );
+    }
+
+    #[test]
+    fn binds_the_nonce_to_the_requesting_origin() {
+        const APP: &str = "https://chat.example.com";
+
+        let (stored, mut request) = login_with_nonce_for(OWN_ADDRESS, OWN_ADDRESS);
+        let stored = StoredNonce {
+            origin: Some(APP.to_owned()),
+            ..stored
+        };
+        request.origin = Some(APP.to_owned());
+        assert_eq!(check_challenge_binding(&stored, &request), Ok(()));
+
+        // A site relaying the challenge, or a client not reporting the origin at all.
+        for origin in [Some("https://chat.example.com.evil.example"), None] {
+            request.origin = origin.map(str::to_owned);
+            assert_eq!(
+                check_challenge_binding(&stored, &request),
+                Err(WRONG_ORIGIN)
+            );
+        }
+        assert_eq!(
+            failure_category(&login_error(WRONG_ORIGIN)),
+            LoginFailure::ChallengeMismatch
+        );
+
+        // Nor can a challenge issued to a native client be used from a web origin.
+        let (stored, mut request) = login_with_nonce_for(OWN_ADDRESS, OWN_ADDRESS);
+        request.origin = Some(APP.to_owned());
+        assert_eq!(
+            check_challenge_binding(&stored, &request),
+            Err(WRONG_ORIGIN)
+        );
+
+        let body =
+            serde_json::json!({ "address": "a", "signature": "s", "nonce": "n", "origin": APP });
+        let request = SolanaLoginRequest::from_login_body(&body, None, None).unwrap();
+        assert_eq!(request.origin.as_deref(), Some(APP));
+    }
+
+    #[test]
+    fn only_allowed_origins_get_challenges() {
+        let allowed = |list: &[&str], origin: Option<&str>| {
+            let list = list
+                .iter()
+                .map(|&origin| origin.to_owned())
+                .collect::<Vec<_>>();
+            check_allowed_origin(origin, &list).is_ok()
+        };
+
+        // Without an allowlist every origin, and no origin, is fine.
+        assert!(allowed(&[], Some("https://anywhere.example")));
+        assert!(allowed(&[], None));
+
+        let list = ["https://chat.example.com"];
+        assert!(allowed(&list, Some("https://chat.example.com")));
+        assert!(!allowed(&list, Some("https://evil.example")));
+        assert!(!allowed(&list, Some("https://chat.example.com:8443")));
+        // Native clients need the marker.
+        assert!(!allowed(&list, None));
+        assert!(allowed(&["https://chat.example.com", NATIVE_ORIGIN], None));
+    }
+
+    #[test]
+    fn advertises_the_nonce_route() {
+        let data = login_type_data("https://matrix.example.com/", SERVER, STATEMENT);
+        assert_eq!(data["nonce_endpoint"], NONCE_PATH);
+        assert_eq!(
+            data["nonce_url"],
+            format!("https://matrix.example.com{NONCE_PATH}")
+        );
+        assert_eq!(data["unstable_features"]["org.solana.auth.nonce"], true);
+        assert!(NONCE_PATH.starts_with("/_matrix/client/"));
+    }
+
+    #[test]
+    fn the_advertised_template_is_the_message_to_sign() {
+        let data = login_type_data("https://matrix.example.com/", SERVER, STATEMENT);
+        let template = data["message_template"].as_str().unwrap();
+        assert_eq!(template, sign_message_template(SERVER, STATEMENT));
+
+        let nonce = generate_random_nonce(32);
+        assert_eq!(
+            template
+                .replace("{nonce}", &nonce)
+                .replace("{issued_at}", &format_timestamp(ISSUED_AT))
+                .replace("{expires_at}", &format_timestamp(EXPIRES_AT)),
+            format_sign_message(SERVER, &nonce, ISSUED_AT, EXPIRES_AT, None, STATEMENT, None)
+        );
+    }
+
+    #[test]
+    fn checks_the_displayed_domain() {
+        assert_eq!(check_displayed_domain(Some(SERVER), SERVER), Ok(()));
+        assert_eq!(
+            check_displayed_domain(Some("chat.evil.example"), SERVER),
+            Err(WRONG_SERVER)
+        );
+        assert_eq!(check_displayed_domain(None, SERVER), Ok(()));
+
+        let body = serde_json::json!({
+            "address": "a",
+            "signature": "s",
+            "nonce": "n",
+            "domain": "chat.evil.example",
+        });
+        let request = SolanaLoginRequest::from_login_body(&body, None, None).unwrap();
+        assert_eq!(request.domain.as_deref(), Some("chat.evil.example"));
+    }
+
+    #[test]
+    fn verified_login_forms_agree() {
+        let pubkey: [u8; 32] = bs58::decode(OWN_ADDRESS)
+            .into_vec()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let verified = VerifiedSolanaLogin::new(pubkey, None);
+
+        assert_eq!(verified.pubkey, pubkey);
+        assert_eq!(verified.base58_address, OWN_ADDRESS);
+        assert_eq!(
+            verified.hex_localpart,
+            format!("solana_{}", hex::encode(pubkey))
+        );
+        assert_eq!(
+            localpart_to_pubkey(&verified.hex_localpart, None),
+            Some(pubkey)
+        );
+
+        // Under a namespace the localpart is the namespaced one, and still maps back.
+        let namespaced = VerifiedSolanaLogin::new(pubkey, Some("chat.example.com"));
+        assert_eq!(
+            namespaced.hex_localpart,
+            pubkey_to_localpart(&pubkey, Some("chat.example.com"))
+        );
+        assert_ne!(namespaced.hex_localpart, verified.hex_localpart);
+        assert_eq!(
+            localpart_to_pubkey(&namespaced.hex_localpart, Some("chat.example.com")),
+            Some(pubkey)
+        );
+    }
+
+    fn address_error(address: &str) -> &'static str {
+        match decode_address(address) {
+            Err(Error::BadRequest(_, message)) => message,
+            other => panic!("{address:?} was accepted: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn trims_pasted_addresses() {
+        let pubkey = decode_address(OWN_ADDRESS).unwrap();
+        for pasted in [
+            format!("  {OWN_ADDRESS} "),
+            format!("\t{OWN_ADDRESS}\r\n"),
+            format!("\u{200B}{OWN_ADDRESS}\u{FEFF}"),
+        ] {
+            assert_eq!(decode_address(&pasted).unwrap(), pubkey);
+            assert_eq!(normalize_address(&pasted).unwrap(), OWN_ADDRESS);
+        }
+    }
+
+    #[test]
+    fn rejects_addresses_with_embedded_control_characters() {
+        let (start, end) = OWN_ADDRESS.split_at(20);
+        for address in [
+            format!("{start}\n{end}"),
+            format!("{start} {end}"),
+            format!("{start}\u{200B}{end}"),
+            format!("{OWN_ADDRESS}\0"),
+        ] {
+            assert_eq!(
+                address_error(&address),
+                "Solana address may not contain whitespace or control characters."
+            );
+        }
+    }
+
+    #[test]
+    fn tells_bad_characters_from_bad_lengths() {
+        // 0, O, I and l aren't in the base58 alphabet.
+        assert_eq!(
+            address_error("0OIl-not-a-wallet"),
+            "Solana address contains characters that aren't base58."
+        );
+        assert_eq!(
+            address_error(&OWN_ADDRESS[..20]),
+            "Solana address must decode to exactly 32 bytes."
+        );
+    }
+
+    /// Collects the fields of every span as `name=value`, as a log exporter would see them.
+    #[derive(Clone, Default)]
+    struct SpanFields(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl tracing::field::Visit for SpanFields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{}={value:?}", field.name()));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanFields {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            attrs.record(&mut self.clone());
+        }
+
+        fn on_record(
+            &self,
+            _: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    /// The span fields recorded for a sign-in with `nonce` that ended in `result`.
+    fn login_span_fields(nonce: &str, result: Result<()>) -> Vec<String> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let fields = SpanFields::default();
+        let subscriber = tracing_subscriber::registry().with(fields.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = login_span(OWN_ADDRESS, nonce);
+            record_login_outcome(&span, &result);
+        });
+        let recorded = fields.0.lock().unwrap().clone();
+        recorded
+    }
+
+    #[test]
+    fn login_spans_tie_challenges_to_sign_ins() {
+        let nonce = "0123456789abcdef".repeat(4);
+        let login_id = format!("login_id={}", login_id(&nonce));
+
+        let fields = login_span_fields(&nonce, Ok(()));
+        for expected in [
+            login_id.as_str(),
+            "address=4vJ9...kLKi",
+            "nonce=01234567",
+            r#"outcome="ok""#,
+        ] {
+            assert!(fields.iter().any(|field| field == expected), "{fields:?}");
+        }
+
+        let fields = login_span_fields(
+            &nonce,
+            Err(Error::BadRequest(
+                ruma::api::client::error::ErrorKind::forbidden(),
+                BAD_SIGNATURE,
+            )),
+        );
+        for expected in [
+            login_id.as_str(),
+            r#"outcome="refused""#,
+            &format!("reason={BAD_SIGNATURE:?}"),
+        ] {
+            assert!(fields.iter().any(|field| field == expected), "{fields:?}");
+        }
+
+        // Neither the nonce nor anything signed with it ends up in the logs.
+        assert!(fields.iter().all(|field| !field.contains(&nonce)));
+    }
+
+    #[test]
+    fn expires_wallet_sessions_only_for_clients_that_can_refresh() {
+        assert_eq!(expiring_session_ttl(Some(300), true), Some(300));
+        assert_eq!(expiring_session_ttl(Some(300), false), None);
+        assert_eq!(expiring_session_ttl(None, true), None);
+
+        let body = serde_json::json!({ "address": OWN_ADDRESS, "signature": "s", "nonce": "n" });
+        let mut request = SolanaLoginRequest::from_login_body(&body, None, None).unwrap();
+        assert!(!request.refresh_token);
+        request.refresh_token = true;
+        assert_eq!(
+            expiring_session_ttl(Some(300), request.refresh_token).map(Duration::from_secs),
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn linked_wallets_log_in_to_the_linked_account() {
+        let verified = VerifiedSolanaLogin::new(decode_address(OWN_ADDRESS).unwrap(), None);
+        let server_name = <&ServerName>::try_from(SERVER).unwrap();
+        let alice = UserId::parse(format!("@alice:{SERVER}")).unwrap();
+
+        assert_eq!(
+            login_user_id(&verified, None, server_name, None)
+                .unwrap()
+                .as_str(),
+            own_user_id()
+        );
+        assert_eq!(
+            login_user_id(&verified, Some(alice.clone()), server_name, None).unwrap(),
+            alice
+        );
+    }
+
+    #[test]
+    fn linked_accounts_can_start_the_step_ups_they_complete() {
+        let wallet = decode_address(OWN_ADDRESS).unwrap();
+        let server_name = <&ServerName>::try_from(SERVER).unwrap();
+        let alice = UserId::parse(format!("@alice:{SERVER}")).unwrap();
+        let own = UserId::parse(own_user_id()).unwrap();
+
+        // A password account signs step-ups with the wallet linked to it, the same
+        // wallet whose login resolves to it.
+        assert!(!has_wallet(&alice, server_name, None, &[]));
+        assert!(has_wallet(&alice, server_name, None, &[wallet]));
+        assert_eq!(
+            login_user_id(
+                &VerifiedSolanaLogin::new(wallet, None),
+                Some(alice.clone()),
+                server_name,
+                None
+            )
+            .unwrap(),
+            alice
+        );
+
+        // Wallet accounts sign with their own wallet, but only on this server.
+        assert!(has_wallet(&own, server_name, None, &[]));
+        let elsewhere = <&ServerName>::try_from("other.example.com").unwrap();
+        assert!(!has_wallet(&own, elsewhere, None, &[]));
+    }
+
+    #[test]
+    fn links_wallets_without_an_account_of_their_own() {
+        let own = UserId::parse(own_user_id()).unwrap();
+        let alice = UserId::parse(format!("@alice:{SERVER}")).unwrap();
+
+        assert!(check_link(&alice, &own, false, None).is_ok());
+        // Linking again, e.g. after a lost response, is harmless.
+        assert!(check_link(&alice, &own, false, Some(&alice)).is_ok());
+    }
+
+    #[test]
+    fn refuses_links_that_conflict_with_another_account() {
+        let own = UserId::parse(own_user_id()).unwrap();
+        let alice = UserId::parse(format!("@alice:{SERVER}")).unwrap();
+        let bob = UserId::parse(format!("@bob:{SERVER}")).unwrap();
+
+        // The wallet already signed in to its own account.
+        assert!(matches!(
+            check_link(&alice, &own, true, None),
+            Err(Error::BadRequest(
+                ruma::api::client::error::ErrorKind::UserInUse,
+                _
+            ))
+        ));
+        // The wallet already signs in to someone else.
+        assert!(matches!(
+            check_link(&alice, &own, false, Some(&bob)),
+            Err(Error::BadRequest(
+                ruma::api::client::error::ErrorKind::UserInUse,
+                _
+            ))
+        ));
+        // A wallet account can't link its own wallet.
+        assert!(check_link(&own, &own, true, None).is_err());
+    }
+
+    #[test]
+    fn rotates_tokens_of_existing_devices_only() {
+        let device_id: OwnedDeviceId = "PHONE".into();
+        assert!(check_rotation(Some(&device_id), true).is_ok());
+
+        assert!(matches!(
+            check_rotation(Some(&device_id), false),
+            Err(Error::BadRequest(
+                ruma::api::client::error::ErrorKind::NotFound,
+                _
+            ))
+        ));
+        assert!(matches!(
+            check_rotation(None, false),
+            Err(Error::BadRequest(
+                ruma::api::client::error::ErrorKind::MissingParam,
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn only_allowlisted_wallets_sign_in() {
+        let own = decode_address(OWN_ADDRESS).unwrap();
+        let other = decode_address(OTHER_ADDRESS).unwrap();
+
+        // The address is padded in the config and listed again, with a comment, in the file.
+        let file = format!("# Team wallets\n\n  {OWN_ADDRESS}\n");
+        let allowed = parse_allowed_addresses(&[format!(" {OWN_ADDRESS}")], Some(&file)).unwrap();
+        assert_eq!(allowed.len(), 1);
+        assert!(check_allowed_address(&own, &allowed).is_ok());
+        assert!(matches!(
+            check_allowed_address(&other, &allowed),
+            Err(Error::BadRequest(
+                ruma::api::client::error::ErrorKind::Forbidden { .. },
+                _
+            ))
+        ));
+
+        let allowed = parse_allowed_addresses(&[], Some(OTHER_ADDRESS)).unwrap();
+        assert!(check_allowed_address(&own, &allowed).is_err());
+        assert!(check_allowed_address(&other, &allowed).is_ok());
+
+        // No allowlist lets every wallet in.
+        let allowed = parse_allowed_addresses(&[], None).unwrap();
+        assert!(allowed.is_empty());
+        assert!(check_allowed_address(&own, &allowed).is_ok());
+        assert!(check_allowed_address(&other, &allowed).is_ok());
+
+        for invalid in ["0OIl", "11111111"] {
+            assert!(parse_allowed_addresses(&[invalid.to_owned()], None).is_err());
+            assert!(parse_allowed_addresses(&[], Some(invalid)).is_err());
+        }
+    }
+
+    #[test]
+    fn recognises_wallet_localparts() {
+        let pubkey = [1; 32];
+        assert_eq!(
+            localpart_to_pubkey(&pubkey_to_localpart(&pubkey, None), None),
+            Some(pubkey)
+        );
+    }
+
+    #[test]
+    fn every_encoding_of_a_key_gets_one_localpart() {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let wallet = SigningKey::from_bytes(&[6; 32]);
+        let pubkey = wallet.verifying_key().to_bytes();
+        let address = bs58::encode(pubkey).into_string();
+        let localpart = pubkey_to_localpart(&pubkey, None);
+        assert!(is_canonical_hex(localpart.strip_prefix("solana_").unwrap()));
+
+        // Addresses as clients paste them.
+        let mut localparts = std::collections::HashSet::new();
+        for pasted in [
+            address.clone(),
+            format!("  {address}\n"),
+            format!("\u{200B}{address}\u{FEFF}"),
+        ] {
+            localparts.insert(pubkey_to_localpart(&decode_address(&pasted).unwrap(), None));
+        }
+
+        // A wallet-standard `signIn` output, with the bytes as base64 and as an array.
+        let signed = format!(
+            "{SERVER} wants you to sign in with your Solana account:\n{address}\n\nVersion: 1\nNonce: abc"
+        );
+        let signature = wallet.sign(signed.as_bytes()).to_bytes();
+        for (signed_message, signature) in [
+            (
+                serde_json::json!(general_purpose::STANDARD.encode(&signed)),
+                serde_json::json!(general_purpose::STANDARD.encode(signature)),
+            ),
+            (
+                serde_json::json!(signed.as_bytes()),
+                serde_json::json!(signature.as_slice()),
+            ),
+        ] {
+            let body = serde_json::json!({
+                "sign_in_output": {
+                    "account": { "address": address },
+                    "signedMessage": signed_message,
+                    "signature": signature,
+                },
+            });
+            let request = SolanaLoginRequest::from_login_body(&body, None, None).unwrap();
+            localparts.insert(pubkey_to_localpart(
+                &decode_address(&request.address).unwrap(),
+                None,
+            ));
+        }
+
+        assert_eq!(localparts.len(), 1);
+        assert!(localparts.contains(&localpart));
+
+        // And only that spelling of the localpart maps back to the wallet.
+        assert_eq!(localpart_to_pubkey(&localpart, None), Some(pubkey));
+        let hex_part = localpart.strip_prefix("solana_").unwrap();
+        for other in [
+            localpart.to_uppercase(),
+            format!("solana_{}", hex_part.to_uppercase()),
+            format!("SOLANA_{hex_part}"),
+            format!("solana_0x{hex_part}"),
+        ] {
+            assert_eq!(localpart_to_pubkey(&other, None), None, "{other}");
+        }
+    }
+
+    #[test]
+    fn rejects_lookalike_localparts() {
+        let valid = pubkey_to_localpart(&[1; 32], None);
+        let hex_part = valid.strip_prefix("solana_").unwrap();
+
+        // No prefix, wrong length, uppercase and non-hex
+        assert_eq!(localpart_to_pubkey(hex_part, None), None);
+        assert_eq!(localpart_to_pubkey(&valid[..valid.len() - 2], None), None);
+        assert_eq!(localpart_to_pubkey(&format!("{valid}00"), None), None);
+        assert_eq!(localpart_to_pubkey(&valid.to_uppercase(), None), None);
+        assert_eq!(
+            localpart_to_pubkey(&format!("solana_{}", "g".repeat(64)), None),
+            None
+        );
+
+        // 64 lowercase hex characters that aren't an ed25519 point
+        let mut not_a_point = [0; 32];
+        not_a_point[0] = 2;
+        assert_eq!(
+            localpart_to_pubkey(&pubkey_to_localpart(&not_a_point, None), None),
+            None
+        );
+    }
+
+    #[test]
+    fn converts_between_localparts_and_base58_addresses() {
+        let pubkey = ed25519_dalek::SigningKey::from_bytes(&[6; 32])
+            .verifying_key()
+            .to_bytes();
+        let address = bs58::encode(pubkey).into_string();
+        let localpart = pubkey_to_localpart(&pubkey, None);
+
+        assert_eq!(
+            localpart_to_base58(&localpart, None).as_deref(),
+            Some(&*address)
+        );
+        assert_eq!(
+            base58_to_localpart(&address, None).as_deref(),
+            Some(&*localpart)
+        );
+
+        // Wrong length and non-hex localparts, and addresses that aren't 32 bytes.
+        assert_eq!(
+            localpart_to_base58(&localpart[..localpart.len() - 2], None),
+            None
+        );
+        assert_eq!(localpart_to_base58(&format!("{localpart}00"), None), None);
+        assert_eq!(
+            localpart_to_base58(&format!("solana_{}", "z".repeat(64)), None),
+            None
+        );
+        assert_eq!(localpart_to_base58("alice", None), None);
+        assert_eq!(
+            base58_to_localpart(&address[..address.len() - 4], None),
+            None
+        );
+        assert_eq!(base58_to_localpart("not base58 0OIl", None), None);
+    }
+
+    #[test]
+    fn namespaced_localparts_are_stable_and_distinct() {
+        let pubkey = ed25519_dalek::SigningKey::from_bytes(&[6; 32])
+            .verifying_key()
+            .to_bytes();
+        let raw = pubkey_to_localpart(&pubkey, None);
+        assert_eq!(raw, format!("solana_{}", hex::encode(pubkey)));
+
+        let namespaced = pubkey_to_localpart(&pubkey, Some("chat.example.com"));
+        assert_eq!(
+            namespaced,
+            pubkey_to_localpart(&pubkey, Some("chat.example.com"))
+        );
+        assert_ne!(namespaced, raw);
+        assert_ne!(
+            namespaced,
+            pubkey_to_localpart(&pubkey, Some("other.example.com"))
+        );
+
+        // The wallet can still be read back, but only under the same namespace.
+        assert_eq!(
+            localpart_to_pubkey(&namespaced, Some("chat.example.com")),
+            Some(pubkey)
+        );
+        assert_ne!(
+            localpart_to_pubkey(&namespaced, Some("other.example.com")),
+            Some(pubkey)
+        );
+        assert_ne!(localpart_to_pubkey(&namespaced, None), Some(pubkey));
+    }
+
+    #[test]
+    fn reads_the_auto_join_room() {
+        let server_name = <&ServerName>::try_from("chat.example.com").unwrap();
+        let target = |configured: &str| {
+            configured_room(configured, server_name).map(|target| target.to_string())
+        };
+
+        assert_eq!(target("lobby"), Some("#lobby:chat.example.com".to_owned()));
+        assert_eq!(
+            target("#general:other.example.com"),
+            Some("#general:other.example.com".to_owned())
+        );
+        assert_eq!(
+            target("!abc123:chat.example.com"),
+            Some("!abc123:chat.example.com".to_owned())
+        );
+        assert_eq!(target("#missing-server"), None);
+    }
+
+    #[test]
+    fn auto_joins_every_room_it_can() {
+        use futures_util::FutureExt;
+        use ruma::api::client::error::ErrorKind;
+        use std::cell::RefCell;
+
+        let targets: Vec<OwnedRoomOrAliasId> = [
+            "#general:chat.example.com",
+            "!help:chat.example.com",
+            "!news:chat.example.com",
+        ]
+        .into_iter()
+        .map(|target| target.try_into().unwrap())
+        .collect();
+        let room = |room_id: &str| OwnedRoomId::try_from(room_id).unwrap();
+
+        let joined = RefCell::new(HashSet::new());
+        let joins = RefCell::new(0);
+        let auto_join = |unresolvable: &str, unjoinable: &str| {
+            join_rooms(
+                &targets,
+                |target| {
+                    let resolved = match target.as_str() {
+                        target if target == unresolvable => Err(Error::BadRequest(
+                            ErrorKind::NotFound,
+                            "Room alias not found.",
+                        )),
+                        "#general:chat.example.com" => {
+                            Ok((Vec::new(), room("!general:chat.example.com")))
+                        }
+                        target => Ok((Vec::new(), room(target))),
+                    };
+                    std::future::ready(resolved)
+                },
+                |room_id| joined.borrow().contains(room_id),
+                |room_id, _| {
+                    *joins.borrow_mut() += 1;
+                    let result = if room_id.as_str() == unjoinable {
+                        Err(Error::BadRequest(
+                            ErrorKind::forbidden(),
+                            "You are not invited to this room.",
+                        ))
+                    } else {
+                        joined.borrow_mut().insert(room_id);
+                        Ok(())
+                    };
+                    std::future::ready(result)
+                },
+            )
+            .now_or_never()
+            .unwrap()
+        };
+
+        // The alias can't be resolved and one room refuses the join, but the others are
+        // still joined and the first of them becomes the default room.
+        assert_eq!(
+            auto_join("#general:chat.example.com", "!help:chat.example.com"),
+            Some(room("!news:chat.example.com"))
+        );
+        assert_eq!(
+            *joined.borrow(),
+            HashSet::from([room("!news:chat.example.com")])
+        );
+
+        // Joining again only joins what's missing, and the default room follows the list.
+        *joins.borrow_mut() = 0;
+        assert_eq!(auto_join("", ""), Some(room("!general:chat.example.com")));
+        assert_eq!(*joins.borrow(), 2);
+        assert_eq!(joined.borrow().len(), 3);
+
+        // Once the user is in every room, nothing is joined.
+        *joins.borrow_mut() = 0;
+        assert_eq!(auto_join("", ""), Some(room("!general:chat.example.com")));
+        assert_eq!(*joins.borrow(), 0);
+
+        // For a user in none of the rooms, a room that refuses passes the default room on.
+        joined.borrow_mut().clear();
+        assert_eq!(
+            auto_join("", "!general:chat.example.com"),
+            Some(room("!help:chat.example.com"))
+        );
+
+        // When no room can be joined there is no default room.
+        let none_joinable = join_rooms(
+            &targets,
+            |_| {
+                std::future::ready(Err(Error::BadRequest(
+                    ErrorKind::NotFound,
+                    "Room alias not found.",
+                )))
+            },
+            |_| false,
+            |_, _| std::future::ready(Ok(())),
+        )
+        .now_or_never()
+        .unwrap();
+        assert_eq!(none_joinable, None);
+    }
+
+    #[test]
+    fn gates_only_power_actions() {
+        let own = own_user_id();
+        let sender = UserId::parse(own.as_str()).unwrap();
+        let other = format!("@alice:{SERVER}");
+        let action = |event_type: &str, state_key: &str, content: &str| {
+            power_action(&event_type.into(), state_key, &sender, content)
+        };
+
+        assert_eq!(
+            action("m.room.power_levels", "", r#"{"users":{}}"#),
+            Some(PowerAction::PowerLevels)
+        );
+        assert_eq!(
+            action("m.room.member", &other, r#"{"membership":"leave"}"#),
+            Some(PowerAction::Kick)
+        );
+        assert_eq!(
+            action("m.room.member", &other, r#"{"membership":"ban"}"#),
+            Some(PowerAction::Ban)
+        );
+        assert_eq!(
+            action(POWER_ACTION_SIGNATURES, "", r#"{"required":false}"#),
+            Some(PowerAction::SignatureMode)
+        );
+
+        // Leaving, inviting and ordinary room state stay ungated.
+        assert_eq!(
+            action("m.room.member", &own, r#"{"membership":"leave"}"#),
+            None
+        );
+        assert_eq!(
+            action("m.room.member", &other, r#"{"membership":"invite"}"#),
+            None
+        );
+        assert_eq!(action("m.room.topic", "", r#"{"topic":"Treasury"}"#), None);
+
+        // Member content that doesn't parse is gated rather than let through.
+        assert_eq!(
+            action("m.room.member", &other, "not json"),
+            Some(PowerAction::Ban)
+        );
+        assert_eq!(
+            action("m.room.member", &other, r#"{"membership":7}"#),
+            Some(PowerAction::Ban)
+        );
+    }
+
+    #[test]
+    fn takes_the_forwarded_ip_the_proxy_appended() {
+        let remote: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let forwarded = |value: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert("x-forwarded-for", value.parse().unwrap());
+            headers
+        };
+        let ip = |addr: &str| addr.parse::<IpAddr>().unwrap();
+
+        // The client wrote the left entry, the proxy appended the right one.
+        let headers = forwarded("198.51.100.9, 203.0.113.7");
+        assert_eq!(
+            forwarded_client_ip(remote, &headers, true),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            forwarded_client_ip(remote, &headers, false),
+            ip("192.0.2.1")
+        );
+
+        // Missing or unreadable headers fall back to the peer, never to no IP.
+        for headers in [http::HeaderMap::new(), forwarded("198.51.100.9, junk")] {
+            assert_eq!(forwarded_client_ip(remote, &headers, true), ip("192.0.2.1"));
+        }
+    }
 }
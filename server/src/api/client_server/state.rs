@@ -1,5 +1,6 @@
 use std::sync::Arc;
 
+use super::solana_auth;
 use crate::{service::pdu::PduBuilder, services, Error, Result, Ruma, RumaResponse};
 use ruma::{
     api::client::{
@@ -29,6 +30,20 @@ pub async fn send_state_event_for_key_route(
 ) -> Result<send_state_event::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    if let Some(action) = solana_auth::power_action(
+        &body.event_type,
+        &body.state_key,
+        sender_user,
+        body.body.body.json().get(),
+    ) {
+        solana_auth::require_power_action_signature(
+            sender_user,
+            body.sender_device.as_deref(),
+            &body.room_id,
+            action,
+        )?;
+    }
+
     let event_id = send_state_event_for_key_helper(
         sender_user,
         &body.room_id,
@@ -67,6 +82,20 @@ pub async fn send_state_event_for_empty_key_route(
         ));
     }
 
+    if let Some(action) = solana_auth::power_action(
+        &body.event_type,
+        &body.state_key,
+        sender_user,
+        body.body.body.json().get(),
+    ) {
+        solana_auth::require_power_action_signature(
+            sender_user,
+            body.sender_device.as_deref(),
+            &body.room_id,
+            action,
+        )?;
+    }
+
     let event_id = send_state_event_for_key_helper(
         sender_user,
         &body.room_id,
@@ -1,6 +1,10 @@
-use ruma::api::client::discovery::discover_homeserver::{self, HomeserverInfo};
+use ruma::api::client::{
+    discovery::discover_homeserver::{self, HomeserverInfo, IdentityServerInfo},
+    error::ErrorKind,
+};
 
-use crate::{services, Result, Ruma};
+use super::solana_auth;
+use crate::{services, Error, Result, Ruma};
 
 /// # `GET /.well-known/matrix/client`
 ///
@@ -14,6 +18,99 @@ pub async fn well_known_client(
         homeserver: HomeserverInfo {
             base_url: client_url.clone(),
         },
-        identity_server: None,
+        identity_server: services()
+            .globals
+            .well_known_identity_server()
+            .map(IdentityServerInfo::new),
     })
 }
+
+/// # `GET /_matrix/client/unstable/org.solana.auth/well-known?address=<wallet>`
+///
+/// Client discovery for a wallet: a `/.well-known/matrix/client` response pointing at the
+/// homeserver the wallet's on-chain delegation names first, so a client that only knows a
+/// Solana address can find where to log in.
+pub async fn get_wallet_well_known_route(address: &str) -> Result<serde_json::Value> {
+    if !services().globals.allow_solana_auth() || services().globals.solana_rpc_url().is_none() {
+        return Err(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "Wallet discovery is not enabled on this server.",
+        ));
+    }
+
+    let owner = solana_auth::decode_address(address)?;
+    let homeserver = services()
+        .solana_registry
+        .resolve_homeserver(&owner)
+        .await?;
+
+    wallet_well_known(
+        homeserver.as_deref(),
+        services().globals.server_name().as_str(),
+        &services().globals.well_known_client(),
+    )
+}
+
+/// The well-known response for a wallet delegated to `homeserver`. We know our own client
+/// URL; any other homeserver is pointed at by its server name, which is where a client
+/// would look for its own well-known.
+fn wallet_well_known(
+    homeserver: Option<&str>,
+    server_name: &str,
+    client_url: &str,
+) -> Result<serde_json::Value> {
+    let homeserver = homeserver.ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "This wallet has not delegated to a homeserver.",
+    ))?;
+
+    let base_url = if homeserver == server_name {
+        client_url.to_owned()
+    } else {
+        format!("https://{homeserver}")
+    };
+    Ok(serde_json::json!({ "m.homeserver": { "base_url": base_url } }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "chat.example.com";
+    const CLIENT_URL: &str = "https://matrix.example.com";
+
+    #[test]
+    fn points_a_delegated_wallet_at_its_homeserver() {
+        assert_eq!(
+            wallet_well_known(Some("other.example.org:8448"), SERVER, CLIENT_URL).unwrap(),
+            serde_json::json!({ "m.homeserver": { "base_url": "https://other.example.org:8448" } })
+        );
+        assert_eq!(
+            wallet_well_known(Some(SERVER), SERVER, CLIENT_URL).unwrap(),
+            serde_json::json!({ "m.homeserver": { "base_url": CLIENT_URL } })
+        );
+    }
+
+    #[test]
+    fn unregistered_wallets_are_not_found() {
+        assert!(matches!(
+            wallet_well_known(None, SERVER, CLIENT_URL),
+            Err(Error::BadRequest(ErrorKind::NotFound, _))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        assert!(solana_auth::decode_address("4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi").is_ok());
+        for address in [
+            "not-base58-0OIl",
+            "",
+            "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLK",
+        ] {
+            assert!(matches!(
+                solana_auth::decode_address(address),
+                Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+            ));
+        }
+    }
+}
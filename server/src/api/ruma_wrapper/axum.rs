@@ -1,8 +1,8 @@
-use std::{collections::BTreeMap, error::Error as _, iter::FromIterator, str};
+use std::{collections::BTreeMap, error::Error as _, iter::FromIterator, net::SocketAddr, str};
 
 use axum::{
     body::Body,
-    extract::{FromRequest, Path},
+    extract::{ConnectInfo, FromRequest, Path},
     response::{IntoResponse, Response},
     RequestPartsExt,
 };
@@ -24,7 +24,9 @@ use serde::Deserialize;
 use tracing::{debug, error, warn};
 
 use super::{Ruma, RumaResponse};
-use crate::{service::appservice::RegistrationInfo, services, Error, Result};
+use crate::{
+    api::client_server::solana_auth, service::appservice::RegistrationInfo, services, Error, Result,
+};
 
 enum Token {
     Appservice(Box<RegistrationInfo>),
@@ -81,6 +83,10 @@ where
                 None
             };
         let path_params: Path<Vec<String>> = parts.extract().await?;
+        let client_ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(remote)| solana_auth::client_ip(*remote, &parts.headers));
 
         let query = parts.uri.query().unwrap_or_default();
         let query_params: QueryParams = match serde_html_form::from_str(query) {
@@ -103,6 +109,25 @@ where
             if let Some(reg_info) = services().appservice.find_from_token(token).await {
                 Token::Appservice(Box::new(reg_info.clone()))
             } else if let Some((user_id, device_id)) = services().users.find_from_token(token)? {
+                // Wallet sessions can have an absolute lifetime, after which the wallet has
+                // to sign in again.
+                if solana_auth::session_expired(&user_id, &device_id)? {
+                    services().users.remove_device(&user_id, &device_id)?;
+                    return Err(Error::BadRequest(
+                        ErrorKind::UnknownToken { soft_logout: false },
+                        "Wallet session expired. Sign in with your wallet again.",
+                    ));
+                }
+                // Their access tokens can also expire sooner, to be renewed with a refresh
+                // token.
+                if solana_auth::access_token_expired(&user_id, &device_id, token)? {
+                    return Err(Error::BadRequest(
+                        ErrorKind::UnknownToken { soft_logout: true },
+                        "Access token expired. Use your refresh token to get a new one.",
+                    ));
+                }
+                // And be limited to the rooms their login challenge listed.
+                solana_auth::check_scoped_request(&user_id, &device_id, &path_params.0)?;
                 Token::User((user_id, device_id))
             } else {
                 Token::Invalid
@@ -375,6 +400,7 @@ where
             sender_servername,
             appservice_info,
             json_body,
+            client_ip,
         })
     }
 }
@@ -3,7 +3,7 @@ use ruma::{
     api::client::uiaa::UiaaResponse, CanonicalJsonValue, OwnedDeviceId, OwnedServerName,
     OwnedUserId,
 };
-use std::ops::Deref;
+use std::{net::IpAddr, ops::Deref};
 
 #[cfg(feature = "conduit_bin")]
 mod axum;
@@ -17,6 +17,8 @@ pub struct Ruma<T> {
     // This is None when body is not a valid string
     pub json_body: Option<CanonicalJsonValue>,
     pub appservice_info: Option<RegistrationInfo>,
+    /// The IP the request came from, see [`crate::api::client_server::solana_auth::client_ip`].
+    pub client_ip: Option<IpAddr>,
 }
 
 impl<T> Deref for Ruma<T> {
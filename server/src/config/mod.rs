@@ -9,7 +9,7 @@ use std::{
 
 use bytesize::ByteSize;
 use ruma::{api::federation::discovery::VerifyKey, serde::Base64, OwnedServerName, RoomVersionId};
-use serde::{de::IgnoredAny, Deserialize};
+use serde::{de::IgnoredAny, Deserialize, Deserializer};
 use tokio::time::{interval, Interval};
 use tracing::warn;
 use url::Url;
@@ -21,6 +21,17 @@ use self::proxy::ProxyConfig;
 
 const SHA256_HEX_LENGTH: u8 = 64;
 
+/// Longest accepted `solana_nonce_ttl`. A challenge that stays valid for longer is more
+/// likely to leak than to help a slow wallet.
+const MAX_SOLANA_NONCE_TTL: u64 = 60 * 60;
+
+/// Accepted range of `solana_nonce_bytes`. Fewer than 16 random bytes could be guessed,
+/// and more than 64 only lengthen the message a wallet shows.
+const SOLANA_NONCE_BYTES: std::ops::RangeInclusive<usize> = 16..=64;
+
+/// Longest accepted `solana_challenge_statement`, in characters. Wallet popups are small.
+const MAX_SOLANA_CHALLENGE_STATEMENT_LENGTH: usize = 300;
+
 #[derive(Deserialize)]
 pub struct IncompleteConfig {
     #[serde(default = "default_address")]
@@ -80,9 +91,192 @@ pub struct IncompleteConfig {
     /// When true, users can log in by signing a challenge with their Solana wallet.
     #[serde(default = "false_fn")]
     pub allow_solana_auth: bool,
-    /// Room alias to auto-join new Solana-authenticated users into (e.g. "lobby").
-    /// Creates #lobby:server and joins users on first login.
+    /// Room to join new Solana-authenticated users into on first login: a room ID, an alias,
+    /// or a bare name like "lobby" for #lobby:server. The room must already exist.
     pub solana_auto_join_room: Option<String>,
+    /// Reject Solana logins from wallets whose on-chain delegation points at another homeserver.
+    #[serde(default = "false_fn")]
+    pub solana_enforce_registry: bool,
+    /// Accept a caller-supplied `Delegation` account in the login body instead of fetching it over RPC.
+    /// Only enable this behind a trusted proxy that fills the field itself: whoever supplies the
+    /// bytes decides the outcome of registry enforcement.
+    #[serde(default = "false_fn")]
+    pub solana_trust_supplied_delegation: bool,
+    /// Solana JSON-RPC endpoint used to read the homeserver registry.
+    pub solana_rpc_url: Option<Url>,
+    /// Program ID of the homeserver registry.
+    #[serde(default = "default_solana_registry_program_id")]
+    pub solana_registry_program_id: String,
+    /// Notify the admin room when one address repeatedly fails Solana signature verification.
+    #[serde(default = "false_fn")]
+    pub solana_notice_repeated_failures: bool,
+    /// Failures within ten minutes before `solana_notice_repeated_failures` fires.
+    #[serde(default = "default_solana_notice_failure_threshold")]
+    pub solana_notice_failure_threshold: u32,
+    /// Notify the admin room when a correctly signed Solana login is refused by server policy.
+    #[serde(default = "false_fn")]
+    pub solana_notice_denied_attempts: bool,
+    /// Notify the admin room when the Solana nonce store holds an unusual number of unexpired nonces.
+    #[serde(default = "false_fn")]
+    pub solana_notice_nonce_cap: bool,
+    /// Maximum concurrent Solana signature verifications. Defaults to the CPU count.
+    #[serde(default = "default_solana_verify_workers")]
+    pub solana_verify_workers: usize,
+    /// How many of a wallet's most recent registry transactions the delegation history endpoint replays.
+    #[serde(default = "default_solana_history_limit")]
+    pub solana_history_limit: u32,
+    /// Solana CLI keypair file (JSON byte array) the registry relayer pays fees from. The relayer is off when unset.
+    pub solana_relayer_keypair: Option<PathBuf>,
+    /// Base58 wallets the relayer will pay for. Empty allows any wallet.
+    #[serde(default)]
+    pub solana_relayer_allowed_wallets: Vec<String>,
+    /// Relayed registry transactions allowed per wallet per day.
+    #[serde(default = "default_solana_relayer_max_per_wallet")]
+    pub solana_relayer_max_per_wallet: u32,
+    /// Most rent (in lamports) the relayer will transfer to a wallet in one transaction, in a system transfer
+    /// before the `register`. 0, the default, pays fees only and refuses any transfer; set it to at least the
+    /// delegation account's rent-exempt minimum (`solana rent <bytes>`) to also cover rent.
+    #[serde(default)]
+    pub solana_relayer_max_rent_lamports: u64,
+    /// How strictly display names are checked for impersonating other users: "off", "existing" or "strict".
+    #[serde(default)]
+    pub solana_displayname_guard: DisplaynameGuard,
+    /// Only allow Solana logins from wallets with a confirmed transaction in the last this many seconds. Off when unset.
+    pub solana_recent_activity_window: Option<u64>,
+    /// What Solana login checks that tolerate RPC outages do when the RPC call fails: "deny" or "allow".
+    #[serde(default)]
+    pub solana_rpc_failure_policy: RpcFailurePolicy,
+    /// Older registry program IDs still checked during a migration, alongside `solana_registry_program_id`.
+    #[serde(default)]
+    pub solana_registry_previous_program_ids: Vec<String>,
+    /// What to do when a wallet has delegations to different homeservers in several registry programs: "error" or "newest".
+    #[serde(default)]
+    pub solana_registry_conflict_policy: RegistryConflictPolicy,
+    /// Seconds after a wallet account is deactivated before a Solana login may recreate it. 0 disables the cooldown.
+    #[serde(default)]
+    pub solana_recreation_cooldown: u64,
+    /// Solana CLI keypair file (JSON byte array) for the server operator key, used to sign operator proofs.
+    /// Clients fetch the public half from the operator-key endpoint, which is off when unset.
+    pub solana_operator_keypair: Option<PathBuf>,
+    /// Include well-known discovery info (homeserver and identity server URLs) in Solana login responses.
+    #[serde(default = "false_fn")]
+    pub solana_login_discovery: bool,
+    /// Seconds a wallet account session lasts before its token is invalidated and the wallet must sign in again.
+    /// Unlimited when unset.
+    pub solana_session_lifetime: Option<u64>,
+    /// Seconds a wallet has to start signing a login challenge after it is issued. Advertised as `expires_in_seconds`.
+    /// Must be between 1 and 3600.
+    #[serde(
+        default = "default_solana_nonce_ttl",
+        deserialize_with = "deserialize_solana_nonce_ttl"
+    )]
+    pub solana_nonce_ttl: u64,
+    /// Seconds after issuance a signature over a login challenge is still accepted. Defaults to `solana_nonce_ttl`
+    /// and can only extend it.
+    pub solana_challenge_replay_window: Option<u64>,
+    /// Display name for new wallet accounts, e.g. "Wallet {abbrev}". `{abbrev}` is replaced with the shortened
+    /// base58 address. New accounts are named with the full base58 address when unset.
+    pub solana_displayname_template: Option<String>,
+    /// Post an hourly summary of Solana login failures by category to the admin room once they pass a threshold.
+    #[serde(default = "false_fn")]
+    pub solana_notice_failure_summary: bool,
+    /// Failed Solana logins within an hour before `solana_notice_failure_summary` fires.
+    #[serde(default = "default_solana_notice_failure_summary_threshold")]
+    pub solana_notice_failure_summary_threshold: u32,
+    /// Friendly name for this homeserver, advertised to clients in the capabilities response and wallet
+    /// delegation lookups. Purely informational.
+    pub server_display_name: Option<String>,
+    /// Domains besides the server name accepted in Sign In With Solana messages, e.g. the web client's host.
+    #[serde(default)]
+    pub solana_sign_in_domains: Vec<String>,
+    /// How many returning wallets to remember the account of, to skip the database lookup on login.
+    #[serde(default = "default_solana_user_cache_capacity")]
+    pub solana_user_cache_capacity: usize,
+    /// Seconds a step-up wallet signature stays usable for a power action in rooms that require one.
+    #[serde(default = "default_solana_step_up_window")]
+    pub solana_step_up_window: u64,
+    /// Login challenges one client IP, and separately one wallet address, may request per minute. 0 disables the limit.
+    #[serde(default = "default_solana_nonce_rate_limit")]
+    pub solana_nonce_rate_limit: u32,
+    /// Take the client IP for challenge rate limiting and login notices from the right-most X-Forwarded-For entry,
+    /// the one the proxy appended, falling back to the peer address without one.
+    /// Only enable this behind a reverse proxy that appends to the header, or clients can pick their own IP.
+    #[serde(default = "false_fn")]
+    pub solana_trust_forwarded_for: bool,
+    /// Only allow Solana logins from wallets holding an NFT verified as part of this Metaplex collection (base58 collection mint). Off when unset.
+    pub solana_required_collection: Option<String>,
+    /// Only allow Solana logins from wallets holding at least `solana_required_min_balance` of this SPL token (base58 mint). Off when unset.
+    pub solana_required_mint: Option<String>,
+    /// Least balance of `solana_required_mint` a wallet needs, in the mint's base units (10^decimals per token).
+    #[serde(default = "default_solana_required_min_balance")]
+    pub solana_required_min_balance: u64,
+    /// Check `solana_required_mint` on every login, not only when the account is created, so wallets that drop below the threshold are locked out.
+    #[serde(default = "true_fn")]
+    pub solana_required_balance_on_return: bool,
+    /// Post an admin room notice for every wallet login, not just registrations.
+    #[serde(default = "false_fn")]
+    pub solana_notify_on_login: bool,
+    /// Domains whose hosts are never used as a wallet's delegated homeserver, e.g. "internal" for *.internal.
+    /// Private and reserved IP addresses are always refused.
+    #[serde(default = "default_solana_homeserver_denylist")]
+    pub solana_homeserver_denylist: Vec<String>,
+    /// Sentence at the end of every login challenge, e.g. to translate it or link to terms of service. One line of
+    /// at most 300 characters.
+    #[serde(
+        default = "default_solana_challenge_statement",
+        deserialize_with = "deserialize_solana_challenge_statement"
+    )]
+    pub solana_challenge_statement: String,
+    /// Seconds a wallet login's access token lasts before the client has to use its refresh token. Only clients that
+    /// send `refresh_token: true` get expiring tokens. Tokens never expire when unset.
+    pub solana_session_ttl: Option<u64>,
+    /// Serve the dry-run login verification endpoint for wallet client developers.
+    #[serde(default = "false_fn")]
+    pub solana_debug_endpoints: bool,
+    /// Base58 wallets allowed to sign in with Solana, for a private homeserver. Empty allows any wallet.
+    #[serde(default)]
+    pub solana_allowed_addresses: Vec<String>,
+    /// File listing more allowed wallets, one base58 address per line. Blank lines and lines starting with # are skipped.
+    pub solana_allowed_addresses_file: Option<PathBuf>,
+    /// When `solana_enforce_registry` refuses a login over a delegation to another homeserver, first
+    /// check that homeserver answers, so users with a dead or bogus delegation are told so. Adds up to a
+    /// few seconds to such logins; results are cached for five minutes.
+    #[serde(default = "false_fn")]
+    pub solana_probe_delegated_homeserver: bool,
+    /// Accept a login challenge signed by the auth key a wallet designates in its registry delegation, as
+    /// a login of that wallet. Needs `solana_rpc_url`.
+    #[serde(default = "false_fn")]
+    pub solana_registry_auth_keys: bool,
+    /// Room new wallet registrations are announced in instead of the admin room: a room ID,
+    /// an alias, or a bare name like "signups" for #signups:server. A local alias that doesn't
+    /// resolve yet is created at startup.
+    pub solana_signup_log_room: Option<String>,
+    /// Random bytes in each login challenge nonce, sent hex-encoded. Must be between 16 and 64.
+    #[serde(
+        default = "default_solana_nonce_bytes",
+        deserialize_with = "deserialize_solana_nonce_bytes"
+    )]
+    pub solana_nonce_bytes: usize,
+    /// Web origins browser clients may request login challenges from, e.g. "https://chat.example.com". "native"
+    /// allows clients that report no origin. Empty allows every origin.
+    #[serde(default)]
+    pub solana_allowed_origins: Vec<String>,
+    /// More rooms to join new Solana-authenticated users into, after `solana_auto_join_room`, named the
+    /// same way. A room that can't be joined is skipped.
+    #[serde(default)]
+    pub solana_auto_join_rooms: Vec<String>,
+    /// Mixed into wallet localparts, so servers sharing one database give the same wallet
+    /// different accounts. Unset, localparts are the raw key. Changing it orphans every
+    /// existing wallet account. The key is masked, not hashed, so it can be read back from
+    /// the localpart: this is not a privacy measure.
+    pub solana_localpart_namespace: Option<String>,
+    /// Name new wallet accounts after the wallet's primary SNS domain, like "alice.sol", when it
+    /// has one, instead of its address.
+    #[serde(default)]
+    pub solana_resolve_sns: bool,
+    /// Seconds a login signed with a `signed_at` time stays acceptable after that time.
+    #[serde(default = "default_solana_signature_max_age")]
+    pub solana_signature_max_age: u64,
     #[serde(default = "default_trusted_servers")]
     pub trusted_servers: Vec<OwnedServerName>,
     #[serde(default = "default_log")]
@@ -143,6 +337,60 @@ pub struct Config {
     pub jwt_secret: Option<String>,
     pub allow_solana_auth: bool,
     pub solana_auto_join_room: Option<String>,
+    pub solana_enforce_registry: bool,
+    pub solana_trust_supplied_delegation: bool,
+    pub solana_rpc_url: Option<Url>,
+    pub solana_registry_program_id: String,
+    pub solana_notice_repeated_failures: bool,
+    pub solana_notice_failure_threshold: u32,
+    pub solana_notice_denied_attempts: bool,
+    pub solana_notice_nonce_cap: bool,
+    pub solana_verify_workers: usize,
+    pub solana_history_limit: u32,
+    pub solana_relayer_keypair: Option<PathBuf>,
+    pub solana_relayer_allowed_wallets: Vec<String>,
+    pub solana_relayer_max_per_wallet: u32,
+    pub solana_relayer_max_rent_lamports: u64,
+    pub solana_displayname_guard: DisplaynameGuard,
+    pub solana_recent_activity_window: Option<u64>,
+    pub solana_rpc_failure_policy: RpcFailurePolicy,
+    pub solana_registry_previous_program_ids: Vec<String>,
+    pub solana_registry_conflict_policy: RegistryConflictPolicy,
+    pub solana_recreation_cooldown: u64,
+    pub solana_operator_keypair: Option<PathBuf>,
+    pub solana_login_discovery: bool,
+    pub solana_session_lifetime: Option<u64>,
+    pub solana_nonce_ttl: u64,
+    pub solana_challenge_replay_window: Option<u64>,
+    pub solana_displayname_template: Option<String>,
+    pub solana_notice_failure_summary: bool,
+    pub solana_notice_failure_summary_threshold: u32,
+    pub server_display_name: Option<String>,
+    pub solana_sign_in_domains: Vec<String>,
+    pub solana_user_cache_capacity: usize,
+    pub solana_step_up_window: u64,
+    pub solana_nonce_rate_limit: u32,
+    pub solana_trust_forwarded_for: bool,
+    pub solana_required_collection: Option<String>,
+    pub solana_required_mint: Option<String>,
+    pub solana_required_min_balance: u64,
+    pub solana_required_balance_on_return: bool,
+    pub solana_notify_on_login: bool,
+    pub solana_homeserver_denylist: Vec<String>,
+    pub solana_challenge_statement: String,
+    pub solana_session_ttl: Option<u64>,
+    pub solana_debug_endpoints: bool,
+    pub solana_allowed_addresses: Vec<String>,
+    pub solana_allowed_addresses_file: Option<PathBuf>,
+    pub solana_probe_delegated_homeserver: bool,
+    pub solana_registry_auth_keys: bool,
+    pub solana_signup_log_room: Option<String>,
+    pub solana_nonce_bytes: usize,
+    pub solana_allowed_origins: Vec<String>,
+    pub solana_auto_join_rooms: Vec<String>,
+    pub solana_localpart_namespace: Option<String>,
+    pub solana_resolve_sns: bool,
+    pub solana_signature_max_age: u64,
     pub trusted_servers: Vec<OwnedServerName>,
     pub log: String,
 
@@ -191,6 +439,60 @@ impl From<IncompleteConfig> for Config {
             jwt_secret,
             allow_solana_auth,
             solana_auto_join_room,
+            solana_enforce_registry,
+            solana_trust_supplied_delegation,
+            solana_rpc_url,
+            solana_registry_program_id,
+            solana_notice_repeated_failures,
+            solana_notice_failure_threshold,
+            solana_notice_denied_attempts,
+            solana_notice_nonce_cap,
+            solana_verify_workers,
+            solana_history_limit,
+            solana_relayer_keypair,
+            solana_relayer_allowed_wallets,
+            solana_relayer_max_per_wallet,
+            solana_relayer_max_rent_lamports,
+            solana_displayname_guard,
+            solana_recent_activity_window,
+            solana_rpc_failure_policy,
+            solana_registry_previous_program_ids,
+            solana_registry_conflict_policy,
+            solana_recreation_cooldown,
+            solana_operator_keypair,
+            solana_login_discovery,
+            solana_session_lifetime,
+            solana_nonce_ttl,
+            solana_challenge_replay_window,
+            solana_displayname_template,
+            solana_notice_failure_summary,
+            solana_notice_failure_summary_threshold,
+            server_display_name,
+            solana_sign_in_domains,
+            solana_user_cache_capacity,
+            solana_step_up_window,
+            solana_nonce_rate_limit,
+            solana_trust_forwarded_for,
+            solana_required_collection,
+            solana_required_mint,
+            solana_required_min_balance,
+            solana_required_balance_on_return,
+            solana_notify_on_login,
+            solana_homeserver_denylist,
+            solana_challenge_statement,
+            solana_session_ttl,
+            solana_debug_endpoints,
+            solana_allowed_addresses,
+            solana_allowed_addresses_file,
+            solana_probe_delegated_homeserver,
+            solana_registry_auth_keys,
+            solana_signup_log_room,
+            solana_nonce_bytes,
+            solana_allowed_origins,
+            solana_auto_join_rooms,
+            solana_localpart_namespace,
+            solana_resolve_sns,
+            solana_signature_max_age,
             trusted_servers,
             log,
             turn_username,
@@ -241,6 +543,7 @@ impl From<IncompleteConfig> for Config {
         let well_known = WellKnownConfig {
             client: well_known_client,
             server: well_known_server,
+            identity_server: well_known.identity_server.map(String::from),
         };
 
         let media = MediaConfig {
@@ -298,6 +601,60 @@ impl From<IncompleteConfig> for Config {
             jwt_secret,
             allow_solana_auth,
             solana_auto_join_room,
+            solana_enforce_registry,
+            solana_trust_supplied_delegation,
+            solana_rpc_url,
+            solana_registry_program_id,
+            solana_notice_repeated_failures,
+            solana_notice_failure_threshold,
+            solana_notice_denied_attempts,
+            solana_notice_nonce_cap,
+            solana_verify_workers,
+            solana_history_limit,
+            solana_relayer_keypair,
+            solana_relayer_allowed_wallets,
+            solana_relayer_max_per_wallet,
+            solana_relayer_max_rent_lamports,
+            solana_displayname_guard,
+            solana_recent_activity_window,
+            solana_rpc_failure_policy,
+            solana_registry_previous_program_ids,
+            solana_registry_conflict_policy,
+            solana_recreation_cooldown,
+            solana_operator_keypair,
+            solana_login_discovery,
+            solana_session_lifetime,
+            solana_nonce_ttl,
+            solana_challenge_replay_window,
+            solana_displayname_template,
+            solana_notice_failure_summary,
+            solana_notice_failure_summary_threshold,
+            server_display_name,
+            solana_sign_in_domains,
+            solana_user_cache_capacity,
+            solana_step_up_window,
+            solana_nonce_rate_limit,
+            solana_trust_forwarded_for,
+            solana_required_collection,
+            solana_required_mint,
+            solana_required_min_balance,
+            solana_required_balance_on_return,
+            solana_notify_on_login,
+            solana_homeserver_denylist,
+            solana_challenge_statement,
+            solana_session_ttl,
+            solana_debug_endpoints,
+            solana_allowed_addresses,
+            solana_allowed_addresses_file,
+            solana_probe_delegated_homeserver,
+            solana_registry_auth_keys,
+            solana_signup_log_room,
+            solana_nonce_bytes,
+            solana_allowed_origins,
+            solana_auto_join_rooms,
+            solana_localpart_namespace,
+            solana_resolve_sns,
+            solana_signature_max_age,
             trusted_servers,
             log,
             turn,
@@ -336,6 +693,7 @@ pub struct IncompleteWellKnownConfig {
     // We use URL here so that the user gets an error if the config isn't a valid url
     pub client: Option<Url>,
     pub server: Option<OwnedServerName>,
+    pub identity_server: Option<Url>,
 }
 
 #[derive(Clone, Debug)]
@@ -344,6 +702,7 @@ pub struct WellKnownConfig {
     // URL, just for it to be converted back into a &str
     pub client: String,
     pub server: OwnedServerName,
+    pub identity_server: Option<String>,
 }
 
 #[derive(Deserialize, Default)]
@@ -488,6 +847,42 @@ impl Default for ScopedMediaRetentionConfig {
     }
 }
 
+/// How a wallet with conflicting delegations across registry programs is resolved.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistryConflictPolicy {
+    /// Refuse to pick one and fail enforcement.
+    #[default]
+    Error,
+    /// Use the most recently updated delegation.
+    Newest,
+}
+
+/// Outcome of a Solana login check whose RPC call failed.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RpcFailurePolicy {
+    /// Refuse the login.
+    #[default]
+    Deny,
+    /// Skip the check and log a warning.
+    Allow,
+}
+
+/// How strictly display names are checked for impersonation of other users.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplaynameGuard {
+    /// No checks.
+    Off,
+    /// Reject another local user's exact user ID, or the wallet address of an existing account.
+    #[default]
+    Existing,
+    /// Reject anything that looks like another user's ID or wallet address, ignoring
+    /// surrounding whitespace and the case of user IDs.
+    Strict,
+}
+
 #[derive(Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum MediaRetentionScope {
@@ -690,6 +1085,10 @@ impl fmt::Display for Config {
             ),
             ("Allow registration", &self.allow_registration.to_string()),
             ("Allow Solana auth", &self.allow_solana_auth.to_string()),
+            (
+                "Enforce Solana registry",
+                &self.solana_enforce_registry.to_string(),
+            ),
             (
                 "Enabled lightning bolt",
                 &self.enable_lightning_bolt.to_string(),
@@ -793,6 +1192,116 @@ fn default_log() -> String {
     "warn,state_res=warn,_=off".to_owned()
 }
 
+fn default_solana_registry_program_id() -> String {
+    crate::service::solana_registry::DEFAULT_REGISTRY_PROGRAM_ID.to_owned()
+}
+
+fn default_solana_notice_failure_threshold() -> u32 {
+    5
+}
+
+fn default_solana_notice_failure_summary_threshold() -> u32 {
+    20
+}
+
+fn default_solana_verify_workers() -> usize {
+    num_cpus::get()
+}
+
+fn default_solana_history_limit() -> u32 {
+    20
+}
+
+fn default_solana_relayer_max_per_wallet() -> u32 {
+    1
+}
+
+fn default_solana_user_cache_capacity() -> usize {
+    1000
+}
+
+fn default_solana_step_up_window() -> u64 {
+    2 * 60
+}
+
+fn default_solana_signature_max_age() -> u64 {
+    60
+}
+
+fn default_solana_nonce_rate_limit() -> u32 {
+    10
+}
+
+fn default_solana_nonce_ttl() -> u64 {
+    5 * 60
+}
+
+fn default_solana_nonce_bytes() -> usize {
+    32
+}
+
+pub fn default_solana_challenge_statement() -> String {
+    "This signature will not trigger a blockchain transaction or cost any fees.".to_owned()
+}
+
+fn default_solana_homeserver_denylist() -> Vec<String> {
+    vec![
+        "local".to_owned(),
+        "internal".to_owned(),
+        "localhost".to_owned(),
+    ]
+}
+
+fn default_solana_required_min_balance() -> u64 {
+    1
+}
+
+fn deserialize_solana_nonce_ttl<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<u64, D::Error> {
+    let ttl = u64::deserialize(deserializer)?;
+    if ttl == 0 || ttl > MAX_SOLANA_NONCE_TTL {
+        return Err(serde::de::Error::custom(format!(
+            "solana_nonce_ttl must be between 1 and {MAX_SOLANA_NONCE_TTL} seconds"
+        )));
+    }
+    Ok(ttl)
+}
+
+fn deserialize_solana_nonce_bytes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<usize, D::Error> {
+    let bytes = usize::deserialize(deserializer)?;
+    if !SOLANA_NONCE_BYTES.contains(&bytes) {
+        return Err(serde::de::Error::custom(format!(
+            "solana_nonce_bytes must be between {} and {}",
+            SOLANA_NONCE_BYTES.start(),
+            SOLANA_NONCE_BYTES.end()
+        )));
+    }
+    Ok(bytes)
+}
+
+/// The statement is rebuilt into the message a login's signature is checked against, and
+/// the challenge formats are line-based, so it has to stay on one line.
+fn deserialize_solana_challenge_statement<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let statement = String::deserialize(deserializer)?;
+    if statement.is_empty()
+        || statement.trim() != statement
+        || statement.chars().count() > MAX_SOLANA_CHALLENGE_STATEMENT_LENGTH
+        || statement
+            .chars()
+            .any(|c| c.is_control() || matches!(c, '\u{2028}' | '\u{2029}'))
+    {
+        return Err(serde::de::Error::custom(format!(
+            "solana_challenge_statement must be one line of at most {MAX_SOLANA_CHALLENGE_STATEMENT_LENGTH} characters, without leading or trailing whitespace"
+        )));
+    }
+    Ok(statement)
+}
+
 fn default_turn_ttl() -> u64 {
     60 * 60 * 24
 }
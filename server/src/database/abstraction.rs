@@ -34,6 +34,10 @@ pub trait KvTree: Send + Sync {
 
     fn remove(&self, key: &[u8]) -> Result<()>;
 
+    /// Remove `key` and return the value it had, in one step: of several callers taking
+    /// the same key at once, only one gets the value.
+    fn take(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
     fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>;
 
     fn iter_from<'a>(
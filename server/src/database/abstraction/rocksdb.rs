@@ -173,6 +173,23 @@ impl KvTree for RocksDbEngineTree<'_> {
             .delete_cf_opt(&self.cf(), key, &writeoptions)?)
     }
 
+    fn take(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let readoptions = rocksdb::ReadOptions::default();
+        let writeoptions = rocksdb::WriteOptions::default();
+
+        let lock = self.write_lock.write().unwrap();
+
+        let old = self.db.rocks.get_cf_opt(&self.cf(), key, &readoptions)?;
+        if old.is_some() {
+            self.db
+                .rocks
+                .delete_cf_opt(&self.cf(), key, &writeoptions)?;
+        }
+
+        drop(lock);
+        Ok(old)
+    }
+
     fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
         let readoptions = rocksdb::ReadOptions::default();
 
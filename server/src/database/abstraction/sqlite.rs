@@ -239,6 +239,15 @@ impl KvTree for SqliteTable {
         Ok(())
     }
 
+    fn take(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let guard = self.engine.write_lock();
+
+        Ok(guard
+            .prepare(format!("DELETE FROM {} WHERE key = ? RETURNING value", self.name).as_str())?
+            .query_row([key], |row| row.get(0))
+            .optional()?)
+    }
+
     fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
         let guard = self.engine.read_lock_iterator();
 
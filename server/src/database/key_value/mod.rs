@@ -8,6 +8,7 @@ pub(super) mod media;
 mod pusher;
 mod rooms;
 mod sending;
+mod solana;
 mod transaction_ids;
 mod uiaa;
 mod users;
@@ -0,0 +1,273 @@
+use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, UserId};
+
+use crate::{
+    database::KeyValueDatabase,
+    service,
+    service::solana::{StoredNonce, StoredSession},
+    utils, Error, Result,
+};
+
+/// Key of a nonce in `solananonceexpiry_nonce`: big-endian so they sort by expiry.
+fn nonce_expiry_key(expires_at: u64, nonce: &str) -> Vec<u8> {
+    let mut key = expires_at.to_be_bytes().to_vec();
+    key.extend_from_slice(nonce.as_bytes());
+    key
+}
+
+/// Key of a login in `solanaconsumedat_login`: big-endian so they sort by age.
+fn consumed_login_key(consumed_at: u64, fingerprint: &[u8; 32]) -> Vec<u8> {
+    let mut key = consumed_at.to_be_bytes().to_vec();
+    key.extend_from_slice(fingerprint);
+    key
+}
+
+impl KeyValueDatabase {
+    /// Rebuild the index of nonce expiries, which nonces stored by older versions are
+    /// missing from. Run once, by the migration to database version 19.
+    pub(crate) fn reindex_solana_nonces(&self) -> Result<()> {
+        self.solananonceexpiry_nonce.clear()?;
+        for (nonce, bytes) in self.solananonce_challenge.iter() {
+            let stored: StoredNonce = serde_json::from_slice(&bytes)
+                .map_err(|_| Error::bad_database("Invalid Solana challenge in db."))?;
+            let nonce = utils::string_from_bytes(&nonce)
+                .map_err(|_| Error::bad_database("Solana nonce in db is invalid unicode."))?;
+            self.solananonceexpiry_nonce
+                .insert(&nonce_expiry_key(stored.expires_at, &nonce), &[])?;
+        }
+        Ok(())
+    }
+}
+
+impl service::solana::Data for KeyValueDatabase {
+    fn store_nonce(&self, nonce: &str, stored: &StoredNonce) -> Result<()> {
+        // Remove the old expiry if the nonce is being replaced
+        if let Some(old) = self.get_nonce(nonce)? {
+            self.solananonceexpiry_nonce
+                .remove(&nonce_expiry_key(old.expires_at, nonce))?;
+        }
+
+        self.solananonce_challenge.insert(
+            nonce.as_bytes(),
+            &serde_json::to_vec(stored).expect("StoredNonce can be serialized"),
+        )?;
+        self.solananonceexpiry_nonce
+            .insert(&nonce_expiry_key(stored.expires_at, nonce), &[])
+    }
+
+    fn get_nonce(&self, nonce: &str) -> Result<Option<StoredNonce>> {
+        self.solananonce_challenge
+            .get(nonce.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid Solana challenge in db."))
+            })
+            .transpose()
+    }
+
+    fn remove_nonce(&self, nonce: &str) -> Result<()> {
+        if let Some(old) = self.get_nonce(nonce)? {
+            self.solananonceexpiry_nonce
+                .remove(&nonce_expiry_key(old.expires_at, nonce))?;
+        }
+        self.solananonce_challenge.remove(nonce.as_bytes())
+    }
+
+    fn take_nonce(&self, nonce: &str) -> Result<Option<StoredNonce>> {
+        let Some(bytes) = self.solananonce_challenge.take(nonce.as_bytes())? else {
+            return Ok(None);
+        };
+        let stored: StoredNonce = serde_json::from_slice(&bytes)
+            .map_err(|_| Error::bad_database("Invalid Solana challenge in db."))?;
+        self.solananonceexpiry_nonce
+            .remove(&nonce_expiry_key(stored.expires_at, nonce))?;
+        Ok(Some(stored))
+    }
+
+    fn nonces_by_expiry<'a>(
+        &'a self,
+        latest_first: bool,
+    ) -> Box<dyn Iterator<Item = Result<(u64, String)>> + 'a> {
+        let entries = if latest_first {
+            self.solananonceexpiry_nonce.iter_from(&[0xff; 9], true)
+        } else {
+            self.solananonceexpiry_nonce.iter()
+        };
+
+        Box::new(entries.map(|(key, _)| {
+            let (expires_at, nonce) = key
+                .split_first_chunk::<8>()
+                .ok_or_else(|| Error::bad_database("Solana nonce expiry in db is too short."))?;
+            let nonce = utils::string_from_bytes(nonce)
+                .map_err(|_| Error::bad_database("Solana nonce in db is invalid unicode."))?;
+            Ok((u64::from_be_bytes(*expires_at), nonce))
+        }))
+    }
+
+    fn store_session(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        session: &StoredSession,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        // Remove the old refresh token
+        if let Some(old) = self.get_session(user_id, device_id)? {
+            self.solanarefreshtoken_userdeviceid
+                .remove(old.refresh_token.as_bytes())?;
+        }
+
+        self.userdeviceid_solanasession.insert(
+            &userdeviceid,
+            &serde_json::to_vec(session).expect("StoredSession can be serialized"),
+        )?;
+        self.solanarefreshtoken_userdeviceid
+            .insert(session.refresh_token.as_bytes(), &userdeviceid)
+    }
+
+    fn get_session(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<StoredSession>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_solanasession
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid Solana session in db."))
+            })
+            .transpose()
+    }
+
+    fn remove_session(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        if let Some(session) = self.get_session(user_id, device_id)? {
+            self.solanarefreshtoken_userdeviceid
+                .remove(session.refresh_token.as_bytes())?;
+        }
+        self.userdeviceid_solanasession.remove(&userdeviceid)
+    }
+
+    fn find_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId)>> {
+        self.solanarefreshtoken_userdeviceid
+            .get(refresh_token.as_bytes())?
+            .map(|bytes| {
+                let mut parts = bytes.split(|&b| b == 0xff);
+                let user_id = parts
+                    .next()
+                    .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+                    .and_then(|user_id| UserId::parse(user_id).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database(
+                            "User ID in solanarefreshtoken_userdeviceid is invalid.",
+                        )
+                    })?;
+                let device_id = parts
+                    .next()
+                    .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database(
+                            "Device ID in solanarefreshtoken_userdeviceid is invalid.",
+                        )
+                    })?;
+                Ok((user_id, device_id.into()))
+            })
+            .transpose()
+    }
+
+    fn store_scopes(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        scopes: &[String],
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        if scopes.is_empty() {
+            return self.userdeviceid_solanascopes.remove(&userdeviceid);
+        }
+        self.userdeviceid_solanascopes.insert(
+            &userdeviceid,
+            &serde_json::to_vec(scopes).expect("scopes can be serialized"),
+        )
+    }
+
+    fn get_scopes(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<String>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_solanascopes
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid Solana scopes in db."))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn record_consumed_login(&self, fingerprint: &[u8; 32], consumed_at: u64) -> Result<()> {
+        self.solanalogin_consumedat
+            .insert(fingerprint, &consumed_at.to_be_bytes())?;
+        self.solanaconsumedat_login
+            .insert(&consumed_login_key(consumed_at, fingerprint), &[])
+    }
+
+    fn is_consumed_login(&self, fingerprint: &[u8; 32]) -> Result<bool> {
+        Ok(self.solanalogin_consumedat.get(fingerprint)?.is_some())
+    }
+
+    fn remove_consumed_login(&self, fingerprint: &[u8; 32]) -> Result<()> {
+        if let Some(consumed_at) = self.solanalogin_consumedat.get(fingerprint)? {
+            let consumed_at = utils::u64_from_bytes(&consumed_at).map_err(|_| {
+                Error::bad_database("Invalid consumption time in solanalogin_consumedat.")
+            })?;
+            self.solanaconsumedat_login
+                .remove(&consumed_login_key(consumed_at, fingerprint))?;
+        }
+        self.solanalogin_consumedat.remove(fingerprint)
+    }
+
+    fn consumed_logins_newest_first<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<[u8; 32]>> + 'a> {
+        Box::new(
+            self.solanaconsumedat_login
+                .iter_from(&[0xff; 41], true)
+                .map(|(key, _)| {
+                    key.get(8..)
+                        .and_then(|fingerprint| fingerprint.try_into().ok())
+                        .ok_or_else(|| {
+                            Error::bad_database(
+                                "Invalid login fingerprint in solanaconsumedat_login.",
+                            )
+                        })
+                }),
+        )
+    }
+
+    fn is_wallet_blocked(&self, wallet: &[u8; 32]) -> Result<bool> {
+        Ok(self.wallet_blockedat.get(wallet)?.is_some())
+    }
+
+    fn block_wallet(&self, wallet: &[u8; 32], millis: u64) -> Result<()> {
+        self.wallet_blockedat.insert(wallet, &millis.to_be_bytes())
+    }
+
+    fn unblock_wallet(&self, wallet: &[u8; 32]) -> Result<bool> {
+        let blocked = self.is_wallet_blocked(wallet)?;
+        self.wallet_blockedat.remove(wallet)?;
+        Ok(blocked)
+    }
+}
@@ -40,6 +40,84 @@ impl service::users::Data for KeyValueDatabase {
         Ok(self.userid_password.iter().count())
     }
 
+    fn last_deactivation(&self, localpart: &str) -> Result<Option<u64>> {
+        self.localpart_deactivatedat
+            .get(localpart.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Deactivation time in db is invalid."))
+            })
+            .transpose()
+    }
+
+    fn set_last_deactivation(&self, localpart: &str, millis: u64) -> Result<()> {
+        self.localpart_deactivatedat
+            .insert(localpart.as_bytes(), &millis.to_be_bytes())
+    }
+
+    fn session_started(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<u64>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_sessionstartedat
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Session start time in db is invalid."))
+            })
+            .transpose()
+    }
+
+    fn set_session_started(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        millis: u64,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_sessionstartedat
+            .insert(&userdeviceid, &millis.to_be_bytes())
+    }
+
+    fn linked_wallets(&self, user_id: &UserId) -> Result<Vec<[u8; 32]>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.useridwallet_linked
+            .scan_prefix(prefix.clone())
+            .map(|(key, _)| {
+                key[prefix.len()..]
+                    .try_into()
+                    .map_err(|_| Error::bad_database("Linked wallet in db is invalid."))
+            })
+            .collect()
+    }
+
+    fn link_wallet(&self, user_id: &UserId, wallet: &[u8; 32]) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(wallet);
+
+        self.useridwallet_linked.insert(&key, &[])?;
+        self.wallet_userid.insert(wallet, user_id.as_bytes())
+    }
+
+    fn wallet_login_user(&self, wallet: &[u8; 32]) -> Result<Option<OwnedUserId>> {
+        self.wallet_userid
+            .get(wallet)?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in wallet_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in wallet_userid is invalid."))
+            })
+            .transpose()
+    }
+
     /// Find out which user an access token belongs to.
     fn find_from_token(&self, token: &str) -> Result<Option<(OwnedUserId, OwnedDeviceId)>> {
         self.token_userdeviceid
@@ -253,6 +331,11 @@ impl service::users::Data for KeyValueDatabase {
             .increment(user_id.as_bytes())?;
 
         self.userdeviceid_metadata.remove(&userdeviceid)?;
+        self.userdeviceid_sessionstartedat.remove(&userdeviceid)?;
+
+        // Remove the device's wallet session, its refresh token and its scopes
+        service::solana::Data::remove_session(self, user_id, device_id)?;
+        self.userdeviceid_solanascopes.remove(&userdeviceid)?;
 
         Ok(())
     }
@@ -74,6 +74,18 @@ pub struct KeyValueDatabase {
     pub(super) userid_displayname: Arc<dyn KvTree>,
     pub(super) userid_avatarurl: Arc<dyn KvTree>,
     pub(super) userid_blurhash: Arc<dyn KvTree>,
+    pub(super) localpart_deactivatedat: Arc<dyn KvTree>, // DeactivatedAt = u64 millis
+    pub(super) userdeviceid_sessionstartedat: Arc<dyn KvTree>, // SessionStartedAt = u64 millis
+    pub(super) useridwallet_linked: Arc<dyn KvTree>, // UserIdWallet = UserId + 0xff + 32-byte public key
+    pub(super) wallet_userid: Arc<dyn KvTree>,       // Wallet = 32-byte public key
+    pub(super) wallet_blockedat: Arc<dyn KvTree>,    // BlockedAt = u64 millis
+    pub(super) solananonce_challenge: Arc<dyn KvTree>, // Challenge = JSON StoredNonce
+    pub(super) solananonceexpiry_nonce: Arc<dyn KvTree>, // NonceExpiry = u64 millis + Nonce
+    pub(super) userdeviceid_solanasession: Arc<dyn KvTree>, // Session = JSON StoredSession
+    pub(super) solanarefreshtoken_userdeviceid: Arc<dyn KvTree>,
+    pub(super) userdeviceid_solanascopes: Arc<dyn KvTree>, // Scopes = JSON list of strings
+    pub(super) solanalogin_consumedat: Arc<dyn KvTree>, // Login = 32-byte fingerprint, ConsumedAt = u64 millis
+    pub(super) solanaconsumedat_login: Arc<dyn KvTree>, // ConsumedAt = u64 millis + fingerprint
     pub(super) userdeviceid_token: Arc<dyn KvTree>,
     pub(super) userdeviceid_metadata: Arc<dyn KvTree>, // This is also used to check if a device exists
     pub(super) userid_devicelistversion: Arc<dyn KvTree>, // DevicelistVersion = u64
@@ -306,6 +318,18 @@ impl KeyValueDatabase {
             userid_displayname: builder.open_tree("userid_displayname")?,
             userid_avatarurl: builder.open_tree("userid_avatarurl")?,
             userid_blurhash: builder.open_tree("userid_blurhash")?,
+            localpart_deactivatedat: builder.open_tree("localpart_deactivatedat")?,
+            userdeviceid_sessionstartedat: builder.open_tree("userdeviceid_sessionstartedat")?,
+            useridwallet_linked: builder.open_tree("useridwallet_linked")?,
+            wallet_userid: builder.open_tree("wallet_userid")?,
+            wallet_blockedat: builder.open_tree("wallet_blockedat")?,
+            solananonce_challenge: builder.open_tree("solananonce_challenge")?,
+            solananonceexpiry_nonce: builder.open_tree("solananonceexpiry_nonce")?,
+            userdeviceid_solanasession: builder.open_tree("userdeviceid_solanasession")?,
+            solanarefreshtoken_userdeviceid: builder.open_tree("solanarefreshtoken_userdeviceid")?,
+            userdeviceid_solanascopes: builder.open_tree("userdeviceid_solanascopes")?,
+            solanalogin_consumedat: builder.open_tree("solanalogin_consumedat")?,
+            solanaconsumedat_login: builder.open_tree("solanaconsumedat_login")?,
             userdeviceid_token: builder.open_tree("userdeviceid_token")?,
             userdeviceid_metadata: builder.open_tree("userdeviceid_metadata")?,
             userid_devicelistversion: builder.open_tree("userid_devicelistversion")?,
@@ -459,7 +483,7 @@ impl KeyValueDatabase {
         }
 
         // If the database has any data, perform data migrations before starting
-        let latest_database_version = 18;
+        let latest_database_version = 20;
 
         if services().users.count()? > 0 {
             // MIGRATIONS
@@ -1090,6 +1114,29 @@ impl KeyValueDatabase {
                 warn!("Migration: 17 -> 18 finished");
             }
 
+            if services().globals.database_version()? < 19 {
+                // Index the expiries of Solana nonces stored before the index existed
+                db.reindex_solana_nonces()?;
+
+                services().globals.bump_database_version(19)?;
+
+                warn!("Migration: 18 -> 19 finished");
+            }
+
+            if services().globals.database_version()? < 20 {
+                // Wallets linked to an account for login also count for its registry check
+                for (wallet, user_id) in db.wallet_userid.iter() {
+                    let mut key = user_id;
+                    key.push(0xff);
+                    key.extend_from_slice(&wallet);
+                    db.useridwallet_linked.insert(&key, &[])?;
+                }
+
+                services().globals.bump_database_version(20)?;
+
+                warn!("Migration: 19 -> 20 finished");
+            }
+
             assert_eq!(
                 services().globals.database_version().unwrap(),
                 latest_database_version
@@ -1118,6 +1165,7 @@ impl KeyValueDatabase {
         // This data is probably outdated
         db.presenceid_presence.clear()?;
 
+        services().admin.open_signup_log_room().await?;
         services().admin.start_handler();
 
         // Set emergency access for the conduit user
@@ -1251,6 +1299,10 @@ impl KeyValueDatabase {
                 } else {
                     debug!("cleanup: Finished in {:?}", start.elapsed());
                 }
+
+                if let Err(e) = services().solana.remove_expired_nonces() {
+                    error!("cleanup: Failed to remove expired Solana nonces: {}", e);
+                }
             }
         });
     }
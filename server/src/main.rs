@@ -2,7 +2,7 @@ use std::{future::Future, io, net::SocketAddr, sync::atomic, time::Duration};
 
 use axum::{
     body::Body,
-    extract::{FromRequestParts, MatchedPath},
+    extract::{ConnectInfo, FromRequestParts, MatchedPath},
     middleware::map_response,
     response::{IntoResponse, Response},
     routing::{any, get, on, MethodFilter},
@@ -242,7 +242,9 @@ async fn run_server() -> io::Result<()> {
         )
         .layer(map_response(set_csp_header));
 
-    let app = routes(config).layer(middlewares).into_make_service();
+    let app = routes(config)
+        .layer(middlewares)
+        .into_make_service_with_connect_info::<SocketAddr>();
     let handle = ServerHandle::new();
 
     tokio::spawn(shutdown_signal(handle.clone()));
@@ -309,30 +311,464 @@ async fn unrecognized_method(
 /// The client signs the returned message with their wallet, then submits
 /// the signature to the standard login endpoint with type `m.login.solana.signature`.
 async fn solana_nonce_handler(
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    headers: http::HeaderMap,
     axum::Json(body): axum::Json<client_server::solana_auth::NonceRequest>,
 ) -> std::result::Result<axum::Json<client_server::solana_auth::NonceResponse>, StatusCode> {
-    use conduit::services;
-    if !services().globals.allow_solana_auth() {
+    use client_server::solana_auth;
+    use conduit::{services, Error};
+    if !services().globals.solana_auth_ready() {
         return Err(StatusCode::NOT_FOUND);
     }
-    client_server::solana_auth::generate_nonce(&body.address)
+    solana_auth::generate_nonce(&body, Some(solana_auth::client_ip(remote, &headers)))
+        .map(axum::Json)
+        .map_err(|error| match error {
+            Error::BadRequest(ErrorKind::LimitExceeded { .. }, _) => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::BAD_REQUEST,
+        })
+}
+
+/// Handler for `GET /_matrix/client/unstable/org.solana.auth/well-known?address=<wallet>`
+///
+/// See [`client_server::get_wallet_well_known_route`].
+async fn solana_wallet_well_known_handler(
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
+) -> conduit::Result<axum::Json<serde_json::Value>> {
+    use conduit::Error;
+    use ruma::api::client::error::ErrorKind;
+
+    let address = query
+        .as_deref()
+        .and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "address")
+                .map(|(_, address)| address.into_owned())
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Missing address.",
+        ))?;
+
+    client_server::get_wallet_well_known_route(&address)
+        .await
+        .map(axum::Json)
+}
+
+/// Handler for `GET /_matrix/client/unstable/org.solana.auth/profile/{userId}`
+///
+/// See [`client_server::get_wallet_profile_route`].
+async fn solana_wallet_profile_handler(
+    axum::extract::Path(user_id): axum::extract::Path<String>,
+) -> conduit::Result<axum::Json<serde_json::Value>> {
+    client_server::get_wallet_profile_route(&user_id)
+        .await
         .map(axum::Json)
-        .map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+/// Handler for `GET /_matrix/client/unstable/org.solana.auth/delegations/{address}/history`
+///
+/// Replays a wallet's on-chain `register`/`unregister` calls, newest first, so moderators
+/// and users can audit which homeservers it has delegated to over time.
+async fn solana_delegation_history_handler(
+    axum::extract::Path(address): axum::extract::Path<String>,
+) -> std::result::Result<axum::Json<serde_json::Value>, StatusCode> {
+    use conduit::services;
+    if !services().globals.allow_solana_auth() || services().globals.solana_rpc_url().is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let owner: [u8; 32] = bs58::decode(&address)
+        .into_vec()
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let history = services()
+        .solana_registry
+        .delegation_history(&owner)
+        .await
+        .map_err(|_| StatusCode::BAD_GATEWAY)?;
+
+    // The registry only stores hostnames, so label delegations to this server with its
+    // configured display name.
+    let server_name = services().globals.server_name();
+    let display_name = services().globals.server_display_name();
+    let history: Vec<_> = history
+        .into_iter()
+        .map(|change| {
+            let label = display_name.filter(|_| {
+                change.homeservers.as_ref().is_some_and(|homeservers| {
+                    homeservers
+                        .iter()
+                        .any(|homeserver| homeserver == server_name.as_str())
+                })
+            });
+            let mut change = serde_json::to_value(change).expect("serialization can't fail");
+            if let Some(label) = label {
+                change["homeserver_display_name"] = label.into();
+            }
+            change
+        })
+        .collect();
+
+    Ok(axum::Json(serde_json::json!({
+        "address": address,
+        "history": history,
+    })))
+}
+
+/// Handler for `POST /_matrix/client/unstable/org.solana.auth/challenge`
+///
+/// One endpoint for both halves of a wallet login, for CLI and bot clients. A body with
+/// `address` returns a challenge and a `session`; a body with that `session` and the
+/// `signature` over the challenge message logs in and returns an access token.
+async fn solana_challenge_handler(
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    headers: http::HeaderMap,
+    axum::Json(body): axum::Json<client_server::solana_auth::ChallengeLoginRequest>,
+) -> conduit::Result<axum::Json<serde_json::Value>> {
+    use client_server::solana_auth;
+    use conduit::{services, Error};
+    use ruma::api::client::error::ErrorKind;
+
+    if !services().globals.solana_auth_ready() {
+        return Err(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "Solana authentication is not enabled on this server.",
+        ));
+    }
+
+    match (body.session, body.signature, body.address) {
+        (Some(session), Some(signature), _) => {
+            let mut request = solana_auth::challenge_login_request(&session, signature)
+                .inspect_err(|error| {
+                    services()
+                        .solana
+                        .record_login_failure(solana_auth::failure_category(error))
+                })?;
+            if body.initial_device_display_name.is_some() {
+                request.initial_device_display_name = body.initial_device_display_name;
+            }
+            request.client_ip = Some(solana_auth::client_ip(remote, &headers));
+            let response = client_server::complete_solana_login(request).await?;
+            Ok(axum::Json(serde_json::json!({
+                "user_id": response.user_id,
+                "access_token": response.access_token,
+                "device_id": response.device_id,
+                "home_server": services().globals.server_name(),
+                "well_known": response.well_known,
+            })))
+        }
+        (None, None, Some(address)) => {
+            let challenge = solana_auth::generate_nonce(
+                &solana_auth::NonceRequest {
+                    address,
+                    device_id: body.device_id,
+                    initial_device_display_name: body.initial_device_display_name,
+                    format: body.format,
+                    scopes: body.scopes,
+                    origin: None,
+                },
+                Some(solana_auth::client_ip(remote, &headers)),
+            )?;
+            Ok(axum::Json(serde_json::json!({
+                "session": challenge.nonce,
+                "message": challenge.message,
+                "expires_in_seconds": challenge.expires_in_seconds,
+            })))
+        }
+        _ => Err(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Send address to get a challenge, or session and signature to log in.",
+        )),
+    }
+}
+
+/// Handler for `POST /_matrix/client/unstable/org.solana.auth/step_up`
+///
+/// Step-up authentication for a logged-in wallet account. The device requests a
+/// challenge from the nonce endpoint with its own `device_id`, signs it, and posts the
+/// `nonce` and `signature` here with its access token. It can then perform one power
+/// action in rooms that require a fresh wallet signature.
+async fn solana_step_up_handler(
+    headers: http::HeaderMap,
+    axum::Json(body): axum::Json<client_server::solana_auth::StepUpRequest>,
+) -> conduit::Result<axum::Json<serde_json::Value>> {
+    use client_server::solana_auth;
+    use conduit::{services, Error};
+    use ruma::api::client::error::ErrorKind;
+
+    if !services().globals.solana_auth_ready() {
+        return Err(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "Solana authentication is not enabled on this server.",
+        ));
+    }
+
+    let (user_id, device_id) = solana_auth_device(&headers)?;
+    solana_auth::complete_step_up(&user_id, &device_id, body).await?;
+
+    Ok(axum::Json(serde_json::json!({
+        "expires_in_seconds": services().globals.solana_step_up_window(),
+    })))
+}
+
+/// Handler for `POST /_matrix/client/unstable/org.solana.auth/link_solana_wallet`
+///
+/// Lets a signed-in account, e.g. one with a password, add wallet login: the device
+/// requests a nonce with its own `device_id` for the wallet, signs it, and posts
+/// `{"nonce": ..., "signature": ...}` with its access token. Later
+/// `m.login.solana.signature` logins with the wallet sign in to this account.
+async fn solana_link_wallet_handler(
+    headers: http::HeaderMap,
+    axum::Json(body): axum::Json<client_server::solana_auth::LinkWalletRequest>,
+) -> conduit::Result<axum::Json<serde_json::Value>> {
+    use client_server::solana_auth;
+    use conduit::{services, Error};
+    use ruma::api::client::error::ErrorKind;
+
+    if !services().globals.solana_auth_ready() {
+        return Err(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "Solana authentication is not enabled on this server.",
+        ));
+    }
+
+    let (user_id, device_id) = solana_auth_device(&headers)?;
+    let address = solana_auth::link_solana_wallet(&user_id, &device_id, body).await?;
+
+    Ok(axum::Json(serde_json::json!({
+        "address": address,
+        "user_id": user_id,
+    })))
+}
+
+/// Handler for `POST /_matrix/client/unstable/org.solana.auth/rotate_solana_device_token`
+///
+/// A new access token for an existing device, e.g. after the old one was lost, without
+/// a full login. The client requests a nonce with the device's `device_id`, signs it,
+/// and posts `{"nonce": ..., "signature": ...}`. Devices the wallet's account doesn't
+/// have are refused with `M_NOT_FOUND`.
+async fn solana_rotate_device_token_handler(
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    headers: http::HeaderMap,
+    axum::Json(body): axum::Json<client_server::solana_auth::RotateDeviceTokenRequest>,
+) -> conduit::Result<axum::Json<serde_json::Value>> {
+    use client_server::solana_auth;
+    use conduit::{services, Error};
+    use ruma::api::client::error::ErrorKind;
+
+    if !services().globals.solana_auth_ready() {
+        return Err(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "Solana authentication is not enabled on this server.",
+        ));
+    }
+
+    let mut request = solana_auth::challenge_login_request(&body.nonce, body.signature)
+        .inspect_err(|error| {
+            services()
+                .solana
+                .record_login_failure(solana_auth::failure_category(error))
+        })?;
+    request.client_ip = Some(solana_auth::client_ip(remote, &headers));
+    request.refresh_token = body.refresh_token;
+    let response = client_server::rotate_solana_device_token(request).await?;
+
+    let mut json = serde_json::json!({
+        "user_id": response.user_id,
+        "access_token": response.access_token,
+        "device_id": response.device_id,
+    });
+    if let Some(refresh_token) = response.refresh_token {
+        json["refresh_token"] = refresh_token.into();
+    }
+    if let Some(expires_in) = response.expires_in {
+        json["expires_in_ms"] = u64::try_from(expires_in.as_millis())
+            .unwrap_or(u64::MAX)
+            .into();
+    }
+    Ok(axum::Json(json))
+}
+
+/// Handler for `POST /_matrix/client/unstable/m.login.solana/verify_test`
+///
+/// Runs an `m.login.solana.signature` login body through the login checks without
+/// logging in, and reports the step that failed, for wallet client developers. The
+/// nonce isn't consumed. Only served when `solana_debug_endpoints` is on.
+async fn solana_verify_test_handler(
+    axum::Json(body): axum::Json<serde_json::Value>,
+) -> conduit::Result<axum::Json<client_server::solana_auth::LoginDiagnosis>> {
+    use client_server::solana_auth;
+    use conduit::{services, Error};
+    use ruma::api::client::error::ErrorKind;
+
+    if !services().globals.solana_auth_ready() || !services().globals.solana_debug_endpoints() {
+        return Err(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "Solana debug endpoints are not enabled on this server.",
+        ));
+    }
+
+    let field = |name: &str| {
+        body.get(name)
+            .and_then(|value| value.as_str())
+            .map(str::to_owned)
+    };
+    let request = solana_auth::SolanaLoginRequest::from_login_body(
+        &body,
+        field("device_id"),
+        field("initial_device_display_name"),
+    )?;
+    Ok(axum::Json(solana_auth::dry_run_solana_login(&request)?))
+}
+
+/// The user and device of the access token on one of our own authenticated endpoints.
+fn solana_auth_device(
+    headers: &http::HeaderMap,
+) -> conduit::Result<(ruma::OwnedUserId, ruma::OwnedDeviceId)> {
+    use client_server::solana_auth;
+    use conduit::{services, Error};
+    use ruma::api::client::error::ErrorKind;
+
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "Missing access token.",
+        ))?;
+    services()
+        .users
+        .find_from_token(token)?
+        .filter(|(user_id, device_id)| {
+            !solana_auth::session_expired(user_id, device_id).unwrap_or(true)
+                && !solana_auth::access_token_expired(user_id, device_id, token).unwrap_or(true)
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::UnknownToken { soft_logout: false },
+            "Unknown access token.",
+        ))
+}
+
+/// Handler for `GET /_matrix/client/unstable/m.login.solana/operator-key`
+///
+/// Publishes the server's operator public key so clients and the registry's operator
+/// proof flow can check what this server signs. Only served when an operator key is
+/// configured. The key only changes with the config, so responses are cacheable.
+async fn solana_operator_key_handler() -> std::result::Result<Response, StatusCode> {
+    use conduit::services;
+    let operator_key = services()
+        .solana_registry
+        .operator_key()
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok((
+        [(header::CACHE_CONTROL, "public, max-age=86400")],
+        axum::Json(serde_json::json!({
+            "server_name": services().globals.server_name(),
+            "public_key": bs58::encode(operator_key.as_bytes()).into_string(),
+            "algorithm": "ed25519",
+            "role": "operator",
+        })),
+    )
+        .into_response())
+}
+
+#[derive(serde::Deserialize)]
+struct RelayRegisterRequest {
+    /// Base64 wire transaction, signed by the wallet with the relayer as fee payer.
+    transaction: String,
+}
+
+/// Handler for `POST /_matrix/client/unstable/org.solana.auth/relay/register`
+///
+/// Co-signs a wallet's `register` transaction as fee payer and submits it, so wallets
+/// without SOL can still delegate to this homeserver.
+async fn solana_relay_register_handler(
+    axum::Json(body): axum::Json<RelayRegisterRequest>,
+) -> conduit::Result<axum::Json<serde_json::Value>> {
+    use base64::{engine::general_purpose, Engine as _};
+    use conduit::{services, Error};
+    use ruma::api::client::error::ErrorKind;
+
+    if !services().globals.allow_solana_auth() || !services().solana_registry.relayer_enabled() {
+        return Err(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "The registry relayer is not enabled on this server.",
+        ));
+    }
+    let transaction = general_purpose::STANDARD
+        .decode(&body.transaction)
+        .map_err(|_| {
+            Error::BadRequest(ErrorKind::InvalidParam, "Transaction is not valid base64.")
+        })?;
+
+    let signature = services()
+        .solana_registry
+        .relay_register(&transaction)
+        .await?;
+
+    Ok(axum::Json(serde_json::json!({ "signature": signature })))
 }
 
 fn routes(config: &Config) -> Router {
+    // Wallet login is only advertised once the route below that issues its challenges exists.
+    services().globals.register_solana_nonce_route();
+
     let router = Router::new()
         // Solana auth nonce endpoint (not a ruma route — it's our own API)
         .route(
-            "/_matrix/client/unstable/org.solana.auth/nonce",
+            client_server::solana_auth::NONCE_PATH,
             axum::routing::post(solana_nonce_handler),
         )
+        .route(
+            "/_matrix/client/unstable/org.solana.auth/delegations/{address}/history",
+            get(solana_delegation_history_handler),
+        )
+        .route(
+            "/_matrix/client/unstable/org.solana.auth/well-known",
+            get(solana_wallet_well_known_handler),
+        )
+        .route(
+            "/_matrix/client/unstable/org.solana.auth/profile/{user_id}",
+            get(solana_wallet_profile_handler),
+        )
+        .route(
+            "/_matrix/client/unstable/m.login.solana/operator-key",
+            get(solana_operator_key_handler),
+        )
+        .route(
+            "/_matrix/client/unstable/org.solana.auth/challenge",
+            axum::routing::post(solana_challenge_handler),
+        )
+        .route(
+            "/_matrix/client/unstable/org.solana.auth/step_up",
+            axum::routing::post(solana_step_up_handler),
+        )
+        .route(
+            "/_matrix/client/unstable/org.solana.auth/link_solana_wallet",
+            axum::routing::post(solana_link_wallet_handler),
+        )
+        .route(
+            "/_matrix/client/unstable/org.solana.auth/rotate_solana_device_token",
+            axum::routing::post(solana_rotate_device_token_handler),
+        )
+        .route(
+            "/_matrix/client/unstable/m.login.solana/verify_test",
+            axum::routing::post(solana_verify_test_handler),
+        )
+        .route(
+            "/_matrix/client/unstable/org.solana.auth/relay/register",
+            axum::routing::post(solana_relay_register_handler),
+        )
         .ruma_route(client_server::ping_appservice_route)
         .ruma_route(client_server::get_supported_versions_route)
         .ruma_route(client_server::get_register_available_route)
         .ruma_route(client_server::register_route)
         .ruma_route(client_server::get_login_types_route)
         .ruma_route(client_server::login_route)
+        .ruma_route(client_server::refresh_token_route)
         .ruma_route(client_server::whoami_route)
         .ruma_route(client_server::logout_route)
         .ruma_route(client_server::logout_all_route)
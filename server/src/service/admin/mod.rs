@@ -2,7 +2,8 @@ use std::{
     borrow::Cow,
     collections::BTreeMap,
     convert::TryFrom,
-    sync::Arc,
+    net::IpAddr,
+    sync::{Arc, OnceLock},
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
@@ -33,11 +34,12 @@ use ruma::{
         TimelineEventType,
     },
     room_version_rules::RoomVersionRules,
-    EventId, MilliSecondsSinceUnixEpoch, MxcUri, OwnedMxcUri, OwnedRoomAliasId, OwnedRoomId,
-    OwnedServerName, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
+    DeviceId, EventId, MilliSecondsSinceUnixEpoch, MxcUri, OwnedMxcUri, OwnedRoomAliasId,
+    OwnedRoomId, OwnedServerName, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde_json::value::to_raw_value;
 use tokio::sync::{mpsc, Mutex, RwLock};
+use tracing::warn;
 
 use crate::{
     api::client_server::{self, leave_all_rooms, AUTO_GEN_PASSWORD_LENGTH},
@@ -90,6 +92,73 @@ enum AdminCommand {
     /// List users in the database
     ListLocalUsers,
 
+    /// List accounts created by Solana wallet login, with their wallet addresses
+    ListSolanaUsers,
+
+    /// Link another wallet to a Solana wallet account
+    ///
+    /// The wallet then signs in to the account, and when registry enforcement is on, a
+    /// login is accepted if any of the account's wallets is delegated to this server.
+    /// Wallets with their own account or linked elsewhere are refused. Only link wallets
+    /// you have verified the account holder controls.
+    LinkSolanaWallet {
+        /// The wallet account to link to
+        user_id: Box<UserId>,
+        /// Base58 address of the wallet to link
+        address: String,
+    },
+
+    /// Log out every session of a Solana wallet account
+    ///
+    /// For a compromised wallet: removes all devices of the account the wallet logs in
+    /// as, so none of its access tokens work any more. The wallet can still sign in
+    /// again; deactivate the account to stop that.
+    LogoutSolanaWallet {
+        /// Base58 address of the wallet
+        address: String,
+    },
+
+    /// Permanently stop a wallet from signing in and log out its account
+    ///
+    /// The block is on the wallet's key, so it holds even if the account the wallet
+    /// derives is deactivated and would otherwise be recreated on the next login.
+    BlockSolanaWallet {
+        /// Base58 address of the wallet
+        address: String,
+    },
+
+    /// Let a blocked wallet sign in again
+    UnblockSolanaWallet {
+        /// Base58 address of the wallet
+        address: String,
+    },
+
+    /// Check a wallet login a user reported trouble with
+    ///
+    /// Runs the address, nonce and signature through the same checks as a real login and
+    /// replies with the account it signs in as, or why it was refused. A good signature
+    /// uses up the nonce, as a real login would, but no account is created.
+    VerifySolanaLogin {
+        /// Base58 address of the wallet
+        address: String,
+        /// The nonce the wallet signed
+        nonce: String,
+        /// Base58 signature over the challenge
+        signature: String,
+    },
+
+    /// Read `solana_allowed_addresses_file` again without restarting
+    ///
+    /// If the file can't be read or lists an invalid address, the current allowlist is
+    /// kept.
+    ReloadSolanaAllowlist,
+
+    /// Show counters of Solana login challenges and outcomes since startup
+    ///
+    /// Failed logins are broken down by reason. The output is in the Prometheus text
+    /// format.
+    ShowSolanaMetrics,
+
     /// List all rooms we are currently handling an incoming pdu from
     IncomingFederation,
 
@@ -416,11 +485,15 @@ pub struct ListMediaArgs {
 pub enum AdminRoomEvent {
     ProcessMessage(String),
     SendMessage(RoomMessageEventContent),
+    /// A new wallet account, announced in `solana_signup_log_room` when one is open.
+    SendSignupNotice(RoomMessageEventContent),
 }
 
 pub struct Service {
     pub sender: mpsc::UnboundedSender<AdminRoomEvent>,
     receiver: Mutex<mpsc::UnboundedReceiver<AdminRoomEvent>>,
+    /// The room opened for `solana_signup_log_room` at startup.
+    signup_log_room: OnceLock<OwnedRoomId>,
 }
 
 impl Service {
@@ -429,6 +502,7 @@ impl Service {
         Arc::new(Self {
             sender,
             receiver: Mutex::new(receiver),
+            signup_log_room: OnceLock::new(),
         })
     }
 
@@ -450,8 +524,9 @@ impl Service {
             loop {
                 tokio::select! {
                     Some(event) = receiver.recv() => {
+                        let room_id = event_room(&event, &conduit_room, self.signup_log_room.get()).to_owned();
                         let message_content = match event {
-                            AdminRoomEvent::SendMessage(content) => content.into(),
+                            AdminRoomEvent::SendMessage(content) | AdminRoomEvent::SendSignupNotice(content) => content.into(),
                             AdminRoomEvent::ProcessMessage(room_message) => self.process_admin_message(room_message).await,
                         };
 
@@ -460,7 +535,7 @@ impl Service {
                                 .roomid_mutex_state
                                 .write()
                                 .await
-                                .entry(conduit_room.to_owned())
+                                .entry(room_id.clone())
                                 .or_default(),
                         );
 
@@ -480,7 +555,7 @@ impl Service {
                                     timestamp: None,
                                 },
                                 conduit_user,
-                                &conduit_room,
+                                &room_id,
                                 &state_lock,
                             )
                             .await.unwrap();
@@ -502,6 +577,31 @@ impl Service {
             .unwrap();
     }
 
+    /// Announce a new wallet account in the signup log room, or the admin room when none
+    /// is open.
+    pub fn send_signup_notice(&self, message_content: RoomMessageEventContent) {
+        self.sender
+            .send(AdminRoomEvent::SendSignupNotice(message_content))
+            .unwrap();
+    }
+
+    /// Announce a successful wallet login, new or returning, in the admin room when
+    /// `notify_on_login` (`solana_notify_on_login`) is on.
+    pub fn send_login_notice(
+        &self,
+        notify_on_login: bool,
+        user_id: &UserId,
+        address: &str,
+        device_id: &DeviceId,
+        client_ip: Option<IpAddr>,
+    ) {
+        if notify_on_login {
+            self.send_message(RoomMessageEventContent::notice_plain(
+                client_server::solana_auth::login_notice(user_id, address, device_id, client_ip),
+            ));
+        }
+    }
+
     // Parse and process a message from the admin room
     async fn process_admin_message(&self, room_message: String) -> MessageType {
         let mut lines = room_message.lines().filter(|l| !l.trim().is_empty());
@@ -644,6 +744,123 @@ impl Service {
                 Err(e) => RoomMessageEventContent::text_plain(e.to_string()),
             }
             .into(),
+            AdminCommand::ListSolanaUsers => {
+                let wallets: Vec<_> = services()
+                    .users
+                    .iter()
+                    .filter_map(|user_id| user_id.ok())
+                    .filter_map(|user_id| {
+                        let pubkey = client_server::solana_auth::localpart_to_pubkey(
+                            user_id.localpart(),
+                            services().globals.solana_localpart_namespace(),
+                        )?;
+                        Some(format!("{user_id} {}", bs58::encode(pubkey).into_string()))
+                    })
+                    .collect();
+
+                let mut msg = format!("Found {} Solana wallet account(s):\n", wallets.len());
+                msg += &wallets.join("\n");
+                RoomMessageEventContent::text_plain(&msg).into()
+            }
+            AdminCommand::LinkSolanaWallet { user_id, address } => {
+                let wallet: Option<[u8; 32]> = bs58::decode(&address)
+                    .into_vec()
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok());
+
+                let is_wallet_account = user_id.server_name() == services().globals.server_name()
+                    && client_server::solana_auth::localpart_to_pubkey(
+                        user_id.localpart(),
+                        services().globals.solana_localpart_namespace(),
+                    )
+                    .is_some();
+
+                let message = if !is_wallet_account {
+                    RoomMessageEventContent::text_plain(format!(
+                        "User {user_id} is not a local Solana wallet account"
+                    ))
+                } else if !services().users.exists(&user_id)? {
+                    RoomMessageEventContent::text_plain(format!(
+                        "User {user_id} doesn't exist on this server"
+                    ))
+                } else if let Some(wallet) = wallet {
+                    let wallet = client_server::solana_auth::VerifiedSolanaLogin::new(
+                        wallet,
+                        services().globals.solana_localpart_namespace(),
+                    );
+                    match client_server::solana_auth::link_wallet(&user_id, &wallet) {
+                        Ok(()) => RoomMessageEventContent::text_plain(format!(
+                            "Linked wallet {address} to {user_id}"
+                        )),
+                        Err(Error::BadRequest(_, message)) => {
+                            RoomMessageEventContent::text_plain(format!("{address}: {message}"))
+                        }
+                        Err(error) => return Err(error),
+                    }
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "{address} is not a valid base58 wallet address"
+                    ))
+                };
+                message.into()
+            }
+            AdminCommand::LogoutSolanaWallet { address } => {
+                let message = match client_server::solana_auth::logout_wallet(&address) {
+                    Ok(Some((user_id, devices))) => {
+                        format!("Logged out {devices} device(s) of {user_id}")
+                    }
+                    Ok(None) => format!("Wallet {address} has no account on this server"),
+                    Err(Error::BadRequest(_, message)) => format!("{address}: {message}"),
+                    Err(error) => return Err(error),
+                };
+                RoomMessageEventContent::text_plain(message).into()
+            }
+            AdminCommand::BlockSolanaWallet { address } => {
+                let message = match client_server::solana_auth::decode_address(&address) {
+                    Ok(wallet) => {
+                        services().solana.blocked_wallets.block(&wallet)?;
+                        match client_server::solana_auth::logout_wallet(&address)? {
+                            Some((user_id, devices)) => format!(
+                                "Blocked wallet {address} and logged out {devices} device(s) of {user_id}"
+                            ),
+                            None => format!("Blocked wallet {address}"),
+                        }
+                    }
+                    Err(_) => format!("{address} is not a valid base58 wallet address"),
+                };
+                RoomMessageEventContent::text_plain(message).into()
+            }
+            AdminCommand::UnblockSolanaWallet { address } => {
+                let message = match client_server::solana_auth::decode_address(&address) {
+                    Ok(wallet) if services().solana.blocked_wallets.unblock(&wallet)? => {
+                        format!("Unblocked wallet {address}")
+                    }
+                    Ok(_) => format!("Wallet {address} is not blocked"),
+                    Err(_) => format!("{address} is not a valid base58 wallet address"),
+                };
+                RoomMessageEventContent::text_plain(message).into()
+            }
+            AdminCommand::VerifySolanaLogin {
+                address,
+                nonce,
+                signature,
+            } => RoomMessageEventContent::text_plain(
+                client_server::solana_auth::verify_login_for_admin(&address, &nonce, &signature)
+                    .await?,
+            )
+            .into(),
+            AdminCommand::ReloadSolanaAllowlist => {
+                let message = match services().solana.reload_allowed_addresses() {
+                    Ok(0) => "Solana allowlist is empty: every wallet may sign in".to_owned(),
+                    Ok(count) => format!("Solana allowlist reloaded with {count} wallet(s)"),
+                    Err(error) => format!("Kept the current Solana allowlist: {error}"),
+                };
+                RoomMessageEventContent::text_plain(message).into()
+            }
+            AdminCommand::ShowSolanaMetrics => {
+                RoomMessageEventContent::text_plain(services().solana.metrics.snapshot().render())
+                    .into()
+            }
             AdminCommand::IncomingFederation => {
                 let map = services().globals.roomid_federationhandletime.read().await;
                 let mut msg: String = format!("Handling {} incoming pdus:\n", map.len());
@@ -1724,6 +1941,26 @@ impl Service {
 
         services().users.create(conduit_user, None)?;
 
+        let server_name = services().globals.server_name();
+        self.create_server_room(
+            services().globals.admin_alias(),
+            format!("{server_name} Admin Room"),
+            format!("Manage {server_name}"),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Create an invite-only room owned by the server user, under the local `alias`.
+    async fn create_server_room(
+        &self,
+        alias: &RoomAliasId,
+        room_name: String,
+        topic: String,
+    ) -> Result<OwnedRoomId> {
+        let conduit_user = services().globals.server_user();
+
         let room_version = services().globals.default_room_version();
         let rules = room_version
             .rules()
@@ -1872,7 +2109,6 @@ impl Service {
             .await?;
 
         // 5. Events implied by name and topic
-        let room_name = format!("{} Admin Room", services().globals.server_name());
         services()
             .rooms
             .timeline
@@ -1898,11 +2134,8 @@ impl Service {
             .build_and_append_pdu(
                 PduBuilder {
                     event_type: TimelineEventType::RoomTopic,
-                    content: to_raw_value(&RoomTopicEventContent::new(format!(
-                        "Manage {}",
-                        services().globals.server_name()
-                    )))
-                    .expect("event is valid, we just created it"),
+                    content: to_raw_value(&RoomTopicEventContent::new(topic))
+                        .expect("event is valid, we just created it"),
                     unsigned: None,
                     state_key: Some("".to_owned()),
                     redacts: None,
@@ -1915,7 +2148,7 @@ impl Service {
             .await?;
 
         // 6. Room alias
-        let alias: OwnedRoomAliasId = services().globals.admin_alias().to_owned();
+        let alias: OwnedRoomAliasId = alias.to_owned();
 
         services()
             .rooms
@@ -1944,6 +2177,133 @@ impl Service {
             .alias
             .set_alias(&alias, &room_id, conduit_user)?;
 
+        Ok(room_id)
+    }
+
+    /// Open the room `solana_signup_log_room` names, so new wallet accounts are announced
+    /// there. A local alias that doesn't resolve yet gets a new room, with the admins
+    /// invited; any other room is joined by the server user. When the room can't be
+    /// opened, a warning is logged and notices keep going to the admin room.
+    pub(crate) async fn open_signup_log_room(&self) -> Result<()> {
+        let Some(configured) = services().globals.solana_signup_log_room() else {
+            return Ok(());
+        };
+        let server_name = services().globals.server_name();
+        let Some(target) = client_server::solana_auth::configured_room(configured, server_name)
+        else {
+            warn!("solana_signup_log_room {configured:?} is not a room ID or alias");
+            return Ok(());
+        };
+        let conduit_user = services().globals.server_user();
+
+        let local_alias = OwnedRoomId::try_from(target.clone())
+            .err()
+            .filter(|alias| alias.server_name() == server_name);
+        let room_id = match local_alias {
+            Some(alias)
+                if services()
+                    .rooms
+                    .alias
+                    .resolve_local_alias(&alias)?
+                    .is_none() =>
+            {
+                let room_id = self
+                    .create_server_room(
+                        &alias,
+                        format!("{server_name} Signups"),
+                        format!("New wallet accounts on {server_name}"),
+                    )
+                    .await?;
+                self.invite_admins(&room_id).await?;
+                room_id
+            }
+            _ => {
+                let joined = async {
+                    let (servers, room_id) = services()
+                        .rooms
+                        .state_cache
+                        .get_room_id_and_via_servers(conduit_user, target, Vec::new())
+                        .await?;
+                    if !services()
+                        .rooms
+                        .state_cache
+                        .is_joined(conduit_user, &room_id)?
+                    {
+                        services()
+                            .rooms
+                            .helpers
+                            .join_room_by_id(conduit_user, &room_id, None, &servers, None)
+                            .await?;
+                    }
+                    Ok::<_, Error>(room_id)
+                };
+                match joined.await {
+                    Ok(room_id) => room_id,
+                    Err(error) => {
+                        warn!("Could not join solana_signup_log_room {configured:?}: {error}");
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        let _ = self.signup_log_room.set(room_id);
+        Ok(())
+    }
+
+    /// Invite everyone in the admin room to `room_id`.
+    async fn invite_admins(&self, room_id: &RoomId) -> Result<()> {
+        let Some(admin_room) = self.get_admin_room()? else {
+            return Ok(());
+        };
+        let conduit_user = services().globals.server_user();
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .await
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        for user_id in services().rooms.state_cache.room_members(&admin_room) {
+            let user_id = user_id?;
+            if &*user_id == conduit_user {
+                continue;
+            }
+
+            services()
+                .rooms
+                .timeline
+                .build_and_append_pdu(
+                    PduBuilder {
+                        event_type: TimelineEventType::RoomMember,
+                        content: to_raw_value(&RoomMemberEventContent {
+                            membership: MembershipState::Invite,
+                            displayname: None,
+                            avatar_url: None,
+                            is_direct: None,
+                            third_party_invite: None,
+                            blurhash: None,
+                            reason: None,
+                            join_authorized_via_users_server: None,
+                        })
+                        .expect("event is valid, we just created it"),
+                        unsigned: None,
+                        state_key: Some(user_id.to_string()),
+                        redacts: None,
+                        timestamp: None,
+                    },
+                    conduit_user,
+                    room_id,
+                    &state_lock,
+                )
+                .await?;
+        }
+
         Ok(())
     }
 
@@ -2103,6 +2463,19 @@ impl Service {
     }
 }
 
+/// The room `event` is posted in: signup notices go to the signup log room when one is
+/// open, and everything else to the admin room.
+fn event_room<'a>(
+    event: &AdminRoomEvent,
+    admin_room: &'a RoomId,
+    signup_log_room: Option<&'a OwnedRoomId>,
+) -> &'a RoomId {
+    match (event, signup_log_room) {
+        (AdminRoomEvent::SendSignupNotice(_), Some(signup_log_room)) => signup_log_room,
+        _ => admin_room,
+    }
+}
+
 fn userids_from_body<'a>(
     body: &'a [&'a str],
 ) -> Result<Result<Vec<&'a UserId>, MessageType>, Error> {
@@ -2221,6 +2594,140 @@ mod test {
         get_help_inner("help");
     }
 
+    #[test]
+    fn posts_signup_notices_in_the_signup_log_room() {
+        let admin_room = <&RoomId>::try_from("!admin:chat.example.com").unwrap();
+        let signup_log_room = OwnedRoomId::try_from("!signups:chat.example.com").unwrap();
+        let user_id = <&UserId>::try_from("@alice:chat.example.com").unwrap();
+        let notice = AdminRoomEvent::SendSignupNotice(RoomMessageEventContent::notice_plain(
+            client_server::solana_auth::signup_notice(
+                "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi",
+                user_id,
+                1_700_000_000_000,
+                None,
+            ),
+        ));
+
+        assert_eq!(
+            event_room(&notice, admin_room, Some(&signup_log_room)),
+            &*signup_log_room
+        );
+        assert_eq!(event_room(&notice, admin_room, None), admin_room);
+
+        // Other messages stay in the admin room either way.
+        let message = AdminRoomEvent::SendMessage(RoomMessageEventContent::notice_plain("hi"));
+        assert_eq!(
+            event_room(&message, admin_room, Some(&signup_log_room)),
+            admin_room
+        );
+
+        let AdminRoomEvent::SendSignupNotice(content) = notice else {
+            unreachable!()
+        };
+        assert_eq!(
+            content.body(),
+            "New Solana user 4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi registered as \
+             @alice:chat.example.com at 2023-11-14T22:13:20Z."
+        );
+    }
+
+    #[tokio::test]
+    async fn notifies_returning_logins_only_when_enabled() {
+        let admin = Service::build();
+        let mut receiver = admin.receiver.lock().await;
+        let user_id = <&UserId>::try_from("@alice:chat.example.com").unwrap();
+        let address = "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi";
+        let device_id = <&DeviceId>::from("PHONE");
+        let ip = "203.0.113.7".parse().ok();
+
+        admin.send_login_notice(false, user_id, address, device_id, ip);
+        assert!(receiver.try_recv().is_err());
+
+        admin.send_login_notice(true, user_id, address, device_id, ip);
+        admin.send_login_notice(true, user_id, address, device_id, None);
+        for from in ["203.0.113.7", "an unknown IP"] {
+            let Ok(AdminRoomEvent::SendMessage(content)) = receiver.try_recv() else {
+                panic!("expected a login notice in the admin room");
+            };
+            assert_eq!(
+                content.body(),
+                format!(
+                    "Solana user @alice:chat.example.com logged in with wallet {address} on \
+                     device PHONE from {from}."
+                )
+            );
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn parses_logout_solana_wallet() {
+        let command = AdminCommand::try_parse_from([
+            "argv[0] doesn't matter",
+            "logout-solana-wallet",
+            "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi",
+        ])
+        .unwrap();
+        assert!(matches!(
+            command,
+            AdminCommand::LogoutSolanaWallet { address }
+                if address == "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi"
+        ));
+    }
+
+    #[test]
+    fn parses_block_and_unblock_solana_wallet() {
+        let address = "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi";
+        let command = AdminCommand::try_parse_from([
+            "argv[0] doesn't matter",
+            "block-solana-wallet",
+            address,
+        ])
+        .unwrap();
+        assert!(matches!(
+            command,
+            AdminCommand::BlockSolanaWallet { address: parsed } if parsed == address
+        ));
+
+        let command = AdminCommand::try_parse_from([
+            "argv[0] doesn't matter",
+            "unblock-solana-wallet",
+            address,
+        ])
+        .unwrap();
+        assert!(matches!(
+            command,
+            AdminCommand::UnblockSolanaWallet { address: parsed } if parsed == address
+        ));
+    }
+
+    #[test]
+    fn parses_verify_solana_login() {
+        let command = AdminCommand::try_parse_from([
+            "argv[0] doesn't matter",
+            "verify-solana-login",
+            "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi",
+            "abc",
+            "5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnbJLgp8uirBgmQpjKhoR4tjF3ZpRzrFmBV6UjKdiSZkQUW",
+        ])
+        .unwrap();
+        assert!(matches!(
+            command,
+            AdminCommand::VerifySolanaLogin { address, nonce, signature }
+                if address == "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi"
+                    && nonce == "abc"
+                    && signature.starts_with("5VERv8")
+        ));
+    }
+
+    #[test]
+    fn parses_show_solana_metrics() {
+        let command =
+            AdminCommand::try_parse_from(["argv[0] doesn't matter", "show-solana-metrics"])
+                .unwrap();
+        assert!(matches!(command, AdminCommand::ShowSolanaMetrics));
+    }
+
     fn get_help_inner(input: &str) {
         let error = AdminCommand::try_parse_from(["argv[0] doesn't matter", input])
             .unwrap_err()
@@ -8,7 +8,10 @@ use ruma::{
 use crate::api::server_server::DestinationResponse;
 
 use crate::{
-    config::{DirectoryStructure, MediaBackendConfig, TurnConfig},
+    config::{
+        DirectoryStructure, DisplaynameGuard, MediaBackendConfig, RegistryConflictPolicy,
+        RpcFailurePolicy, TurnConfig,
+    },
     services, Config, Error, Result,
 };
 use futures_util::FutureExt;
@@ -37,6 +40,7 @@ use std::{
 use tokio::sync::{broadcast, watch::Receiver, Mutex, RwLock, Semaphore};
 use tower_service::Service as TowerService;
 use tracing::{error, info};
+use url::Url;
 
 type WellKnownMap = HashMap<OwnedServerName, DestinationResponse>;
 type TlsNameMap = HashMap<String, (Vec<IpAddr>, u16)>;
@@ -75,6 +79,8 @@ pub struct Service {
     pub rotate: RotationHandler,
 
     pub shutdown: AtomicBool,
+    /// Set once the Solana nonce challenge route is mounted. See [`Self::solana_auth_ready`].
+    solana_nonce_route: AtomicBool,
 }
 
 /// Handles "rotation" of long-polling requests. "Rotation" in this context is similar to "rotation" of log files and the like.
@@ -229,6 +235,7 @@ impl Service {
             sync_receivers: RwLock::new(HashMap::new()),
             rotate: RotationHandler::new(),
             shutdown: AtomicBool::new(false),
+            solana_nonce_route: AtomicBool::new(false),
         };
 
         if let MediaBackendConfig::FileSystem { path, .. } = &s.config.media.backend {
@@ -369,6 +376,10 @@ impl Service {
         self.config.well_known.client.clone()
     }
 
+    pub fn well_known_identity_server(&self) -> Option<String> {
+        self.config.well_known.identity_server.clone()
+    }
+
     pub fn dns_resolver(&self) -> &TokioResolver {
         &self.dns_resolver
     }
@@ -381,10 +392,213 @@ impl Service {
         self.config.allow_solana_auth
     }
 
+    /// Record that the Solana nonce challenge route has been mounted.
+    pub fn register_solana_nonce_route(&self) {
+        self.solana_nonce_route.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Whether wallet login works end to end: `allow_solana_auth` is on and the nonce
+    /// challenge route is mounted. Advertising and accepting `m.login.solana.signature`
+    /// both go by this, so clients are never offered a login they can't complete.
+    pub fn solana_auth_ready(&self) -> bool {
+        self.allow_solana_auth() && self.solana_nonce_route.load(atomic::Ordering::Relaxed)
+    }
+
     pub fn solana_auto_join_room(&self) -> Option<&str> {
         self.config.solana_auto_join_room.as_deref()
     }
 
+    pub fn solana_enforce_registry(&self) -> bool {
+        self.config.solana_enforce_registry
+    }
+
+    pub fn solana_trust_supplied_delegation(&self) -> bool {
+        self.config.solana_trust_supplied_delegation
+    }
+
+    pub fn solana_rpc_url(&self) -> Option<&Url> {
+        self.config.solana_rpc_url.as_ref()
+    }
+
+    pub fn solana_registry_program_id(&self) -> &str {
+        &self.config.solana_registry_program_id
+    }
+
+    pub fn solana_notice_repeated_failures(&self) -> bool {
+        self.config.solana_notice_repeated_failures
+    }
+
+    pub fn solana_notice_failure_threshold(&self) -> u32 {
+        self.config.solana_notice_failure_threshold
+    }
+
+    pub fn solana_notice_denied_attempts(&self) -> bool {
+        self.config.solana_notice_denied_attempts
+    }
+
+    pub fn solana_notice_nonce_cap(&self) -> bool {
+        self.config.solana_notice_nonce_cap
+    }
+
+    pub fn solana_history_limit(&self) -> u32 {
+        self.config.solana_history_limit
+    }
+
+    pub fn solana_relayer_allowed_wallets(&self) -> &[String] {
+        &self.config.solana_relayer_allowed_wallets
+    }
+
+    pub fn solana_relayer_max_per_wallet(&self) -> u32 {
+        self.config.solana_relayer_max_per_wallet
+    }
+
+    pub fn solana_relayer_max_rent_lamports(&self) -> u64 {
+        self.config.solana_relayer_max_rent_lamports
+    }
+
+    pub fn solana_displayname_guard(&self) -> DisplaynameGuard {
+        self.config.solana_displayname_guard
+    }
+
+    pub fn solana_recent_activity_window(&self) -> Option<u64> {
+        self.config.solana_recent_activity_window
+    }
+
+    pub fn solana_rpc_failure_policy(&self) -> RpcFailurePolicy {
+        self.config.solana_rpc_failure_policy
+    }
+
+    pub fn solana_registry_previous_program_ids(&self) -> &[String] {
+        &self.config.solana_registry_previous_program_ids
+    }
+
+    pub fn solana_registry_conflict_policy(&self) -> RegistryConflictPolicy {
+        self.config.solana_registry_conflict_policy
+    }
+
+    pub fn solana_recreation_cooldown(&self) -> u64 {
+        self.config.solana_recreation_cooldown
+    }
+
+    pub fn solana_login_discovery(&self) -> bool {
+        self.config.solana_login_discovery
+    }
+
+    pub fn solana_session_lifetime(&self) -> Option<u64> {
+        self.config.solana_session_lifetime
+    }
+
+    pub fn solana_nonce_ttl(&self) -> u64 {
+        self.config.solana_nonce_ttl
+    }
+
+    pub fn solana_challenge_replay_window(&self) -> Option<u64> {
+        self.config.solana_challenge_replay_window
+    }
+
+    pub fn solana_displayname_template(&self) -> Option<&str> {
+        self.config.solana_displayname_template.as_deref()
+    }
+
+    pub fn solana_notice_failure_summary(&self) -> bool {
+        self.config.solana_notice_failure_summary
+    }
+
+    pub fn solana_notice_failure_summary_threshold(&self) -> u32 {
+        self.config.solana_notice_failure_summary_threshold
+    }
+
+    pub fn server_display_name(&self) -> Option<&str> {
+        self.config.server_display_name.as_deref()
+    }
+
+    pub fn solana_sign_in_domains(&self) -> &[String] {
+        &self.config.solana_sign_in_domains
+    }
+
+    pub fn solana_step_up_window(&self) -> u64 {
+        self.config.solana_step_up_window
+    }
+
+    pub fn solana_trust_forwarded_for(&self) -> bool {
+        self.config.solana_trust_forwarded_for
+    }
+
+    pub fn solana_required_collection(&self) -> Option<&str> {
+        self.config.solana_required_collection.as_deref()
+    }
+
+    pub fn solana_required_mint(&self) -> Option<&str> {
+        self.config.solana_required_mint.as_deref()
+    }
+
+    pub fn solana_required_min_balance(&self) -> u64 {
+        self.config.solana_required_min_balance
+    }
+
+    pub fn solana_required_balance_on_return(&self) -> bool {
+        self.config.solana_required_balance_on_return
+    }
+
+    pub fn solana_notify_on_login(&self) -> bool {
+        self.config.solana_notify_on_login
+    }
+
+    pub fn solana_homeserver_denylist(&self) -> &[String] {
+        &self.config.solana_homeserver_denylist
+    }
+
+    pub fn solana_challenge_statement(&self) -> &str {
+        &self.config.solana_challenge_statement
+    }
+
+    pub fn solana_session_ttl(&self) -> Option<u64> {
+        self.config.solana_session_ttl
+    }
+
+    pub fn solana_debug_endpoints(&self) -> bool {
+        self.config.solana_debug_endpoints
+    }
+
+    pub fn solana_probe_delegated_homeserver(&self) -> bool {
+        self.config.solana_probe_delegated_homeserver
+    }
+
+    pub fn solana_registry_auth_keys(&self) -> bool {
+        self.config.solana_registry_auth_keys
+    }
+
+    pub fn solana_signup_log_room(&self) -> Option<&str> {
+        self.config.solana_signup_log_room.as_deref()
+    }
+
+    pub fn solana_nonce_bytes(&self) -> usize {
+        self.config.solana_nonce_bytes
+    }
+
+    pub fn solana_allowed_origins(&self) -> &[String] {
+        &self.config.solana_allowed_origins
+    }
+
+    pub fn solana_auto_join_rooms(&self) -> &[String] {
+        &self.config.solana_auto_join_rooms
+    }
+
+    pub fn solana_localpart_namespace(&self) -> Option<&str> {
+        self.config
+            .solana_localpart_namespace
+            .as_deref()
+            .filter(|namespace| !namespace.is_empty())
+    }
+
+    pub fn solana_resolve_sns(&self) -> bool {
+        self.config.solana_resolve_sns
+    }
+
+    pub fn solana_signature_max_age(&self) -> u64 {
+        self.config.solana_signature_max_age
+    }
+
     pub fn emergency_password(&self) -> &Option<String> {
         &self.config.emergency_password
     }
@@ -19,6 +19,8 @@ pub mod pdu;
 pub mod pusher;
 pub mod rooms;
 pub mod sending;
+pub mod solana;
+pub mod solana_registry;
 pub mod transaction_ids;
 pub mod uiaa;
 pub mod users;
@@ -36,6 +38,8 @@ pub struct Services {
     pub key_backups: key_backups::Service,
     pub media: Arc<media::Service>,
     pub sending: Arc<sending::Service>,
+    pub solana: solana::Service,
+    pub solana_registry: solana_registry::Service,
 }
 
 impl Services {
@@ -51,6 +55,7 @@ impl Services {
             + key_backups::Data
             + media::Data
             + sending::Data
+            + solana::Data
             + 'static,
     >(
         db: &'static D,
@@ -122,6 +127,8 @@ impl Services {
             key_backups: key_backups::Service { db },
             media: Arc::new(media::Service { db }),
             sending: sending::Service::build(db, &config),
+            solana: solana::Service::build(db, &config)?,
+            solana_registry: solana_registry::Service::build(&config)?,
 
             globals: globals::Service::load(db, config)?,
         })
@@ -163,6 +170,7 @@ impl Services {
             .lock()
             .await
             .len();
+        let solana_user_cache = self.solana.user_cache_stats();
 
         format!(
             "\
@@ -171,7 +179,8 @@ server_visibility_cache: {server_visibility_cache}
 user_visibility_cache: {user_visibility_cache}
 stateinfo_cache: {stateinfo_cache}
 lasttimelinecount_cache: {lasttimelinecount_cache}
-roomid_spacechunk_cache: {roomid_spacehierarchy_cache}\
+roomid_spacechunk_cache: {roomid_spacehierarchy_cache}
+{solana_user_cache}\
             "
         )
     }
@@ -224,5 +233,8 @@ roomid_spacechunk_cache: {roomid_spacehierarchy_cache}\
                 .await
                 .clear();
         }
+        if amount > 6 {
+            self.solana.clear_user_cache();
+        }
     }
 }
@@ -0,0 +1,76 @@
+//! Wallets an admin blocked from signing in with `block-solana-wallet`.
+
+use ruma::api::client::error::ErrorKind;
+
+use super::Data;
+use crate::{utils, Error, Result};
+
+pub struct BlockedWallets {
+    db: &'static dyn Data,
+}
+
+impl BlockedWallets {
+    pub fn new(db: &'static dyn Data) -> Self {
+        Self { db }
+    }
+
+    /// Whether an admin blocked the wallet from signing in, whatever account it maps to.
+    pub fn is_blocked(&self, wallet: &[u8; 32]) -> Result<bool> {
+        self.db.is_wallet_blocked(wallet)
+    }
+
+    /// Block the wallet from signing in. It stays blocked if its account is deactivated
+    /// or recreated, since the block is on the key itself.
+    pub fn block(&self, wallet: &[u8; 32]) -> Result<()> {
+        self.db
+            .block_wallet(wallet, utils::millis_since_unix_epoch())
+    }
+
+    /// Let a blocked wallet sign in again. Returns whether it was blocked.
+    pub fn unblock(&self, wallet: &[u8; 32]) -> Result<bool> {
+        self.db.unblock_wallet(wallet)
+    }
+
+    /// Refuse a blocked wallet. Logins and wallet links check this before the wallet's
+    /// account is looked up, so deactivating and recreating the account it derives
+    /// doesn't lift the block.
+    pub fn check(&self, wallet: &[u8; 32]) -> Result<()> {
+        if self.is_blocked(wallet)? {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "This wallet is blocked from signing in to this server.",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::data::SharedDb, *};
+
+    #[test]
+    fn blocked_wallets_stay_out_until_unblocked() {
+        let db: &'static SharedDb = Box::leak(Box::default());
+        let blocked_wallets = BlockedWallets::new(db);
+        let (wallet, other) = ([1; 32], [2; 32]);
+
+        assert!(blocked_wallets.check(&wallet).is_ok());
+        blocked_wallets.block(&wallet).unwrap();
+        assert!(matches!(
+            blocked_wallets.check(&wallet),
+            Err(Error::BadRequest(ErrorKind::Forbidden { .. }, _))
+        ));
+        // Other wallets are unaffected.
+        assert!(blocked_wallets.check(&other).is_ok());
+
+        // The block is kept in the database, so a restarted server still refuses it.
+        let restarted = BlockedWallets::new(db);
+        assert!(restarted.check(&wallet).is_err());
+
+        assert!(restarted.unblock(&wallet).unwrap());
+        assert!(blocked_wallets.check(&wallet).is_ok());
+        // Unblocking a wallet that isn't blocked says so.
+        assert!(!restarted.unblock(&wallet).unwrap());
+    }
+}
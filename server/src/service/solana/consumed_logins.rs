@@ -0,0 +1,149 @@
+//! Fingerprints of the logins that consumed their nonce, kept in the database so an
+//! exact replay is refused even if the nonce store was wiped or restored from a backup
+//! in between.
+
+use sha2::{Digest, Sha256};
+
+use super::Data;
+use crate::Result;
+
+/// Most login fingerprints kept after a sweep. The oldest beyond it are forgotten.
+pub const MAX_CONSUMED_LOGINS: usize = 100_000;
+
+/// Identifies one signed login: SHA-256 over the wallet's decoded public key, the nonce
+/// and the signature, each prefixed with its length so no two different logins hash the
+/// same bytes. The key rather than the address the client sent, so resending a login with
+/// the address padded in a way login accepts still hashes the same. Collisions are as
+/// unlikely as for SHA-256 itself, so an honest login is never mistaken for a replay.
+pub fn login_fingerprint(pubkey: &[u8; 32], nonce: &str, signature: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in [pubkey.as_slice(), nonce.as_bytes(), signature] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+pub struct ConsumedLogins {
+    db: &'static dyn Data,
+}
+
+impl ConsumedLogins {
+    pub fn new(db: &'static dyn Data) -> Self {
+        Self { db }
+    }
+
+    /// Whether a login with this fingerprint has already signed in.
+    pub fn is_replay(&self, fingerprint: &[u8; 32]) -> Result<bool> {
+        self.db.is_consumed_login(fingerprint)
+    }
+
+    /// Remember a login that just consumed its nonce at `now`.
+    pub fn record(&self, fingerprint: &[u8; 32], now: u64) -> Result<()> {
+        self.db.record_consumed_login(fingerprint, now)
+    }
+
+    /// Forget the oldest fingerprints until at most `cap` are left, returning how many
+    /// went.
+    pub fn evict_oldest(&self, cap: usize) -> Result<usize> {
+        let excess = self
+            .db
+            .consumed_logins_newest_first()
+            .skip(cap)
+            .collect::<Result<Vec<_>>>()?;
+
+        for fingerprint in &excess {
+            self.db.remove_consumed_login(fingerprint)?;
+        }
+        Ok(excess.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{data::SharedDb, ChallengeFormat, Nonces, Redemption, StoredNonce},
+        *,
+    };
+
+    const ADDRESS: &str = "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi";
+    const WALLET: [u8; 32] = [7; 32];
+
+    fn stored() -> StoredNonce {
+        StoredNonce {
+            issued_at: 0,
+            expires_at: 1_000,
+            address: ADDRESS.to_owned(),
+            device_id: None,
+            initial_device_display_name: None,
+            format: ChallengeFormat::Legacy,
+            statement: crate::config::default_solana_challenge_statement(),
+            scopes: Vec::new(),
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn replays_are_refused_after_the_nonce_store_is_reset() {
+        use futures_util::FutureExt;
+
+        let db: &'static SharedDb = Box::leak(Box::default());
+        let (nonces, consumed) = (Nonces::new(db), ConsumedLogins::new(db));
+
+        // Redeemed the way `verify_solana_login` redeems them, with every signature good.
+        let login = |nonce: &str, signature: &[u8]| {
+            let fingerprint = login_fingerprint(&WALLET, nonce, signature);
+            nonces
+                .redeem(nonce, &consumed, &fingerprint, 500, |_| async { Ok(()) })
+                .now_or_never()
+                .unwrap()
+                .unwrap()
+        };
+
+        nonces.issue("abc", &stored()).unwrap();
+        assert_eq!(
+            login("abc", b"signature over abc"),
+            Redemption::Redeemed(())
+        );
+
+        // The store is rolled back to when the nonce was still waiting to be signed.
+        nonces.issue("abc", &stored()).unwrap();
+        assert_eq!(login("abc", b"signature over abc"), Redemption::Replayed);
+
+        // A fresh challenge signs in as usual.
+        nonces.issue("def", &stored()).unwrap();
+        assert_eq!(
+            login("def", b"signature over def"),
+            Redemption::Redeemed(())
+        );
+    }
+
+    #[test]
+    fn fingerprints_cover_the_key_nonce_and_signature() {
+        let fingerprint = login_fingerprint(&WALLET, "abc", b"sig");
+        assert_eq!(fingerprint, login_fingerprint(&WALLET, "abc", b"sig"));
+        assert_ne!(fingerprint, login_fingerprint(&[8; 32], "abc", b"sig"));
+        assert_ne!(fingerprint, login_fingerprint(&WALLET, "abd", b"sig"));
+        assert_ne!(fingerprint, login_fingerprint(&WALLET, "abc", b"sih"));
+        // Moving bytes between fields doesn't collide.
+        assert_ne!(login_fingerprint(&WALLET, "abcs", b"ig"), fingerprint);
+    }
+
+    #[test]
+    fn evicts_the_oldest_fingerprints_beyond_the_cap() {
+        let db: &'static SharedDb = Box::leak(Box::default());
+        let consumed = ConsumedLogins::new(db);
+        for i in 0..100u64 {
+            consumed
+                .record(&login_fingerprint(&WALLET, &format!("n{i}"), b"sig"), i)
+                .unwrap();
+        }
+
+        assert_eq!(consumed.evict_oldest(100).unwrap(), 0);
+        assert_eq!(consumed.evict_oldest(60).unwrap(), 40);
+        for i in 0..100u64 {
+            let fingerprint = login_fingerprint(&WALLET, &format!("n{i}"), b"sig");
+            assert_eq!(consumed.is_replay(&fingerprint).unwrap(), i >= 40);
+        }
+    }
+}
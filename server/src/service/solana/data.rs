@@ -0,0 +1,275 @@
+#[cfg(test)]
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, UserId};
+
+use crate::Result;
+
+use super::{StoredNonce, StoredSession};
+
+pub trait Data: Send + Sync {
+    /// Store a login challenge under its nonce, replacing any previous one.
+    fn store_nonce(&self, nonce: &str, stored: &StoredNonce) -> Result<()>;
+
+    fn get_nonce(&self, nonce: &str) -> Result<Option<StoredNonce>>;
+
+    fn remove_nonce(&self, nonce: &str) -> Result<()>;
+
+    /// Remove a nonce and return it, in one step: of several server processes taking the
+    /// same nonce at once, only one gets it.
+    fn take_nonce(&self, nonce: &str) -> Result<Option<StoredNonce>>;
+
+    /// Returns the stored nonces with their expiry, soonest-expiring first or, with
+    /// `latest_first`, the other way round. Read lazily from an index of expiries, so
+    /// callers only pay for the entries they take.
+    fn nonces_by_expiry<'a>(
+        &'a self,
+        latest_first: bool,
+    ) -> Box<dyn Iterator<Item = Result<(u64, String)>> + 'a>;
+
+    /// Store the expiring session of a device, replacing any previous one along with its
+    /// refresh token.
+    fn store_session(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        session: &StoredSession,
+    ) -> Result<()>;
+
+    fn get_session(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<StoredSession>>;
+
+    fn remove_session(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()>;
+
+    /// Find the device a refresh token was issued to.
+    fn find_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId)>>;
+
+    /// Store the scopes a device signed in with, replacing any previous ones. No scopes
+    /// removes the entry.
+    fn store_scopes(&self, user_id: &UserId, device_id: &DeviceId, scopes: &[String])
+        -> Result<()>;
+
+    fn get_scopes(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<String>>;
+
+    /// Remember the fingerprint of a login that consumed its nonce at `consumed_at`.
+    fn record_consumed_login(&self, fingerprint: &[u8; 32], consumed_at: u64) -> Result<()>;
+
+    fn is_consumed_login(&self, fingerprint: &[u8; 32]) -> Result<bool>;
+
+    fn remove_consumed_login(&self, fingerprint: &[u8; 32]) -> Result<()>;
+
+    /// Returns the remembered login fingerprints, most recently consumed first. Read
+    /// lazily, like [`Data::nonces_by_expiry`].
+    fn consumed_logins_newest_first<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<[u8; 32]>> + 'a>;
+
+    /// Whether an admin blocked the wallet from signing in.
+    fn is_wallet_blocked(&self, wallet: &[u8; 32]) -> Result<bool>;
+
+    /// Block the wallet from signing in, recording that it happened at `millis`.
+    fn block_wallet(&self, wallet: &[u8; 32], millis: u64) -> Result<()>;
+
+    /// Let the wallet sign in again. Returns whether it was blocked.
+    fn unblock_wallet(&self, wallet: &[u8; 32]) -> Result<bool>;
+}
+
+/// Stands in for a database shared by several server processes.
+#[cfg(test)]
+#[derive(Default)]
+pub struct SharedDb {
+    nonces: Mutex<HashMap<String, StoredNonce>>,
+    nonce_expiries: Mutex<BTreeSet<(u64, String)>>,
+    /// How many entries have been read from `nonces_by_expiry`.
+    pub expiry_reads: AtomicUsize,
+    sessions: Mutex<HashMap<(OwnedUserId, OwnedDeviceId), StoredSession>>,
+    scopes: Mutex<HashMap<(OwnedUserId, OwnedDeviceId), Vec<String>>>,
+    consumed_logins: Mutex<HashMap<[u8; 32], u64>>,
+    blocked_wallets: Mutex<HashMap<[u8; 32], u64>>,
+}
+
+#[cfg(test)]
+impl Data for SharedDb {
+    fn store_nonce(&self, nonce: &str, stored: &StoredNonce) -> Result<()> {
+        self.remove_nonce(nonce)?;
+        self.nonces
+            .lock()
+            .unwrap()
+            .insert(nonce.to_owned(), stored.clone());
+        self.nonce_expiries
+            .lock()
+            .unwrap()
+            .insert((stored.expires_at, nonce.to_owned()));
+        Ok(())
+    }
+
+    fn get_nonce(&self, nonce: &str) -> Result<Option<StoredNonce>> {
+        Ok(self.nonces.lock().unwrap().get(nonce).cloned())
+    }
+
+    fn remove_nonce(&self, nonce: &str) -> Result<()> {
+        self.take_nonce(nonce)?;
+        Ok(())
+    }
+
+    fn take_nonce(&self, nonce: &str) -> Result<Option<StoredNonce>> {
+        let stored = self.nonces.lock().unwrap().remove(nonce);
+        if let Some(stored) = &stored {
+            self.nonce_expiries
+                .lock()
+                .unwrap()
+                .remove(&(stored.expires_at, nonce.to_owned()));
+        }
+        Ok(stored)
+    }
+
+    fn nonces_by_expiry<'a>(
+        &'a self,
+        latest_first: bool,
+    ) -> Box<dyn Iterator<Item = Result<(u64, String)>> + 'a> {
+        let mut expiries: Vec<_> = self
+            .nonce_expiries
+            .lock()
+            .unwrap()
+            .clone()
+            .into_iter()
+            .collect();
+        if latest_first {
+            expiries.reverse();
+        }
+        Box::new(expiries.into_iter().map(|entry| {
+            self.expiry_reads.fetch_add(1, Ordering::Relaxed);
+            Ok(entry)
+        }))
+    }
+
+    fn store_session(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        session: &StoredSession,
+    ) -> Result<()> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert((user_id.to_owned(), device_id.to_owned()), session.clone());
+        Ok(())
+    }
+
+    fn get_session(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<StoredSession>> {
+        Ok(self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&(user_id.to_owned(), device_id.to_owned()))
+            .cloned())
+    }
+
+    fn remove_session(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(&(user_id.to_owned(), device_id.to_owned()));
+        Ok(())
+    }
+
+    fn find_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId)>> {
+        Ok(self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, session)| session.refresh_token == refresh_token)
+            .map(|(device, _)| device.clone()))
+    }
+
+    fn store_scopes(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        scopes: &[String],
+    ) -> Result<()> {
+        let device = (user_id.to_owned(), device_id.to_owned());
+        let mut stored = self.scopes.lock().unwrap();
+        if scopes.is_empty() {
+            stored.remove(&device);
+        } else {
+            stored.insert(device, scopes.to_vec());
+        }
+        Ok(())
+    }
+
+    fn get_scopes(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<String>> {
+        Ok(self
+            .scopes
+            .lock()
+            .unwrap()
+            .get(&(user_id.to_owned(), device_id.to_owned()))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn record_consumed_login(&self, fingerprint: &[u8; 32], consumed_at: u64) -> Result<()> {
+        self.consumed_logins
+            .lock()
+            .unwrap()
+            .insert(*fingerprint, consumed_at);
+        Ok(())
+    }
+
+    fn is_consumed_login(&self, fingerprint: &[u8; 32]) -> Result<bool> {
+        Ok(self
+            .consumed_logins
+            .lock()
+            .unwrap()
+            .contains_key(fingerprint))
+    }
+
+    fn remove_consumed_login(&self, fingerprint: &[u8; 32]) -> Result<()> {
+        self.consumed_logins.lock().unwrap().remove(fingerprint);
+        Ok(())
+    }
+
+    fn consumed_logins_newest_first<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<[u8; 32]>> + 'a> {
+        let mut consumed: Vec<_> = self
+            .consumed_logins
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(fingerprint, consumed_at)| (*consumed_at, *fingerprint))
+            .collect();
+        consumed.sort_unstable_by(|a, b| b.cmp(a));
+        Box::new(consumed.into_iter().map(|(_, fingerprint)| Ok(fingerprint)))
+    }
+
+    fn is_wallet_blocked(&self, wallet: &[u8; 32]) -> Result<bool> {
+        Ok(self.blocked_wallets.lock().unwrap().contains_key(wallet))
+    }
+
+    fn block_wallet(&self, wallet: &[u8; 32], millis: u64) -> Result<()> {
+        self.blocked_wallets.lock().unwrap().insert(*wallet, millis);
+        Ok(())
+    }
+
+    fn unblock_wallet(&self, wallet: &[u8; 32]) -> Result<bool> {
+        Ok(self
+            .blocked_wallets
+            .lock()
+            .unwrap()
+            .remove(wallet)
+            .is_some())
+    }
+}
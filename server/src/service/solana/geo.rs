@@ -0,0 +1,60 @@
+//! Coarse location of the IP a wallet signed up from, shown in signup notices to help
+//! spot abuse.
+
+use std::net::IpAddr;
+
+/// Looks up the country of a client IP, e.g. in a MaxMind GeoLite2 database. Plug one in
+/// as [`Service::geo`](super::Service::geo); the default, [`NoGeoLookup`], looks nothing
+/// up, so there's no database to ship. It runs once per signup, on the login request.
+pub trait GeoLookup: Send + Sync {
+    /// The country `ip` is in, as an ISO 3166-1 alpha-2 code like "NZ", if known.
+    fn country(&self, ip: IpAddr) -> Option<String>;
+}
+
+/// Knows no locations, so signup notices leave the country out.
+pub struct NoGeoLookup;
+
+impl GeoLookup for NoGeoLookup {
+    fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::UserId;
+
+    use super::*;
+    use crate::api::client_server::solana_auth::signup_notice;
+
+    const ADDRESS: &str = "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi";
+
+    struct StubGeoLookup;
+
+    impl GeoLookup for StubGeoLookup {
+        fn country(&self, ip: IpAddr) -> Option<String> {
+            (ip == IpAddr::from([203, 0, 113, 7])).then(|| "NZ".to_owned())
+        }
+    }
+
+    #[test]
+    fn signup_notices_name_the_country_when_it_is_known() {
+        let user_id = <&UserId>::try_from("@alice:chat.example.com").unwrap();
+        let notice = |geo: &dyn GeoLookup, ip: [u8; 4]| {
+            let country = geo.country(IpAddr::from(ip));
+            signup_notice(ADDRESS, user_id, 1_700_000_000_000, country.as_deref())
+        };
+
+        assert_eq!(
+            notice(&StubGeoLookup, [203, 0, 113, 7]),
+            "New Solana user 4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi registered as \
+             @alice:chat.example.com at 2023-11-14T22:13:20Z from NZ."
+        );
+
+        // Without a lookup, or for an IP it doesn't know, the country is left out.
+        let without_country = "New Solana user 4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi \
+                               registered as @alice:chat.example.com at 2023-11-14T22:13:20Z.";
+        assert_eq!(notice(&NoGeoLookup, [203, 0, 113, 7]), without_country);
+        assert_eq!(notice(&StubGeoLookup, [198, 51, 100, 1]), without_country);
+    }
+}
@@ -0,0 +1,152 @@
+//! Counters of wallet login activity, for operators watching auth health.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::LoginFailure;
+
+/// Running totals since startup. Every counter only goes up.
+#[derive(Default)]
+pub struct SolanaMetrics {
+    nonces_issued: AtomicU64,
+    nonces_expired: AtomicU64,
+    nonces_evicted: AtomicU64,
+    logins_succeeded: AtomicU64,
+    /// Failed logins, indexed by [`LoginFailure`].
+    logins_failed: [AtomicU64; LoginFailure::ALL.len()],
+    registrations: AtomicU64,
+}
+
+/// The counters of [`SolanaMetrics`] read at one moment.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub nonces_issued: u64,
+    pub nonces_expired: u64,
+    pub nonces_evicted: u64,
+    pub logins_succeeded: u64,
+    pub logins_failed: [u64; LoginFailure::ALL.len()],
+    pub registrations: u64,
+}
+
+impl SolanaMetrics {
+    /// A login challenge was issued.
+    pub fn nonce_issued(&self) {
+        self.nonces_issued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `count` challenges expired unused and were swept.
+    pub fn nonces_expired(&self, count: usize) {
+        self.nonces_expired
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// `count` unexpired challenges were evicted because the store was over its cap, a
+    /// sign of a flood of challenge requests or of a cap too small for the traffic.
+    pub fn nonces_evicted(&self, count: usize) {
+        self.nonces_evicted
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// A wallet signed in, `registered` when the login created its account.
+    pub fn login_succeeded(&self, registered: bool) {
+        self.logins_succeeded.fetch_add(1, Ordering::Relaxed);
+        if registered {
+            self.registrations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A login failed for `reason`.
+    pub fn login_failed(&self, reason: LoginFailure) {
+        self.logins_failed[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Read every counter.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            nonces_issued: self.nonces_issued.load(Ordering::Relaxed),
+            nonces_expired: self.nonces_expired.load(Ordering::Relaxed),
+            nonces_evicted: self.nonces_evicted.load(Ordering::Relaxed),
+            logins_succeeded: self.logins_succeeded.load(Ordering::Relaxed),
+            logins_failed: self
+                .logins_failed
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed)),
+            registrations: self.registrations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    /// The counters in the Prometheus text format, one per line.
+    pub fn render(&self) -> String {
+        let mut lines = vec![
+            format!("solana_nonces_issued_total {}", self.nonces_issued),
+            format!("solana_nonces_expired_total {}", self.nonces_expired),
+            format!("solana_nonces_evicted_total {}", self.nonces_evicted),
+            format!("solana_logins_succeeded_total {}", self.logins_succeeded),
+        ];
+        for reason in LoginFailure::ALL {
+            lines.push(format!(
+                "solana_logins_failed_total{{reason=\"{}\"}} {}",
+                reason.label(),
+                self.logins_failed[reason as usize]
+            ));
+        }
+        lines.push(format!("solana_registrations_total {}", self.registrations));
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_a_sequence_of_good_and_bad_logins() {
+        let metrics = SolanaMetrics::default();
+
+        // A new wallet signs in, then comes back on another device.
+        metrics.nonce_issued();
+        metrics.login_succeeded(true);
+        metrics.nonce_issued();
+        metrics.login_succeeded(false);
+
+        // A forged signature, a reused nonce, an expired one and a bad address.
+        metrics.nonce_issued();
+        metrics.login_failed(LoginFailure::SignatureMismatch);
+        metrics.login_failed(LoginFailure::UnknownNonce);
+        metrics.nonce_issued();
+        metrics.login_failed(LoginFailure::NonceExpired);
+        metrics.login_failed(LoginFailure::BadEncoding);
+        metrics.login_failed(LoginFailure::SignatureMismatch);
+
+        // The sweep removes the challenges nobody used.
+        metrics.nonces_expired(2);
+        metrics.nonces_expired(0);
+        metrics.nonces_evicted(3);
+
+        let mut logins_failed = [0; LoginFailure::ALL.len()];
+        logins_failed[LoginFailure::BadEncoding as usize] = 1;
+        logins_failed[LoginFailure::UnknownNonce as usize] = 1;
+        logins_failed[LoginFailure::NonceExpired as usize] = 1;
+        logins_failed[LoginFailure::SignatureMismatch as usize] = 2;
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            snapshot,
+            MetricsSnapshot {
+                nonces_issued: 4,
+                nonces_expired: 2,
+                nonces_evicted: 3,
+                logins_succeeded: 2,
+                logins_failed,
+                registrations: 1,
+            }
+        );
+
+        let rendered = snapshot.render();
+        assert!(rendered.contains("solana_nonces_evicted_total 3\n"));
+        assert!(rendered.contains("solana_logins_succeeded_total 2\n"));
+        assert!(rendered.contains("solana_logins_failed_total{reason=\"signature mismatch\"} 2\n"));
+        assert!(rendered.contains("solana_logins_failed_total{reason=\"gating denied\"} 0\n"));
+        assert!(rendered.ends_with("solana_registrations_total 1"));
+    }
+}
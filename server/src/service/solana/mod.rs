@@ -0,0 +1,484 @@
+//! State shared by the Solana wallet login flow.
+
+mod blocked_wallets;
+mod consumed_logins;
+mod data;
+mod geo;
+mod metrics;
+mod nonces;
+mod rate_limit;
+mod sessions;
+mod signup_locks;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use lru_cache::LruCache;
+use ruma::{
+    api::client::error::{ErrorKind, RetryAfter},
+    events::room::message::RoomMessageEventContent,
+    DeviceId, OwnedDeviceId, OwnedUserId, UserId,
+};
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::{api::client_server::solana_auth, services, utils, Config, Error, Result};
+
+pub use blocked_wallets::BlockedWallets;
+pub use consumed_logins::{login_fingerprint, ConsumedLogins, MAX_CONSUMED_LOGINS};
+pub use data::Data;
+pub use geo::{GeoLookup, NoGeoLookup};
+pub use metrics::{MetricsSnapshot, SolanaMetrics};
+pub use nonces::{
+    ChallengeFormat, NonceClaim, Nonces, Redemption, StoredNonce, REISSUE_GRACE_MILLIS,
+};
+use rate_limit::RateLimiter;
+pub use sessions::{Sessions, StoredSession};
+pub use signup_locks::SignupLocks;
+
+/// Most unexpired nonces kept after a sweep. The oldest beyond it are evicted, and the
+/// admin room is told.
+const MAX_NONCES: usize = 10_000;
+
+/// Failed verifications for one address within this window count towards an alert.
+const FAILURE_WINDOW: Duration = Duration::from_secs(600);
+
+/// Most wallets whose recent failures are tracked. The least recently failed are
+/// forgotten beyond it.
+const MAX_FAILING_WALLETS: usize = 10_000;
+
+/// Minimum time between two admin room notices of the same category.
+const NOTICE_THROTTLE: Duration = Duration::from_secs(300);
+
+/// Window over which login failures are counted for one failure summary. At most one
+/// summary is sent per window.
+const SUMMARY_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Security-relevant events that can be reported to the admin room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SecurityNotice {
+    /// One address failed verification repeatedly.
+    RepeatedFailures,
+    /// A correctly signed login was refused by server policy.
+    DeniedAttempt,
+    /// The nonce store held more than [`MAX_NONCES`] unexpired nonces and the oldest were
+    /// evicted.
+    NonceCapReached,
+    /// Many logins failed recently; the notice breaks them down by [`LoginFailure`].
+    FailureSummary,
+}
+
+/// Why a Solana login failed, as counted in the failure summary.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LoginFailure {
+    /// The login body, address or signature couldn't be decoded.
+    BadEncoding,
+    /// The nonce was never issued or has already been used.
+    UnknownNonce,
+    /// The nonce was used after its acceptance window.
+    NonceExpired,
+    /// The login didn't match what the challenge was issued for: device, device name or
+    /// sign-in domain.
+    ChallengeMismatch,
+    /// The signature doesn't match the challenge.
+    SignatureMismatch,
+    /// The signature was valid but server policy refused the login.
+    GatingDenied,
+}
+
+impl LoginFailure {
+    const ALL: [Self; 6] = [
+        Self::BadEncoding,
+        Self::UnknownNonce,
+        Self::NonceExpired,
+        Self::ChallengeMismatch,
+        Self::SignatureMismatch,
+        Self::GatingDenied,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            LoginFailure::BadEncoding => "bad encoding",
+            LoginFailure::UnknownNonce => "unknown or reused nonce",
+            LoginFailure::NonceExpired => "nonce expired",
+            LoginFailure::ChallengeMismatch => "challenge mismatch",
+            LoginFailure::SignatureMismatch => "signature mismatch",
+            LoginFailure::GatingDenied => "gating denied",
+        }
+    }
+}
+
+/// Login failures counted in the current [`SUMMARY_WINDOW`].
+struct FailureSummary {
+    started: Instant,
+    counts: BTreeMap<LoginFailure, u32>,
+    sent: bool,
+}
+
+impl FailureSummary {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            counts: BTreeMap::new(),
+            sent: false,
+        }
+    }
+}
+
+pub struct Service {
+    /// Login challenges waiting to be signed.
+    pub nonces: Nonces,
+    /// Expiring wallet sessions and their refresh tokens.
+    pub sessions: Sessions,
+    /// Fingerprints of recent logins, so exact replays are refused across nonce store
+    /// resets.
+    pub consumed_logins: ConsumedLogins,
+    /// Wallets an admin blocked from signing in.
+    pub blocked_wallets: BlockedWallets,
+    /// Counters of challenges and login outcomes since startup.
+    pub metrics: SolanaMetrics,
+    /// Held while a wallet's account is created, so concurrent first logins create it once.
+    pub signup_locks: SignupLocks,
+    /// Finds the country new wallet accounts signed up from, for the signup notice.
+    pub geo: Box<dyn GeoLookup>,
+    /// Limits how often one client IP or address can request a challenge.
+    nonce_limiter: RateLimiter,
+    nonce_rate_limit: u32,
+    /// Recent verification failures per wallet public key: (count, first failure in window).
+    failures: Mutex<LruCache<[u8; 32], (u32, Instant)>>,
+    /// Login failures by category, for the failure summary.
+    failure_summary: Mutex<FailureSummary>,
+    /// When each notice category was last sent.
+    last_notice: Mutex<HashMap<SecurityNotice, Instant>>,
+    /// Bounds how many signature verifications run on the blocking pool at once.
+    verify_permits: Semaphore,
+    /// User IDs of wallets that recently logged in to an existing account, by base58 address.
+    resolved_users: Mutex<LruCache<String, OwnedUserId>>,
+    user_cache_hits: AtomicU64,
+    user_cache_misses: AtomicU64,
+    /// When each device last proved its wallet with a step-up signature, until a gated
+    /// power action uses it.
+    step_ups: Mutex<HashMap<(OwnedUserId, OwnedDeviceId), Instant>>,
+    /// Wallets allowed to sign in, from `solana_allowed_addresses` and
+    /// `solana_allowed_addresses_file`. Empty allows every wallet.
+    allowed_addresses: RwLock<HashSet<[u8; 32]>>,
+}
+
+impl Service {
+    pub fn build(db: &'static dyn Data, config: &Config) -> Result<Self> {
+        Ok(Self {
+            nonces: Nonces::new(db),
+            sessions: Sessions::new(db),
+            consumed_logins: ConsumedLogins::new(db),
+            blocked_wallets: BlockedWallets::new(db),
+            metrics: SolanaMetrics::default(),
+            signup_locks: SignupLocks::default(),
+            geo: Box::new(NoGeoLookup),
+            nonce_limiter: RateLimiter::new(config.solana_nonce_rate_limit),
+            nonce_rate_limit: config.solana_nonce_rate_limit,
+            failures: Mutex::new(LruCache::new(MAX_FAILING_WALLETS)),
+            failure_summary: Mutex::new(FailureSummary::new()),
+            last_notice: Mutex::new(HashMap::new()),
+            verify_permits: Semaphore::new(config.solana_verify_workers.max(1)),
+            resolved_users: Mutex::new(LruCache::new(config.solana_user_cache_capacity)),
+            user_cache_hits: AtomicU64::new(0),
+            user_cache_misses: AtomicU64::new(0),
+            step_ups: Mutex::new(HashMap::new()),
+            allowed_addresses: RwLock::new(load_allowed_addresses(config)?),
+        })
+    }
+
+    /// The user ID a verified wallet logs in as, and whether that account exists yet.
+    ///
+    /// Wallets with an existing account are kept in an LRU of `solana_user_cache_capacity`
+    /// entries, so returning wallets skip building the user ID and the database lookups.
+    pub fn resolve_user(
+        &self,
+        wallet: &solana_auth::VerifiedSolanaLogin,
+    ) -> Result<(OwnedUserId, bool)> {
+        let address = &wallet.base58_address;
+        if let Some(user_id) = self.resolved_users.lock().unwrap().get_mut(address) {
+            self.user_cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok((user_id.clone(), true));
+        }
+        self.user_cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        let user_id = solana_auth::login_user_id(
+            wallet,
+            services().users.wallet_login_user(&wallet.pubkey)?,
+            services().globals.server_name(),
+            services().globals.solana_localpart_namespace(),
+        )?;
+
+        let exists = services().users.exists(&user_id)?;
+        if exists {
+            self.resolved_users
+                .lock()
+                .unwrap()
+                .insert(address.to_owned(), user_id.clone());
+        }
+
+        Ok((user_id, exists))
+    }
+
+    /// Drop an account from the resolved user cache, e.g. when it is deactivated.
+    pub fn forget_user(&self, user_id: &UserId) {
+        let mut resolved_users = self.resolved_users.lock().unwrap();
+        let addresses = resolved_users
+            .iter()
+            .filter(|(_, cached)| **cached == user_id)
+            .map(|(address, _)| address.clone())
+            .collect::<Vec<_>>();
+        for address in addresses {
+            resolved_users.remove(&address);
+        }
+    }
+
+    /// Size and hit rate of the resolved user cache, for the admin memory usage report.
+    pub fn user_cache_stats(&self) -> String {
+        format!(
+            "solana_user_cache: {} ({} hits, {} misses)",
+            self.resolved_users.lock().unwrap().len(),
+            self.user_cache_hits.load(Ordering::Relaxed),
+            self.user_cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Empty the resolved user cache.
+    pub fn clear_user_cache(&self) {
+        self.resolved_users.lock().unwrap().clear();
+    }
+
+    /// Verify a wallet signature under `scheme` off the async runtime.
+    ///
+    /// Verification is CPU-bound, so it runs on tokio's blocking pool with at most
+    /// `solana_verify_workers` in flight; extra requests wait for a permit instead of
+    /// stalling the request handlers.
+    pub async fn verify_signature(
+        &self,
+        public_key: [u8; 32],
+        message: Vec<u8>,
+        signature: Vec<u8>,
+        scheme: solana_auth::SignatureScheme,
+    ) -> bool {
+        let _permit = self
+            .verify_permits
+            .acquire()
+            .await
+            .expect("verify semaphore is never closed");
+
+        tokio::task::spawn_blocking(move || {
+            scheme.verifier().verify(&public_key, &message, &signature)
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// Record a failed verification for the wallet `pubkey`, notifying admins once it
+    /// reaches the configured number of failures within [`FAILURE_WINDOW`].
+    pub fn record_failure(&self, pubkey: &[u8; 32]) {
+        let count = {
+            let mut failures = self.failures.lock().unwrap();
+            let now = Instant::now();
+            // Failures from before the window start a new count
+            let entry = match failures.get_mut(pubkey) {
+                Some(&mut (count, first)) if now.duration_since(first) < FAILURE_WINDOW => {
+                    (count + 1, first)
+                }
+                _ => (1, now),
+            };
+            failures.insert(*pubkey, entry);
+            entry.0
+        };
+
+        if count == services().globals.solana_notice_failure_threshold() {
+            self.notify(
+                SecurityNotice::RepeatedFailures,
+                format!(
+                    "Solana address {} failed signature verification {count} times in the last {} minutes.",
+                    bs58::encode(pubkey).into_string(),
+                    FAILURE_WINDOW.as_secs() / 60
+                ),
+            );
+        }
+    }
+
+    /// Count a challenge request against both the client's IP and the wallet address,
+    /// refusing it once either has used up `solana_nonce_rate_limit` for the minute.
+    pub fn check_nonce_rate(&self, client_ip: Option<IpAddr>, address: &str) -> Result<()> {
+        let now = Instant::now();
+        let ip_allowed =
+            client_ip.is_none_or(|ip| self.nonce_limiter.try_acquire(&format!("ip:{ip}"), now));
+        if !ip_allowed
+            || !self
+                .nonce_limiter
+                .try_acquire(&format!("address:{address}"), now)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after: Some(RetryAfter::Delay(rate_limit::retry_after(
+                        self.nonce_rate_limit,
+                    ))),
+                },
+                "Too many login challenges requested. Try again shortly.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Delete expired login challenges, then evict the oldest beyond [`MAX_NONCES`],
+    /// counting them and telling the admin room if there were any. Runs with the periodic database cleanup.
+    pub fn remove_expired_nonces(&self) -> Result<()> {
+        // Nonces that only just expired are kept for a little longer, so a late login can
+        // still be given a new challenge.
+        let expired = self.nonces.remove_expired(
+            utils::millis_since_unix_epoch().saturating_sub(REISSUE_GRACE_MILLIS),
+        )?;
+        self.metrics.nonces_expired(expired);
+        let evicted = self.nonces.evict_oldest(MAX_NONCES)?;
+        self.metrics.nonces_evicted(evicted);
+        if evicted > 0 {
+            self.notify(
+                SecurityNotice::NonceCapReached,
+                format!("Solana nonce store held more than {MAX_NONCES} unexpired nonces. Evicted the {evicted} oldest."),
+            );
+        }
+        self.consumed_logins.evict_oldest(MAX_CONSUMED_LOGINS)?;
+        Ok(())
+    }
+
+    /// Refuse the wallet `pubkey` if an allowlist is configured and doesn't include it.
+    pub fn check_allowed_address(&self, pubkey: &[u8; 32]) -> Result<()> {
+        solana_auth::check_allowed_address(pubkey, &self.allowed_addresses.read().unwrap())
+    }
+
+    /// Read `solana_allowed_addresses_file` again, returning how many wallets are now
+    /// allowed. On error the previous allowlist stays in place.
+    pub fn reload_allowed_addresses(&self) -> Result<usize> {
+        let allowed = load_allowed_addresses(&services().globals.config)?;
+        let count = allowed.len();
+        *self.allowed_addresses.write().unwrap() = allowed;
+        Ok(count)
+    }
+
+    /// Remember that a device just signed a step-up challenge with its account's wallet.
+    pub fn record_step_up(&self, user_id: &UserId, device_id: &DeviceId) {
+        let window = Duration::from_secs(services().globals.solana_step_up_window());
+        let mut step_ups = self.step_ups.lock().unwrap();
+        step_ups.retain(|_, signed| signed.elapsed() < window);
+        step_ups.insert((user_id.to_owned(), device_id.to_owned()), Instant::now());
+    }
+
+    /// Use up a device's step-up signature. Returns whether it signed one within
+    /// `solana_step_up_window`; each signature covers a single action.
+    pub fn take_step_up(&self, user_id: &UserId, device_id: &DeviceId) -> bool {
+        let window = Duration::from_secs(services().globals.solana_step_up_window());
+        self.step_ups
+            .lock()
+            .unwrap()
+            .remove(&(user_id.to_owned(), device_id.to_owned()))
+            .is_some_and(|signed| signed.elapsed() < window)
+    }
+
+    /// Forget failures for the wallet `pubkey` after it logs in successfully.
+    pub fn clear_failures(&self, pubkey: &[u8; 32]) {
+        self.failures.lock().unwrap().remove(pubkey);
+    }
+
+    /// Count a failed login by category, in [`Self::metrics`] and the failure summary. Once `solana_notice_failure_summary_threshold`
+    /// logins have failed within [`SUMMARY_WINDOW`], the counts are sent as a single
+    /// notice, and nothing more is sent until the window ends. The summary only carries
+    /// categories and counts, never addresses or signatures.
+    pub fn record_login_failure(&self, kind: LoginFailure) {
+        self.metrics.login_failed(kind);
+
+        let (total, breakdown) = {
+            let mut summary = self.failure_summary.lock().unwrap();
+            if summary.started.elapsed() >= SUMMARY_WINDOW {
+                *summary = FailureSummary::new();
+            }
+            *summary.counts.entry(kind).or_default() += 1;
+
+            let total: u32 = summary.counts.values().sum();
+            if summary.sent || total < services().globals.solana_notice_failure_summary_threshold()
+            {
+                return;
+            }
+            summary.sent = true;
+
+            let breakdown = summary
+                .counts
+                .iter()
+                .map(|(kind, count)| format!("{count} {}", kind.label()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            (total, breakdown)
+        };
+
+        self.notify(
+            SecurityNotice::FailureSummary,
+            format!(
+                "{total} Solana logins failed in the last {} minutes: {breakdown}.",
+                SUMMARY_WINDOW.as_secs() / 60
+            ),
+        );
+    }
+
+    /// Send a security notice to the admin room if its category is enabled and it
+    /// hasn't fired within [`NOTICE_THROTTLE`]. Everything is logged regardless.
+    pub fn notify(&self, kind: SecurityNotice, message: String) {
+        warn!("Solana auth security notice ({kind:?}): {message}");
+
+        let globals = &services().globals;
+        let enabled = match kind {
+            SecurityNotice::RepeatedFailures => globals.solana_notice_repeated_failures(),
+            SecurityNotice::DeniedAttempt => globals.solana_notice_denied_attempts(),
+            SecurityNotice::NonceCapReached => globals.solana_notice_nonce_cap(),
+            SecurityNotice::FailureSummary => globals.solana_notice_failure_summary(),
+        };
+        if !enabled {
+            return;
+        }
+
+        {
+            let mut last_notice = self.last_notice.lock().unwrap();
+            let now = Instant::now();
+            if last_notice
+                .get(&kind)
+                .is_some_and(|last| now.duration_since(*last) < NOTICE_THROTTLE)
+            {
+                return;
+            }
+            last_notice.insert(kind, now);
+        }
+
+        services()
+            .admin
+            .send_message(RoomMessageEventContent::notice_plain(message));
+    }
+}
+
+/// The wallet allowlist from `config` and the file it names.
+fn load_allowed_addresses(config: &Config) -> Result<HashSet<[u8; 32]>> {
+    let file = config
+        .solana_allowed_addresses_file
+        .as_ref()
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .map_err(|_| Error::bad_config("solana_allowed_addresses_file could not be read."))
+        })
+        .transpose()?;
+    let allowed =
+        solana_auth::parse_allowed_addresses(&config.solana_allowed_addresses, file.as_deref())?;
+    if file.is_some() && allowed.is_empty() {
+        warn!("solana_allowed_addresses_file lists no wallets, so every wallet may sign in");
+    }
+    Ok(allowed)
+}
@@ -0,0 +1,464 @@
+//! Login challenges waiting to be signed, kept in the database so that every server
+//! process sharing it can consume a nonce issued by any of them.
+
+use std::future::Future;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{ConsumedLogins, Data};
+use crate::Result;
+
+/// How long past its expiry a nonce is kept, in milliseconds, so that a login arriving
+/// just too late can be given a new challenge instead of failing outright. See
+/// [`StoredNonce::can_be_reissued`].
+pub const REISSUE_GRACE_MILLIS: u64 = 30 * 1000;
+
+/// The text a challenge asks the wallet to sign.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeFormat {
+    /// Our own plaintext challenge.
+    #[default]
+    Legacy,
+    /// A Sign In With Solana message, which wallets recognise and check the domain of.
+    Siws,
+}
+
+/// A nonce waiting to be consumed by a login.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredNonce {
+    /// When the nonce was issued, in milliseconds since the unix epoch.
+    pub issued_at: u64,
+    /// When the nonce stops being accepted, in milliseconds since the unix epoch.
+    pub expires_at: u64,
+    /// The address the challenge was issued to.
+    pub address: String,
+    /// The device the nonce was requested for, if the client scoped it to one.
+    pub device_id: Option<String>,
+    /// Device display name included in the signed message, if the client bound one.
+    pub initial_device_display_name: Option<String>,
+    /// The format of the message the wallet was asked to sign. Nonces stored before
+    /// formats existed are legacy ones.
+    #[serde(default)]
+    pub format: ChallengeFormat,
+    /// The `solana_challenge_statement` the challenge was issued with, so a config change
+    /// doesn't break logins already being signed. Nonces stored before it was configurable
+    /// have the default.
+    #[serde(default = "crate::config::default_solana_challenge_statement")]
+    pub statement: String,
+    /// Rooms or other scopes listed in the signed message, which the device signing in
+    /// is limited to. Empty grants the usual unscoped session.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    /// The web origin the challenge was requested from, if the client reported one. A
+    /// login with the nonce has to report the same origin.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+}
+
+impl StoredNonce {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now > self.expires_at
+    }
+
+    /// Whether the nonce has expired, but within [`REISSUE_GRACE_MILLIS`], so a login
+    /// using it gets a fresh challenge for the same wallet and device.
+    pub fn can_be_reissued(&self, now: u64) -> bool {
+        self.is_expired(now) && now - self.expires_at <= REISSUE_GRACE_MILLIS
+    }
+}
+
+/// What became of a login's nonce, see [`Nonces::redeem`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Redemption<T> {
+    /// No such nonce, or another login holds or used it.
+    Unknown,
+    /// The same login already signed in once, see [`ConsumedLogins`].
+    Replayed,
+    /// The nonce had expired, and is used up.
+    Expired(StoredNonce),
+    /// The login's check passed, and the nonce is used up.
+    Redeemed(T),
+}
+
+pub struct Nonces {
+    db: &'static dyn Data,
+}
+
+impl Nonces {
+    pub fn new(db: &'static dyn Data) -> Self {
+        Self { db }
+    }
+
+    pub fn issue(&self, nonce: &str, stored: &StoredNonce) -> Result<()> {
+        self.db.store_nonce(nonce, stored)
+    }
+
+    /// Look up a nonce without consuming it.
+    pub fn get(&self, nonce: &str) -> Result<Option<StoredNonce>> {
+        self.db.get_nonce(nonce)
+    }
+
+    /// Claim a nonce for a login that is about to check the signature over it. The nonce
+    /// is taken out of the database in one step, so while it is claimed no other login,
+    /// in this process or any other sharing the database, can use it, and `None` is
+    /// returned as for an unknown nonce.
+    ///
+    /// [`NonceClaim::consume`] keeps the nonce out once the signature checks out. Dropping
+    /// the claim instead puts it back, so a bad signature doesn't cost an honest client
+    /// its challenge; the nonce still expires as usual.
+    pub fn claim(&self, nonce: &str) -> Result<Option<NonceClaim<'_>>> {
+        Ok(self.db.take_nonce(nonce)?.map(|stored| NonceClaim {
+            nonces: self,
+            nonce: nonce.to_owned(),
+            stored,
+            consumed: false,
+        }))
+    }
+
+    /// Use up a nonce for a login with `fingerprint` at `now`: claim it, refuse it if the
+    /// same login already signed in or the nonce has expired, and otherwise run `check`,
+    /// the signature check, over it. Only once `check` passes are the nonce used up and
+    /// the fingerprint recorded; if it fails, the nonce is put back for a retry.
+    pub async fn redeem<T, F, Fut>(
+        &self,
+        nonce: &str,
+        consumed_logins: &ConsumedLogins,
+        fingerprint: &[u8; 32],
+        now: u64,
+        check: F,
+    ) -> Result<Redemption<T>>
+    where
+        F: FnOnce(StoredNonce) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let Some(claim) = self.claim(nonce)? else {
+            return Ok(Redemption::Unknown);
+        };
+        // A login that already signed in is refused even if its nonce is back in the
+        // store, e.g. after a restore from backup.
+        if consumed_logins.is_replay(fingerprint)? {
+            return Ok(Redemption::Replayed);
+        }
+        if claim.stored.is_expired(now) {
+            let expired = claim.stored.clone();
+            claim.consume();
+            return Ok(Redemption::Expired(expired));
+        }
+
+        let checked = check(claim.stored.clone()).await?;
+        claim.consume();
+        consumed_logins.record(fingerprint, now)?;
+        Ok(Redemption::Redeemed(checked))
+    }
+
+    /// Delete nonces that expired before `now`, returning how many. Only the expired
+    /// entries are read, soonest-expiring first, however many are still valid.
+    pub fn remove_expired(&self, now: u64) -> Result<usize> {
+        let mut expired = Vec::new();
+        for entry in self.db.nonces_by_expiry(false) {
+            let (expires_at, nonce) = entry?;
+            if expires_at >= now {
+                break;
+            }
+            expired.push(nonce);
+        }
+
+        for nonce in &expired {
+            self.db.remove_nonce(nonce)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Evict the oldest nonces, those expiring soonest, until at most `cap` are left,
+    /// returning how many went. A backstop for floods of challenge requests; it reads
+    /// at most `cap` entries besides the evicted ones.
+    pub fn evict_oldest(&self, cap: usize) -> Result<usize> {
+        let excess = self
+            .db
+            .nonces_by_expiry(true)
+            .skip(cap)
+            .map(|entry| entry.map(|(_, nonce)| nonce))
+            .collect::<Result<Vec<_>>>()?;
+
+        for nonce in &excess {
+            self.db.remove_nonce(nonce)?;
+        }
+        Ok(excess.len())
+    }
+}
+
+/// A nonce held by one login while it is verified. See [`Nonces::claim`].
+pub struct NonceClaim<'a> {
+    nonces: &'a Nonces,
+    nonce: String,
+    pub stored: StoredNonce,
+    consumed: bool,
+}
+
+impl NonceClaim<'_> {
+    /// Use up the nonce for good, once the signature over it has been verified (or it
+    /// has expired, when it is no use to anyone).
+    pub fn consume(mut self) {
+        self.consumed = true;
+    }
+}
+
+impl Drop for NonceClaim<'_> {
+    fn drop(&mut self) {
+        if self.consumed {
+            return;
+        }
+        if let Err(error) = self.nonces.db.store_nonce(&self.nonce, &self.stored) {
+            warn!("Failed to put back a claimed Solana nonce: {error}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::{super::data::SharedDb, *};
+
+    fn two_instances() -> (Nonces, Nonces) {
+        let db: &'static SharedDb = Box::leak(Box::default());
+        (Nonces::new(db), Nonces::new(db))
+    }
+
+    fn stored(expires_at: u64) -> StoredNonce {
+        StoredNonce {
+            issued_at: 0,
+            expires_at,
+            address: "4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi".to_owned(),
+            device_id: Some("PHONE".to_owned()),
+            initial_device_display_name: None,
+            format: ChallengeFormat::Legacy,
+            statement: crate::config::default_solana_challenge_statement(),
+            scopes: Vec::new(),
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn nonce_issued_on_one_instance_is_consumable_on_another() {
+        let (first, second) = two_instances();
+        first.issue("abc", &stored(1_000)).unwrap();
+
+        assert_eq!(second.get("abc").unwrap(), Some(stored(1_000)));
+        let claim = second.claim("abc").unwrap().unwrap();
+        assert_eq!(claim.stored, stored(1_000));
+        claim.consume();
+    }
+
+    #[test]
+    fn nonce_can_only_be_consumed_once_across_instances() {
+        let (first, second) = two_instances();
+        first.issue("abc", &stored(1_000)).unwrap();
+
+        second.claim("abc").unwrap().unwrap().consume();
+        assert!(first.claim("abc").unwrap().is_none());
+        assert!(second.claim("abc").unwrap().is_none());
+    }
+
+    #[test]
+    fn a_claimed_nonce_is_held_until_released() {
+        let (first, second) = two_instances();
+        first.issue("abc", &stored(1_000)).unwrap();
+
+        // The claim takes the nonce out of the shared database, so neither instance
+        // can claim it again meanwhile.
+        let claim = first.claim("abc").unwrap().unwrap();
+        assert!(first.claim("abc").unwrap().is_none());
+        assert!(second.claim("abc").unwrap().is_none());
+
+        // A failed verification drops its claim, leaving the nonce for a retry.
+        drop(claim);
+        assert_eq!(second.get("abc").unwrap(), Some(stored(1_000)));
+        second.claim("abc").unwrap().unwrap().consume();
+        assert_eq!(first.get("abc").unwrap(), None);
+    }
+
+    #[test]
+    fn parallel_redemptions_of_one_nonce_log_in_once() {
+        use std::sync::{atomic::AtomicUsize, Barrier};
+
+        use ed25519_dalek::{Signature, Signer, SigningKey};
+        use futures_util::FutureExt;
+        use ruma::api::client::error::ErrorKind;
+
+        use crate::{service::solana::login_fingerprint, Error};
+
+        let db: &'static SharedDb = Box::leak(Box::default());
+        let instances = [Nonces::new(db), Nonces::new(db)];
+        let consumed_logins = ConsumedLogins::new(db);
+        instances[0].issue("abc", &stored(1_000)).unwrap();
+        let wallet = SigningKey::from_bytes(&[1; 32]);
+        let message = b"Nonce: abc";
+        let valid = wallet.sign(message).to_bytes();
+        let forged = SigningKey::from_bytes(&[2; 32]).sign(message).to_bytes();
+
+        // Redeem on either instance, checking the signature as the login does.
+        let login = |instance: &Nonces, signature: &[u8; 64]| {
+            let key = wallet.verifying_key();
+            let fingerprint = login_fingerprint(&key.to_bytes(), "abc", signature);
+            let signature = Signature::from_bytes(signature);
+            let check = move |_| async move {
+                key.verify_strict(message, &signature)
+                    .map_err(|_| Error::BadRequest(ErrorKind::forbidden(), "Bad signature."))
+            };
+            instance
+                .redeem("abc", &consumed_logins, &fingerprint, 500, check)
+                .now_or_never()
+                .unwrap()
+        };
+
+        // Replays of the valid signature race forged ones, on both instances.
+        let attempts = 16;
+        let barrier = Barrier::new(attempts);
+        let successes = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for attempt in 0..attempts {
+                let signature = if attempt % 2 == 0 { &valid } else { &forged };
+                let instance = &instances[attempt / 2 % 2];
+                let (barrier, successes, login) = (&barrier, &successes, &login);
+                scope.spawn(move || {
+                    barrier.wait();
+                    if let Ok(Redemption::Redeemed(())) = login(instance, signature) {
+                        successes.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+
+        let successes = successes.into_inner();
+        assert!(successes <= 1);
+        // If every valid attempt found the nonce claimed by a forged one, it was put back
+        // rather than used up, so the honest client can still retry.
+        if successes == 0 {
+            assert_eq!(
+                login(&instances[1], &valid).unwrap(),
+                Redemption::Redeemed(())
+            );
+        }
+        for instance in &instances {
+            assert_eq!(login(instance, &valid).unwrap(), Redemption::Unknown);
+        }
+        assert_eq!(instances[0].get("abc").unwrap(), None);
+    }
+
+    #[test]
+    fn expired_nonces_are_used_up_without_a_check() {
+        use futures_util::FutureExt;
+
+        let db: &'static SharedDb = Box::leak(Box::default());
+        let (nonces, consumed_logins) = (Nonces::new(db), ConsumedLogins::new(db));
+        nonces.issue("abc", &stored(1_000)).unwrap();
+
+        let redemption = nonces
+            .redeem("abc", &consumed_logins, &[0; 32], 2_000, |_| async {
+                unreachable!("expired nonces aren't checked")
+            })
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(redemption, Redemption::<()>::Expired(stored(1_000)));
+        assert_eq!(nonces.get("abc").unwrap(), None);
+    }
+
+    #[test]
+    fn sweep_removes_only_expired_nonces() {
+        let (first, second) = two_instances();
+        first.issue("old", &stored(1_000)).unwrap();
+        first.issue("new", &stored(5_000)).unwrap();
+
+        assert_eq!(second.remove_expired(2_000).unwrap(), 1);
+        assert_eq!(first.get("old").unwrap(), None);
+        assert!(first.get("new").unwrap().is_some());
+    }
+
+    #[test]
+    fn sweeps_read_only_the_expired_nonces() {
+        let db: &'static SharedDb = Box::leak(Box::default());
+        let nonces = Nonces::new(db);
+        // One in five expired, issued in no particular order of expiry.
+        for i in 0..50_000u64 {
+            let expires_at = if i % 5 == 0 { i } else { 100_000 + i };
+            nonces.issue(&format!("n{i}"), &stored(expires_at)).unwrap();
+        }
+
+        db.expiry_reads.store(0, Ordering::Relaxed);
+        assert_eq!(nonces.remove_expired(100_000).unwrap(), 10_000);
+        // The expired ones, and the first valid one that ends the sweep.
+        assert_eq!(db.expiry_reads.load(Ordering::Relaxed), 10_001);
+        for i in 0..50_000u64 {
+            assert_eq!(
+                nonces.get(&format!("n{i}")).unwrap().is_some(),
+                i % 5 != 0,
+                "n{i}"
+            );
+        }
+
+        db.expiry_reads.store(0, Ordering::Relaxed);
+        assert_eq!(nonces.remove_expired(100_000).unwrap(), 0);
+        assert_eq!(db.expiry_reads.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn evicts_the_oldest_nonces_beyond_the_cap() {
+        let db: &'static SharedDb = Box::leak(Box::default());
+        let nonces = Nonces::new(db);
+        for i in 0..100u64 {
+            nonces.issue(&format!("n{i}"), &stored(1_000 + i)).unwrap();
+        }
+
+        assert_eq!(nonces.evict_oldest(100).unwrap(), 0);
+        assert_eq!(nonces.evict_oldest(60).unwrap(), 40);
+        for i in 0..100u64 {
+            assert_eq!(nonces.get(&format!("n{i}")).unwrap().is_some(), i >= 40);
+        }
+
+        // Reissuing a nonce moves it in the expiry order.
+        nonces.issue("n40", &stored(5_000)).unwrap();
+        assert_eq!(nonces.evict_oldest(59).unwrap(), 1);
+        assert!(nonces.get("n40").unwrap().is_some());
+        assert_eq!(nonces.get("n41").unwrap(), None);
+    }
+
+    #[test]
+    fn floods_of_unexpired_nonces_stay_bounded() {
+        let db: &'static SharedDb = Box::leak(Box::default());
+        let nonces = Nonces::new(db);
+        let outstanding = || db.nonces_by_expiry(true).count();
+
+        // Bursts of challenges nobody signs, all still valid at each sweep.
+        let mut evicted = 0;
+        for burst in 0..5u64 {
+            for i in 0..50 {
+                let id = burst * 50 + i;
+                nonces
+                    .issue(&format!("n{id}"), &stored(1_000 + id))
+                    .unwrap();
+            }
+            assert_eq!(nonces.remove_expired(0).unwrap(), 0);
+            evicted += nonces.evict_oldest(80).unwrap();
+            assert!(outstanding() <= 80);
+        }
+
+        assert_eq!(evicted, 250 - 80);
+        // The newest are the ones kept.
+        for id in 0..250u64 {
+            assert_eq!(nonces.get(&format!("n{id}")).unwrap().is_some(), id >= 170);
+        }
+    }
+
+    #[test]
+    fn nonces_stored_without_a_format_or_statement_have_the_defaults() {
+        let json = r#"{"issued_at":0,"expires_at":1000,"address":"4vJ9JU1bJJE96FWSJKvHsmmFADCg4gpZQff4P3bkLKi","device_id":"PHONE","initial_device_display_name":null}"#;
+        assert_eq!(
+            serde_json::from_str::<StoredNonce>(json).unwrap(),
+            stored(1_000)
+        );
+    }
+}
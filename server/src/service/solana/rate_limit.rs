@@ -0,0 +1,117 @@
+//! Token buckets that limit how often one client IP or wallet address can ask for a
+//! login challenge.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lru_cache::LruCache;
+
+/// Most buckets kept. The least recently used are dropped beyond it, so a flood of new
+/// keys can't grow the limiter without bound.
+const MAX_BUCKETS: usize = 10_000;
+
+pub struct RateLimiter {
+    /// Tokens per bucket, refilled evenly over a minute. Zero turns the limiter off.
+    per_minute: u32,
+    buckets: Mutex<LruCache<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(LruCache::new(MAX_BUCKETS)),
+        }
+    }
+
+    /// Take a token from `key`'s bucket. Returns false if it is empty.
+    pub fn try_acquire(&self, key: &str, now: Instant) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+        let capacity = f64::from(self.per_minute);
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            (bucket.tokens + elapsed.as_secs_f64() * capacity / 60.0).min(capacity)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(key) {
+            buckets.insert(
+                key.to_owned(),
+                Bucket {
+                    tokens: capacity,
+                    updated: now,
+                },
+            );
+        }
+
+        let bucket = buckets.get_mut(key).expect("bucket was just inserted");
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// How long until a bucket that was just emptied has a token again.
+pub fn retry_after(per_minute: u32) -> Duration {
+    Duration::from_secs(60) / per_minute.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_a_hammering_client() {
+        let limiter = RateLimiter::new(10);
+        let start = Instant::now();
+
+        let granted = (0..100)
+            .filter(|_| limiter.try_acquire("ip:203.0.113.7", start))
+            .count();
+        assert_eq!(granted, 10);
+
+        // Other clients keep their own allowance.
+        assert!(limiter.try_acquire("ip:198.51.100.1", start));
+
+        // A token comes back every six seconds, enough for a client retrying a request.
+        let later = start + retry_after(10);
+        assert!(limiter.try_acquire("ip:203.0.113.7", later));
+        assert!(!limiter.try_acquire("ip:203.0.113.7", later));
+    }
+
+    #[test]
+    fn drops_the_least_recently_used_bucket_past_the_cap() {
+        let limiter = RateLimiter::new(1);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire("ip:203.0.113.7", now));
+        assert!(!limiter.try_acquire("ip:203.0.113.7", now));
+        for i in 0..MAX_BUCKETS {
+            assert!(limiter.try_acquire(&format!("address:{i}"), now));
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_BUCKETS);
+
+        // The oldest bucket was dropped for the newest, so it starts full again.
+        assert!(limiter.try_acquire("ip:203.0.113.7", now));
+    }
+
+    #[test]
+    fn zero_turns_the_limit_off() {
+        let limiter = RateLimiter::new(0);
+        let now = Instant::now();
+        assert!((0..1000).all(|_| limiter.try_acquire("address:a", now)));
+    }
+}
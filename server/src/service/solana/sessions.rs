@@ -0,0 +1,195 @@
+//! Expiring wallet sessions: the access token a wallet login or refresh issued, when it
+//! stops being accepted, and the refresh token that renews it.
+
+use std::sync::Mutex;
+
+use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, UserId};
+use serde::{Deserialize, Serialize};
+
+use super::Data;
+use crate::{utils, Result};
+
+/// Length of a refresh token, the same as an access token.
+const REFRESH_TOKEN_LENGTH: usize = 32;
+
+/// The expiring session of one device.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredSession {
+    /// The access token the session was started or last refreshed with.
+    pub access_token: String,
+    pub refresh_token: String,
+    /// When `access_token` stops being accepted, in milliseconds since the unix epoch.
+    pub expires_at: u64,
+}
+
+pub struct Sessions {
+    db: &'static dyn Data,
+    /// Held while a refresh token is being used up, so two requests can't both redeem it.
+    redeeming: Mutex<()>,
+}
+
+impl Sessions {
+    pub fn new(db: &'static dyn Data) -> Self {
+        Self {
+            db,
+            redeeming: Mutex::new(()),
+        }
+    }
+
+    /// Start an expiring session for the device's new `access_token`, replacing its
+    /// previous one, and return the refresh token for it.
+    pub fn issue(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        access_token: &str,
+        expires_at: u64,
+    ) -> Result<String> {
+        let refresh_token = utils::random_string(REFRESH_TOKEN_LENGTH);
+        self.db.store_session(
+            user_id,
+            device_id,
+            &StoredSession {
+                access_token: access_token.to_owned(),
+                refresh_token: refresh_token.clone(),
+                expires_at,
+            },
+        )?;
+        Ok(refresh_token)
+    }
+
+    /// Whether `access_token` is the device's expiring session and it ended before `now`.
+    /// Tokens issued without one, including any that replaced it since, never expire.
+    pub fn is_expired(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        access_token: &str,
+        now: u64,
+    ) -> Result<bool> {
+        Ok(self
+            .db
+            .get_session(user_id, device_id)?
+            .is_some_and(|session| {
+                session.access_token == access_token && now > session.expires_at
+            }))
+    }
+
+    /// Use up a refresh token, returning the device it was issued to and the access token
+    /// it renews. Each refresh token works once, and only while it is the device's latest.
+    pub fn redeem(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId, String)>> {
+        let _redeeming = self.redeeming.lock().unwrap();
+
+        let Some((user_id, device_id)) = self.db.find_refresh_token(refresh_token)? else {
+            return Ok(None);
+        };
+        let Some(session) = self
+            .db
+            .get_session(&user_id, &device_id)?
+            .filter(|session| session.refresh_token == refresh_token)
+        else {
+            return Ok(None);
+        };
+
+        self.db.remove_session(&user_id, &device_id)?;
+        Ok(Some((user_id, device_id, session.access_token)))
+    }
+
+    /// Limit a device to the scopes its login challenge granted, or lift the limit with
+    /// none. Each login of the device replaces them, and removing the device drops them.
+    pub fn set_scopes(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        scopes: &[String],
+    ) -> Result<()> {
+        self.db.store_scopes(user_id, device_id, scopes)
+    }
+
+    /// The scopes a device is limited to. Empty when it signed in unscoped.
+    pub fn scopes(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<String>> {
+        self.db.get_scopes(user_id, device_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{device_id, user_id};
+
+    use super::{super::data::SharedDb, *};
+
+    fn sessions() -> Sessions {
+        Sessions::new(Box::leak(Box::<SharedDb>::default()))
+    }
+
+    #[test]
+    fn access_tokens_expire_and_refresh_tokens_work_once() {
+        let sessions = sessions();
+        let user_id = user_id!("@solana_ab:chat.example.com");
+        let device_id = device_id!("PHONE");
+
+        let refresh_token = sessions.issue(user_id, device_id, "first", 1_000).unwrap();
+        assert!(!sessions
+            .is_expired(user_id, device_id, "first", 1_000)
+            .unwrap());
+        assert!(sessions
+            .is_expired(user_id, device_id, "first", 1_001)
+            .unwrap());
+
+        assert_eq!(
+            sessions.redeem(&refresh_token).unwrap(),
+            Some((user_id.to_owned(), device_id.to_owned(), "first".to_owned()))
+        );
+        assert_eq!(sessions.redeem(&refresh_token).unwrap(), None);
+        assert_eq!(sessions.redeem("made up").unwrap(), None);
+    }
+
+    #[test]
+    fn replaced_sessions_and_tokens_neither_expire_nor_refresh() {
+        let sessions = sessions();
+        let user_id = user_id!("@solana_ab:chat.example.com");
+        let device_id = device_id!("PHONE");
+
+        let first = sessions.issue(user_id, device_id, "first", 1_000).unwrap();
+        let second = sessions.issue(user_id, device_id, "second", 2_000).unwrap();
+        assert_eq!(sessions.redeem(&first).unwrap(), None);
+
+        // A password login on the device replaced the token, without an expiry.
+        assert!(!sessions
+            .is_expired(user_id, device_id, "third", 5_000)
+            .unwrap());
+        assert!(sessions
+            .is_expired(user_id, device_id, "second", 5_000)
+            .unwrap());
+        assert!(sessions.redeem(&second).unwrap().is_some());
+    }
+
+    #[test]
+    fn scopes_are_kept_per_device_until_the_next_login() {
+        let db: &'static SharedDb = Box::leak(Box::default());
+        let (first, second) = (Sessions::new(db), Sessions::new(db));
+        let user_id = user_id!("@solana_ab:chat.example.com");
+        let scopes = vec!["!lobby:chat.example.com".to_owned()];
+
+        first
+            .set_scopes(user_id, device_id!("PHONE"), &scopes)
+            .unwrap();
+        assert_eq!(second.scopes(user_id, device_id!("PHONE")).unwrap(), scopes);
+        assert!(second
+            .scopes(user_id, device_id!("LAPTOP"))
+            .unwrap()
+            .is_empty());
+
+        // An unscoped login of the device lifts the limit.
+        second
+            .set_scopes(user_id, device_id!("PHONE"), &[])
+            .unwrap();
+        assert!(first
+            .scopes(user_id, device_id!("PHONE"))
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+//! Per-account locks taken while a wallet's account is created, so two first logins of
+//! the same wallet racing each other create it once.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, Weak},
+};
+
+use ruma::{OwnedUserId, UserId};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use crate::Result;
+
+#[derive(Default)]
+pub struct SignupLocks {
+    /// One lock per account being created. An entry is dropped once nobody holds or waits
+    /// for its lock.
+    locks: Mutex<HashMap<OwnedUserId, Weak<AsyncMutex<()>>>>,
+}
+
+impl SignupLocks {
+    /// Wait until no other login is creating `user_id`, and keep others out until the
+    /// guard is dropped. The caller checks again whether the account exists once it has
+    /// the lock.
+    pub async fn lock(&self, user_id: &UserId) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            match locks.get(user_id).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(AsyncMutex::new(()));
+                    locks.insert(user_id.to_owned(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        lock.lock_owned().await
+    }
+
+    /// Create `user_id` with `create` unless `exists` finds it once the account's lock is
+    /// held, keeping the lock until `create` is done. Of several first logins racing each
+    /// other, exactly one creates the account and the rest find it. Returns whether this
+    /// call created it.
+    pub async fn create_once<E, C, Fut>(
+        &self,
+        user_id: &UserId,
+        exists: E,
+        create: C,
+    ) -> Result<bool>
+    where
+        E: FnOnce() -> Result<bool>,
+        C: FnOnce() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let _guard = self.lock(user_id).await;
+        if exists()? {
+            return Ok(false);
+        }
+        create().await?;
+        Ok(true)
+    }
+
+    /// How many accounts have a login holding or waiting for their lock.
+    #[cfg(test)]
+    fn in_use(&self) -> usize {
+        let mut locks = self.locks.lock().unwrap();
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        locks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use ruma::user_id;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_first_logins_create_one_account() {
+        let locks = SignupLocks::default();
+        let user_id = user_id!("@solana_abc:chat.example.com");
+        let accounts = Mutex::new(HashSet::new());
+        let (locks, accounts) = (&locks, &accounts);
+
+        let signup = || async move {
+            locks
+                .create_once(
+                    user_id,
+                    || Ok(accounts.lock().unwrap().contains(user_id)),
+                    || async move {
+                        // Let the other login run in between, as a slow database would.
+                        tokio::task::yield_now().await;
+                        assert!(accounts.lock().unwrap().insert(user_id.to_owned()));
+                        Ok(())
+                    },
+                )
+                .await
+                .unwrap()
+        };
+        let (first, second) = tokio::join!(signup(), signup());
+
+        assert!(first != second, "exactly one login creates the account");
+        assert_eq!(accounts.lock().unwrap().len(), 1);
+        assert_eq!(locks.in_use(), 0);
+
+        // Once it exists, later logins don't create it again.
+        assert!(!signup().await);
+    }
+
+    #[tokio::test]
+    async fn a_failed_signup_lets_the_next_login_retry() {
+        use ruma::api::client::error::ErrorKind;
+
+        use crate::Error;
+
+        let locks = SignupLocks::default();
+        let user_id = user_id!("@solana_abc:chat.example.com");
+
+        let failed = locks
+            .create_once(
+                user_id,
+                || Ok(false),
+                || async { Err(Error::BadRequest(ErrorKind::Unknown, "Database is down.")) },
+            )
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(locks.in_use(), 0);
+
+        let created = locks
+            .create_once(user_id, || Ok(false), || async { Ok(()) })
+            .await
+            .unwrap();
+        assert!(created);
+    }
+
+    #[tokio::test]
+    async fn different_accounts_are_created_side_by_side() {
+        let locks = SignupLocks::default();
+        let alice = locks.lock(user_id!("@solana_a:chat.example.com")).await;
+        let bob = locks.lock(user_id!("@solana_b:chat.example.com")).await;
+        assert_eq!(locks.in_use(), 2);
+
+        drop(alice);
+        assert_eq!(locks.in_use(), 1);
+        drop(bob);
+        assert_eq!(locks.in_use(), 0);
+    }
+}
@@ -0,0 +1,288 @@
+//! Checks whether a wallet holds an NFT from a Metaplex collection.
+//!
+//! The wallet's SPL token accounts are read with `getTokenAccountsByOwner`, and the
+//! metadata PDA of each mint it holds with `getMultipleAccounts`. An NFT counts when its
+//! metadata names the collection and the collection authority has verified it, so anyone
+//! can mint a token claiming the collection but it won't pass.
+
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+
+use super::{
+    create_program_address,
+    token_account::{decode_program_id, TokenAccount, TOKEN_PROGRAM_ID},
+    AccountInfo, BorshReader,
+};
+use crate::{Error, Result};
+
+/// The Metaplex Token Metadata program.
+const TOKEN_METADATA_PROGRAM_ID: &str = "metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s";
+
+/// Seed prefix for metadata PDAs.
+const METADATA_SEED: &[u8] = b"metadata";
+
+/// Metaplex account key for a `MetadataV1` account.
+const METADATA_V1: u8 = 4;
+
+/// Parameters for `getTokenAccountsByOwner` that list a wallet's SPL token accounts.
+pub fn token_accounts_params(owner: &[u8; 32]) -> serde_json::Value {
+    serde_json::json!([
+        bs58::encode(owner).into_string(),
+        { "programId": TOKEN_PROGRAM_ID },
+        { "encoding": "base64", "commitment": "confirmed" },
+    ])
+}
+
+/// One account in a `getTokenAccountsByOwner` response.
+#[derive(Deserialize)]
+pub struct KeyedAccount {
+    account: AccountInfo,
+}
+
+#[derive(Deserialize)]
+pub struct TokenAccountsResult {
+    pub value: Vec<KeyedAccount>,
+}
+
+/// The mints `owner` holds a non-zero balance of, from its token accounts.
+pub fn held_mints(owner: &[u8; 32], accounts: Vec<KeyedAccount>) -> Result<Vec<[u8; 32]>> {
+    let mut mints = Vec::new();
+    for KeyedAccount { account } in accounts {
+        let token_account = TokenAccount::from_account(account)?;
+        if &token_account.owner == owner
+            && token_account.amount > 0
+            && !mints.contains(&token_account.mint)
+        {
+            mints.push(token_account.mint);
+        }
+    }
+    Ok(mints)
+}
+
+/// Find the metadata PDA of a mint, like `findProgramAddressSync`.
+pub fn derive_metadata_address(mint: &[u8; 32]) -> Option<[u8; 32]> {
+    let program_id = decode_program_id(TOKEN_METADATA_PROGRAM_ID);
+    (0..=u8::MAX).rev().find_map(|bump| {
+        create_program_address(&[METADATA_SEED, &program_id, mint, &[bump]], &program_id)
+    })
+}
+
+/// Whether any of the metadata accounts `getMultipleAccounts` returned for `mints`, in
+/// the same order, puts its NFT in `collection`. Mints without metadata don't count.
+pub fn any_in_collection(
+    mints: &[[u8; 32]],
+    accounts: Vec<Option<AccountInfo>>,
+    collection: &[u8; 32],
+) -> Result<bool> {
+    if accounts.len() != mints.len() {
+        return Err(Error::BadServerResponse(
+            "Solana RPC returned the wrong number of accounts.",
+        ));
+    }
+
+    for (mint, account) in mints.iter().zip(accounts) {
+        let Some(account) = account else {
+            continue;
+        };
+
+        if account.owner != TOKEN_METADATA_PROGRAM_ID {
+            return Err(Error::BadServerResponse(
+                "Metadata account is not owned by the token metadata program.",
+            ));
+        }
+
+        let data = general_purpose::STANDARD
+            .decode(account.data.0)
+            .map_err(|_| Error::BadServerResponse("Bad account data encoding"))?;
+
+        if verified_collection(&data, mint).as_ref() == Some(collection) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The verified collection in a `MetadataV1` account for `mint`, if it has one.
+///
+/// Metadata written before collections existed ends early, which reads as no collection.
+fn verified_collection(data: &[u8], mint: &[u8; 32]) -> Option<[u8; 32]> {
+    let mut reader = BorshReader(data);
+    if reader.array::<1>()? != [METADATA_V1] {
+        return None;
+    }
+    let _update_authority = reader.array::<32>()?;
+    if &reader.array::<32>()? != mint {
+        return None;
+    }
+
+    // name, symbol, uri, seller_fee_basis_points
+    for _ in 0..3 {
+        reader.string()?;
+    }
+    reader.take(2)?;
+
+    // creators: Option<Vec<{ address, verified, share }>>
+    if option(&mut reader)? {
+        let count = u32::from_le_bytes(reader.array::<4>()?) as usize;
+        reader.take(count.checked_mul(34)?)?;
+    }
+
+    // primary_sale_happened, is_mutable
+    reader.take(2)?;
+
+    // edition_nonce, token_standard
+    for _ in 0..2 {
+        if option(&mut reader)? {
+            reader.take(1)?;
+        }
+    }
+
+    // collection: Option<{ verified, key }>
+    if !option(&mut reader)? {
+        return None;
+    }
+    let verified = reader.array::<1>()? == [1];
+    let key = reader.array::<32>()?;
+    verified.then_some(key)
+}
+
+/// Read a borsh `Option` tag, returning whether a value follows.
+fn option(reader: &mut BorshReader<'_>) -> Option<bool> {
+    match reader.array::<1>()? {
+        [0] => Some(false),
+        [1] => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: [u8; 32] = [1; 32];
+    const MINT: [u8; 32] = [2; 32];
+    const COLLECTION: [u8; 32] = [3; 32];
+
+    fn borsh_string(out: &mut Vec<u8>, value: &str) {
+        out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    /// A `MetadataV1` account as the Token Metadata program lays it out.
+    fn encode_metadata(mint: [u8; 32], collection: Option<(bool, [u8; 32])>) -> Vec<u8> {
+        let mut data = vec![METADATA_V1];
+        data.extend_from_slice(&[9; 32]);
+        data.extend_from_slice(&mint);
+        borsh_string(&mut data, "Member #1\0\0\0");
+        borsh_string(&mut data, "MBR");
+        borsh_string(&mut data, "https://example.com/1.json");
+        data.extend_from_slice(&500u16.to_le_bytes());
+        data.push(1);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&[9; 32]);
+        data.extend_from_slice(&[1, 100]);
+        data.extend_from_slice(&[1, 1]);
+        data.extend_from_slice(&[1, 255]);
+        data.extend_from_slice(&[1, 4]);
+        match collection {
+            Some((verified, key)) => {
+                data.extend_from_slice(&[1, verified as u8]);
+                data.extend_from_slice(&key);
+            }
+            None => data.push(0),
+        }
+        data
+    }
+
+    /// A `getTokenAccountsByOwner` result holding `amount` of `MINT`.
+    fn mock_token_accounts(amount: u64) -> TokenAccountsResult {
+        let mut data = MINT.to_vec();
+        data.extend_from_slice(&OWNER);
+        data.extend_from_slice(&amount.to_le_bytes());
+        data.resize(165, 0);
+
+        serde_json::from_value(serde_json::json!({
+            "context": { "slot": 1 },
+            "value": [{
+                "pubkey": bs58::encode([4; 32]).into_string(),
+                "account": {
+                    "data": [general_purpose::STANDARD.encode(data), "base64"],
+                    "executable": false,
+                    "lamports": 2_039_280,
+                    "owner": TOKEN_PROGRAM_ID,
+                    "rentEpoch": 0,
+                },
+            }],
+        }))
+        .unwrap()
+    }
+
+    /// A `getMultipleAccounts` result with one metadata account.
+    fn mock_metadata(data: &[u8]) -> Vec<Option<AccountInfo>> {
+        serde_json::from_value::<super::super::MultipleAccountsResult>(serde_json::json!({
+            "context": { "slot": 1 },
+            "value": [{
+                "data": [general_purpose::STANDARD.encode(data), "base64"],
+                "executable": false,
+                "lamports": 5_616_720,
+                "owner": TOKEN_METADATA_PROGRAM_ID,
+                "rentEpoch": 0,
+            }],
+        }))
+        .unwrap()
+        .value
+    }
+
+    #[test]
+    fn derives_the_metadata_address() {
+        // Metadata PDA of the wrapped SOL mint, as `findProgramAddressSync` computes it.
+        let mint = bs58::decode("So11111111111111111111111111111111111111112")
+            .into_vec()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            derive_metadata_address(&mint).map(|address| bs58::encode(address).into_string()),
+            Some("6dM4TqWyWJsbx7obrdLcviBkTafD5E8av61zfU6jq57X".to_owned())
+        );
+    }
+
+    #[test]
+    fn accepts_a_held_nft_from_the_collection() {
+        let mints = held_mints(&OWNER, mock_token_accounts(1).value).unwrap();
+        assert_eq!(mints, vec![MINT]);
+
+        let metadata = encode_metadata(MINT, Some((true, COLLECTION)));
+        assert!(any_in_collection(&mints, mock_metadata(&metadata), &COLLECTION).unwrap());
+    }
+
+    #[test]
+    fn rejects_wallets_without_a_verified_nft_from_the_collection() {
+        // Sold or burned: the token account is still there with nothing in it.
+        assert_eq!(
+            held_mints(&OWNER, mock_token_accounts(0).value).unwrap(),
+            Vec::<[u8; 32]>::new()
+        );
+        // The token account belongs to someone else.
+        assert_eq!(
+            held_mints(&[5; 32], mock_token_accounts(1).value).unwrap(),
+            Vec::<[u8; 32]>::new()
+        );
+
+        let mints = [MINT];
+        for metadata in [
+            encode_metadata(MINT, Some((false, COLLECTION))),
+            encode_metadata(MINT, Some((true, [6; 32]))),
+            encode_metadata(MINT, None),
+            encode_metadata([7; 32], Some((true, COLLECTION))),
+        ] {
+            assert!(!any_in_collection(&mints, mock_metadata(&metadata), &COLLECTION).unwrap());
+        }
+        assert!(!any_in_collection(&mints, vec![None], &COLLECTION).unwrap());
+
+        let metadata = encode_metadata(MINT, Some((true, COLLECTION)));
+        let mut wrong_owner = mock_metadata(&metadata);
+        wrong_owner[0].as_mut().unwrap().owner = TOKEN_PROGRAM_ID.to_owned();
+        assert!(any_in_collection(&mints, wrong_owner, &COLLECTION).is_err());
+    }
+}
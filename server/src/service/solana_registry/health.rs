@@ -0,0 +1,110 @@
+//! Checks that the homeserver a wallet delegates to answers at all, so a login refused
+//! over a dead or bogus delegation can say so.
+//!
+//! The probe is a `GET` of the client API's `/_matrix/client/versions`, which every
+//! homeserver serves without authentication, bounded by [`PROBE_TIMEOUT`].
+
+use std::{future::Future, pin::pin, time::Duration};
+
+use futures_util::future::{select, Either};
+use tracing::warn;
+
+use crate::Result;
+
+/// Path probed on a delegated homeserver.
+const PROBE_PATH: &str = "/_matrix/client/versions";
+
+/// How long a probe may take before the homeserver counts as unreachable.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What probing a delegated homeserver found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HomeserverHealth {
+    /// It answered the probe successfully.
+    Healthy,
+    /// It answered with an error status, or the request failed outright, e.g. because
+    /// the name doesn't resolve or nothing is listening.
+    Unhealthy,
+    /// It didn't answer before the deadline.
+    TimedOut,
+}
+
+/// Probe `homeserver` with `fetch`, which sends a `GET` to the URL it is handed and says
+/// whether the response had a success status. Gives up once `deadline` completes.
+pub async fn probe_homeserver<F, Fut>(
+    homeserver: &str,
+    fetch: F,
+    deadline: impl Future<Output = ()>,
+) -> HomeserverHealth
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = Result<bool>>,
+{
+    let url = format!("https://{homeserver}{PROBE_PATH}");
+    match select(pin!(fetch(url)), pin!(deadline)).await {
+        Either::Left((Ok(true), _)) => HomeserverHealth::Healthy,
+        Either::Left((Ok(false), _)) => {
+            warn!("Delegated homeserver {homeserver} answered its health probe with an error");
+            HomeserverHealth::Unhealthy
+        }
+        Either::Left((Err(error), _)) => {
+            warn!("Could not probe delegated homeserver {homeserver}: {error}");
+            HomeserverHealth::Unhealthy
+        }
+        Either::Right(_) => {
+            warn!("Delegated homeserver {homeserver} did not answer its health probe in time");
+            HomeserverHealth::TimedOut
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::Error;
+
+    /// Probe with a mock client that answers with `response`, or never answers when it is
+    /// `None`, against a deadline that has already passed when `expired`.
+    fn probe(response: Option<Result<bool>>, expired: bool) -> (HomeserverHealth, String) {
+        let mut requested = String::new();
+        let fetch = |url| {
+            requested = url;
+            async move {
+                match response {
+                    Some(response) => response,
+                    None => std::future::pending().await,
+                }
+            }
+        };
+        let deadline = async move {
+            if !expired {
+                std::future::pending::<()>().await;
+            }
+        };
+        let health = probe_homeserver("chat.example.com:8448", fetch, deadline)
+            .now_or_never()
+            .unwrap();
+        (health, requested)
+    }
+
+    #[test]
+    fn healthy_homeservers_pass() {
+        let (health, url) = probe(Some(Ok(true)), false);
+        assert_eq!(health, HomeserverHealth::Healthy);
+        assert_eq!(url, "https://chat.example.com:8448/_matrix/client/versions");
+    }
+
+    #[test]
+    fn error_statuses_and_failed_requests_are_unhealthy() {
+        assert_eq!(probe(Some(Ok(false)), false).0, HomeserverHealth::Unhealthy);
+        let refused = Err(Error::BadServerResponse("Connection refused."));
+        assert_eq!(probe(Some(refused), false).0, HomeserverHealth::Unhealthy);
+    }
+
+    #[test]
+    fn homeservers_that_never_answer_time_out() {
+        assert_eq!(probe(None, true).0, HomeserverHealth::TimedOut);
+    }
+}
@@ -0,0 +1,1960 @@
+//! Reads wallet → homeserver delegations from the on-chain `homeserver-registry` program.
+//!
+//! Each wallet's delegation lives in a PDA seeded by `["delegation", owner]`. The account
+//! data is Anchor-encoded: an 8-byte discriminator followed by the borsh-serialized
+//! `Delegation` struct from `programs/homeserver-registry/src/state/delegation.rs`.
+
+mod collection;
+mod health;
+mod relayer;
+mod resolver;
+mod rpc;
+mod sns;
+mod token_account;
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose, Engine as _};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use ruma::api::client::error::ErrorKind;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    config::{RegistryConflictPolicy, RpcFailurePolicy},
+    service::solana::SecurityNotice,
+    services, utils, Config, Error, Result,
+};
+pub use health::HomeserverHealth;
+use health::{probe_homeserver, PROBE_TIMEOUT};
+use relayer::RelayTransaction;
+pub use resolver::{BatchResolution, DelegationSummary, ProgramAccount};
+#[cfg(any(test, feature = "mock_registry_rpc"))]
+pub use rpc::MockRegistryRpc;
+pub use rpc::{RegistryRpc, SolanaRpcClient};
+
+/// Program ID of the homeserver registry, matching `declare_id!` in the Anchor program.
+pub const DEFAULT_REGISTRY_PROGRAM_ID: &str = "27JU28YBf5RJmEHAn9BwnWFyfPMLkUdSafKgz9xQB9zn";
+
+/// Seed prefix for delegation PDAs.
+const DELEGATION_SEED: &[u8] = b"delegation";
+
+/// Marker appended to PDA seeds by the Solana runtime.
+const PDA_MARKER: &[u8] = b"ProgramDerivedAddress";
+
+/// How long a batch of delegations read for enforcement is served from cache.
+const DELEGATION_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Most accounts `getMultipleAccounts` returns in one request.
+const MAX_ACCOUNTS_PER_REQUEST: usize = 100;
+
+/// How long a replayed delegation history is served from cache.
+const HISTORY_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How long a wallet's most recent transaction time is served from cache.
+const ACTIVITY_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How long whether a wallet holds the required collection is served from cache.
+const COLLECTION_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How long a wallet's balance of the required token is served from cache.
+const BALANCE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How long a wallet's primary SNS domain is served from cache.
+const SNS_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long the health of a delegated homeserver is served from cache.
+const HEALTH_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Window for `solana_relayer_max_per_wallet`.
+const RELAY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Size of a delegation account, after the discriminator, written by the first version
+/// of the program: owner, a single homeserver string of up to 253 bytes where the list
+/// now goes, updated_at and bump. Every later layout is larger.
+const SINGLE_HOMESERVER_SPACE: usize = 32 + 4 + 253 + 8 + 1;
+
+/// A decoded `Delegation` account.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delegation {
+    /// The wallet that owns this delegation.
+    pub owner: [u8; 32],
+    /// The homeservers the wallet delegated to (e.g. "chat.example.com"), in priority
+    /// order. Empty if the wallet deactivated its delegation.
+    pub homeservers: Vec<String>,
+    /// Unix timestamp when the delegation was created or last updated.
+    pub updated_at: i64,
+    /// PDA bump seed.
+    pub bump: u8,
+    /// Layout version. 0 for accounts written before the program recorded one.
+    pub version: u8,
+    /// Unix timestamp after which the delegation no longer counts, if the owner set one.
+    pub expires_at: Option<i64>,
+    /// A separate key the owner allows to sign logins for it, if it set one.
+    pub auth_pubkey: Option<[u8; 32]>,
+    /// The primary homeserver the wallet last migrated away from, if it ever did.
+    pub previous_homeserver: Option<String>,
+    /// Whether the owner froze the delegation, refusing logins through the registry.
+    pub frozen: bool,
+}
+
+impl Delegation {
+    /// Anchor account discriminator: the first 8 bytes of `sha256("account:Delegation")`.
+    pub fn discriminator() -> [u8; 8] {
+        let hash = Sha256::digest(b"account:Delegation");
+        let mut discriminator = [0u8; 8];
+        discriminator.copy_from_slice(&hash[..8]);
+        discriminator
+    }
+
+    /// Decode raw account data, in any layout the program has written, an account in the
+    /// first one holding a single homeserver. Returns `None` if the bytes aren't a
+    /// `Delegation`.
+    /// This only checks the layout; use [`Service::parse_delegation`] to also check
+    /// that the account belongs to the expected wallet and PDA.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let mut reader = BorshReader(data.strip_prefix(&Self::discriminator())?);
+        if reader.0.len() == SINGLE_HOMESERVER_SPACE {
+            return Some(Self {
+                owner: reader.array::<32>()?,
+                homeservers: vec![reader.string()?],
+                updated_at: i64::from_le_bytes(reader.array::<8>()?),
+                bump: reader.array::<1>()?[0],
+                version: 0,
+                expires_at: None,
+                auth_pubkey: None,
+                previous_homeserver: None,
+                frozen: false,
+            });
+        }
+
+        let owner = reader.array::<32>()?;
+        let homeservers = reader.strings()?;
+        let updated_at = i64::from_le_bytes(reader.array::<8>()?);
+        let bump = reader.array::<1>()?[0];
+        // Fields after `bump` were added later; older accounts may not have room for them.
+        let version = reader.array::<1>().map_or(0, |[version]| version);
+        let expires_at = match reader.array::<1>() {
+            None | Some([0]) => None,
+            Some([1]) => Some(i64::from_le_bytes(reader.array::<8>()?)),
+            Some(_) => return None,
+        };
+        // The label is skipped to reach the auth key, which came in version 4, and the
+        // previous homeserver, which came in version 5. Earlier versions can leave other
+        // bytes where they go.
+        let auth_pubkey = if version >= 4 {
+            match reader.array::<1>()? {
+                [0] => {}
+                [1] => {
+                    reader.string()?;
+                }
+                _ => return None,
+            }
+            match reader.array::<1>()? {
+                [0] => None,
+                [1] => Some(reader.array::<32>()?),
+                _ => return None,
+            }
+        } else {
+            None
+        };
+        let previous_homeserver = if version >= 5 {
+            match reader.array::<1>()? {
+                [0] => None,
+                [1] => Some(reader.string()?),
+                _ => return None,
+            }
+        } else {
+            None
+        };
+        let frozen = if version >= 6 {
+            match reader.array::<1>()? {
+                [0] => false,
+                [1] => true,
+                _ => return None,
+            }
+        } else {
+            false
+        };
+
+        Some(Self {
+            owner,
+            homeservers,
+            updated_at,
+            bump,
+            version,
+            expires_at,
+            auth_pubkey,
+            previous_homeserver,
+            frozen,
+        })
+    }
+
+    /// Whether the delegation names any homeserver and hasn't expired at `now` (unix
+    /// seconds). Deactivated and expired delegations are kept on chain as a record but
+    /// count as no delegation.
+    pub fn is_active(&self, now: i64) -> bool {
+        !self.homeservers.is_empty() && self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+
+    /// Whether `server_name` is any of the wallet's homeservers, fallbacks included.
+    pub fn delegates_to(&self, server_name: &str) -> bool {
+        self.homeservers
+            .iter()
+            .any(|homeserver| homeserver == server_name)
+    }
+}
+
+/// One `register` or `unregister` call found in a wallet's transaction history.
+#[derive(Clone, Debug, Serialize)]
+pub struct DelegationChange {
+    /// Transaction signature (base58).
+    pub signature: String,
+    /// Unix timestamp of the block, if the RPC node knows it.
+    pub block_time: Option<i64>,
+    /// The homeservers registered, in priority order, empty for a `deactivate`, or `None`
+    /// for an `unregister`.
+    pub homeservers: Option<Vec<String>>,
+}
+
+/// Anchor instruction discriminator: the first 8 bytes of `sha256("global:<name>")`.
+fn instruction_discriminator(name: &str) -> [u8; 8] {
+    let hash = Sha256::digest(format!("global:{name}").as_bytes());
+    let mut discriminator = [0u8; 8];
+    discriminator.copy_from_slice(&hash[..8]);
+    discriminator
+}
+
+/// Decode registry instruction data into the change it makes: `Some(Some(homeservers))`
+/// for `register`, `register_homeservers` and `update_homeservers`, `Some(Some(vec![]))`
+/// for `deactivate`, `Some(None)` for `unregister`, and `None` for anything else.
+/// `move_homeserver` and `remove_homeserver` only name positions in a list we may not
+/// have seen, and `migrate` only the new primary, so they are not decoded.
+fn decode_instruction(data: &[u8]) -> Option<Option<Vec<String>>> {
+    if let Some(args) = data.strip_prefix(&instruction_discriminator("register")) {
+        return Some(Some(vec![BorshReader(args).string()?]));
+    }
+
+    for name in ["register_homeservers", "update_homeservers"] {
+        if let Some(args) = data.strip_prefix(&instruction_discriminator(name)) {
+            return Some(Some(BorshReader(args).strings()?));
+        }
+    }
+
+    if data.starts_with(&instruction_discriminator("deactivate")) {
+        return Some(Some(Vec::new()));
+    }
+
+    if data.starts_with(&instruction_discriminator("unregister")) {
+        return Some(None);
+    }
+
+    None
+}
+
+/// Minimal cursor over borsh-encoded bytes.
+struct BorshReader<'a>(&'a [u8]);
+
+impl<'a> BorshReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = u32::from_le_bytes(self.array::<4>()?) as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    fn strings(&mut self) -> Option<Vec<String>> {
+        let count = u32::from_le_bytes(self.array::<4>()?);
+        (0..count).map(|_| self.string()).collect()
+    }
+}
+
+/// Derive a program address from seeds, rejecting results that land on the ed25519 curve
+/// (the same rule the Solana runtime applies).
+pub fn create_program_address(seeds: &[&[u8]], program_id: &[u8; 32]) -> Option<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    for seed in seeds {
+        hasher.update(seed);
+    }
+    hasher.update(program_id);
+    hasher.update(PDA_MARKER);
+    let address: [u8; 32] = hasher.finalize().into();
+
+    if CompressedEdwardsY(address).decompress().is_some() {
+        return None;
+    }
+
+    Some(address)
+}
+
+/// Find the delegation PDA and canonical bump for a wallet, like `findProgramAddressSync`.
+pub fn derive_delegation_address(
+    owner: &[u8; 32],
+    program_id: &[u8; 32],
+) -> Option<([u8; 32], u8)> {
+    (0..=u8::MAX).rev().find_map(|bump| {
+        create_program_address(&[DELEGATION_SEED, owner, &[bump]], program_id)
+            .map(|address| (address, bump))
+    })
+}
+
+pub struct Service {
+    /// Reads the registry's accounts, from `solana_rpc_url` unless replaced.
+    pub rpc: Box<dyn RegistryRpc>,
+    /// Delegations read for a set of wallets, with the time they were fetched.
+    delegation_cache: Mutex<HashMap<Vec<[u8; 32]>, (Instant, Vec<Option<Delegation>>)>>,
+    /// Replayed delegation histories by owner, with the time they were fetched.
+    history_cache: Mutex<HashMap<[u8; 32], (Instant, Vec<DelegationChange>)>>,
+    /// Block time of each wallet's latest confirmed transaction, with the time it was fetched.
+    activity_cache: Mutex<HashMap<[u8; 32], (Instant, Option<i64>)>>,
+    /// Whether each wallet holds an NFT from `solana_required_collection`, with the time
+    /// it was checked.
+    collection_cache: Mutex<HashMap<[u8; 32], (Instant, bool)>>,
+    /// Each wallet's balance of `solana_required_mint`, with the time it was fetched.
+    balance_cache: Mutex<HashMap<[u8; 32], (Instant, u64)>>,
+    /// Each wallet's primary SNS domain, with the time it was read.
+    sns_cache: Mutex<HashMap<[u8; 32], (Instant, Option<String>)>>,
+    /// Health of each delegated homeserver probed, with the time it was probed.
+    health_cache: Mutex<HashMap<String, (Instant, HomeserverHealth)>>,
+    /// Fee payer for relayed `register` transactions, if the relayer is enabled.
+    relayer: Option<SigningKey>,
+    /// Relayed transactions per wallet: (count, first relay in window).
+    relayed: Mutex<HashMap<[u8; 32], (u32, Instant)>>,
+    /// Key this server signs operator proofs and attestations with, if configured.
+    operator: Option<SigningKey>,
+}
+
+impl Service {
+    pub fn build(config: &Config) -> Result<Self> {
+        let relayer = config
+            .solana_relayer_keypair
+            .as_ref()
+            .map(|path| {
+                load_keypair(path).ok_or_else(|| {
+                    Error::bad_config("solana_relayer_keypair is not a readable 64-byte keypair.")
+                })
+            })
+            .transpose()?;
+        if let Some(relayer) = &relayer {
+            info!(
+                "Solana registry relayer enabled, paying from {}",
+                bs58::encode(relayer.verifying_key().as_bytes()).into_string()
+            );
+        }
+
+        let operator = config
+            .solana_operator_keypair
+            .as_ref()
+            .map(|path| {
+                load_keypair(path).ok_or_else(|| {
+                    Error::bad_config("solana_operator_keypair is not a readable 64-byte keypair.")
+                })
+            })
+            .transpose()?;
+
+        #[cfg(feature = "mock_registry_rpc")]
+        warn!("Built with mock_registry_rpc, so the homeserver registry is read from memory");
+
+        Ok(Self {
+            #[cfg(not(feature = "mock_registry_rpc"))]
+            rpc: Box::new(SolanaRpcClient),
+            #[cfg(feature = "mock_registry_rpc")]
+            rpc: Box::new(MockRegistryRpc::default()),
+            delegation_cache: Mutex::new(HashMap::new()),
+            history_cache: Mutex::new(HashMap::new()),
+            activity_cache: Mutex::new(HashMap::new()),
+            collection_cache: Mutex::new(HashMap::new()),
+            balance_cache: Mutex::new(HashMap::new()),
+            sns_cache: Mutex::new(HashMap::new()),
+            health_cache: Mutex::new(HashMap::new()),
+            relayer,
+            relayed: Mutex::new(HashMap::new()),
+            operator,
+        })
+    }
+
+    /// Whether a relayer keypair is configured.
+    pub fn relayer_enabled(&self) -> bool {
+        self.relayer.is_some()
+    }
+
+    /// Public half of the operator key, when operator proofs are enabled.
+    pub fn operator_key(&self) -> Option<VerifyingKey> {
+        self.operator.as_ref().map(SigningKey::verifying_key)
+    }
+
+    /// The homeserver a wallet's on-chain delegation points at first, or `None` if the
+    /// wallet has not registered one or it is a [blocked](is_blocked_homeserver) target.
+    /// Fallback homeservers are not returned.
+    pub async fn resolve_homeserver(&self, owner: &[u8; 32]) -> Result<Option<String>> {
+        let now = utils::secs_since_unix_epoch() as i64;
+        let delegation = self
+            .fetch_delegations(&[*owner])
+            .await?
+            .pop()
+            .flatten()
+            .filter(|delegation| delegation.is_active(now));
+
+        let homeserver =
+            delegation.and_then(|delegation| delegation.homeservers.into_iter().next());
+        Ok(homeserver.filter(|homeserver| {
+            let blocked =
+                is_blocked_homeserver(homeserver, services().globals.solana_homeserver_denylist());
+            if blocked {
+                warn!(
+                    "Solana wallet {} is delegated to blocked homeserver {homeserver}",
+                    bs58::encode(owner).into_string()
+                );
+            }
+            !blocked
+        }))
+    }
+
+    /// Fetch and decode the delegations of several wallets over RPC, lined up with
+    /// `owners`. `None` means that wallet has not registered a delegation or has
+    /// deactivated it.
+    ///
+    /// The PDAs are read with one `getMultipleAccounts` request per registry program, and
+    /// the result is cached for [`DELEGATION_CACHE_TTL`].
+    ///
+    /// During a registry migration every program in `solana_registry_previous_program_ids`
+    /// is read too. If a wallet is delegated to different homeservers in different
+    /// programs, `solana_registry_conflict_policy` decides between failing and using the
+    /// most recently updated delegation.
+    pub async fn fetch_delegations(&self, owners: &[[u8; 32]]) -> Result<Vec<Option<Delegation>>> {
+        let cached = self.delegation_cache.lock().unwrap().get(owners).cloned();
+        if let Some((fetched, delegations)) = cached {
+            if fetched.elapsed() < DELEGATION_CACHE_TTL {
+                return Ok(delegations);
+            }
+        }
+
+        let now = utils::secs_since_unix_epoch() as i64;
+        let mut found = vec![Vec::new(); owners.len()];
+        for program_id in self.program_ids()? {
+            let delegations = self.fetch_delegations_from(owners, &program_id).await?;
+            for (found, delegation) in found.iter_mut().zip(delegations) {
+                found.extend(delegation.filter(|delegation| delegation.is_active(now)));
+            }
+        }
+
+        let policy = services().globals.solana_registry_conflict_policy();
+        let delegations = owners
+            .iter()
+            .zip(found)
+            .map(|(owner, found)| {
+                resolve_delegations(found, policy).map_err(|(newest, other)| {
+                    warn!(
+                        "Solana wallet {} has conflicting registry delegations: {newest} and {other}",
+                        bs58::encode(owner).into_string()
+                    );
+                    Error::BadRequest(
+                        ErrorKind::forbidden(),
+                        "This wallet has conflicting delegations in different registry programs.",
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut cache = self.delegation_cache.lock().unwrap();
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < DELEGATION_CACHE_TTL);
+        cache.insert(owners.to_vec(), (Instant::now(), delegations.clone()));
+
+        Ok(delegations)
+    }
+
+    async fn fetch_delegations_from(
+        &self,
+        owners: &[[u8; 32]],
+        program_id: &[u8; 32],
+    ) -> Result<Vec<Option<Delegation>>> {
+        let mut delegations = Vec::with_capacity(owners.len());
+        for owners in owners.chunks(MAX_ACCOUNTS_PER_REQUEST) {
+            let accounts = self
+                .rpc
+                .get_multiple_accounts(delegation_addresses(owners, program_id)?)
+                .await?;
+            delegations.extend(delegations_from_accounts(owners, accounts, program_id)?);
+        }
+
+        Ok(delegations)
+    }
+
+    /// Resolve the delegations of many wallets, e.g. for a directory of users, lined up
+    /// with `owners`. See [`resolver::resolve_batch`].
+    ///
+    /// Unlike [`Self::fetch_delegations`] this isn't cached, and an RPC failure only
+    /// marks the wallets in the affected chunk as failed instead of failing the batch.
+    /// [Blocked](is_blocked_homeserver) homeservers are left out of the delegations, and
+    /// a delegation left without any counts as none.
+    pub async fn resolve_many(&self, owners: &[[u8; 32]]) -> Result<BatchResolution> {
+        let mut resolution = resolver::resolve_batch(
+            owners,
+            &self.program_ids()?,
+            services().globals.solana_registry_conflict_policy(),
+            utils::secs_since_unix_epoch() as i64,
+            |addresses| self.rpc.get_multiple_accounts(addresses),
+        )
+        .await;
+
+        let denylist = services().globals.solana_homeserver_denylist();
+        for delegation in &mut resolution.delegations {
+            if let Some(found) = delegation {
+                found
+                    .homeservers
+                    .retain(|homeserver| !is_blocked_homeserver(homeserver, denylist));
+                if found.homeservers.is_empty() {
+                    *delegation = None;
+                }
+            }
+        }
+
+        Ok(resolution)
+    }
+
+    /// Every delegation in the registry, by account address, for building a directory
+    /// without knowing the wallets up front. See [`resolver::list_delegations`].
+    ///
+    /// This reads every account of the program in one `getProgramAccounts` call per
+    /// program, which public RPC nodes often refuse or truncate once the registry is
+    /// large; a big directory should be built by an indexer instead.
+    pub async fn list_delegations(&self) -> Result<Vec<([u8; 32], Delegation)>> {
+        let mut delegations = Vec::new();
+        for program_id in self.program_ids()? {
+            delegations.extend(
+                resolver::list_delegations(&program_id, |params| {
+                    self.rpc.get_program_accounts(params)
+                })
+                .await?,
+            );
+        }
+        Ok(delegations)
+    }
+
+    /// Like [`Self::list_delegations`], but only fetches each delegation's owner and
+    /// primary homeserver, which keeps the response small.
+    pub async fn list_primary_homeservers(&self) -> Result<Vec<([u8; 32], DelegationSummary)>> {
+        let mut summaries = Vec::new();
+        for program_id in self.program_ids()? {
+            summaries.extend(
+                resolver::list_primary_homeservers(&program_id, |params| {
+                    self.rpc.get_program_accounts(params)
+                })
+                .await?,
+            );
+        }
+        Ok(summaries)
+    }
+
+    /// Reconstruct a wallet's delegation history, newest first, by replaying the
+    /// `register`/`unregister` instructions sent to its delegation PDA.
+    ///
+    /// Only the latest `solana_history_limit` transactions are read, and results are
+    /// cached for [`HISTORY_CACHE_TTL`] since each call costs one RPC request per
+    /// transaction.
+    pub async fn delegation_history(&self, owner: &[u8; 32]) -> Result<Vec<DelegationChange>> {
+        let cached = self.history_cache.lock().unwrap().get(owner).cloned();
+        if let Some((fetched, history)) = cached {
+            if fetched.elapsed() < HISTORY_CACHE_TTL {
+                return Ok(history);
+            }
+        }
+
+        let program_id = self.program_id()?;
+        let program_id_base58 = bs58::encode(program_id).into_string();
+        let (address, _) = derive_delegation_address(owner, &program_id).ok_or(
+            Error::BadServerResponse("Could not derive the delegation address."),
+        )?;
+
+        #[derive(Deserialize)]
+        struct SignatureInfo {
+            signature: String,
+            err: Option<serde_json::Value>,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct CompiledInstruction {
+            program_id_index: usize,
+            data: String,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Message {
+            account_keys: Vec<String>,
+            instructions: Vec<CompiledInstruction>,
+        }
+        #[derive(Deserialize)]
+        struct Transaction {
+            message: Message,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct TransactionResult {
+            block_time: Option<i64>,
+            transaction: Transaction,
+        }
+
+        let signatures = self
+            .rpc_call::<Vec<SignatureInfo>>(
+                "getSignaturesForAddress",
+                serde_json::json!([
+                    bs58::encode(address).into_string(),
+                    {
+                        "limit": services().globals.solana_history_limit().min(1000),
+                        "commitment": "confirmed",
+                    },
+                ]),
+            )
+            .await?;
+
+        let mut history = Vec::new();
+        for info in signatures.into_iter().filter(|info| info.err.is_none()) {
+            let Some(transaction) = self
+                .rpc_call::<Option<TransactionResult>>(
+                    "getTransaction",
+                    serde_json::json!([
+                        info.signature,
+                        {
+                            "encoding": "json",
+                            "commitment": "confirmed",
+                            "maxSupportedTransactionVersion": 0,
+                        },
+                    ]),
+                )
+                .await?
+            else {
+                continue;
+            };
+
+            let message = &transaction.transaction.message;
+            // A transaction can carry several registry instructions; record them in
+            // reverse so the final list stays newest first.
+            for instruction in message.instructions.iter().rev() {
+                if message.account_keys.get(instruction.program_id_index)
+                    != Some(&program_id_base58)
+                {
+                    continue;
+                }
+                let Ok(data) = bs58::decode(&instruction.data).into_vec() else {
+                    continue;
+                };
+                if let Some(homeservers) = decode_instruction(&data) {
+                    history.push(DelegationChange {
+                        signature: info.signature.clone(),
+                        block_time: transaction.block_time,
+                        homeservers,
+                    });
+                }
+            }
+        }
+
+        self.history_cache
+            .lock()
+            .unwrap()
+            .insert(*owner, (Instant::now(), history.clone()));
+
+        Ok(history)
+    }
+
+    /// Send a JSON-RPC request to `solana_rpc_url` and return its `result`, for the calls
+    /// [`RegistryRpc`] doesn't cover.
+    async fn rpc_call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<T> {
+        SolanaRpcClient.call(method, params).await
+    }
+
+    /// Reject the login if any of the account's wallets has frozen its delegation, or if
+    /// none of them is delegated here and at least one is delegated to another homeserver.
+    ///
+    /// `linked` are wallets linked to the account besides the signing wallet `owner`; a
+    /// delegation here from any of them is enough, since users think of them as one
+    /// identity.
+    ///
+    /// `supplied` is a serialized `Delegation` account for `owner` handed to us by the
+    /// caller, e.g. an edge proxy that already read the chain. It is only used when
+    /// `solana_trust_supplied_delegation` is on: the bytes can't be checked against the
+    /// chain, so whoever supplies them decides the outcome of enforcement. Without it we
+    /// always fetch over RPC. Fetch failures are handled according to
+    /// `solana_rpc_failure_policy`: when it allows logins, only the supplied delegation is
+    /// enforced.
+    pub async fn enforce(
+        &self,
+        owner: &[u8; 32],
+        linked: &[[u8; 32]],
+        supplied: Option<&[u8]>,
+    ) -> Result<()> {
+        if !services().globals.solana_enforce_registry() {
+            return Ok(());
+        }
+
+        let supplied = match supplied {
+            Some(data) if services().globals.solana_trust_supplied_delegation() => {
+                Some(parse_supplied_delegation(owner, data, &self.program_id()?)?)
+            }
+            Some(_) => {
+                warn!(
+                    "Ignoring supplied delegation account: solana_trust_supplied_delegation is off"
+                );
+                None
+            }
+            None => None,
+        };
+
+        let mut wallets = Vec::new();
+        if supplied.is_none() {
+            wallets.push(*owner);
+        }
+        wallets.extend(linked.iter().filter(|wallet| *wallet != owner));
+
+        let mut delegations = vec![supplied];
+        if !wallets.is_empty() {
+            match self.fetch_delegations(&wallets).await {
+                Ok(fetched) => delegations.extend(fetched),
+                Err(error) => match services().globals.solana_rpc_failure_policy() {
+                    RpcFailurePolicy::Deny => return Err(error),
+                    RpcFailurePolicy::Allow => {
+                        warn!("Enforcing only the supplied delegation, Solana RPC failed: {error}");
+                    }
+                },
+            }
+        }
+
+        let now = utils::secs_since_unix_epoch() as i64;
+        if let Some(delegation) = frozen_delegation(&delegations, now) {
+            services().solana.notify(
+                SecurityNotice::DeniedAttempt,
+                format!(
+                    "Refused Solana login for {}: the delegation of wallet {} is frozen.",
+                    bs58::encode(owner).into_string(),
+                    bs58::encode(delegation.owner).into_string()
+                ),
+            );
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "This wallet's delegation is frozen.",
+            ));
+        }
+
+        let server_name = services().globals.server_name();
+        let Some(delegation) = delegated_elsewhere(&delegations, server_name.as_str(), now) else {
+            return Ok(());
+        };
+
+        services().solana.notify(
+            SecurityNotice::DeniedAttempt,
+            format!(
+                "Refused Solana login for {}: wallet {} is delegated to {}{}.",
+                bs58::encode(owner).into_string(),
+                bs58::encode(delegation.owner).into_string(),
+                delegation.homeservers.join(", "),
+                delegation
+                    .previous_homeserver
+                    .as_ref()
+                    .map(|previous| format!(", having moved from {previous}"))
+                    .unwrap_or_default()
+            ),
+        );
+
+        if services().globals.solana_probe_delegated_homeserver() {
+            if let Some(primary) = delegation.homeservers.first() {
+                if self.homeserver_health(primary).await != HomeserverHealth::Healthy {
+                    return Err(Error::BadRequest(
+                        ErrorKind::forbidden(),
+                        "Your delegated homeserver is unreachable.",
+                    ));
+                }
+            }
+        }
+        Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "This wallet has delegated to a different homeserver.",
+        ))
+    }
+
+    /// Probe a delegated homeserver, see [`probe_homeserver`], caching the result for
+    /// [`HEALTH_CACHE_TTL`]. A [blocked](is_blocked_homeserver) homeserver counts as
+    /// unhealthy without being contacted.
+    pub async fn homeserver_health(&self, homeserver: &str) -> HomeserverHealth {
+        if is_blocked_homeserver(homeserver, services().globals.solana_homeserver_denylist()) {
+            return HomeserverHealth::Unhealthy;
+        }
+
+        let cached = self.health_cache.lock().unwrap().get(homeserver).copied();
+        if let Some((probed, health)) = cached {
+            if probed.elapsed() < HEALTH_CACHE_TTL {
+                return health;
+            }
+        }
+
+        let fetch = |url: String| async move {
+            let response = services().globals.default_client().get(url).send().await?;
+            Ok(response.status().is_success())
+        };
+        let health = probe_homeserver(homeserver, fetch, tokio::time::sleep(PROBE_TIMEOUT)).await;
+
+        let mut cache = self.health_cache.lock().unwrap();
+        cache.retain(|_, (probed, _)| probed.elapsed() < HEALTH_CACHE_TTL);
+        cache.insert(homeserver.to_owned(), (Instant::now(), health));
+
+        health
+    }
+
+    /// Reject the login unless the wallet has a confirmed transaction within
+    /// `solana_recent_activity_window` seconds, so a leaked key from a dormant wallet is
+    /// worth less. RPC failures are handled according to `solana_rpc_failure_policy`.
+    pub async fn require_recent_activity(&self, owner: &[u8; 32]) -> Result<()> {
+        let Some(window) = services().globals.solana_recent_activity_window() else {
+            return Ok(());
+        };
+
+        let last_activity = match self.last_activity(owner).await {
+            Ok(last_activity) => last_activity,
+            Err(error) => match services().globals.solana_rpc_failure_policy() {
+                RpcFailurePolicy::Deny => return Err(error),
+                RpcFailurePolicy::Allow => {
+                    warn!("Skipping recent activity check, Solana RPC failed: {error}");
+                    return Ok(());
+                }
+            },
+        };
+
+        let now = utils::secs_since_unix_epoch() as i64;
+        if last_activity.is_some_and(|block_time| now.saturating_sub(block_time) <= window as i64) {
+            return Ok(());
+        }
+
+        services().solana.notify(
+            SecurityNotice::DeniedAttempt,
+            format!(
+                "Refused Solana login for {}: no on-chain activity in the last {window} seconds.",
+                bs58::encode(owner).into_string()
+            ),
+        );
+        Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "This wallet has no recent on-chain activity.",
+        ))
+    }
+
+    /// Block time of the wallet's most recent confirmed transaction, cached for
+    /// [`ACTIVITY_CACHE_TTL`]. `None` if it has never transacted.
+    async fn last_activity(&self, owner: &[u8; 32]) -> Result<Option<i64>> {
+        let cached = self.activity_cache.lock().unwrap().get(owner).copied();
+        if let Some((fetched, last_activity)) = cached {
+            if fetched.elapsed() < ACTIVITY_CACHE_TTL {
+                return Ok(last_activity);
+            }
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct SignatureInfo {
+            block_time: Option<i64>,
+        }
+
+        let last_activity = self
+            .rpc_call::<Vec<SignatureInfo>>(
+                "getSignaturesForAddress",
+                serde_json::json!([
+                    bs58::encode(owner).into_string(),
+                    { "limit": 1, "commitment": "confirmed" },
+                ]),
+            )
+            .await?
+            .first()
+            .and_then(|info| info.block_time);
+
+        let mut cache = self.activity_cache.lock().unwrap();
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < ACTIVITY_CACHE_TTL);
+        cache.insert(*owner, (Instant::now(), last_activity));
+
+        Ok(last_activity)
+    }
+
+    /// Reject the login unless the wallet holds an NFT from `solana_required_collection`.
+    /// RPC failures are handled according to `solana_rpc_failure_policy`.
+    pub async fn require_collection(&self, owner: &[u8; 32]) -> Result<()> {
+        let Some(collection) = services().globals.solana_required_collection() else {
+            return Ok(());
+        };
+        let collection: [u8; 32] = bs58::decode(collection)
+            .into_vec()
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                Error::bad_config("solana_required_collection is not a valid base58 public key.")
+            })?;
+
+        let holds = match self.holds_collection(owner, &collection).await {
+            Ok(holds) => holds,
+            Err(error) => match services().globals.solana_rpc_failure_policy() {
+                RpcFailurePolicy::Deny => return Err(error),
+                RpcFailurePolicy::Allow => {
+                    warn!("Skipping collection check, Solana RPC failed: {error}");
+                    return Ok(());
+                }
+            },
+        };
+        if holds {
+            return Ok(());
+        }
+
+        services().solana.notify(
+            SecurityNotice::DeniedAttempt,
+            format!(
+                "Refused Solana login for {}: wallet holds no NFT from collection {}.",
+                bs58::encode(owner).into_string(),
+                bs58::encode(collection).into_string()
+            ),
+        );
+        Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "This server only allows wallets holding an NFT from its collection.",
+        ))
+    }
+
+    /// Whether the wallet holds an NFT verified as part of `collection`, cached for
+    /// [`COLLECTION_CACHE_TTL`].
+    async fn holds_collection(&self, owner: &[u8; 32], collection: &[u8; 32]) -> Result<bool> {
+        let cached = self.collection_cache.lock().unwrap().get(owner).copied();
+        if let Some((fetched, holds)) = cached {
+            if fetched.elapsed() < COLLECTION_CACHE_TTL {
+                return Ok(holds);
+            }
+        }
+
+        let token_accounts = self
+            .rpc_call::<collection::TokenAccountsResult>(
+                "getTokenAccountsByOwner",
+                collection::token_accounts_params(owner),
+            )
+            .await?
+            .value;
+        let mints = collection::held_mints(owner, token_accounts)?;
+
+        let mut holds = false;
+        for mints in mints.chunks(MAX_ACCOUNTS_PER_REQUEST) {
+            let addresses = mints
+                .iter()
+                .map(|mint| {
+                    collection::derive_metadata_address(mint)
+                        .map(|address| bs58::encode(address).into_string())
+                        .ok_or(Error::BadServerResponse(
+                            "Could not derive the metadata address.",
+                        ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let accounts = self.rpc.get_multiple_accounts(addresses).await?;
+            if collection::any_in_collection(mints, accounts, collection)? {
+                holds = true;
+                break;
+            }
+        }
+
+        let mut cache = self.collection_cache.lock().unwrap();
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < COLLECTION_CACHE_TTL);
+        cache.insert(*owner, (Instant::now(), holds));
+
+        Ok(holds)
+    }
+
+    /// Reject the login unless the wallet holds at least `solana_required_min_balance` of
+    /// `solana_required_mint`. Returning users are only checked when
+    /// `solana_required_balance_on_return` is on. RPC failures are handled according to
+    /// `solana_rpc_failure_policy`.
+    pub async fn require_balance(&self, owner: &[u8; 32], is_new_user: bool) -> Result<()> {
+        let globals = &services().globals;
+        let Some(mint) = globals.solana_required_mint() else {
+            return Ok(());
+        };
+        if !is_new_user && !globals.solana_required_balance_on_return() {
+            return Ok(());
+        }
+        let mint: [u8; 32] = bs58::decode(mint)
+            .into_vec()
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                Error::bad_config("solana_required_mint is not a valid base58 public key.")
+            })?;
+
+        let balance = match self.token_balance(owner, &mint).await {
+            Ok(balance) => balance,
+            Err(error) => match globals.solana_rpc_failure_policy() {
+                RpcFailurePolicy::Deny => return Err(error),
+                RpcFailurePolicy::Allow => {
+                    warn!("Skipping token balance check, Solana RPC failed: {error}");
+                    return Ok(());
+                }
+            },
+        };
+        let required = globals.solana_required_min_balance();
+        if balance >= required {
+            return Ok(());
+        }
+
+        services().solana.notify(
+            SecurityNotice::DeniedAttempt,
+            format!(
+                "Refused Solana login for {}: holds {balance} of {}, needs {required}.",
+                bs58::encode(owner).into_string(),
+                bs58::encode(mint).into_string()
+            ),
+        );
+        Err(Error::BadRequest(
+            ErrorKind::forbidden(),
+            "This wallet does not hold enough of the token this server requires.",
+        ))
+    }
+
+    /// The wallet's balance of `mint` in its associated token account, in base units,
+    /// cached for [`BALANCE_CACHE_TTL`].
+    async fn token_balance(&self, owner: &[u8; 32], mint: &[u8; 32]) -> Result<u64> {
+        let cached = self.balance_cache.lock().unwrap().get(owner).copied();
+        if let Some((fetched, balance)) = cached {
+            if fetched.elapsed() < BALANCE_CACHE_TTL {
+                return Ok(balance);
+            }
+        }
+
+        let address = token_account::derive_associated_token_address(owner, mint).ok_or(
+            Error::BadServerResponse("Could not derive the associated token address."),
+        )?;
+        let account = self
+            .rpc
+            .get_account(bs58::encode(address).into_string())
+            .await?;
+        let balance = token_account::associated_balance(owner, mint, account)?;
+
+        let mut cache = self.balance_cache.lock().unwrap();
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < BALANCE_CACHE_TTL);
+        cache.insert(*owner, (Instant::now(), balance));
+
+        Ok(balance)
+    }
+
+    /// The wallet's primary SNS domain, like "alice.sol", to name a new account after when
+    /// `solana_resolve_sns` is on. A wallet without one, or whose domain can't be read
+    /// because the RPC failed, gets `None` and keeps its address as its name.
+    pub async fn sns_name(&self, owner: &[u8; 32]) -> Option<String> {
+        if !services().globals.solana_resolve_sns() {
+            return None;
+        }
+        self.primary_domain(owner)
+            .await
+            .inspect_err(|error| warn!("Could not resolve the SNS domain of a wallet: {error}"))
+            .ok()
+            .flatten()
+    }
+
+    /// The wallet's primary SNS domain, see [`sns`], cached for [`SNS_CACHE_TTL`].
+    async fn primary_domain(&self, owner: &[u8; 32]) -> Result<Option<String>> {
+        let cached = self.sns_cache.lock().unwrap().get(owner).cloned();
+        if let Some((fetched, name)) = cached {
+            if fetched.elapsed() < SNS_CACHE_TTL {
+                return Ok(name);
+            }
+        }
+
+        let name =
+            sns::primary_domain(owner, |addresses| self.rpc.get_multiple_accounts(addresses))
+                .await?;
+
+        let mut cache = self.sns_cache.lock().unwrap();
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < SNS_CACHE_TTL);
+        cache.insert(*owner, (Instant::now(), name.clone()));
+
+        Ok(name)
+    }
+
+    /// Co-sign a wallet-signed `register` transaction as fee payer and submit it.
+    /// Returns the transaction signature.
+    ///
+    /// The transaction must already carry the wallet's signature and name the relayer as
+    /// fee payer; see [`relayer`] for exactly what is accepted. Each wallet may relay at
+    /// most `solana_relayer_max_per_wallet` transactions per day, and only wallets in
+    /// `solana_relayer_allowed_wallets` when that list is non-empty.
+    pub async fn relay_register(&self, transaction: &[u8]) -> Result<String> {
+        let relayer = self.relayer.as_ref().ok_or(Error::BadRequest(
+            ErrorKind::Unrecognized,
+            "The registry relayer is not enabled on this server.",
+        ))?;
+
+        let program_id = self.program_id()?;
+        let parsed = RelayTransaction::parse(transaction)
+            .and_then(|parsed| {
+                parsed
+                    .validate(
+                        relayer.verifying_key().as_bytes(),
+                        &program_id,
+                        services().globals.solana_relayer_max_rent_lamports(),
+                    )
+                    .map(|_| parsed)
+            })
+            .map_err(|rejection| Error::BadRequest(ErrorKind::InvalidParam, rejection.message()))?;
+        let owner = *parsed
+            .owner()
+            .expect("validated transactions have an owner");
+        let address = bs58::encode(owner).into_string();
+
+        let allowed = services().globals.solana_relayer_allowed_wallets();
+        if !allowed.is_empty() && !allowed.contains(&address) {
+            return Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "This wallet is not eligible for relayed registration.",
+            ));
+        }
+
+        // Reserve a slot before sending so concurrent requests can't exceed the limit.
+        {
+            let mut relayed = self.relayed.lock().unwrap();
+            let now = Instant::now();
+            relayed.retain(|_, (_, first)| now.duration_since(*first) < RELAY_WINDOW);
+
+            let entry = relayed.entry(owner).or_insert((0, now));
+            if entry.0 >= services().globals.solana_relayer_max_per_wallet() {
+                return Err(Error::BadRequest(
+                    ErrorKind::forbidden(),
+                    "This wallet has used its relayed registrations for today.",
+                ));
+            }
+            entry.0 += 1;
+        }
+
+        let signed = parsed.co_sign(relayer);
+        let result = self
+            .rpc_call::<String>(
+                "sendTransaction",
+                serde_json::json!([
+                    general_purpose::STANDARD.encode(&signed),
+                    { "encoding": "base64", "preflightCommitment": "confirmed" },
+                ]),
+            )
+            .await;
+
+        match result {
+            Ok(signature) => {
+                info!("Relayed registry transaction {signature} for {address}");
+                Ok(signature)
+            }
+            Err(e) => {
+                // A failed submission shouldn't count against the wallet.
+                if let Some(entry) = self.relayed.lock().unwrap().get_mut(&owner) {
+                    entry.0 = entry.0.saturating_sub(1);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn program_id(&self) -> Result<[u8; 32]> {
+        bs58::decode(services().globals.solana_registry_program_id())
+            .into_vec()
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                Error::bad_config("solana_registry_program_id is not a valid base58 public key.")
+            })
+    }
+
+    /// The current registry program followed by any previous ones still being read.
+    fn program_ids(&self) -> Result<Vec<[u8; 32]>> {
+        let mut program_ids = vec![self.program_id()?];
+        for previous in services().globals.solana_registry_previous_program_ids() {
+            let program_id = bs58::decode(previous)
+                .into_vec()
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| {
+                    Error::bad_config(
+                        "solana_registry_previous_program_ids contains an invalid public key.",
+                    )
+                })?;
+            if !program_ids.contains(&program_id) {
+                program_ids.push(program_id);
+            }
+        }
+        Ok(program_ids)
+    }
+}
+
+/// An account as an RPC node returns it.
+#[derive(Deserialize)]
+pub struct AccountInfo {
+    /// The account's data and its encoding, which is always base64 here.
+    pub data: (String, String),
+    /// The base58 program that owns the account.
+    pub owner: String,
+}
+
+#[derive(Deserialize)]
+struct MultipleAccountsResult {
+    value: Vec<Option<AccountInfo>>,
+}
+
+/// The base58 delegation PDA addresses of `owners` in `program_id`.
+fn delegation_addresses(owners: &[[u8; 32]], program_id: &[u8; 32]) -> Result<Vec<String>> {
+    owners
+        .iter()
+        .map(|owner| {
+            derive_delegation_address(owner, program_id)
+                .map(|(address, _)| bs58::encode(address).into_string())
+                .ok_or(Error::BadServerResponse(
+                    "Could not derive the delegation address.",
+                ))
+        })
+        .collect()
+}
+
+/// Decode the accounts `getMultipleAccounts` returned for the delegation PDAs of
+/// `owners`, lined up with `owners`. A missing account, or one transferred to another
+/// wallet, means no delegation.
+fn delegations_from_accounts(
+    owners: &[[u8; 32]],
+    accounts: Vec<Option<AccountInfo>>,
+    program_id: &[u8; 32],
+) -> Result<Vec<Option<Delegation>>> {
+    if accounts.len() != owners.len() {
+        return Err(Error::BadServerResponse(
+            "Solana RPC returned the wrong number of accounts.",
+        ));
+    }
+
+    let program_id_base58 = bs58::encode(program_id).into_string();
+    owners
+        .iter()
+        .zip(accounts)
+        .map(|(owner, account)| {
+            let Some(account) = account else {
+                return Ok(None);
+            };
+
+            if account.owner != program_id_base58 {
+                return Err(Error::BadServerResponse(
+                    "Delegation account is not owned by the registry program.",
+                ));
+            }
+
+            let data = general_purpose::STANDARD
+                .decode(account.data.0)
+                .map_err(|_| Error::BadServerResponse("Bad account data encoding"))?;
+
+            // A delegation transferred to another wallet stays at this wallet's PDA but no
+            // longer speaks for it.
+            if Delegation::decode(&data).is_some_and(|delegation| &delegation.owner != owner) {
+                return Ok(None);
+            }
+
+            parse_delegation_for(owner, &data, program_id).map(Some)
+        })
+        .collect()
+}
+
+/// [`parse_delegation_for`] for a delegation the client supplied with its login, so it is
+/// held to exactly the same checks as a fetched one. Bytes that don't describe `owner`'s
+/// delegation are the client's mistake, not the chain's.
+fn parse_supplied_delegation(
+    owner: &[u8; 32],
+    data: &[u8],
+    program_id: &[u8; 32],
+) -> Result<Delegation> {
+    parse_delegation_for(owner, data, program_id).map_err(|_| {
+        Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The supplied delegation account is not a valid delegation of this wallet.",
+        )
+    })
+}
+
+/// Decode delegation bytes for `owner` and check they describe that wallet's PDA in
+/// `program_id`.
+fn parse_delegation_for(
+    owner: &[u8; 32],
+    data: &[u8],
+    program_id: &[u8; 32],
+) -> Result<Delegation> {
+    let delegation = Delegation::decode(data).ok_or(Error::BadServerResponse(
+        "Registry account is not a valid Delegation.",
+    ))?;
+
+    if &delegation.owner != owner {
+        return Err(Error::BadServerResponse(
+            "Registry delegation belongs to a different wallet.",
+        ));
+    }
+
+    let (_, canonical_bump) = derive_delegation_address(owner, program_id).ok_or(
+        Error::BadServerResponse("Could not derive the delegation address."),
+    )?;
+    if delegation.bump != canonical_bump {
+        return Err(Error::BadServerResponse(
+            "Registry delegation has a non-canonical bump.",
+        ));
+    }
+
+    Ok(delegation)
+}
+
+/// Pick the delegation to enforce from those found across registry programs.
+///
+/// Delegations that agree on the homeserver list never conflict. Otherwise `Newest` uses
+/// the most recently updated one and `Error` returns the two disagreeing lists.
+fn resolve_delegations(
+    mut found: Vec<Delegation>,
+    policy: RegistryConflictPolicy,
+) -> std::result::Result<Option<Delegation>, (String, String)> {
+    found.sort_by_key(|delegation| std::cmp::Reverse(delegation.updated_at));
+    let Some(newest) = found.first() else {
+        return Ok(None);
+    };
+
+    if policy == RegistryConflictPolicy::Error {
+        if let Some(other) = found
+            .iter()
+            .find(|delegation| delegation.homeservers != newest.homeservers)
+        {
+            return Err((newest.homeservers.join(", "), other.homeservers.join(", ")));
+        }
+    }
+
+    Ok(found.into_iter().next())
+}
+
+/// The first active delegation of the account's wallets that its owner froze, which
+/// refuses the login wherever the wallets are delegated.
+fn frozen_delegation(delegations: &[Option<Delegation>], now: i64) -> Option<&Delegation> {
+    delegations
+        .iter()
+        .flatten()
+        .find(|delegation| delegation.is_active(now) && delegation.frozen)
+}
+
+/// The delegation to refuse a login over: `None` if any of the account's wallets lists
+/// `server_name`, as primary or fallback, or none of them has an active delegation at
+/// `now`, otherwise the first delegation to other homeservers.
+fn delegated_elsewhere<'a>(
+    delegations: &'a [Option<Delegation>],
+    server_name: &str,
+    now: i64,
+) -> Option<&'a Delegation> {
+    let mut delegations = delegations
+        .iter()
+        .flatten()
+        .filter(|delegation| delegation.is_active(now));
+    if delegations
+        .clone()
+        .any(|delegation| delegation.delegates_to(server_name))
+    {
+        return None;
+    }
+
+    delegations.next()
+}
+
+/// Whether a delegated homeserver points somewhere this server shouldn't send anyone:
+/// a private, loopback or otherwise reserved IP address, including one spelled into a
+/// hostname the way nip.io and sslip.io read them (`127.0.0.1.nip.io`,
+/// `10-0-0-1.sslip.io`), or a host in one of the `denylist` domains.
+///
+/// The registry program only checks that homeservers are well-formed hostnames, since
+/// what counts as internal depends on where they are resolved.
+pub fn is_blocked_homeserver(homeserver: &str, denylist: &[String]) -> bool {
+    let host = homeserver_host(homeserver).to_ascii_lowercase();
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return is_reserved_ip(ip);
+    }
+
+    let labels = host.split('.').collect::<Vec<_>>();
+    let embeds_reserved_ip = labels
+        .windows(4)
+        .map(|labels| labels.join("."))
+        .chain(labels.iter().map(|label| label.replace('-', ".")))
+        .any(|candidate| {
+            candidate
+                .parse::<Ipv4Addr>()
+                .is_ok_and(|ip| is_reserved_ip(IpAddr::V4(ip)))
+        });
+
+    embeds_reserved_ip
+        || denylist.iter().any(|domain| {
+            let domain = domain.trim_matches('.').to_ascii_lowercase();
+            host == domain || host.ends_with(&format!(".{domain}"))
+        })
+}
+
+/// The host part of a homeserver, without its port.
+fn homeserver_host(homeserver: &str) -> &str {
+    if homeserver.parse::<IpAddr>().is_ok() {
+        return homeserver;
+    }
+    if let Some(bracketed) = homeserver.strip_prefix('[') {
+        return bracketed.split(']').next().unwrap_or(bracketed);
+    }
+    match homeserver.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => homeserver,
+    }
+}
+
+/// Addresses that aren't reachable on the public internet: private, loopback,
+/// link-local, shared (CGNAT), documentation, unspecified and reserved ranges.
+fn is_reserved_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || first == 0
+                || (first == 100 && (64..128).contains(&second))
+                || first >= 240
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80
+                || ip
+                    .to_ipv4_mapped()
+                    .is_some_and(|ip| is_reserved_ip(IpAddr::V4(ip)))
+        }
+    }
+}
+
+/// Read a Solana CLI keypair file: a JSON array of 64 bytes, secret key first.
+/// Returns `None` if the file can't be read or doesn't hold a 64-byte keypair.
+fn load_keypair(path: &std::path::Path) -> Option<SigningKey> {
+    let bytes: Vec<u8> = serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+    if bytes.len() != 64 {
+        return None;
+    }
+    let secret: [u8; 32] = bytes[..32].try_into().ok()?;
+
+    Some(SigningKey::from_bytes(&secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program_id() -> [u8; 32] {
+        bs58::decode(DEFAULT_REGISTRY_PROGRAM_ID)
+            .into_vec()
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    fn encode_delegation(
+        owner: [u8; 32],
+        homeservers: &[&str],
+        updated_at: i64,
+        bump: u8,
+    ) -> Vec<u8> {
+        let mut data = Delegation::discriminator().to_vec();
+        data.extend_from_slice(&owner);
+        data.extend_from_slice(&(homeservers.len() as u32).to_le_bytes());
+        for homeserver in homeservers {
+            data.extend_from_slice(&(homeserver.len() as u32).to_le_bytes());
+            data.extend_from_slice(homeserver.as_bytes());
+        }
+        data.extend_from_slice(&updated_at.to_le_bytes());
+        data.push(bump);
+        data.push(2);
+        data.push(0);
+        data
+    }
+
+    #[test]
+    fn derives_the_same_pda_as_the_runtime() {
+        // Reference values from `Pubkey::find_program_address(&[b"delegation", owner], program_id)`
+        let (address, bump) = derive_delegation_address(&[1; 32], &program_id()).unwrap();
+
+        assert_eq!(
+            bs58::encode(address).into_string(),
+            "Yvk5xziYQZp2mBsBdcKbpQpYBR1A4GR4a2ZQBoixRJj"
+        );
+        assert_eq!(bump, 252);
+    }
+
+    #[test]
+    fn decodes_a_delegation_account() {
+        let data = encode_delegation(
+            [7; 32],
+            &["chat.example.com", "backup.example.org"],
+            1_700_000_000,
+            254,
+        );
+
+        let delegation = Delegation::decode(&data).unwrap();
+        assert_eq!(
+            delegation,
+            Delegation {
+                owner: [7; 32],
+                homeservers: vec![
+                    "chat.example.com".to_owned(),
+                    "backup.example.org".to_owned()
+                ],
+                updated_at: 1_700_000_000,
+                bump: 254,
+                version: 2,
+                expires_at: None,
+                auth_pubkey: None,
+                previous_homeserver: None,
+                frozen: false,
+            }
+        );
+        assert!(delegation.delegates_to("backup.example.org"));
+        assert!(!delegation.delegates_to("other.example.com"));
+    }
+
+    #[test]
+    fn decodes_the_auth_key_of_version_4_accounts() {
+        let mut data = encode_delegation([7; 32], &["chat.example.com"], 1_700_000_000, 254);
+        let version = data.len() - 2;
+        data[version] = 4;
+        // A label, then the auth key.
+        data.push(1);
+        data.extend_from_slice(&5u32.to_le_bytes());
+        data.extend_from_slice(b"Alice");
+        data.push(1);
+        data.extend_from_slice(&[8; 32]);
+        assert_eq!(
+            Delegation::decode(&data).unwrap().auth_pubkey,
+            Some([8; 32])
+        );
+
+        // Before version 4 whatever follows the label isn't an auth key.
+        data[version] = 3;
+        assert_eq!(Delegation::decode(&data).unwrap().auth_pubkey, None);
+
+        // Without a label, and with the auth key cleared.
+        let mut data = encode_delegation([7; 32], &["chat.example.com"], 1_700_000_000, 254);
+        data[version] = 4;
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&[0; 32]);
+        assert_eq!(Delegation::decode(&data).unwrap().auth_pubkey, None);
+    }
+
+    #[test]
+    fn decodes_the_previous_homeserver_of_version_5_accounts() {
+        let mut data = encode_delegation([7; 32], &["new.example.com"], 1_700_000_000, 254);
+        let version = data.len() - 2;
+        data[version] = 5;
+        // No label or auth key, then the previous homeserver.
+        data.extend_from_slice(&[0, 0, 1]);
+        data.extend_from_slice(&15u32.to_le_bytes());
+        data.extend_from_slice(b"old.example.com");
+        assert_eq!(
+            Delegation::decode(&data)
+                .unwrap()
+                .previous_homeserver
+                .as_deref(),
+            Some("old.example.com")
+        );
+
+        // Before version 5 whatever follows the auth key isn't a previous homeserver.
+        data[version] = 4;
+        assert_eq!(Delegation::decode(&data).unwrap().previous_homeserver, None);
+    }
+
+    #[test]
+    fn decodes_whether_version_6_accounts_are_frozen() {
+        let mut data = encode_delegation([7; 32], &["chat.example.com"], 1_700_000_000, 254);
+        let version = data.len() - 2;
+        data[version] = 6;
+        // No label, auth key or previous homeserver, then frozen.
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        assert!(Delegation::decode(&data).unwrap().frozen);
+
+        // Before version 6 whatever follows the previous homeserver isn't frozen.
+        data[version] = 5;
+        assert!(!Delegation::decode(&data).unwrap().frozen);
+    }
+
+    #[test]
+    fn decodes_a_single_homeserver_account() {
+        // Written by the first version of the program, before delegations held a list.
+        let fixture: serde_json::Value = serde_json::from_str(include_str!(
+            "../../../../tests/fixtures/single-homeserver-delegation.json"
+        ))
+        .unwrap();
+        let data = general_purpose::STANDARD
+            .decode(fixture["account"]["data"][0].as_str().unwrap())
+            .unwrap();
+        assert_eq!(data.len(), 8 + SINGLE_HOMESERVER_SPACE);
+
+        let (address, bump) = derive_delegation_address(&[8; 32], &program_id()).unwrap();
+        assert_eq!(
+            fixture["pubkey"],
+            bs58::encode(address).into_string().as_str()
+        );
+        assert_eq!(
+            Delegation::decode(&data),
+            Some(Delegation {
+                owner: [8; 32],
+                homeservers: vec!["chat.example.com".to_owned()],
+                updated_at: 1_700_000_000,
+                bump,
+                version: 0,
+                expires_at: None,
+                auth_pubkey: None,
+                previous_homeserver: None,
+                frozen: false,
+            })
+        );
+    }
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn delegation(homeserver: &str, updated_at: i64) -> Delegation {
+        Delegation {
+            owner: [7; 32],
+            homeservers: vec![homeserver.to_owned()],
+            updated_at,
+            bump: 254,
+            version: 2,
+            expires_at: None,
+            auth_pubkey: None,
+            previous_homeserver: None,
+            frozen: false,
+        }
+    }
+
+    #[test]
+    fn resolves_delegations_across_registry_programs() {
+        let old = delegation("old.example.com", 1_600_000_000);
+        let new = delegation("new.example.com", 1_700_000_000);
+
+        assert_eq!(
+            resolve_delegations(Vec::new(), RegistryConflictPolicy::Error),
+            Ok(None)
+        );
+        assert_eq!(
+            resolve_delegations(
+                vec![old.clone(), delegation("old.example.com", 1_650_000_000)],
+                RegistryConflictPolicy::Error
+            )
+            .unwrap()
+            .map(|delegation| delegation.updated_at),
+            Some(1_650_000_000)
+        );
+        assert_eq!(
+            resolve_delegations(
+                vec![old.clone(), new.clone()],
+                RegistryConflictPolicy::Error
+            ),
+            Err(("new.example.com".to_owned(), "old.example.com".to_owned()))
+        );
+        assert_eq!(
+            resolve_delegations(vec![old, new.clone()], RegistryConflictPolicy::Newest),
+            Ok(Some(new))
+        );
+    }
+
+    #[test]
+    fn accepts_when_any_linked_wallet_is_delegated_here() {
+        let here = delegation("chat.example.com", 1_700_000_000);
+        let elsewhere = delegation("other.example.com", 1_700_000_000);
+
+        assert_eq!(
+            delegated_elsewhere(
+                &[Some(elsewhere.clone()), None, Some(here)],
+                "chat.example.com",
+                NOW
+            ),
+            None
+        );
+        assert_eq!(
+            delegated_elsewhere(
+                &[None, Some(elsewhere.clone()), None],
+                "chat.example.com",
+                NOW
+            ),
+            Some(&elsewhere)
+        );
+        assert_eq!(
+            delegated_elsewhere(&[None, None], "chat.example.com", NOW),
+            None
+        );
+
+        let deactivated = Delegation {
+            homeservers: Vec::new(),
+            ..elsewhere.clone()
+        };
+        assert_eq!(
+            delegated_elsewhere(&[Some(deactivated)], "chat.example.com", NOW),
+            None
+        );
+        assert_eq!(delegated_elsewhere(&[], "chat.example.com", NOW), None);
+
+        // An expired delegation is ignored, whether it points here or elsewhere.
+        let expired = Delegation {
+            expires_at: Some(NOW),
+            ..elsewhere.clone()
+        };
+        assert!(!expired.is_active(NOW));
+        assert_eq!(
+            delegated_elsewhere(&[Some(expired)], "chat.example.com", NOW),
+            None
+        );
+        let expiring = Delegation {
+            expires_at: Some(NOW + 1),
+            ..elsewhere
+        };
+        assert!(expiring.is_active(NOW));
+        assert_eq!(
+            delegated_elsewhere(&[Some(expiring.clone())], "chat.example.com", NOW),
+            Some(&expiring)
+        );
+    }
+
+    #[test]
+    fn a_frozen_delegation_blocks_the_login() {
+        let here = delegation("chat.example.com", 1_700_000_000);
+        let frozen = Delegation {
+            frozen: true,
+            ..here.clone()
+        };
+
+        // Frozen, even though it is delegated here.
+        assert_eq!(
+            frozen_delegation(&[Some(frozen.clone())], NOW),
+            Some(&frozen)
+        );
+        assert_eq!(
+            frozen_delegation(&[Some(here.clone()), None, Some(frozen.clone())], NOW),
+            Some(&frozen)
+        );
+        assert_eq!(frozen_delegation(&[Some(here), None], NOW), None);
+
+        // An expired or deactivated delegation no longer counts, frozen or not.
+        let expired = Delegation {
+            expires_at: Some(NOW),
+            ..frozen.clone()
+        };
+        assert_eq!(frozen_delegation(&[Some(expired)], NOW), None);
+        let deactivated = Delegation {
+            homeservers: Vec::new(),
+            ..frozen
+        };
+        assert_eq!(frozen_delegation(&[Some(deactivated)], NOW), None);
+    }
+
+    #[test]
+    fn blocks_private_and_reserved_homeservers() {
+        let denylist = ["local".to_owned(), ".internal".to_owned()];
+        for homeserver in [
+            "127.0.0.1",
+            "127.0.0.1:8448",
+            "10.1.2.3",
+            "172.16.0.9",
+            "192.168.1.20:8008",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "[::1]:8448",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+            "127.0.0.1.nip.io",
+            "chat.192.168.0.10.nip.io:8448",
+            "10-0-0-1.sslip.io",
+            "server.local",
+            "SERVER.LOCAL",
+            "matrix.corp.internal:8448",
+            "internal",
+        ] {
+            assert!(
+                is_blocked_homeserver(homeserver, &denylist),
+                "{homeserver} should be blocked"
+            );
+        }
+    }
+
+    #[test]
+    fn allows_public_homeservers() {
+        let denylist = ["local".to_owned(), "internal".to_owned()];
+        for homeserver in [
+            "chat.example.com",
+            "chat.example.com:8448",
+            "local.example.com",
+            "internal-chat.example.org",
+            "8.8.8.8",
+            "1.2.3.4.nip.io",
+            "2606:4700::1111",
+            "v2.example.com",
+        ] {
+            assert!(
+                !is_blocked_homeserver(homeserver, &denylist),
+                "{homeserver} should be allowed"
+            );
+        }
+        // An empty denylist still refuses reserved addresses.
+        assert!(!is_blocked_homeserver("server.local", &[]));
+        assert!(is_blocked_homeserver("127.0.0.1.nip.io", &[]));
+    }
+
+    #[test]
+    fn decodes_register_and_unregister_instructions() {
+        let mut register = instruction_discriminator("register").to_vec();
+        register.extend_from_slice(&16u32.to_le_bytes());
+        register.extend_from_slice(b"chat.example.com");
+        assert_eq!(
+            decode_instruction(&register),
+            Some(Some(vec!["chat.example.com".to_owned()]))
+        );
+
+        let mut register_homeservers = instruction_discriminator("register_homeservers").to_vec();
+        register_homeservers.extend_from_slice(&2u32.to_le_bytes());
+        for homeserver in ["chat.example.com", "backup.example.org"] {
+            register_homeservers.extend_from_slice(&(homeserver.len() as u32).to_le_bytes());
+            register_homeservers.extend_from_slice(homeserver.as_bytes());
+        }
+        assert_eq!(
+            decode_instruction(&register_homeservers),
+            Some(Some(vec![
+                "chat.example.com".to_owned(),
+                "backup.example.org".to_owned()
+            ]))
+        );
+
+        let deactivate = instruction_discriminator("deactivate").to_vec();
+        assert_eq!(decode_instruction(&deactivate), Some(Some(Vec::new())));
+
+        let unregister = instruction_discriminator("unregister").to_vec();
+        assert_eq!(decode_instruction(&unregister), Some(None));
+
+        assert_eq!(decode_instruction(&[0; 8]), None);
+        assert_eq!(decode_instruction(&register[..12]), None);
+    }
+
+    /// A `getMultipleAccounts` result as the RPC node would send it, for two owners
+    /// where only the first has a delegation.
+    fn mock_rpc_result(owner_of_account: &str, data: &[u8]) -> MultipleAccountsResult {
+        serde_json::from_value(serde_json::json!({
+            "context": { "slot": 1 },
+            "value": [
+                {
+                    "data": [general_purpose::STANDARD.encode(data), "base64"],
+                    "executable": false,
+                    "lamports": 2_000_000,
+                    "owner": owner_of_account,
+                    "rentEpoch": 0,
+                },
+                null,
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_delegations_from_the_rpc_response() {
+        let owner = [1; 32];
+        let (_, bump) = derive_delegation_address(&owner, &program_id()).unwrap();
+        let data = encode_delegation(owner, &["chat.example.com"], 1_700_000_000, bump);
+        let owners = [owner, [2; 32]];
+
+        let accounts = mock_rpc_result(DEFAULT_REGISTRY_PROGRAM_ID, &data).value;
+        let delegations = delegations_from_accounts(&owners, accounts, &program_id()).unwrap();
+        assert_eq!(
+            delegations[0]
+                .as_ref()
+                .map(|delegation| &delegation.homeservers[..]),
+            Some(&["chat.example.com".to_owned()][..])
+        );
+        assert_eq!(delegations[1], None);
+
+        // An account the registry doesn't own can't stand in for a delegation.
+        let accounts = mock_rpc_result("11111111111111111111111111111111", &data).value;
+        assert!(delegations_from_accounts(&owners, accounts, &program_id()).is_err());
+
+        // Once transferred to another wallet, it no longer counts for the one it was
+        // first registered by.
+        let accounts = mock_rpc_result(DEFAULT_REGISTRY_PROGRAM_ID, &data).value;
+        assert_eq!(
+            delegations_from_accounts(&[[3; 32], [2; 32]], accounts, &program_id()).unwrap(),
+            vec![None, None]
+        );
+
+        let accounts = mock_rpc_result(DEFAULT_REGISTRY_PROGRAM_ID, &data).value;
+        assert!(delegations_from_accounts(&[owner], accounts, &program_id()).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_supplied_delegations_as_bad_requests() {
+        let owner = [1; 32];
+        let (_, bump) = derive_delegation_address(&owner, &program_id()).unwrap();
+        let data = encode_delegation(owner, &["chat.example.com"], 1_700_000_000, bump);
+        assert_eq!(
+            parse_supplied_delegation(&owner, &data, &program_id())
+                .unwrap()
+                .homeservers,
+            ["chat.example.com"]
+        );
+
+        // Truncated, foreign or someone else's: the client sent a bad parameter.
+        for (owner, bad) in [
+            (owner, &data[..8]),
+            (owner, &[0; 64][..]),
+            ([2; 32], &data[..]),
+        ] {
+            assert!(matches!(
+                parse_supplied_delegation(&owner, bad, &program_id()),
+                Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_discriminator_and_truncated_data() {
+        let mut data = encode_delegation([7; 32], &["chat.example.com"], 0, 254);
+        assert!(Delegation::decode(&data[..data.len() - 3]).is_none());
+        // An account from before versioning ends at the bump.
+        assert_eq!(
+            Delegation::decode(&data[..data.len() - 2]).map(|delegation| delegation.version),
+            Some(0)
+        );
+
+        let last = data.len() - 1;
+        data[last] = 2;
+        assert!(Delegation::decode(&data).is_none());
+        data[last] = 1;
+        assert!(Delegation::decode(&data).is_none());
+        data.extend_from_slice(&1_800_000_000i64.to_le_bytes());
+        assert_eq!(
+            Delegation::decode(&data).and_then(|delegation| delegation.expires_at),
+            Some(1_800_000_000)
+        );
+
+        data[0] ^= 0xff;
+        assert!(Delegation::decode(&data).is_none());
+    }
+}
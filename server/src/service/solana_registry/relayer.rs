@@ -0,0 +1,367 @@
+//! Checks user-signed registry transactions before the server pays their fees.
+//!
+//! The client builds a legacy transaction with the relayer as fee payer (account 0) and
+//! the wallet as the second signer, signs it with the wallet, and hands it to us. We only
+//! co-sign if every instruction is a `register` for that wallet, optionally preceded by a
+//! capped rent transfer from the relayer to the wallet.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use super::instruction_discriminator;
+
+/// The system program's address (all zeroes, `11111111111111111111111111111111`).
+const SYSTEM_PROGRAM_ID: [u8; 32] = [0; 32];
+
+/// System program instruction index for `Transfer`.
+const SYSTEM_TRANSFER: u32 = 2;
+
+/// Why a transaction was refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RelayRejection {
+    Malformed,
+    UnsupportedVersion,
+    WrongFeePayer,
+    WrongSigners,
+    BadOwnerSignature,
+    DisallowedInstruction,
+    RentTooHigh,
+}
+
+impl RelayRejection {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::Malformed => "Transaction could not be decoded.",
+            Self::UnsupportedVersion => "Only legacy transactions can be relayed.",
+            Self::WrongFeePayer => "Transaction fee payer must be this server's relayer key.",
+            Self::WrongSigners => "Transaction must be signed by exactly the relayer and one wallet.",
+            Self::BadOwnerSignature => "Wallet signature on the transaction is invalid.",
+            Self::DisallowedInstruction => {
+                "Transaction may only contain a registry register instruction for the signing wallet."
+            }
+            Self::RentTooHigh => "Transaction asks the relayer for more rent than allowed.",
+        }
+    }
+}
+
+/// A decoded instruction from a legacy message.
+struct Instruction<'a> {
+    program_id_index: u8,
+    accounts: &'a [u8],
+    data: &'a [u8],
+}
+
+/// The parts of a legacy transaction the relayer needs to inspect.
+pub struct RelayTransaction<'a> {
+    bytes: &'a [u8],
+    signatures_offset: usize,
+    message_offset: usize,
+    num_required_signatures: u8,
+    account_keys: Vec<[u8; 32]>,
+    instructions: Vec<Instruction<'a>>,
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let slice = self
+            .bytes
+            .get(self.position..self.position.checked_add(len)?)?;
+        self.position += len;
+        Some(slice)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|slice| slice[0])
+    }
+
+    /// Solana's "compact-u16" (shortvec) length encoding.
+    fn compact_u16(&mut self) -> Option<usize> {
+        let mut value = 0usize;
+        for shift in [0, 7, 14] {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+impl<'a> RelayTransaction<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, RelayRejection> {
+        Self::parse_inner(bytes).ok_or(RelayRejection::Malformed)?
+    }
+
+    fn parse_inner(bytes: &'a [u8]) -> Option<Result<Self, RelayRejection>> {
+        let mut cursor = Cursor { bytes, position: 0 };
+
+        let num_signatures = cursor.compact_u16()?;
+        let signatures_offset = cursor.position;
+        cursor.take(num_signatures.checked_mul(64)?)?;
+
+        let message_offset = cursor.position;
+        let num_required_signatures = cursor.byte()?;
+        if num_required_signatures & 0x80 != 0 {
+            return Some(Err(RelayRejection::UnsupportedVersion));
+        }
+        if num_required_signatures as usize != num_signatures {
+            return Some(Err(RelayRejection::WrongSigners));
+        }
+        // Read-only signed/unsigned account counts; nothing we rely on.
+        cursor.take(2)?;
+
+        let num_keys = cursor.compact_u16()?;
+        let account_keys = (0..num_keys)
+            .map(|_| cursor.take(32)?.try_into().ok())
+            .collect::<Option<Vec<[u8; 32]>>>()?;
+
+        // Recent blockhash
+        cursor.take(32)?;
+
+        let num_instructions = cursor.compact_u16()?;
+        let mut instructions = Vec::with_capacity(num_instructions);
+        for _ in 0..num_instructions {
+            let program_id_index = cursor.byte()?;
+            let accounts_len = cursor.compact_u16()?;
+            let accounts = cursor.take(accounts_len)?;
+            let data_len = cursor.compact_u16()?;
+            let data = cursor.take(data_len)?;
+            instructions.push(Instruction {
+                program_id_index,
+                accounts,
+                data,
+            });
+        }
+
+        if cursor.position != bytes.len() {
+            return None;
+        }
+
+        Some(Ok(Self {
+            bytes,
+            signatures_offset,
+            message_offset,
+            num_required_signatures,
+            account_keys,
+            instructions,
+        }))
+    }
+
+    /// The wallet that signed alongside the relayer.
+    pub fn owner(&self) -> Option<&[u8; 32]> {
+        self.account_keys.get(1)
+    }
+
+    /// Check the transaction only does what the relayer is willing to pay for, and that the
+    /// wallet really signed it. Returns the rent (in lamports) the relayer would transfer.
+    pub fn validate(
+        &self,
+        relayer: &[u8; 32],
+        program_id: &[u8; 32],
+        max_rent_lamports: u64,
+    ) -> Result<u64, RelayRejection> {
+        if self.account_keys.first() != Some(relayer) {
+            return Err(RelayRejection::WrongFeePayer);
+        }
+        if self.num_required_signatures != 2 {
+            return Err(RelayRejection::WrongSigners);
+        }
+        let owner = self.owner().ok_or(RelayRejection::WrongSigners)?;
+        if owner == relayer {
+            return Err(RelayRejection::WrongSigners);
+        }
+
+        let register = instruction_discriminator("register");
+        let mut rent = 0u64;
+        let mut registers = 0;
+
+        for instruction in &self.instructions {
+            let program = self
+                .account_keys
+                .get(instruction.program_id_index as usize)
+                .ok_or(RelayRejection::Malformed)?;
+
+            if program == program_id && instruction.data.starts_with(&register) {
+                // Accounts: [delegation, owner, system_program]. The relayer must not appear,
+                // otherwise its signature could authorise something other than the fee.
+                if instruction.accounts.get(1) != Some(&1) || instruction.accounts.contains(&0) {
+                    return Err(RelayRejection::DisallowedInstruction);
+                }
+                registers += 1;
+            } else if program == &SYSTEM_PROGRAM_ID && instruction.accounts == [0, 1] {
+                // Rent top-up: transfer from the relayer to the wallet.
+                let (kind, lamports) = instruction
+                    .data
+                    .split_at_checked(4)
+                    .ok_or(RelayRejection::Malformed)?;
+                if kind != SYSTEM_TRANSFER.to_le_bytes() || lamports.len() != 8 {
+                    return Err(RelayRejection::DisallowedInstruction);
+                }
+                rent = rent.saturating_add(u64::from_le_bytes(
+                    lamports.try_into().expect("length checked above"),
+                ));
+            } else {
+                return Err(RelayRejection::DisallowedInstruction);
+            }
+        }
+
+        if registers == 0 {
+            return Err(RelayRejection::DisallowedInstruction);
+        }
+        if rent > max_rent_lamports {
+            return Err(RelayRejection::RentTooHigh);
+        }
+
+        let owner_key =
+            VerifyingKey::from_bytes(owner).map_err(|_| RelayRejection::BadOwnerSignature)?;
+        let owner_signature = Signature::from_bytes(
+            self.signature_slot(1)
+                .try_into()
+                .expect("signature slots are 64 bytes"),
+        );
+        owner_key
+            .verify(self.message(), &owner_signature)
+            .map_err(|_| RelayRejection::BadOwnerSignature)?;
+
+        Ok(rent)
+    }
+
+    /// Sign as fee payer and return the fully signed wire transaction.
+    pub fn co_sign(&self, relayer: &SigningKey) -> Vec<u8> {
+        let signature = relayer.sign(self.message());
+        let mut signed = self.bytes.to_vec();
+        signed[self.signatures_offset..self.signatures_offset + 64]
+            .copy_from_slice(&signature.to_bytes());
+        signed
+    }
+
+    fn message(&self) -> &[u8] {
+        &self.bytes[self.message_offset..]
+    }
+
+    fn signature_slot(&self, index: usize) -> &[u8] {
+        let start = self.signatures_offset + index * 64;
+        &self.bytes[start..start + 64]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM_ID: [u8; 32] = [9; 32];
+
+    fn relayer() -> SigningKey {
+        SigningKey::from_bytes(&[1; 32])
+    }
+
+    fn owner() -> SigningKey {
+        SigningKey::from_bytes(&[2; 32])
+    }
+
+    /// Build a legacy transaction: [relayer, owner, delegation, system, program] with the
+    /// given instructions, signed by the owner only.
+    fn transaction(instructions: &[(u8, Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let keys = [
+            relayer().verifying_key().to_bytes(),
+            owner().verifying_key().to_bytes(),
+            [3; 32],
+            SYSTEM_PROGRAM_ID,
+            PROGRAM_ID,
+        ];
+
+        let mut message = vec![2, 0, 2, keys.len() as u8];
+        for key in keys {
+            message.extend_from_slice(&key);
+        }
+        message.extend_from_slice(&[7; 32]);
+        message.push(instructions.len() as u8);
+        for (program, accounts, data) in instructions {
+            message.push(*program);
+            message.push(accounts.len() as u8);
+            message.extend_from_slice(accounts);
+            message.push(data.len() as u8);
+            message.extend_from_slice(data);
+        }
+
+        let mut bytes = vec![2];
+        bytes.extend_from_slice(&[0; 64]);
+        bytes.extend_from_slice(&owner().sign(&message).to_bytes());
+        bytes.extend_from_slice(&message);
+        bytes
+    }
+
+    fn register() -> (u8, Vec<u8>, Vec<u8>) {
+        let mut data = instruction_discriminator("register").to_vec();
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(b"chat.example.com");
+        (4, vec![2, 1, 3], data)
+    }
+
+    fn transfer(lamports: u64) -> (u8, Vec<u8>, Vec<u8>) {
+        let mut data = SYSTEM_TRANSFER.to_le_bytes().to_vec();
+        data.extend_from_slice(&lamports.to_le_bytes());
+        (3, vec![0, 1], data)
+    }
+
+    fn validate(bytes: &[u8], max_rent: u64) -> Result<u64, RelayRejection> {
+        RelayTransaction::parse(bytes)?.validate(
+            &relayer().verifying_key().to_bytes(),
+            &PROGRAM_ID,
+            max_rent,
+        )
+    }
+
+    #[test]
+    fn accepts_a_register_signed_by_the_owner() {
+        let bytes = transaction(&[register()]);
+        assert_eq!(validate(&bytes, 0), Ok(0));
+
+        let parsed = RelayTransaction::parse(&bytes).unwrap();
+        let signed = parsed.co_sign(&relayer());
+        let relayer_signature = Signature::from_bytes(signed[1..65].try_into().unwrap());
+        assert!(relayer()
+            .verifying_key()
+            .verify(parsed.message(), &relayer_signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn caps_rent_transfers() {
+        let bytes = transaction(&[transfer(2_000_000), register()]);
+        assert_eq!(validate(&bytes, 2_000_000), Ok(2_000_000));
+        assert_eq!(
+            validate(&bytes, 1_000_000),
+            Err(RelayRejection::RentTooHigh)
+        );
+    }
+
+    #[test]
+    fn rejects_instructions_that_touch_the_relayer() {
+        let (program, _, data) = register();
+        let bytes = transaction(&[(program, vec![2, 1, 0], data)]);
+        assert_eq!(
+            validate(&bytes, 0),
+            Err(RelayRejection::DisallowedInstruction)
+        );
+
+        let bytes = transaction(&[(3, vec![0, 2], transfer(1).2)]);
+        assert_eq!(
+            validate(&bytes, u64::MAX),
+            Err(RelayRejection::DisallowedInstruction)
+        );
+    }
+
+    #[test]
+    fn rejects_a_tampered_owner_signature() {
+        let mut bytes = transaction(&[register()]);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(validate(&bytes, 0), Err(RelayRejection::BadOwnerSignature));
+    }
+}
@@ -0,0 +1,500 @@
+//! Resolves the delegations of many wallets at once, e.g. for a directory of users.
+//!
+//! Wallets are read [`MAX_ACCOUNTS_PER_REQUEST`] at a time with `getMultipleAccounts`,
+//! one request per chunk and registry program. A chunk the RPC node fails to answer only
+//! affects the wallets in it: they are reported as failed and the rest still resolve.
+//!
+//! Every delegation of a program can also be listed with `getProgramAccounts`, for
+//! building a directory without knowing the wallets up front. Many RPC providers limit
+//! or refuse that call for programs with many accounts, so at scale a directory should
+//! come from an indexer following the registry's events instead.
+
+use std::future::Future;
+
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use tracing::warn;
+
+use super::{
+    delegation_addresses, delegations_from_accounts, resolve_delegations, AccountInfo, BorshReader,
+    Delegation, MAX_ACCOUNTS_PER_REQUEST,
+};
+use crate::{config::RegistryConflictPolicy, Error, Result};
+
+/// Bytes of a delegation account up to the end of its primary homeserver, at most: the
+/// discriminator, owner, list length and one string of up to 253 bytes.
+const PRIMARY_HOMESERVER_SLICE: usize = 8 + 32 + 4 + 4 + 253;
+
+/// Delegations of many wallets, lined up with the wallets asked about.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BatchResolution {
+    /// Each wallet's active delegation, or `None` if it has none or couldn't be read.
+    pub delegations: Vec<Option<Delegation>>,
+    /// Indexes of the wallets that couldn't be read, in order. Callers should retry these
+    /// rather than treat them as unregistered.
+    pub failed: Vec<usize>,
+}
+
+/// Resolve the delegations of `owners` across `program_ids`, reading each chunk's PDAs
+/// with `get_multiple_accounts`, which is handed the base58 addresses and returns the
+/// accounts in the same order.
+///
+/// Delegations inactive at `now` don't count. A wallet delegated to different homeservers
+/// in different programs is resolved by `policy`, and counts as failed when that is
+/// [`RegistryConflictPolicy::Error`].
+pub async fn resolve_batch<F, Fut>(
+    owners: &[[u8; 32]],
+    program_ids: &[[u8; 32]],
+    policy: RegistryConflictPolicy,
+    now: i64,
+    mut get_multiple_accounts: F,
+) -> BatchResolution
+where
+    F: FnMut(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<Option<AccountInfo>>>>,
+{
+    let mut resolution = BatchResolution::default();
+    for (chunk_index, chunk) in owners.chunks(MAX_ACCOUNTS_PER_REQUEST).enumerate() {
+        let start = chunk_index * MAX_ACCOUNTS_PER_REQUEST;
+
+        let mut found = vec![Vec::new(); chunk.len()];
+        let mut chunk_failed = false;
+        for program_id in program_ids {
+            let delegations = match delegation_addresses(chunk, program_id) {
+                Ok(addresses) => get_multiple_accounts(addresses)
+                    .await
+                    .and_then(|accounts| delegations_from_accounts(chunk, accounts, program_id)),
+                Err(error) => Err(error),
+            };
+            match delegations {
+                Ok(delegations) => {
+                    for (found, delegation) in found.iter_mut().zip(delegations) {
+                        found.extend(delegation.filter(|delegation| delegation.is_active(now)));
+                    }
+                }
+                Err(error) => {
+                    warn!(
+                        "Could not read delegations of wallets {start}..{}: {error}",
+                        start + chunk.len()
+                    );
+                    chunk_failed = true;
+                    break;
+                }
+            }
+        }
+
+        if chunk_failed {
+            resolution
+                .delegations
+                .extend(std::iter::repeat_with(|| None).take(chunk.len()));
+            resolution.failed.extend(start..start + chunk.len());
+            continue;
+        }
+
+        for (offset, found) in found.into_iter().enumerate() {
+            match resolve_delegations(found, policy) {
+                Ok(delegation) => resolution.delegations.push(delegation),
+                Err((newest, other)) => {
+                    warn!(
+                        "Solana wallet {} has conflicting registry delegations: {newest} and {other}",
+                        bs58::encode(chunk[offset]).into_string()
+                    );
+                    resolution.delegations.push(None);
+                    resolution.failed.push(start + offset);
+                }
+            }
+        }
+    }
+
+    resolution
+}
+
+/// One account in a `getProgramAccounts` response.
+#[derive(Deserialize)]
+pub struct ProgramAccount {
+    /// The account's base58 address.
+    pub pubkey: String,
+    pub account: AccountInfo,
+}
+
+/// A delegation listed by [`list_primary_homeservers`]: only its owner and first
+/// homeserver were fetched.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DelegationSummary {
+    pub owner: [u8; 32],
+    /// `None` if the owner deactivated the delegation.
+    pub primary_homeserver: Option<String>,
+}
+
+/// Parameters for `getProgramAccounts` that list the `Delegation` accounts of
+/// `program_id`, by their discriminator. With `slice`, only that many bytes from the
+/// start of each account are returned.
+pub fn delegation_accounts_params(
+    program_id: &[u8; 32],
+    slice: Option<usize>,
+) -> serde_json::Value {
+    let mut config = serde_json::json!({
+        "encoding": "base64",
+        "commitment": "confirmed",
+        "filters": [{
+            "memcmp": {
+                "offset": 0,
+                "bytes": bs58::encode(Delegation::discriminator()).into_string(),
+            },
+        }],
+    });
+    if let Some(length) = slice {
+        config["dataSlice"] = serde_json::json!({ "offset": 0, "length": length });
+    }
+    serde_json::json!([bs58::encode(program_id).into_string(), config])
+}
+
+/// List every delegation in `program_id` with `get_program_accounts`, which is handed
+/// the `getProgramAccounts` parameters, as the account address and the delegation.
+///
+/// The owner is whoever holds the delegation now, so a transferred delegation's address
+/// is seeded by an earlier owner. Accounts that aren't delegations of this program are
+/// left out, in case the RPC node ignored the filter.
+pub async fn list_delegations<F, Fut>(
+    program_id: &[u8; 32],
+    get_program_accounts: F,
+) -> Result<Vec<([u8; 32], Delegation)>>
+where
+    F: FnOnce(serde_json::Value) -> Fut,
+    Fut: Future<Output = Result<Vec<ProgramAccount>>>,
+{
+    let accounts = get_program_accounts(delegation_accounts_params(program_id, None)).await?;
+    program_account_data(program_id, accounts, Delegation::decode)
+}
+
+/// Like [`list_delegations`], but only fetches the start of each account, up to the
+/// primary homeserver, which is much cheaper for a plain listing.
+pub async fn list_primary_homeservers<F, Fut>(
+    program_id: &[u8; 32],
+    get_program_accounts: F,
+) -> Result<Vec<([u8; 32], DelegationSummary)>>
+where
+    F: FnOnce(serde_json::Value) -> Fut,
+    Fut: Future<Output = Result<Vec<ProgramAccount>>>,
+{
+    let params = delegation_accounts_params(program_id, Some(PRIMARY_HOMESERVER_SLICE));
+    let accounts = get_program_accounts(params).await?;
+    program_account_data(program_id, accounts, |data| {
+        let mut reader = BorshReader(data.strip_prefix(&Delegation::discriminator())?);
+        let owner = reader.array::<32>()?;
+        let mut list = BorshReader(reader.0);
+        let primary_homeserver = match u32::from_le_bytes(list.array::<4>()?) {
+            0 => None,
+            // An account in the first layout holds a single string where the list now
+            // goes, so what follows its length is text, which doesn't read as another.
+            _ => Some(list.string().or_else(|| reader.string())?),
+        };
+        Some(DelegationSummary {
+            owner,
+            primary_homeserver,
+        })
+    })
+}
+
+/// Decode the data of each account `getProgramAccounts` returned with `decode`, keeping
+/// the ones owned by `program_id` that decode.
+fn program_account_data<T>(
+    program_id: &[u8; 32],
+    accounts: Vec<ProgramAccount>,
+    decode: impl Fn(&[u8]) -> Option<T>,
+) -> Result<Vec<([u8; 32], T)>> {
+    let program_id_base58 = bs58::encode(program_id).into_string();
+    let mut decoded = Vec::new();
+    for ProgramAccount { pubkey, account } in accounts {
+        if account.owner != program_id_base58 {
+            continue;
+        }
+
+        let address = bs58::decode(&pubkey)
+            .into_vec()
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(Error::BadServerResponse(
+                "Solana RPC returned an invalid account address.",
+            ))?;
+        let data = general_purpose::STANDARD
+            .decode(account.data.0)
+            .map_err(|_| Error::BadServerResponse("Bad account data encoding"))?;
+        decoded.extend(decode(&data).map(|value| (address, value)));
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use base64::{engine::general_purpose, Engine as _};
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::{
+        service::solana_registry::{derive_delegation_address, DEFAULT_REGISTRY_PROGRAM_ID},
+        Error,
+    };
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn program_id() -> [u8; 32] {
+        bs58::decode(DEFAULT_REGISTRY_PROGRAM_ID)
+            .into_vec()
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    fn owner(index: usize) -> [u8; 32] {
+        let mut owner = [0; 32];
+        owner[..8].copy_from_slice(&(index as u64 + 1).to_le_bytes());
+        owner
+    }
+
+    /// A delegation account for `owner`, as the registry program lays it out.
+    fn encode_delegation(owner: [u8; 32], homeserver: &str) -> Vec<u8> {
+        let (_, bump) = derive_delegation_address(&owner, &program_id()).unwrap();
+        let mut data = Delegation::discriminator().to_vec();
+        data.extend_from_slice(&owner);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&(homeserver.len() as u32).to_le_bytes());
+        data.extend_from_slice(homeserver.as_bytes());
+        data.extend_from_slice(&NOW.to_le_bytes());
+        data.extend_from_slice(&[bump, 2, 0]);
+        data
+    }
+
+    /// Stands in for the RPC node: the registry's accounts by address, and the calls made.
+    struct MockRpc {
+        accounts: HashMap<String, Vec<u8>>,
+        calls: Vec<usize>,
+        failing_call: Option<usize>,
+    }
+
+    impl MockRpc {
+        /// Every third of `count` wallets has a delegation to `chat.example.com`.
+        fn new(count: usize) -> Self {
+            let accounts = (0..count)
+                .step_by(3)
+                .map(|index| {
+                    let (address, _) =
+                        derive_delegation_address(&owner(index), &program_id()).unwrap();
+                    (
+                        bs58::encode(address).into_string(),
+                        encode_delegation(owner(index), "chat.example.com"),
+                    )
+                })
+                .collect();
+            Self {
+                accounts,
+                calls: Vec::new(),
+                failing_call: None,
+            }
+        }
+
+        fn get_multiple_accounts(
+            &mut self,
+            addresses: Vec<String>,
+        ) -> std::future::Ready<Result<Vec<Option<AccountInfo>>>> {
+            self.calls.push(addresses.len());
+            if self.failing_call == Some(self.calls.len() - 1) {
+                return std::future::ready(Err(Error::BadServerResponse(
+                    "Solana RPC request failed.",
+                )));
+            }
+
+            let value = addresses
+                .iter()
+                .map(|address| {
+                    self.accounts.get(address).map(|data| {
+                        serde_json::json!({
+                            "data": [general_purpose::STANDARD.encode(data), "base64"],
+                            "executable": false,
+                            "lamports": 2_000_000,
+                            "owner": DEFAULT_REGISTRY_PROGRAM_ID,
+                            "rentEpoch": 0,
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+            let result: super::super::MultipleAccountsResult = serde_json::from_value(
+                serde_json::json!({ "context": { "slot": 1 }, "value": value }),
+            )
+            .unwrap();
+            std::future::ready(Ok(result.value))
+        }
+    }
+
+    fn resolve(rpc: &mut MockRpc, count: usize) -> BatchResolution {
+        let owners = (0..count).map(owner).collect::<Vec<_>>();
+        resolve_batch(
+            &owners,
+            &[program_id()],
+            RegistryConflictPolicy::Error,
+            NOW,
+            |addresses| rpc.get_multiple_accounts(addresses),
+        )
+        .now_or_never()
+        .unwrap()
+    }
+
+    #[test]
+    fn resolves_present_and_absent_delegations_in_chunks() {
+        let mut rpc = MockRpc::new(250);
+        let resolution = resolve(&mut rpc, 250);
+
+        assert_eq!(rpc.calls, vec![100, 100, 50]);
+        assert_eq!(resolution.failed, Vec::<usize>::new());
+        assert_eq!(resolution.delegations.len(), 250);
+        for (index, delegation) in resolution.delegations.iter().enumerate() {
+            match delegation {
+                Some(delegation) => {
+                    assert_eq!(index % 3, 0);
+                    assert_eq!(delegation.owner, owner(index));
+                    assert_eq!(delegation.homeservers, vec!["chat.example.com".to_owned()]);
+                }
+                None => assert_ne!(index % 3, 0),
+            }
+        }
+    }
+
+    #[test]
+    fn a_failed_chunk_only_fails_its_own_wallets() {
+        let mut rpc = MockRpc::new(250);
+        rpc.failing_call = Some(1);
+        let resolution = resolve(&mut rpc, 250);
+
+        assert_eq!(rpc.calls, vec![100, 100, 50]);
+        assert_eq!(resolution.failed, (100..200).collect::<Vec<_>>());
+        assert_eq!(resolution.delegations.len(), 250);
+        assert!(resolution.delegations[100..200].iter().all(Option::is_none));
+        assert!(resolution.delegations[0].is_some());
+        assert!(resolution.delegations[201].is_some());
+    }
+
+    /// A `getProgramAccounts` result with a delegation for each of the first `count`
+    /// wallets, and an account of the program with another discriminator.
+    fn mock_program_accounts(count: usize) -> Vec<ProgramAccount> {
+        let mut accounts = (0..count)
+            .map(|index| {
+                let (address, _) = derive_delegation_address(&owner(index), &program_id()).unwrap();
+                (address, encode_delegation(owner(index), "chat.example.com"))
+            })
+            .collect::<Vec<_>>();
+        let mut other = encode_delegation(owner(count), "chat.example.com");
+        other[..8].copy_from_slice(&[1; 8]);
+        accounts.insert(1, ([9; 32], other));
+
+        let value = accounts
+            .into_iter()
+            .map(|(address, data)| {
+                serde_json::json!({
+                    "pubkey": bs58::encode(address).into_string(),
+                    "account": {
+                        "data": [general_purpose::STANDARD.encode(data), "base64"],
+                        "executable": false,
+                        "lamports": 2_000_000,
+                        "owner": DEFAULT_REGISTRY_PROGRAM_ID,
+                        "rentEpoch": 0,
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(serde_json::Value::Array(value)).unwrap()
+    }
+
+    #[test]
+    fn lists_delegations_by_discriminator() {
+        let mut params = None;
+        let delegations = list_delegations(&program_id(), |sent| {
+            params = Some(sent);
+            std::future::ready(Ok(mock_program_accounts(3)))
+        })
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+
+        let params = params.unwrap();
+        assert_eq!(params[0], DEFAULT_REGISTRY_PROGRAM_ID);
+        assert_eq!(
+            params[1]["filters"][0]["memcmp"]["bytes"],
+            bs58::encode(Delegation::discriminator()).into_string()
+        );
+        assert!(params[1].get("dataSlice").is_none());
+
+        assert_eq!(delegations.len(), 3);
+        for (index, (address, delegation)) in delegations.iter().enumerate() {
+            let (expected, _) = derive_delegation_address(&owner(index), &program_id()).unwrap();
+            assert_eq!(address, &expected);
+            assert_eq!(delegation.owner, owner(index));
+            assert_eq!(delegation.homeservers, vec!["chat.example.com".to_owned()]);
+        }
+    }
+
+    #[test]
+    fn lists_primary_homeservers_from_a_slice() {
+        let mut params = None;
+        let summaries = list_primary_homeservers(&program_id(), |sent| {
+            params = Some(sent);
+            let mut accounts = mock_program_accounts(2);
+            // The node returns only the requested slice of each account.
+            for ProgramAccount { account, .. } in &mut accounts {
+                let mut data = general_purpose::STANDARD.decode(&account.data.0).unwrap();
+                data.truncate(PRIMARY_HOMESERVER_SLICE);
+                account.data.0 = general_purpose::STANDARD.encode(data);
+            }
+            accounts[0].account.owner = "11111111111111111111111111111111".to_owned();
+            // One account still in the first, single-homeserver layout.
+            let mut single = Delegation::discriminator().to_vec();
+            single.extend_from_slice(&owner(5));
+            single.extend_from_slice(&15u32.to_le_bytes());
+            single.extend_from_slice(b"old.example.com");
+            single.resize(PRIMARY_HOMESERVER_SLICE, 0);
+            accounts.push(ProgramAccount {
+                pubkey: bs58::encode([5; 32]).into_string(),
+                account: AccountInfo {
+                    data: (
+                        general_purpose::STANDARD.encode(single),
+                        "base64".to_owned(),
+                    ),
+                    owner: DEFAULT_REGISTRY_PROGRAM_ID.to_owned(),
+                },
+            });
+            std::future::ready(Ok(accounts))
+        })
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            params.unwrap()[1]["dataSlice"],
+            serde_json::json!({ "offset": 0, "length": PRIMARY_HOMESERVER_SLICE })
+        );
+        assert_eq!(
+            summaries
+                .into_iter()
+                .map(|(_, summary)| summary)
+                .collect::<Vec<_>>(),
+            vec![
+                DelegationSummary {
+                    owner: owner(1),
+                    primary_homeserver: Some("chat.example.com".to_owned()),
+                },
+                DelegationSummary {
+                    owner: owner(5),
+                    primary_homeserver: Some("old.example.com".to_owned()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_an_empty_batch_without_requests() {
+        let mut rpc = MockRpc::new(0);
+        assert_eq!(resolve(&mut rpc, 0), BatchResolution::default());
+        assert!(rpc.calls.is_empty());
+    }
+}
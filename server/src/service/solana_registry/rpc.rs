@@ -0,0 +1,475 @@
+//! The Solana RPC calls the registry reads accounts with.
+//!
+//! They go through [`RegistryRpc`], so tests can serve accounts from memory with
+//! [`MockRegistryRpc`] instead of reaching a cluster. The mock is built for tests, and into
+//! the server with the `mock_registry_rpc` feature, which then reads the registry from it
+//! instead of `solana_rpc_url`.
+
+#[cfg(any(test, feature = "mock_registry_rpc"))]
+use std::{collections::BTreeMap, sync::Mutex};
+
+use async_trait::async_trait;
+#[cfg(any(test, feature = "mock_registry_rpc"))]
+use base64::{engine::general_purpose, Engine as _};
+use http::header::CONTENT_TYPE;
+use serde::{de::DeserializeOwned, Deserialize};
+use tracing::warn;
+
+#[cfg(any(test, feature = "mock_registry_rpc"))]
+use super::{derive_delegation_address, Delegation};
+use super::{AccountInfo, MultipleAccountsResult, ProgramAccount};
+use crate::{services, Error, Result};
+
+/// Reads accounts from a Solana cluster. Plug one in as
+/// [`Service::rpc`](super::Service::rpc); the default is [`SolanaRpcClient`].
+#[async_trait]
+pub trait RegistryRpc: Send + Sync {
+    /// Read the account at a base58 address, `None` if there is none.
+    async fn get_account(&self, address: String) -> Result<Option<AccountInfo>>;
+
+    /// Read the accounts at base58 addresses, in the same order, `None` for each address
+    /// without one.
+    async fn get_multiple_accounts(
+        &self,
+        addresses: Vec<String>,
+    ) -> Result<Vec<Option<AccountInfo>>>;
+
+    /// List a program's accounts with `getProgramAccounts` parameters, like those from
+    /// [`delegation_accounts_params`](super::resolver::delegation_accounts_params).
+    async fn get_program_accounts(&self, params: serde_json::Value) -> Result<Vec<ProgramAccount>>;
+}
+
+/// Reads from `solana_rpc_url` over JSON-RPC, at `confirmed` commitment.
+pub struct SolanaRpcClient;
+
+impl SolanaRpcClient {
+    /// Send a JSON-RPC request to `solana_rpc_url` and return its `result`.
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<T> {
+        let rpc_url = services().globals.solana_rpc_url().ok_or_else(|| {
+            Error::bad_config("solana_rpc_url must be set to read the homeserver registry.")
+        })?;
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RpcResponse<T> {
+            Success { result: T },
+            Failure { error: serde_json::Value },
+        }
+
+        let response = services()
+            .globals
+            .default_client()
+            .post(rpc_url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "method": method,
+                    "params": params,
+                })
+                .to_string(),
+            )
+            .send()
+            .await?;
+
+        match serde_json::from_str::<RpcResponse<T>>(&response.text().await?) {
+            Ok(RpcResponse::Success { result }) => Ok(result),
+            Ok(RpcResponse::Failure { error }) => {
+                warn!("Solana RPC {method} failed: {error}");
+                Err(Error::BadServerResponse("Solana RPC returned an error"))
+            }
+            Err(_) => Err(Error::BadServerResponse("Bad Solana RPC response")),
+        }
+    }
+}
+
+#[async_trait]
+impl RegistryRpc for SolanaRpcClient {
+    async fn get_account(&self, address: String) -> Result<Option<AccountInfo>> {
+        #[derive(Deserialize)]
+        struct AccountInfoResult {
+            value: Option<AccountInfo>,
+        }
+
+        Ok(self
+            .call::<AccountInfoResult>(
+                "getAccountInfo",
+                serde_json::json!([
+                    address,
+                    { "encoding": "base64", "commitment": "confirmed" },
+                ]),
+            )
+            .await?
+            .value)
+    }
+
+    async fn get_multiple_accounts(
+        &self,
+        addresses: Vec<String>,
+    ) -> Result<Vec<Option<AccountInfo>>> {
+        Ok(self
+            .call::<MultipleAccountsResult>(
+                "getMultipleAccounts",
+                serde_json::json!([
+                    addresses,
+                    { "encoding": "base64", "commitment": "confirmed" },
+                ]),
+            )
+            .await?
+            .value)
+    }
+
+    async fn get_program_accounts(&self, params: serde_json::Value) -> Result<Vec<ProgramAccount>> {
+        self.call("getProgramAccounts", params).await
+    }
+}
+
+/// Serves accounts from memory, as a cluster holding only the accounts added to it would.
+/// `getProgramAccounts` applies `memcmp` filters and `dataSlice` like an RPC node does.
+#[cfg(any(test, feature = "mock_registry_rpc"))]
+#[derive(Default)]
+pub struct MockRegistryRpc {
+    /// Owning program and data of each account, by base58 address.
+    accounts: Mutex<BTreeMap<String, (String, Vec<u8>)>>,
+}
+
+#[cfg(any(test, feature = "mock_registry_rpc"))]
+#[cfg_attr(not(test), allow(dead_code))]
+impl MockRegistryRpc {
+    /// Serve `delegation` at its owner's PDA in `program_id`, laid out as the registry
+    /// program writes it.
+    pub fn insert_delegation(&self, program_id: &[u8; 32], delegation: &Delegation) {
+        let (address, _) = derive_delegation_address(&delegation.owner, program_id)
+            .expect("Delegation address is derivable");
+        self.insert_account(&address, program_id, encode_delegation(delegation));
+    }
+
+    /// Serve `data` at `address`, owned by `owner`, e.g. to stand in for a malformed or
+    /// foreign account.
+    pub fn insert_account(&self, address: &[u8; 32], owner: &[u8; 32], data: Vec<u8>) {
+        self.accounts.lock().unwrap().insert(
+            bs58::encode(address).into_string(),
+            (bs58::encode(owner).into_string(), data),
+        );
+    }
+
+    /// Stop serving the account at `address`, as if it was closed.
+    pub fn remove_account(&self, address: &[u8; 32]) {
+        self.accounts
+            .lock()
+            .unwrap()
+            .remove(&bs58::encode(address).into_string());
+    }
+}
+
+#[cfg(any(test, feature = "mock_registry_rpc"))]
+#[async_trait]
+impl RegistryRpc for MockRegistryRpc {
+    async fn get_account(&self, address: String) -> Result<Option<AccountInfo>> {
+        Ok(self
+            .accounts
+            .lock()
+            .unwrap()
+            .get(&address)
+            .map(|(owner, data)| account_info(owner, data)))
+    }
+
+    async fn get_multiple_accounts(
+        &self,
+        addresses: Vec<String>,
+    ) -> Result<Vec<Option<AccountInfo>>> {
+        let accounts = self.accounts.lock().unwrap();
+        Ok(addresses
+            .iter()
+            .map(|address| {
+                accounts
+                    .get(address)
+                    .map(|(owner, data)| account_info(owner, data))
+            })
+            .collect())
+    }
+
+    async fn get_program_accounts(&self, params: serde_json::Value) -> Result<Vec<ProgramAccount>> {
+        let bad_params = || Error::BadServerResponse("Bad getProgramAccounts parameters.");
+        let program_id = params[0].as_str().ok_or_else(bad_params)?;
+        let config = &params[1];
+
+        let mut filters = Vec::new();
+        for filter in config["filters"].as_array().into_iter().flatten() {
+            let memcmp = &filter["memcmp"];
+            let offset = memcmp["offset"].as_u64().ok_or_else(bad_params)? as usize;
+            let bytes = memcmp["bytes"]
+                .as_str()
+                .and_then(|bytes| bs58::decode(bytes).into_vec().ok())
+                .ok_or_else(bad_params)?;
+            filters.push((offset, bytes));
+        }
+        let slice = match &config["dataSlice"] {
+            serde_json::Value::Null => None,
+            slice => Some((
+                slice["offset"].as_u64().ok_or_else(bad_params)? as usize,
+                slice["length"].as_u64().ok_or_else(bad_params)? as usize,
+            )),
+        };
+
+        let accounts = self.accounts.lock().unwrap();
+        Ok(accounts
+            .iter()
+            .filter(|(_, (owner, data))| {
+                owner == program_id
+                    && filters.iter().all(|(offset, bytes)| {
+                        data.get(*offset..offset + bytes.len()) == Some(&bytes[..])
+                    })
+            })
+            .map(|(address, (owner, data))| {
+                let data = match slice {
+                    Some((offset, length)) => data
+                        .get(offset..)
+                        .unwrap_or_default()
+                        .iter()
+                        .take(length)
+                        .copied()
+                        .collect(),
+                    None => data.clone(),
+                };
+                ProgramAccount {
+                    pubkey: address.clone(),
+                    account: account_info(owner, &data),
+                }
+            })
+            .collect())
+    }
+}
+
+/// An account as an RPC node returns it, base64-encoded.
+#[cfg(any(test, feature = "mock_registry_rpc"))]
+fn account_info(owner: &str, data: &[u8]) -> AccountInfo {
+    AccountInfo {
+        data: (general_purpose::STANDARD.encode(data), "base64".to_owned()),
+        owner: owner.to_owned(),
+    }
+}
+
+/// `delegation` laid out as the registry program writes it, with the fields its version
+/// has. The label isn't kept in a [`Delegation`], so it is written as none.
+#[cfg(any(test, feature = "mock_registry_rpc"))]
+#[cfg_attr(not(test), allow(dead_code))]
+fn encode_delegation(delegation: &Delegation) -> Vec<u8> {
+    fn push_string(data: &mut Vec<u8>, value: &str) {
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        data.extend_from_slice(value.as_bytes());
+    }
+
+    let mut data = Delegation::discriminator().to_vec();
+    data.extend_from_slice(&delegation.owner);
+    data.extend_from_slice(&(delegation.homeservers.len() as u32).to_le_bytes());
+    for homeserver in &delegation.homeservers {
+        push_string(&mut data, homeserver);
+    }
+    data.extend_from_slice(&delegation.updated_at.to_le_bytes());
+    data.push(delegation.bump);
+    data.push(delegation.version);
+    match delegation.expires_at {
+        Some(expires_at) => {
+            data.push(1);
+            data.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        None => data.push(0),
+    }
+    if delegation.version >= 4 {
+        data.push(0);
+        match delegation.auth_pubkey {
+            Some(auth_pubkey) => {
+                data.push(1);
+                data.extend_from_slice(&auth_pubkey);
+            }
+            None => data.push(0),
+        }
+    }
+    if delegation.version >= 5 {
+        match &delegation.previous_homeserver {
+            Some(previous_homeserver) => {
+                data.push(1);
+                push_string(&mut data, previous_homeserver);
+            }
+            None => data.push(0),
+        }
+    }
+    if delegation.version >= 6 {
+        data.push(delegation.frozen.into());
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::{super::resolver, *};
+    use crate::{
+        config::RegistryConflictPolicy, service::solana_registry::DEFAULT_REGISTRY_PROGRAM_ID,
+    };
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn program_id() -> [u8; 32] {
+        bs58::decode(DEFAULT_REGISTRY_PROGRAM_ID)
+            .into_vec()
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    fn delegation(owner: [u8; 32], homeserver: &str) -> Delegation {
+        let (_, bump) = derive_delegation_address(&owner, &program_id()).unwrap();
+        Delegation {
+            owner,
+            homeservers: vec![homeserver.to_owned()],
+            updated_at: NOW,
+            bump,
+            version: 6,
+            expires_at: None,
+            auth_pubkey: Some([9; 32]),
+            previous_homeserver: Some("old.example.com".to_owned()),
+            frozen: false,
+        }
+    }
+
+    fn delegation_address(owner: [u8; 32]) -> [u8; 32] {
+        derive_delegation_address(&owner, &program_id()).unwrap().0
+    }
+
+    fn resolve(rpc: &MockRegistryRpc, owners: &[[u8; 32]]) -> resolver::BatchResolution {
+        resolver::resolve_batch(
+            owners,
+            &[program_id()],
+            RegistryConflictPolicy::Error,
+            NOW,
+            |addresses| rpc.get_multiple_accounts(addresses),
+        )
+        .now_or_never()
+        .unwrap()
+    }
+
+    #[test]
+    fn serves_accounts_by_address() {
+        let rpc = MockRegistryRpc::default();
+        let present = delegation([1; 32], "chat.example.com");
+        rpc.insert_delegation(&program_id(), &present);
+
+        let address = bs58::encode(delegation_address([1; 32])).into_string();
+        let account = rpc
+            .get_account(address.clone())
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.unwrap().owner, DEFAULT_REGISTRY_PROGRAM_ID);
+        let absent = bs58::encode(delegation_address([2; 32])).into_string();
+        assert!(rpc
+            .get_account(absent.clone())
+            .now_or_never()
+            .unwrap()
+            .unwrap()
+            .is_none());
+
+        let accounts = rpc
+            .get_multiple_accounts(vec![absent, address])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert!(accounts[0].is_none());
+        let data = general_purpose::STANDARD
+            .decode(&accounts[1].as_ref().unwrap().data.0)
+            .unwrap();
+        assert_eq!(Delegation::decode(&data), Some(present));
+
+        rpc.remove_account(&delegation_address([1; 32]));
+        assert_eq!(resolve(&rpc, &[[1; 32]]).delegations, vec![None]);
+    }
+
+    #[test]
+    fn resolves_present_absent_and_malformed_delegations() {
+        let rpc = MockRegistryRpc::default();
+        rpc.insert_delegation(&program_id(), &delegation([1; 32], "chat.example.com"));
+
+        // A delegation and a wallet without one both resolve.
+        let resolution = resolve(&rpc, &[[1; 32], [2; 32]]);
+        assert_eq!(resolution.failed, Vec::<usize>::new());
+        assert_eq!(
+            resolution.delegations,
+            vec![Some(delegation([1; 32], "chat.example.com")), None]
+        );
+
+        // Account data that isn't a delegation can't be told apart from a registration,
+        // so the wallets read with it are reported as failed rather than unregistered.
+        let mut truncated = encode_delegation(&delegation([3; 32], "chat.example.com"));
+        truncated.truncate(50);
+        rpc.insert_account(&delegation_address([3; 32]), &program_id(), truncated);
+        let resolution = resolve(&rpc, &[[1; 32], [3; 32]]);
+        assert_eq!(resolution.failed, vec![0, 1]);
+        assert_eq!(resolution.delegations, vec![None, None]);
+
+        // So does an account at the PDA that another program owns.
+        rpc.insert_account(
+            &delegation_address([3; 32]),
+            &[7; 32],
+            encode_delegation(&delegation([3; 32], "chat.example.com")),
+        );
+        assert_eq!(resolve(&rpc, &[[3; 32]]).failed, vec![0]);
+    }
+
+    #[test]
+    fn lists_only_well_formed_delegations_of_the_program() {
+        let rpc = MockRegistryRpc::default();
+        rpc.insert_delegation(&program_id(), &delegation([1; 32], "chat.example.com"));
+        rpc.insert_delegation(&program_id(), &delegation([2; 32], "other.example.com"));
+        rpc.insert_delegation(&[7; 32], &delegation([3; 32], "chat.example.com"));
+        let mut truncated = encode_delegation(&delegation([4; 32], "chat.example.com"));
+        truncated.truncate(50);
+        rpc.insert_account(&delegation_address([4; 32]), &program_id(), truncated);
+        rpc.insert_account(&[5; 32], &program_id(), vec![1; 80]);
+
+        let mut delegations =
+            resolver::list_delegations(&program_id(), |params| rpc.get_program_accounts(params))
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        delegations.sort_by_key(|(_, delegation)| delegation.owner);
+        assert_eq!(
+            delegations,
+            vec![
+                (
+                    delegation_address([1; 32]),
+                    delegation([1; 32], "chat.example.com")
+                ),
+                (
+                    delegation_address([2; 32]),
+                    delegation([2; 32], "other.example.com")
+                ),
+            ]
+        );
+
+        let mut summaries = resolver::list_primary_homeservers(&program_id(), |params| {
+            rpc.get_program_accounts(params)
+        })
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+        summaries.sort_by_key(|(_, summary)| summary.owner);
+        assert_eq!(
+            summaries
+                .into_iter()
+                .map(|(_, summary)| summary.primary_homeserver)
+                .collect::<Vec<_>>(),
+            vec![
+                Some("chat.example.com".to_owned()),
+                Some("other.example.com".to_owned())
+            ]
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Reads a wallet's primary Solana Name Service (SNS) domain, like "alice.sol".
+//!
+//! The primary domain a wallet picked is recorded by the SNS name offers program in a PDA
+//! seeded by `["favourite_domain", owner]`, which points at the domain's name account.
+//! The name itself is in the domain's reverse lookup account in the SPL Name Service. A
+//! domain only counts while the wallet still owns it, since primary domains aren't
+//! cleared when a domain is sold.
+
+use std::future::Future;
+
+use base64::{engine::general_purpose, Engine as _};
+use sha2::{Digest, Sha256};
+
+use super::{create_program_address, token_account::decode_program_id, AccountInfo};
+use crate::{Error, Result};
+
+/// The SPL Name Service program.
+const NAME_SERVICE_PROGRAM_ID: &str = "namesLPneVptA9Z5rqUDD9tMTWEJwofgaYwp8cawRkX";
+
+/// The SNS name offers program, which records each wallet's primary domain.
+const NAME_OFFERS_PROGRAM_ID: &str = "85iDfUvr3HJyLM2LkY9i2dfE2TadNbEM4XPZP2CKnMWw";
+
+/// Class of the name accounts that map a domain back to its name.
+const REVERSE_LOOKUP_CLASS: &str = "33m47vH6Eav6jr5Ry86XjhRft2jRBLDnDgPSHoquXi2Z";
+
+/// Name account of the `.sol` top-level domain, the parent of every `.sol` domain.
+const SOL_TLD: &str = "58PwtjSDuFHuUkYjH9BYnnQKHfwo9reZhC2zMJv9JPkx";
+
+/// Seed prefix for primary domain PDAs.
+const FAVOURITE_DOMAIN_SEED: &[u8] = b"favourite_domain";
+
+/// Prepended to a name before it is hashed into a name account's seed.
+const HASH_PREFIX: &str = "SPL Name Service";
+
+/// A name account starts with its parent, owner and class, then its data.
+const NAME_HEADER_LENGTH: usize = 96;
+
+/// Find the PDA recording `owner`'s primary domain, like `FavouriteDomain.getKeySync`.
+pub fn derive_favourite_domain_address(owner: &[u8; 32]) -> Option<[u8; 32]> {
+    let program_id = decode_program_id(NAME_OFFERS_PROGRAM_ID);
+    (0..=u8::MAX).rev().find_map(|bump| {
+        create_program_address(&[FAVOURITE_DOMAIN_SEED, owner, &[bump]], &program_id)
+    })
+}
+
+/// Find the reverse lookup account of the domain at `domain`, like `getReverseKeySync`.
+pub fn derive_reverse_lookup_address(domain: &[u8; 32]) -> Option<[u8; 32]> {
+    let program_id = decode_program_id(NAME_SERVICE_PROGRAM_ID);
+    let hashed_name = Sha256::new()
+        .chain_update(HASH_PREFIX)
+        .chain_update(bs58::encode(domain).into_string())
+        .finalize();
+    let class = decode_program_id(REVERSE_LOOKUP_CLASS);
+    (0..=u8::MAX).rev().find_map(|bump| {
+        create_program_address(&[&hashed_name, &class, &[0; 32], &[bump]], &program_id)
+    })
+}
+
+/// The primary `.sol` domain of `owner`, reading accounts by base58 address with `fetch`,
+/// which returns them in the same order like `getMultipleAccounts`. `None` if the wallet
+/// picked none, no longer owns it, or it isn't a plain `.sol` domain.
+pub async fn primary_domain<F, Fut>(owner: &[u8; 32], mut fetch: F) -> Result<Option<String>>
+where
+    F: FnMut(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<Option<AccountInfo>>>>,
+{
+    let favourite = derive_favourite_domain_address(owner).ok_or(Error::BadServerResponse(
+        "Could not derive the primary domain address.",
+    ))?;
+    let [favourite] = fetch_accounts(&mut fetch, [favourite]).await?;
+    let Some(favourite) = favourite else {
+        return Ok(None);
+    };
+    let domain = account_data(favourite, NAME_OFFERS_PROGRAM_ID)?
+        .get(1..33)
+        .and_then(|domain| <[u8; 32]>::try_from(domain).ok())
+        .ok_or(Error::BadServerResponse(
+            "Primary domain account is truncated.",
+        ))?;
+
+    let reverse = derive_reverse_lookup_address(&domain).ok_or(Error::BadServerResponse(
+        "Could not derive the reverse lookup address.",
+    ))?;
+    let [Some(domain_account), Some(reverse)] =
+        fetch_accounts(&mut fetch, [domain, reverse]).await?
+    else {
+        return Ok(None);
+    };
+
+    let domain_account = account_data(domain_account, NAME_SERVICE_PROGRAM_ID)?;
+    let parent = domain_account.get(..32);
+    let domain_owner = domain_account.get(32..64);
+    if parent != Some(&decode_program_id(SOL_TLD)[..]) || domain_owner != Some(&owner[..]) {
+        return Ok(None);
+    }
+
+    let reverse = account_data(reverse, NAME_SERVICE_PROGRAM_ID)?;
+    Ok(reverse_lookup_name(&reverse).map(|name| format!("{name}.sol")))
+}
+
+/// Read `addresses` with `fetch`, checking one account came back for each.
+async fn fetch_accounts<F, Fut, const N: usize>(
+    fetch: &mut F,
+    addresses: [[u8; 32]; N],
+) -> Result<[Option<AccountInfo>; N]>
+where
+    F: FnMut(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<Option<AccountInfo>>>>,
+{
+    let addresses = addresses
+        .iter()
+        .map(|address| bs58::encode(address).into_string())
+        .collect();
+    fetch(addresses)
+        .await?
+        .try_into()
+        .map_err(|_| Error::BadServerResponse("Solana RPC returned the wrong number of accounts."))
+}
+
+/// The data of an account fetched over RPC, checking `program_id` owns it.
+fn account_data(account: AccountInfo, program_id: &str) -> Result<Vec<u8>> {
+    if account.owner != program_id {
+        return Err(Error::BadServerResponse(
+            "SNS account is not owned by the SNS program.",
+        ));
+    }
+    general_purpose::STANDARD
+        .decode(account.data.0)
+        .map_err(|_| Error::BadServerResponse("Bad account data encoding"))
+}
+
+/// The name in a reverse lookup account: a length-prefixed string after the header. Names
+/// that couldn't be shown as a display name, empty or with dots, spaces or control
+/// characters, read as none.
+fn reverse_lookup_name(data: &[u8]) -> Option<&str> {
+    let data = data.get(NAME_HEADER_LENGTH..)?;
+    let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let name = std::str::from_utf8(data.get(4..4 + len)?).ok()?;
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c != '.' && !c.is_whitespace() && !c.is_control());
+    valid.then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    const OWNER: [u8; 32] = [1; 32];
+    const DOMAIN: [u8; 32] = [2; 32];
+
+    fn name_account(parent: &[u8], owner: &[u8; 32], data: &[u8]) -> Vec<u8> {
+        let mut account = parent.to_vec();
+        account.extend_from_slice(owner);
+        account.extend_from_slice(&[0; 32]);
+        account.extend_from_slice(data);
+        account
+    }
+
+    fn reverse_lookup(name: &str) -> Vec<u8> {
+        let mut data = (name.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(name.as_bytes());
+        name_account(&[0; 32], &[0; 32], &data)
+    }
+
+    /// The accounts of a wallet whose primary domain is `DOMAIN`, named `name` and owned
+    /// by `domain_owner`.
+    fn mock_chain(
+        name: &str,
+        domain_owner: [u8; 32],
+    ) -> HashMap<[u8; 32], (&'static str, Vec<u8>)> {
+        let mut favourite = vec![1];
+        favourite.extend_from_slice(&DOMAIN);
+        HashMap::from([
+            (
+                derive_favourite_domain_address(&OWNER).unwrap(),
+                (NAME_OFFERS_PROGRAM_ID, favourite),
+            ),
+            (
+                DOMAIN,
+                (
+                    NAME_SERVICE_PROGRAM_ID,
+                    name_account(&decode_program_id(SOL_TLD), &domain_owner, &[]),
+                ),
+            ),
+            (
+                derive_reverse_lookup_address(&DOMAIN).unwrap(),
+                (NAME_SERVICE_PROGRAM_ID, reverse_lookup(name)),
+            ),
+        ])
+    }
+
+    /// Resolve `OWNER`'s primary domain against a mocked `getMultipleAccounts`.
+    fn resolve(chain: &HashMap<[u8; 32], (&'static str, Vec<u8>)>) -> Result<Option<String>> {
+        let fetch = |addresses: Vec<String>| {
+            let accounts = addresses
+                .iter()
+                .map(|address| {
+                    let address: [u8; 32] = bs58::decode(address)
+                        .into_vec()
+                        .unwrap()
+                        .try_into()
+                        .unwrap();
+                    let (owner, data) = chain.get(&address)?;
+                    Some(AccountInfo {
+                        data: (general_purpose::STANDARD.encode(data), "base64".to_owned()),
+                        owner: (*owner).to_owned(),
+                    })
+                })
+                .collect();
+            std::future::ready(Ok(accounts))
+        };
+        primary_domain(&OWNER, fetch).now_or_never().unwrap()
+    }
+
+    #[test]
+    fn resolves_the_primary_domain_the_wallet_owns() {
+        assert_eq!(
+            resolve(&mock_chain("alice", OWNER)).unwrap().as_deref(),
+            Some("alice.sol")
+        );
+    }
+
+    #[test]
+    fn wallets_without_a_usable_primary_domain_have_none() {
+        // No primary domain picked.
+        assert_eq!(resolve(&HashMap::new()).unwrap(), None);
+
+        // The domain was sold since.
+        assert_eq!(resolve(&mock_chain("alice", [9; 32])).unwrap(), None);
+
+        // A subdomain, or a name that would make an odd display name.
+        let mut subdomain = mock_chain("alice", OWNER);
+        subdomain.get_mut(&DOMAIN).unwrap().1[..32].copy_from_slice(&[8; 32]);
+        assert_eq!(resolve(&subdomain).unwrap(), None);
+        for name in ["", "bob.alice", "alice\n"] {
+            assert_eq!(resolve(&mock_chain(name, OWNER)).unwrap(), None, "{name:?}");
+        }
+
+        // An account the name service doesn't own is an RPC error, not a missing name.
+        let mut forged = mock_chain("alice", OWNER);
+        forged.get_mut(&DOMAIN).unwrap().0 = NAME_OFFERS_PROGRAM_ID;
+        assert!(resolve(&forged).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+//! SPL token accounts: their layout and where a wallet's balance of a mint lives.
+
+use base64::{engine::general_purpose, Engine as _};
+
+use super::{create_program_address, AccountInfo, BorshReader};
+use crate::{Error, Result};
+
+/// The SPL Token program.
+pub const TOKEN_PROGRAM_ID: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+
+/// The Associated Token Account program.
+const ASSOCIATED_TOKEN_PROGRAM_ID: &str = "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL";
+
+/// The fields we read from an SPL token account.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TokenAccount {
+    pub mint: [u8; 32],
+    pub owner: [u8; 32],
+    /// Balance in the mint's base units, before applying its decimals.
+    pub amount: u64,
+}
+
+impl TokenAccount {
+    /// Decode an account fetched over RPC, checking the token program owns it.
+    pub fn from_account(account: AccountInfo) -> Result<Self> {
+        if account.owner != TOKEN_PROGRAM_ID {
+            return Err(Error::BadServerResponse(
+                "Token account is not owned by the token program.",
+            ));
+        }
+
+        let data = general_purpose::STANDARD
+            .decode(account.data.0)
+            .map_err(|_| Error::BadServerResponse("Bad account data encoding"))?;
+        Self::decode(&data).ok_or(Error::BadServerResponse("Token account data is truncated."))
+    }
+
+    /// Token account layout: mint, owner, then the amount as a little-endian u64.
+    fn decode(data: &[u8]) -> Option<Self> {
+        let mut reader = BorshReader(data);
+        Some(Self {
+            mint: reader.array::<32>()?,
+            owner: reader.array::<32>()?,
+            amount: u64::from_le_bytes(reader.array::<8>()?),
+        })
+    }
+}
+
+/// Decode one of the well-known program IDs above.
+pub fn decode_program_id(address: &str) -> [u8; 32] {
+    bs58::decode(address)
+        .into_vec()
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .expect("Program IDs are valid public keys")
+}
+
+/// Find the associated token account of `owner` for `mint`, like
+/// `getAssociatedTokenAddressSync`.
+pub fn derive_associated_token_address(owner: &[u8; 32], mint: &[u8; 32]) -> Option<[u8; 32]> {
+    let token_program = decode_program_id(TOKEN_PROGRAM_ID);
+    let program_id = decode_program_id(ASSOCIATED_TOKEN_PROGRAM_ID);
+    (0..=u8::MAX).rev().find_map(|bump| {
+        create_program_address(&[owner, &token_program, mint, &[bump]], &program_id)
+    })
+}
+
+/// The balance of `mint` in the associated token account `getMultipleAccounts` returned
+/// for `owner`, in base units. A wallet that never held the token has no account, which
+/// is a balance of 0.
+pub fn associated_balance(
+    owner: &[u8; 32],
+    mint: &[u8; 32],
+    account: Option<AccountInfo>,
+) -> Result<u64> {
+    let Some(account) = account else {
+        return Ok(0);
+    };
+
+    let token_account = TokenAccount::from_account(account)?;
+    if &token_account.mint != mint || &token_account.owner != owner {
+        return Err(Error::BadServerResponse(
+            "Associated token account is for a different mint or wallet.",
+        ));
+    }
+    Ok(token_account.amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: [u8; 32] = [1; 32];
+    const MINT: [u8; 32] = [2; 32];
+    /// 100 tokens of a 6-decimal mint, in base units.
+    const THRESHOLD: u64 = 100_000_000;
+
+    /// The associated token account `getMultipleAccounts` returns for a wallet holding
+    /// `amount` base units of `MINT`, or `null` when it has none.
+    fn mock_account(amount: Option<u64>) -> Option<AccountInfo> {
+        let value = amount.map(|amount| {
+            let mut data = MINT.to_vec();
+            data.extend_from_slice(&OWNER);
+            data.extend_from_slice(&amount.to_le_bytes());
+            data.resize(165, 0);
+            serde_json::json!({
+                "data": [general_purpose::STANDARD.encode(data), "base64"],
+                "executable": false,
+                "lamports": 2_039_280,
+                "owner": TOKEN_PROGRAM_ID,
+                "rentEpoch": 0,
+            })
+        });
+        serde_json::from_value::<super::super::MultipleAccountsResult>(serde_json::json!({
+            "context": { "slot": 1 },
+            "value": [value],
+        }))
+        .unwrap()
+        .value
+        .pop()
+        .unwrap()
+    }
+
+    #[test]
+    fn compares_raw_balances_against_the_threshold() {
+        for (amount, allowed) in [
+            (Some(THRESHOLD - 1), false),
+            (Some(THRESHOLD), true),
+            (Some(THRESHOLD + 1), true),
+            (Some(0), false),
+            (None, false),
+        ] {
+            let balance = associated_balance(&OWNER, &MINT, mock_account(amount)).unwrap();
+            assert_eq!(balance, amount.unwrap_or(0));
+            assert_eq!(balance >= THRESHOLD, allowed, "{amount:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_accounts_for_another_mint_or_wallet() {
+        assert!(associated_balance(&[5; 32], &MINT, mock_account(Some(THRESHOLD))).is_err());
+        assert!(associated_balance(&OWNER, &[5; 32], mock_account(Some(THRESHOLD))).is_err());
+
+        let mut wrong_program = mock_account(Some(THRESHOLD));
+        wrong_program.as_mut().unwrap().owner = "11111111111111111111111111111111".to_owned();
+        assert!(associated_balance(&OWNER, &MINT, wrong_program).is_err());
+    }
+}
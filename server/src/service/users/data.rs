@@ -19,6 +19,33 @@ pub trait Data: Send + Sync {
     /// Returns the number of users registered on this server.
     fn count(&self) -> Result<usize>;
 
+    /// When an account with this localpart was last deactivated, in milliseconds since the
+    /// unix epoch.
+    fn last_deactivation(&self, localpart: &str) -> Result<Option<u64>>;
+
+    /// Record that an account with this localpart was deactivated at `millis`.
+    fn set_last_deactivation(&self, localpart: &str, millis: u64) -> Result<()>;
+
+    /// When the device's current wallet session started, in milliseconds since the unix epoch.
+    fn session_started(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<u64>>;
+
+    /// Record that the device's current wallet session started at `millis`.
+    fn set_session_started(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        millis: u64,
+    ) -> Result<()>;
+
+    /// Wallets linked to the account in addition to the one it signs in with.
+    fn linked_wallets(&self, user_id: &UserId) -> Result<Vec<[u8; 32]>>;
+
+    /// Link a wallet to the account, and make the wallet sign in to it.
+    fn link_wallet(&self, user_id: &UserId, wallet: &[u8; 32]) -> Result<()>;
+
+    /// The account a wallet signs in to, if one was linked to it.
+    fn wallet_login_user(&self, wallet: &[u8; 32]) -> Result<Option<OwnedUserId>>;
+
     /// Find out which user an access token belongs to.
     fn find_from_token(&self, token: &str) -> Result<Option<(OwnedUserId, OwnedDeviceId)>>;
 
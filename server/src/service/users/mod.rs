@@ -22,7 +22,7 @@ use ruma::{
 use tokio::{sync::Mutex, time::interval};
 use tracing::{debug, warn};
 
-use crate::{services, Error, Result};
+use crate::{services, utils, Error, Result};
 
 pub struct SlidingSyncCache {
     lists: BTreeMap<String, sync_events::v5::request::List>,
@@ -318,6 +318,13 @@ impl Service {
         self.db.password_hash(user_id)
     }
 
+    /// Returns true if the user can log in with a password. Never exposes the hash itself.
+    pub fn has_password(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self
+            .password_hash(user_id)?
+            .is_some_and(|hash| !hash.is_empty()))
+    }
+
     /// Hash and set the user's password to the Argon2 hash
     pub fn set_password(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
         self.db.set_password(user_id, password)
@@ -604,10 +611,48 @@ impl Service {
         // password without logging in should check if the account is deactivated.
         self.db.set_password(user_id, None)?;
 
+        // Remembered so wallet logins can refuse to recreate the account straight away
+        self.db
+            .set_last_deactivation(user_id.localpart(), utils::millis_since_unix_epoch())?;
+        services().solana.forget_user(user_id);
+
         // TODO: Unhook 3PID
         Ok(())
     }
 
+    /// When an account with this localpart was last deactivated, in milliseconds since the
+    /// unix epoch.
+    pub fn last_deactivation(&self, localpart: &str) -> Result<Option<u64>> {
+        self.db.last_deactivation(localpart)
+    }
+
+    /// When the device's current wallet session started, in milliseconds since the unix epoch.
+    pub fn session_started(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<u64>> {
+        self.db.session_started(user_id, device_id)
+    }
+
+    /// Start the lifetime clock for the device's wallet session now.
+    pub fn start_session(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        self.db
+            .set_session_started(user_id, device_id, utils::millis_since_unix_epoch())
+    }
+
+    /// Wallets linked to the account in addition to the one it signs in with.
+    pub fn linked_wallets(&self, user_id: &UserId) -> Result<Vec<[u8; 32]>> {
+        self.db.linked_wallets(user_id)
+    }
+
+    /// Link a wallet to the account: `m.login.solana.signature` logins with the wallet
+    /// sign in to the account, and its registry delegation counts for it.
+    pub fn link_wallet(&self, user_id: &UserId, wallet: &[u8; 32]) -> Result<()> {
+        self.db.link_wallet(user_id, wallet)
+    }
+
+    /// The account a wallet signs in to instead of its own, if one was linked to it.
+    pub fn wallet_login_user(&self, wallet: &[u8; 32]) -> Result<Option<OwnedUserId>> {
+        self.db.wallet_login_user(wallet)
+    }
+
     /// Creates a new sync filter. Returns the filter id.
     pub fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String> {
         self.db.create_filter(user_id, filter)
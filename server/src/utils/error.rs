@@ -11,7 +11,10 @@ use ruma::{
 use thiserror::Error;
 use tracing::{error, info};
 
-use crate::RumaResponse;
+use crate::{
+    api::client_server::solana_auth::{self, NonceResponse},
+    RumaResponse,
+};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -66,6 +69,19 @@ pub enum Error {
     Uiaa(UiaaInfo),
     #[error("{n}: {1}", n = _0.errcode())]
     BadRequest(ErrorKind, &'static str),
+    /// A refused Solana login with its own errcode, e.g. `M_SOLANA_NONCE_EXPIRED`, so
+    /// clients can tell the failures they can recover from apart. Returned as 403.
+    #[error("{0}: {1}")]
+    SolanaAuth(&'static str, &'static str),
+    /// A Solana login whose nonce had only just expired, with a new challenge for the
+    /// client to have signed instead. Returned as 403 `M_SOLANA_NONCE_EXPIRED` with the
+    /// challenge under `retry`.
+    #[error(
+        "{}: {}",
+        solana_auth::ERRCODE_NONCE_EXPIRED,
+        solana_auth::EXPIRED_NONCE
+    )]
+    SolanaNonceReissued(Box<NonceResponse>),
     #[error("{0}")]
     Conflict(&'static str), // This is only needed for when a room alias already exists
     #[cfg(feature = "conduit_bin")]
@@ -112,6 +128,26 @@ impl Error {
             return RumaResponse(UiaaResponse::MatrixError(error));
         }
 
+        let solana_body = match self {
+            Self::SolanaAuth(errcode, message) => Some(serde_json::json!({
+                "errcode": errcode,
+                "error": message,
+            })),
+            Self::SolanaNonceReissued(challenge) => Some(serde_json::json!({
+                "errcode": solana_auth::ERRCODE_NONCE_EXPIRED,
+                "error": solana_auth::EXPIRED_NONCE,
+                "retry": challenge,
+            })),
+            _ => None,
+        };
+        if let Some(body) = solana_body {
+            info!("Returning an error: {}: {self}", StatusCode::FORBIDDEN);
+            return RumaResponse(UiaaResponse::MatrixError(RumaError {
+                body: ErrorBody::Json(body),
+                status_code: StatusCode::FORBIDDEN,
+            }));
+        }
+
         let message = format!("{self}");
 
         use ErrorKind::*;
@@ -0,0 +1,81 @@
+//! Login throughput with inline vs pooled signature verification.
+//!
+//! Mirrors `services().solana.verify_signature`: verifications are handed to a pool of
+//! `solana_verify_workers` threads (the CPU count by default) instead of running one
+//! after another on the request thread.
+//!
+//! Run with `cargo bench`.
+
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+const LOGINS: usize = 4_000;
+
+fn signed_logins() -> Vec<(VerifyingKey, Vec<u8>, Signature)> {
+    (0..LOGINS)
+        .map(|index| {
+            let secret: [u8; 32] = Sha256::digest(index.to_le_bytes()).into();
+            let signing_key = SigningKey::from_bytes(&secret);
+            let message = format!(
+                "Sign in to chat.example.com\n\nNonce: {index:064x}\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees."
+            )
+            .into_bytes();
+            let signature = signing_key.sign(&message);
+            (signing_key.verifying_key(), message, signature)
+        })
+        .collect()
+}
+
+fn inline(logins: &[(VerifyingKey, Vec<u8>, Signature)]) -> Duration {
+    let start = Instant::now();
+    for (key, message, signature) in logins {
+        assert!(key.verify(message, signature).is_ok());
+    }
+    start.elapsed()
+}
+
+fn pooled(logins: Arc<Vec<(VerifyingKey, Vec<u8>, Signature)>>, workers: usize) -> Duration {
+    let start = Instant::now();
+    let (results, verified) = mpsc::channel();
+
+    let handles: Vec<_> = (0..workers)
+        .map(|worker| {
+            let logins = Arc::clone(&logins);
+            let results = results.clone();
+            thread::spawn(move || {
+                for (key, message, signature) in logins.iter().skip(worker).step_by(workers) {
+                    results.send(key.verify(message, signature).is_ok()).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(results);
+
+    assert_eq!(verified.iter().filter(|ok| *ok).count(), LOGINS);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let workers = thread::available_parallelism().map_or(1, |count| count.get());
+    let logins = signed_logins();
+
+    let inline_time = inline(&logins);
+    let pooled_time = pooled(Arc::new(logins), workers);
+
+    let per_second = |elapsed: Duration| LOGINS as f64 / elapsed.as_secs_f64();
+    println!("{LOGINS} logins");
+    println!("inline:            {:>10.0} logins/s", per_second(inline_time));
+    println!(
+        "pool ({workers:>2} workers): {:>10.0} logins/s",
+        per_second(pooled_time)
+    );
+}
@@ -91,7 +91,7 @@ fn sign_and_verify_challenge_message() {
     let server_name = "chat.example.com";
     let nonce = "abc123def456";
     let message = format!(
-        "Sign in to {server_name}\n\nNonce: {nonce}\n\nThis signature will not trigger a blockchain transaction or cost any fees."
+        "Sign in to {server_name}\n\nNonce: {nonce}\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees."
     );
 
     // Sign the message (this is what the wallet does)
@@ -106,7 +106,7 @@ fn wrong_key_fails_verification() {
     let signing_key = test_signing_key(6);
     let wrong_key = test_signing_key(7);
 
-    let message = "Sign in to chat.example.com\n\nNonce: test123\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
+    let message = "Sign in to chat.example.com\n\nNonce: test123\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
     let signature = signing_key.sign(message.as_bytes());
 
     // Verification with wrong key must fail
@@ -119,11 +119,11 @@ fn tampered_message_fails_verification() {
     let signing_key = test_signing_key(8);
     let verifying_key = signing_key.verifying_key();
 
-    let message = "Sign in to chat.example.com\n\nNonce: test123\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
+    let message = "Sign in to chat.example.com\n\nNonce: test123\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
     let signature = signing_key.sign(message.as_bytes());
 
     // Tamper with the message
-    let tampered = "Sign in to evil.example.com\n\nNonce: test123\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
+    let tampered = "Sign in to evil.example.com\n\nNonce: test123\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
     assert!(verifying_key.verify(tampered.as_bytes(), &signature).is_err());
 }
 
@@ -132,14 +132,27 @@ fn different_nonce_fails_verification() {
     let signing_key = test_signing_key(9);
     let verifying_key = signing_key.verifying_key();
 
-    let message = "Sign in to chat.example.com\n\nNonce: original_nonce\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
+    let message = "Sign in to chat.example.com\n\nNonce: original_nonce\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
     let signature = signing_key.sign(message.as_bytes());
 
     // Replay attack: verify against a different nonce
-    let replayed = "Sign in to chat.example.com\n\nNonce: different_nonce\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
+    let replayed = "Sign in to chat.example.com\n\nNonce: different_nonce\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
     assert!(verifying_key.verify(replayed.as_bytes(), &signature).is_err());
 }
 
+#[test]
+fn extended_expiry_fails_verification() {
+    let signing_key = test_signing_key(11);
+    let verifying_key = signing_key.verifying_key();
+
+    let message = "Sign in to chat.example.com\n\nNonce: test123\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
+    let signature = signing_key.sign(message.as_bytes());
+
+    // A client can't stretch a challenge's lifetime by rewriting the expiry line
+    let extended = "Sign in to chat.example.com\n\nNonce: test123\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-17T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees.";
+    assert!(verifying_key.verify(extended.as_bytes(), &signature).is_err());
+}
+
 #[test]
 fn base58_signature_roundtrip() {
     let signing_key = test_signing_key(10);
@@ -209,7 +222,7 @@ fn full_auth_flow_simulation() {
     let server_name = "solchat.example.com";
     let nonce = hex::encode([0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89]);
     let challenge = format!(
-        "Sign in to {server_name}\n\nNonce: {nonce}\n\nThis signature will not trigger a blockchain transaction or cost any fees."
+        "Sign in to {server_name}\n\nNonce: {nonce}\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees."
     );
 
     // Step 3: Client signs the challenge
@@ -238,7 +251,7 @@ fn full_auth_flow_simulation() {
 
     // Reconstruct the challenge message on the server side
     let server_challenge = format!(
-        "Sign in to {server_name}\n\nNonce: {nonce}\n\nThis signature will not trigger a blockchain transaction or cost any fees."
+        "Sign in to {server_name}\n\nNonce: {nonce}\nIssued At: 2026-10-16T12:00:00Z\nExpires At: 2026-10-16T12:05:00Z\n\nThis signature will not trigger a blockchain transaction or cost any fees."
     );
 
     // Verify
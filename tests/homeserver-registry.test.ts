@@ -1,8 +1,16 @@
 import { before, describe, test } from "node:test";
 import assert from "node:assert";
+import { createHash } from "node:crypto";
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
-import { PublicKey, Keypair, SystemProgram } from "@solana/web3.js";
+import {
+  PublicKey,
+  Keypair,
+  SystemProgram,
+  Ed25519Program,
+  SYSVAR_INSTRUCTIONS_PUBKEY,
+  Transaction,
+} from "@solana/web3.js";
 import { HomeserverRegistry } from "../target/types/homeserver_registry";
 
 describe("homeserver-registry", () => {
@@ -22,6 +30,19 @@ describe("homeserver-registry", () => {
     return delegationAddress;
   };
 
+  const eventParser = new anchor.EventParser(
+    program.programId,
+    new anchor.BorshCoder(program.idl)
+  );
+
+  const eventsFrom = async (signature: string) => {
+    const transaction = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    return [...eventParser.parseLogs(transaction?.meta?.logMessages ?? [])];
+  };
+
   test("registers a homeserver delegation", async () => {
     const homeserver = "chat.example.com";
     const delegationAddress = getDelegationAddress(owner.publicKey);
@@ -40,9 +61,12 @@ describe("homeserver-registry", () => {
     );
 
     assert.equal(delegation.owner.toBase58(), owner.publicKey.toBase58());
-    assert.equal(delegation.homeserver, homeserver);
+    assert.deepEqual(delegation.homeservers, [homeserver]);
     assert.ok(delegation.updatedAt.toNumber() > 0);
     assert.ok(delegation.bump > 0);
+    assert.equal(delegation.version, 7);
+    assert.equal(delegation.expiresAt, null);
+    assert.equal(delegation.label, null);
   });
 
   test("updates an existing delegation to a new homeserver", async () => {
@@ -62,7 +86,7 @@ describe("homeserver-registry", () => {
       delegationAddress
     );
 
-    assert.equal(delegation.homeserver, newHomeserver);
+    assert.deepEqual(delegation.homeservers, [newHomeserver]);
   });
 
   test("rejects empty homeserver", async () => {
@@ -156,6 +180,38 @@ describe("homeserver-registry", () => {
     }
   });
 
+  test("rejects a homeserver with a malformed port", async () => {
+    const otherWallet = Keypair.generate();
+    const delegationAddress = getDelegationAddress(otherWallet.publicKey);
+
+    const airdropSignature = await provider.connection.requestAirdrop(
+      otherWallet.publicKey,
+      1_000_000_000
+    );
+    await provider.connection.confirmTransaction(airdropSignature);
+
+    for (const homeserver of ["example.com:", "foo:bar:baz.com", "chat.example.com:65536"]) {
+      try {
+        await program.methods
+          .register(homeserver)
+          .accounts({
+            delegation: delegationAddress,
+            owner: otherWallet.publicKey,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([otherWallet])
+          .rpc();
+        assert.fail(`${homeserver} should have thrown`);
+      } catch (thrownObject) {
+        const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+        assert.ok(
+          error.message.includes("InvalidHomeserver"),
+          `Expected InvalidHomeserver error for ${homeserver}, got: ${error.message}`
+        );
+      }
+    }
+  });
+
   test("another wallet cannot close someone else's delegation", async () => {
     const delegationAddress = getDelegationAddress(owner.publicKey);
     const attacker = Keypair.generate();
@@ -225,7 +281,7 @@ describe("homeserver-registry", () => {
       delegationAddress
     );
 
-    assert.equal(delegation.homeserver, homeserver);
+    assert.deepEqual(delegation.homeservers, [homeserver]);
   });
 
   test("allows homeserver with port number", async () => {
@@ -252,7 +308,7 @@ describe("homeserver-registry", () => {
       delegationAddress
     );
 
-    assert.equal(delegation.homeserver, "chat.example.com:8448");
+    assert.deepEqual(delegation.homeservers, ["chat.example.com:8448"]);
   });
 
   test("lookup by wallet address works (PDA derivation)", async () => {
@@ -263,6 +319,1091 @@ describe("homeserver-registry", () => {
     );
 
     assert.equal(delegation.owner.toBase58(), owner.publicKey.toBase58());
-    assert.ok(delegation.homeserver.length > 0);
+    assert.ok(delegation.homeservers.length > 0);
+  });
+
+  const authorizationMessage = (
+    ownerPublicKey: PublicKey,
+    homeserver: string,
+    expiresAt: number
+  ): Buffer =>
+    Buffer.from(
+      `Delegate ${ownerPublicKey.toBase58()} to homeserver ${homeserver}\nProgram: ${program.programId.toBase58()}\nExpires: ${expiresAt}`
+    );
+
+  test("registers a delegation for another wallet with its signed authorization", async () => {
+    // The custodied wallet never signs a transaction or pays anything
+    const custodiedWallet = Keypair.generate();
+    const homeserver = "chat.custodian.io";
+    const expiresAt = Math.floor(Date.now() / 1000) + 600;
+    const delegationAddress = getDelegationAddress(custodiedWallet.publicKey);
+
+    await program.methods
+      .registerFor(custodiedWallet.publicKey, homeserver, new anchor.BN(expiresAt))
+      .accounts({
+        delegation: delegationAddress,
+        payer: owner.publicKey,
+        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+        systemProgram: SystemProgram.programId,
+      })
+      .preInstructions([
+        Ed25519Program.createInstructionWithPrivateKey({
+          privateKey: custodiedWallet.secretKey,
+          message: authorizationMessage(custodiedWallet.publicKey, homeserver, expiresAt),
+        }),
+      ])
+      .rpc();
+
+    const delegation = await program.account.delegation.fetch(
+      delegationAddress
+    );
+
+    assert.equal(delegation.owner.toBase58(), custodiedWallet.publicKey.toBase58());
+    assert.deepEqual(delegation.homeservers, [homeserver]);
+  });
+
+  test("rejects register_for when the authorization names another homeserver", async () => {
+    const custodiedWallet = Keypair.generate();
+    const expiresAt = Math.floor(Date.now() / 1000) + 600;
+    const delegationAddress = getDelegationAddress(custodiedWallet.publicKey);
+
+    try {
+      await program.methods
+        .registerFor(custodiedWallet.publicKey, "chat.attacker.io", new anchor.BN(expiresAt))
+        .accounts({
+          delegation: delegationAddress,
+          payer: owner.publicKey,
+          instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+          systemProgram: SystemProgram.programId,
+        })
+        .preInstructions([
+          Ed25519Program.createInstructionWithPrivateKey({
+            privateKey: custodiedWallet.secretKey,
+            message: authorizationMessage(custodiedWallet.publicKey, "chat.custodian.io", expiresAt),
+          }),
+        ])
+        .rpc();
+      assert.fail("Should have thrown");
+    } catch (thrownObject) {
+      const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+      assert.ok(
+        error.message.includes("InvalidAuthorization"),
+        `Expected InvalidAuthorization error, got: ${error.message}`
+      );
+    }
+  });
+
+  test("rejects register_for without a signature check", async () => {
+    const custodiedWallet = Keypair.generate();
+    const expiresAt = Math.floor(Date.now() / 1000) + 600;
+    const delegationAddress = getDelegationAddress(custodiedWallet.publicKey);
+
+    try {
+      await program.methods
+        .registerFor(custodiedWallet.publicKey, "chat.custodian.io", new anchor.BN(expiresAt))
+        .accounts({
+          delegation: delegationAddress,
+          payer: owner.publicKey,
+          instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+      assert.fail("Should have thrown");
+    } catch (thrownObject) {
+      const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+      assert.ok(
+        error.message.includes("MissingAuthorization"),
+        `Expected MissingAuthorization error, got: ${error.message}`
+      );
+    }
+  });
+
+  test("rejects an expired authorization", async () => {
+    const custodiedWallet = Keypair.generate();
+    const homeserver = "chat.custodian.io";
+    const expiresAt = Math.floor(Date.now() / 1000) - 60;
+    const delegationAddress = getDelegationAddress(custodiedWallet.publicKey);
+
+    try {
+      await program.methods
+        .registerFor(custodiedWallet.publicKey, homeserver, new anchor.BN(expiresAt))
+        .accounts({
+          delegation: delegationAddress,
+          payer: owner.publicKey,
+          instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+          systemProgram: SystemProgram.programId,
+        })
+        .preInstructions([
+          Ed25519Program.createInstructionWithPrivateKey({
+            privateKey: custodiedWallet.secretKey,
+            message: authorizationMessage(custodiedWallet.publicKey, homeserver, expiresAt),
+          }),
+        ])
+        .rpc();
+      assert.fail("Should have thrown");
+    } catch (thrownObject) {
+      const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+      assert.ok(
+        error.message.includes("AuthorizationExpired"),
+        `Expected AuthorizationExpired error, got: ${error.message}`
+      );
+    }
+  });
+
+  test("registers a delegation signed by its owner and paid for by another wallet", async () => {
+    // The member signs but holds no SOL; the other wallet pays the rent and fees
+    const member = Keypair.generate();
+    const delegationAddress = getDelegationAddress(member.publicKey);
+
+    await program.methods
+      .registerDelegated(["chat.dao.example"], null, "DAO members")
+      .accounts({
+        delegation: delegationAddress,
+        owner: member.publicKey,
+        payer: owner.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([member])
+      .rpc();
+
+    const delegation = await program.account.delegation.fetch(
+      delegationAddress
+    );
+
+    assert.equal(delegation.owner.toBase58(), member.publicKey.toBase58());
+    assert.deepEqual(delegation.homeservers, ["chat.dao.example"]);
+    assert.equal(delegation.label, "DAO members");
+    assert.equal(await provider.connection.getBalance(member.publicKey), 0);
+  });
+
+  test("rejects register_delegated without the owner's signature", async () => {
+    const member = Keypair.generate();
+    const delegationAddress = getDelegationAddress(member.publicKey);
+
+    const instruction = await program.methods
+      .registerDelegated(["chat.attacker.io"], null, null)
+      .accounts({
+        delegation: delegationAddress,
+        owner: member.publicKey,
+        payer: owner.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .instruction();
+    // Send it with only the payer's signature, as if the payer were acting alone
+    for (const key of instruction.keys) {
+      if (key.pubkey.equals(member.publicKey)) {
+        key.isSigner = false;
+      }
+    }
+
+    try {
+      await provider.sendAndConfirm(new Transaction().add(instruction));
+      assert.fail("Should have thrown");
+    } catch (thrownObject) {
+      const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+      assert.ok(
+        error.message.includes("AccountNotSigner"),
+        `Expected AccountNotSigner error, got: ${error.message}`
+      );
+    }
+  });
+
+  test("emits an event when a delegation is created, updated and removed", async () => {
+    const wallet = Keypair.generate();
+    const delegationAddress = getDelegationAddress(wallet.publicKey);
+
+    const airdropSignature = await provider.connection.requestAirdrop(
+      wallet.publicKey,
+      1_000_000_000
+    );
+    await provider.connection.confirmTransaction(airdropSignature);
+
+    const register = (homeserver: string) =>
+      program.methods
+        .register(homeserver)
+        .accounts({
+          delegation: delegationAddress,
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([wallet])
+        .rpc({ commitment: "confirmed" });
+
+    const [created] = await eventsFrom(await register("chat.events.io"));
+    assert.equal(created.name, "delegationRegistered");
+    assert.equal(created.data.owner.toBase58(), wallet.publicKey.toBase58());
+    assert.deepEqual(created.data.homeservers, ["chat.events.io"]);
+    assert.ok(created.data.updatedAt.toNumber() > 0);
+    assert.equal(created.data.created, true);
+
+    const [updated] = await eventsFrom(await register("chat.moved.io"));
+    assert.equal(updated.name, "delegationRegistered");
+    assert.deepEqual(updated.data.homeservers, ["chat.moved.io"]);
+    assert.equal(updated.data.created, false);
+
+    const removeSignature = await program.methods
+      .unregister()
+      .accounts({
+        delegation: delegationAddress,
+        owner: wallet.publicKey,
+      })
+      .signers([wallet])
+      .rpc({ commitment: "confirmed" });
+
+    const [removed] = await eventsFrom(removeSignature);
+    assert.equal(removed.name, "delegationRemoved");
+    assert.equal(removed.data.owner.toBase58(), wallet.publicKey.toBase58());
+  });
+
+  describe("multiple homeservers", () => {
+    const wallet = Keypair.generate();
+    const delegationAddress = getDelegationAddress(wallet.publicKey);
+
+    before(async () => {
+      const airdropSignature = await provider.connection.requestAirdrop(
+        wallet.publicKey,
+        1_000_000_000
+      );
+      await provider.connection.confirmTransaction(airdropSignature);
+    });
+
+    const registerHomeservers = (
+      homeservers: Array<string>,
+      expiresAt: number | null = null,
+      label: string | null = null
+    ) =>
+      program.methods
+        .registerHomeservers(homeservers, expiresAt === null ? null : new anchor.BN(expiresAt), label)
+        .accounts({
+          delegation: delegationAddress,
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([wallet])
+        .rpc();
+
+    const editAccounts = {
+      delegation: delegationAddress,
+      owner: wallet.publicKey,
+      systemProgram: SystemProgram.programId,
+    };
+
+    const fetchHomeservers = async () =>
+      (await program.account.delegation.fetch(delegationAddress)).homeservers;
+
+    const assertFails = async (transaction: Promise<unknown>, errorName: string) => {
+      try {
+        await transaction;
+        assert.fail("Should have thrown");
+      } catch (thrownObject) {
+        const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+        assert.ok(
+          error.message.includes(errorName),
+          `Expected ${errorName} error, got: ${error.message}`
+        );
+      }
+    };
+
+    test("registers homeservers in priority order", async () => {
+      await registerHomeservers(["primary.example.com", "backup.example.com", "last.example.com"]);
+
+      assert.deepEqual(await fetchHomeservers(), [
+        "primary.example.com",
+        "backup.example.com",
+        "last.example.com",
+      ]);
+    });
+
+    test("rejects more than three homeservers", async () => {
+      await assertFails(
+        registerHomeservers(["a.example.com", "b.example.com", "c.example.com", "d.example.com"]),
+        "TooManyHomeservers"
+      );
+    });
+
+    test("rejects an empty list, duplicates and invalid entries", async () => {
+      await assertFails(registerHomeservers([]), "NoHomeservers");
+      await assertFails(
+        registerHomeservers(["a.example.com", "a.example.com"]),
+        "DuplicateHomeserver"
+      );
+      await assertFails(
+        registerHomeservers(["a.example.com", "https://b.example.com"]),
+        "InvalidHomeserver"
+      );
+    });
+
+    test("moves one homeserver without rewriting the list", async () => {
+      await program.methods.moveHomeserver(2, 0).accounts(editAccounts).signers([wallet]).rpc();
+
+      assert.deepEqual(await fetchHomeservers(), [
+        "last.example.com",
+        "primary.example.com",
+        "backup.example.com",
+      ]);
+
+      await assertFails(
+        program.methods.moveHomeserver(0, 3).accounts(editAccounts).signers([wallet]).rpc(),
+        "HomeserverIndexOutOfRange"
+      );
+    });
+
+    test("removes one homeserver but never the last", async () => {
+      await program.methods.removeHomeserver(1).accounts(editAccounts).signers([wallet]).rpc();
+      assert.deepEqual(await fetchHomeservers(), ["last.example.com", "backup.example.com"]);
+
+      await program.methods.removeHomeserver(0).accounts(editAccounts).signers([wallet]).rpc();
+      assert.deepEqual(await fetchHomeservers(), ["backup.example.com"]);
+
+      await assertFails(
+        program.methods.removeHomeserver(0).accounts(editAccounts).signers([wallet]).rpc(),
+        "LastHomeserver"
+      );
+    });
+
+    test("register replaces the list with a single homeserver", async () => {
+      await registerHomeservers(["primary.example.com", "backup.example.com"]);
+
+      await program.methods
+        .register("only.example.com")
+        .accounts({
+          delegation: delegationAddress,
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([wallet])
+        .rpc();
+
+      assert.deepEqual(await fetchHomeservers(), ["only.example.com"]);
+    });
+
+    test("registers a delegation that expires, and register without one clears it", async () => {
+      const expiresAt = Math.floor(Date.now() / 1000) + 3600;
+      await registerHomeservers(["event.example.com"], expiresAt);
+
+      const delegation = await program.account.delegation.fetch(delegationAddress);
+      assert.deepEqual(delegation.homeservers, ["event.example.com"]);
+      assert.equal(delegation.expiresAt?.toNumber(), expiresAt);
+
+      await registerHomeservers(["only.example.com"]);
+      assert.equal(
+        (await program.account.delegation.fetch(delegationAddress)).expiresAt,
+        null
+      );
+    });
+
+    test("rejects an expiry in the past", async () => {
+      const expiresAt = Math.floor(Date.now() / 1000) - 60;
+      await assertFails(registerHomeservers(["event.example.com"], expiresAt), "ExpiryInPast");
+    });
+
+    test("registers a delegation with a label, and without one clears it", async () => {
+      await registerHomeservers(["dao.example.com"], null, "Alice's DAO server");
+
+      const delegation = await program.account.delegation.fetch(delegationAddress);
+      assert.deepEqual(delegation.homeservers, ["dao.example.com"]);
+      assert.equal(delegation.label, "Alice's DAO server");
+
+      await registerHomeservers(["dao.example.com"]);
+      assert.equal((await program.account.delegation.fetch(delegationAddress)).label, null);
+    });
+
+    test("clearing a label leaves no stale bytes behind the delegation", async () => {
+      await registerHomeservers(["dao.example.com"], null, "Alice's DAO server");
+      await registerHomeservers(["dao.example.com"]);
+
+      // Discriminator, owner, one 15-byte homeserver, updated_at, bump, version, and
+      // empty expiry, label, auth key and previous homeserver, not frozen and not counted.
+      const end = 8 + 32 + 4 + 4 + 15 + 8 + 1 + 1 + 1 + 1 + 1 + 1 + 1 + 1;
+      const accountInfo = await provider.connection.getAccountInfo(delegationAddress);
+      assert.ok(accountInfo.data.subarray(end).every((byte) => byte === 0));
+    });
+
+    test("rejects an over-length or control-character label", async () => {
+      await assertFails(registerHomeservers(["dao.example.com"], null, "a".repeat(65)), "LabelTooLong");
+      await assertFails(registerHomeservers(["dao.example.com"], null, "Alice\nBob"), "InvalidLabel");
+      await assertFails(registerHomeservers(["dao.example.com"], null, ""), "InvalidLabel");
+    });
+
+    const migrate = (newHomeserver: string) =>
+      program.methods
+        .migrate(newHomeserver)
+        .accounts({ ...editAccounts, systemProgram: SystemProgram.programId })
+        .signers([wallet])
+        .rpc({ commitment: "confirmed" });
+
+    test("migrates the primary homeserver and remembers the previous one", async () => {
+      await registerHomeservers(["old.example.com", "new.example.com", "backup.example.com"]);
+
+      const [event] = await eventsFrom(await migrate("new.example.com"));
+      assert.equal(event.name, "delegationMigrated");
+      assert.equal(event.data.owner.toBase58(), wallet.publicKey.toBase58());
+      assert.equal(event.data.previousHomeserver, "old.example.com");
+      assert.equal(event.data.homeserver, "new.example.com");
+
+      const delegation = await program.account.delegation.fetch(delegationAddress);
+      assert.deepEqual(delegation.homeservers, ["new.example.com", "backup.example.com"]);
+      assert.equal(delegation.previousHomeserver, "old.example.com");
+    });
+
+    test("rejects migrating to the current homeserver or an invalid one", async () => {
+      await assertFails(migrate("new.example.com"), "SameHomeserver");
+      await assertFails(migrate("https://other.example.com"), "InvalidHomeserver");
+      assert.equal(
+        (await program.account.delegation.fetch(delegationAddress)).previousHomeserver,
+        "old.example.com"
+      );
+    });
+  });
+
+  describe("deactivation", () => {
+    const wallet = Keypair.generate();
+    const delegationAddress = getDelegationAddress(wallet.publicKey);
+
+    before(async () => {
+      const airdropSignature = await provider.connection.requestAirdrop(
+        wallet.publicKey,
+        1_000_000_000
+      );
+      await provider.connection.confirmTransaction(airdropSignature);
+
+      await program.methods
+        .register("chat.before.io")
+        .accounts({
+          delegation: delegationAddress,
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([wallet])
+        .rpc();
+    });
+
+    test("another wallet cannot deactivate someone else's delegation", async () => {
+      const attacker = Keypair.generate();
+
+      try {
+        await program.methods
+          .deactivate()
+          .accounts({
+            delegation: delegationAddress,
+            owner: attacker.publicKey,
+          })
+          .signers([attacker])
+          .rpc();
+        assert.fail("Should have thrown");
+      } catch (thrownObject) {
+        const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+        // Should fail because the PDA seeds won't match or has_one will reject
+        assert.ok(error.message.length > 0);
+      }
+    });
+
+    test("deactivates a delegation, keeping the account, then reactivates it", async () => {
+      const registered = await program.account.delegation.fetch(delegationAddress);
+      const rentBefore = await provider.connection.getBalance(delegationAddress);
+
+      const signature = await program.methods
+        .deactivate()
+        .accounts({
+          delegation: delegationAddress,
+          owner: wallet.publicKey,
+        })
+        .signers([wallet])
+        .rpc({ commitment: "confirmed" });
+
+      const deactivated = await program.account.delegation.fetch(delegationAddress);
+      assert.deepEqual(deactivated.homeservers, []);
+      assert.ok(deactivated.updatedAt.toNumber() >= registered.updatedAt.toNumber());
+      assert.equal(await provider.connection.getBalance(delegationAddress), rentBefore);
+
+      const [event] = await eventsFrom(signature);
+      assert.equal(event.name, "delegationDeactivated");
+      assert.equal(event.data.owner.toBase58(), wallet.publicKey.toBase58());
+      assert.equal(event.data.updatedAt.toNumber(), deactivated.updatedAt.toNumber());
+
+      await program.methods
+        .register("chat.after.io")
+        .accounts({
+          delegation: delegationAddress,
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([wallet])
+        .rpc();
+
+      const reactivated = await program.account.delegation.fetch(delegationAddress);
+      assert.deepEqual(reactivated.homeservers, ["chat.after.io"]);
+    });
+  });
+
+  test("transfers ownership to a new wallet, which then controls the delegation", async () => {
+    const oldWallet = Keypair.generate();
+    const newWallet = Keypair.generate();
+    const delegationAddress = getDelegationAddress(oldWallet.publicKey);
+
+    for (const wallet of [oldWallet, newWallet]) {
+      const airdropSignature = await provider.connection.requestAirdrop(
+        wallet.publicKey,
+        1_000_000_000
+      );
+      await provider.connection.confirmTransaction(airdropSignature);
+    }
+
+    await program.methods
+      .register("chat.rotating.io")
+      .accounts({
+        delegation: delegationAddress,
+        owner: oldWallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([oldWallet])
+      .rpc();
+
+    const signature = await program.methods
+      .transferOwnership(newWallet.publicKey)
+      .accounts({
+        delegation: delegationAddress,
+        owner: oldWallet.publicKey,
+      })
+      .signers([oldWallet])
+      .rpc({ commitment: "confirmed" });
+
+    const [event] = await eventsFrom(signature);
+    assert.equal(event.name, "delegationTransferred");
+    assert.equal(event.data.previousOwner.toBase58(), oldWallet.publicKey.toBase58());
+    assert.equal(event.data.newOwner.toBase58(), newWallet.publicKey.toBase58());
+
+    const transferred = await program.account.delegation.fetch(delegationAddress);
+    assert.equal(transferred.owner.toBase58(), newWallet.publicKey.toBase58());
+    assert.equal(transferred.updatedAt.toNumber(), event.data.updatedAt.toNumber());
+
+    // The new owner can update the delegation at its original address.
+    await program.methods
+      .updateHomeservers(["chat.rotated.io"])
+      .accounts({
+        delegation: delegationAddress,
+        owner: newWallet.publicKey,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([newWallet])
+      .rpc();
+    assert.deepEqual(
+      (await program.account.delegation.fetch(delegationAddress)).homeservers,
+      ["chat.rotated.io"]
+    );
+
+    // The old owner can neither update it nor take it back with register.
+    const oldOwnerAttempts = [
+      () => program.methods
+        .updateHomeservers(["chat.stolen.io"])
+        .accounts({
+          delegation: delegationAddress,
+          owner: oldWallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([oldWallet])
+        .rpc(),
+      () => program.methods
+        .register("chat.stolen.io")
+        .accounts({
+          delegation: delegationAddress,
+          owner: oldWallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([oldWallet])
+        .rpc(),
+    ];
+    for (const attempt of oldOwnerAttempts) {
+      try {
+        await attempt();
+        assert.fail("Should have thrown");
+      } catch (thrownObject) {
+        const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+        assert.ok(
+          error.message.includes("ConstraintHasOne") || error.message.includes("NotDelegationOwner"),
+          `Expected an ownership error, got: ${error.message}`
+        );
+      }
+    }
+
+    assert.deepEqual(
+      (await program.account.delegation.fetch(delegationAddress)).homeservers,
+      ["chat.rotated.io"]
+    );
+  });
+
+  describe("auth keys", () => {
+    const wallet = Keypair.generate();
+    const authKey = Keypair.generate();
+    const delegationAddress = getDelegationAddress(wallet.publicKey);
+
+    const setAuthKey = (authPubkey: PublicKey | null, signer = wallet) =>
+      program.methods
+        .setAuthKey(authPubkey)
+        .accounts({
+          delegation: delegationAddress,
+          owner: signer.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([signer])
+        .rpc({ commitment: "confirmed" });
+
+    before(async () => {
+      const airdropSignature = await provider.connection.requestAirdrop(
+        wallet.publicKey,
+        1_000_000_000
+      );
+      await provider.connection.confirmTransaction(airdropSignature);
+
+      await program.methods
+        .register("chat.example.com")
+        .accounts({
+          delegation: delegationAddress,
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([wallet])
+        .rpc();
+    });
+
+    test("sets and clears the auth key", async () => {
+      assert.equal((await program.account.delegation.fetch(delegationAddress)).authPubkey, null);
+
+      const signature = await setAuthKey(authKey.publicKey);
+      const delegation = await program.account.delegation.fetch(delegationAddress);
+      assert.equal(delegation.authPubkey?.toBase58(), authKey.publicKey.toBase58());
+      assert.deepEqual(delegation.homeservers, ["chat.example.com"]);
+
+      const [event] = await eventsFrom(signature);
+      assert.equal(event.name, "authKeyChanged");
+      assert.equal(event.data.owner.toBase58(), wallet.publicKey.toBase58());
+      assert.equal(event.data.authPubkey.toBase58(), authKey.publicKey.toBase58());
+
+      await setAuthKey(null);
+      assert.equal((await program.account.delegation.fetch(delegationAddress)).authPubkey, null);
+    });
+
+    test("rejects the owner's own key and the default key as auth keys", async () => {
+      for (const authPubkey of [wallet.publicKey, PublicKey.default]) {
+        try {
+          await setAuthKey(authPubkey);
+          assert.fail("Should have thrown");
+        } catch (thrownObject) {
+          const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+          assert.ok(
+            error.message.includes("InvalidAuthKey"),
+            `Expected InvalidAuthKey error, got: ${error.message}`
+          );
+        }
+      }
+    });
+
+    test("another wallet cannot set someone else's auth key", async () => {
+      const attacker = Keypair.generate();
+      const airdropSignature = await provider.connection.requestAirdrop(
+        attacker.publicKey,
+        1_000_000_000
+      );
+      await provider.connection.confirmTransaction(airdropSignature);
+
+      try {
+        await setAuthKey(attacker.publicKey, attacker);
+        assert.fail("Should have thrown");
+      } catch (thrownObject) {
+        const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+        assert.ok(
+          error.message.includes("ConstraintHasOne"),
+          `Expected ConstraintHasOne error, got: ${error.message}`
+        );
+      }
+      assert.equal((await program.account.delegation.fetch(delegationAddress)).authPubkey, null);
+    });
+  });
+
+  describe("freezing", () => {
+    const wallet = Keypair.generate();
+    const delegationAddress = getDelegationAddress(wallet.publicKey);
+
+    const setFrozen = (frozen: boolean, signer = wallet) =>
+      program.methods
+        .setFrozen(frozen)
+        .accounts({
+          delegation: delegationAddress,
+          owner: signer.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([signer])
+        .rpc({ commitment: "confirmed" });
+
+    before(async () => {
+      const airdropSignature = await provider.connection.requestAirdrop(
+        wallet.publicKey,
+        1_000_000_000
+      );
+      await provider.connection.confirmTransaction(airdropSignature);
+
+      await program.methods
+        .register("chat.example.com")
+        .accounts({
+          delegation: delegationAddress,
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([wallet])
+        .rpc();
+    });
+
+    test("freezes and unfreezes the delegation", async () => {
+      assert.equal((await program.account.delegation.fetch(delegationAddress)).frozen, false);
+
+      const signature = await setFrozen(true);
+      const delegation = await program.account.delegation.fetch(delegationAddress);
+      assert.equal(delegation.frozen, true);
+      assert.deepEqual(delegation.homeservers, ["chat.example.com"]);
+
+      const [event] = await eventsFrom(signature);
+      assert.equal(event.name, "delegationFrozen");
+      assert.equal(event.data.owner.toBase58(), wallet.publicKey.toBase58());
+      assert.equal(event.data.frozen, true);
+
+      await setFrozen(false);
+      assert.equal((await program.account.delegation.fetch(delegationAddress)).frozen, false);
+    });
+
+    test("re-registering keeps a delegation frozen", async () => {
+      await setFrozen(true);
+      await program.methods
+        .register("chat.rotated.io")
+        .accounts({
+          delegation: delegationAddress,
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([wallet])
+        .rpc();
+
+      const delegation = await program.account.delegation.fetch(delegationAddress);
+      assert.equal(delegation.frozen, true);
+      assert.deepEqual(delegation.homeservers, ["chat.rotated.io"]);
+      await setFrozen(false);
+    });
+
+    test("another wallet cannot freeze someone else's delegation", async () => {
+      const attacker = Keypair.generate();
+      const airdropSignature = await provider.connection.requestAirdrop(
+        attacker.publicKey,
+        1_000_000_000
+      );
+      await provider.connection.confirmTransaction(airdropSignature);
+
+      try {
+        await setFrozen(true, attacker);
+        assert.fail("Should have thrown");
+      } catch (thrownObject) {
+        const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+        assert.ok(
+          error.message.includes("ConstraintHasOne"),
+          `Expected ConstraintHasOne error, got: ${error.message}`
+        );
+      }
+      assert.equal((await program.account.delegation.fetch(delegationAddress)).frozen, false);
+    });
+  });
+
+  describe("homeserver stats", () => {
+    const [alice, bob] = [Keypair.generate(), Keypair.generate()];
+    const homeserver = "counted.example.com";
+
+    const getStatsAddress = (homeserver: string): PublicKey => {
+      const [statsAddress] = PublicKey.findProgramAddressSync(
+        [Buffer.from("homeserver_stats"), createHash("sha256").update(homeserver).digest()],
+        program.programId
+      );
+      return statsAddress;
+    };
+    const statsAddress = getStatsAddress(homeserver);
+
+    const register = (
+      wallet: Keypair,
+      homeserver: string,
+      homeserverStats: PublicKey | null,
+      countedStats: PublicKey | null = null
+    ) =>
+      program.methods
+        .register(homeserver)
+        .accounts({
+          delegation: getDelegationAddress(wallet.publicKey),
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+          homeserverStats,
+          countedStats,
+        })
+        .signers([wallet])
+        .rpc();
+
+    const updateHomeservers = (
+      wallet: Keypair,
+      homeservers: string[],
+      homeserverStats: PublicKey | null,
+      countedStats: PublicKey | null
+    ) =>
+      program.methods
+        .updateHomeservers(homeservers)
+        .accounts({
+          delegation: getDelegationAddress(wallet.publicKey),
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+          homeserverStats,
+          countedStats,
+        })
+        .signers([wallet])
+        .rpc();
+
+    const migrate = (
+      wallet: Keypair,
+      newHomeserver: string,
+      homeserverStats: PublicKey | null,
+      countedStats: PublicKey | null
+    ) =>
+      program.methods
+        .migrate(newHomeserver)
+        .accounts({
+          delegation: getDelegationAddress(wallet.publicKey),
+          owner: wallet.publicKey,
+          systemProgram: SystemProgram.programId,
+          homeserverStats,
+          countedStats,
+        })
+        .signers([wallet])
+        .rpc();
+
+    const deactivate = (wallet: Keypair, homeserverStats: PublicKey | null) =>
+      program.methods
+        .deactivate()
+        .accounts({
+          delegation: getDelegationAddress(wallet.publicKey),
+          owner: wallet.publicKey,
+          homeserverStats,
+        })
+        .signers([wallet])
+        .rpc();
+
+    const unregister = (wallet: Keypair, homeserverStats: PublicKey | null) =>
+      program.methods
+        .unregister()
+        .accounts({
+          delegation: getDelegationAddress(wallet.publicKey),
+          owner: wallet.publicKey,
+          homeserverStats,
+        })
+        .signers([wallet])
+        .rpc();
+
+    const initStats = (homeserver: string) =>
+      program.methods
+        .initHomeserverStats(homeserver)
+        .accounts({
+          homeserverStats: getStatsAddress(homeserver),
+          payer: owner.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .rpc();
+
+    const count = async (stats = statsAddress) =>
+      (await program.account.homeserverStats.fetch(stats)).delegations.toNumber();
+
+    const countedIn = async (wallet: Keypair) =>
+      (
+        await program.account.delegation.fetch(getDelegationAddress(wallet.publicKey))
+      ).countedIn?.toBase58() ?? null;
+
+    const assertFails = async (transaction: Promise<unknown>, errorName: string) => {
+      try {
+        await transaction;
+        assert.fail("Should have thrown");
+      } catch (thrownObject) {
+        const error = thrownObject instanceof Error ? thrownObject : new Error(String(thrownObject));
+        assert.ok(
+          error.message.includes(errorName),
+          `Expected ${errorName} error, got: ${error.message}`
+        );
+      }
+    };
+
+    before(async () => {
+      for (const wallet of [alice, bob]) {
+        const airdropSignature = await provider.connection.requestAirdrop(
+          wallet.publicKey,
+          1_000_000_000
+        );
+        await provider.connection.confirmTransaction(airdropSignature);
+      }
+      await initStats(homeserver);
+      await initStats("other.example.com");
+    });
+
+    test("starts counting at zero", async () => {
+      const stats = await program.account.homeserverStats.fetch(statsAddress);
+      assert.equal(stats.homeserver, homeserver);
+      assert.equal(stats.delegations.toNumber(), 0);
+    });
+
+    test("counts registrations that opt in, once each", async () => {
+      await register(alice, homeserver, statsAddress);
+      assert.equal(await count(), 1);
+      const delegation = await program.account.delegation.fetch(getDelegationAddress(alice.publicKey));
+      assert.equal(delegation.countedIn?.toBase58(), statsAddress.toBase58());
+
+      // Registering again to the same homeserver, counted or not, doesn't count twice.
+      await register(alice, homeserver, statsAddress);
+      await register(alice, homeserver, null);
+      assert.equal(await count(), 1);
+
+      // Without the stats account a registration isn't counted.
+      await register(bob, homeserver, null);
+      assert.equal(await count(), 1);
+      await register(bob, homeserver, statsAddress);
+      assert.equal(await count(), 2);
+    });
+
+    test("rejects stats for another homeserver", async () => {
+      await assertFails(
+        register(alice, homeserver, getStatsAddress("other.example.com")),
+        "HomeserverStatsMismatch"
+      );
+      // Moving to another homeserver needs the stats the delegation is counted in.
+      await assertFails(
+        register(alice, "other.example.com", getStatsAddress("other.example.com")),
+        "MissingHomeserverStats"
+      );
+      assert.equal(await count(), 2);
+    });
+
+    test("unregistering takes the delegation off the count", async () => {
+      await assertFails(unregister(alice, null), "MissingHomeserverStats");
+      await assertFails(
+        unregister(alice, getStatsAddress("other.example.com")),
+        "MissingHomeserverStats"
+      );
+
+      await unregister(alice, statsAddress);
+      assert.equal(await count(), 1);
+
+      // Registered again, the new delegation is counted afresh.
+      await register(alice, homeserver, statsAddress);
+      assert.equal(await count(), 2);
+
+      await unregister(alice, statsAddress);
+      await unregister(bob, statsAddress);
+      assert.equal(await count(), 0);
+    });
+
+    test("moves the count with the primary homeserver", async () => {
+      const otherStats = getStatsAddress("other.example.com");
+      await register(alice, homeserver, statsAddress);
+      assert.equal(await count(), 1);
+
+      await assertFails(
+        updateHomeservers(alice, ["other.example.com", homeserver], otherStats, null),
+        "MissingHomeserverStats"
+      );
+      await updateHomeservers(alice, ["other.example.com", homeserver], otherStats, statsAddress);
+      assert.equal(await count(), 0);
+      assert.equal(await count(otherStats), 1);
+      assert.equal(await countedIn(alice), otherStats.toBase58());
+
+      // Without the new primary's stats the delegation is only taken off the old count.
+      await migrate(alice, homeserver, null, otherStats);
+      assert.equal(await count(otherStats), 0);
+      assert.equal(await count(), 0);
+      assert.equal(await countedIn(alice), null);
+
+      // Edits that keep the primary leave the count alone.
+      await register(alice, homeserver, statsAddress);
+      await updateHomeservers(alice, [homeserver, "backup.example.com"], null, null);
+      assert.equal(await count(), 1);
+      assert.equal(await countedIn(alice), statsAddress.toBase58());
+
+      await assertFails(deactivate(alice, null), "MissingHomeserverStats");
+      await deactivate(alice, statsAddress);
+      assert.equal(await count(), 0);
+      assert.equal(await countedIn(alice), null);
+    });
+
+    test("rejects stats for an invalid homeserver", async () => {
+      await assertFails(initStats("not a hostname"), "InvalidHomeserver");
+    });
+  });
+
+  describe("layout upgrades", () => {
+    // Loaded by the test validator from tests/fixtures/single-homeserver-delegation.json:
+    // a delegation written by the first version of the program, which held one homeserver
+    // string where the list now goes, so Anchor can't decode it.
+    const fixtureOwner = new PublicKey(Buffer.alloc(32, 8));
+    const fixtureAddress = getDelegationAddress(fixtureOwner);
+    const currentSize = 1228;
+    const crank = Keypair.generate();
+
+    const migrateAccount = (delegation: PublicKey) =>
+      program.methods
+        .migrateAccount()
+        .accounts({
+          delegation,
+          payer: crank.publicKey,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([crank])
+        .rpc({ commitment: "confirmed" });
+
+    before(async () => {
+      const airdropSignature = await provider.connection.requestAirdrop(
+        crank.publicKey,
+        1_000_000_000
+      );
+      await provider.connection.confirmTransaction(airdropSignature);
+    });
+
+    test("anyone can upgrade an old delegation, keeping its fields", async () => {
+      const original = await provider.connection.getAccountInfo(fixtureAddress);
+      assert.equal(original.data.length, 306);
+      await assert.rejects(program.account.delegation.fetch(fixtureAddress));
+
+      const signature = await migrateAccount(fixtureAddress);
+
+      const upgraded = await provider.connection.getAccountInfo(fixtureAddress);
+      assert.equal(upgraded.data.length, currentSize);
+      const delegation = await program.account.delegation.fetch(fixtureAddress);
+      assert.equal(delegation.owner.toBase58(), fixtureOwner.toBase58());
+      assert.deepEqual(delegation.homeservers, ["chat.example.com"]);
+      assert.equal(delegation.updatedAt.toNumber(), 1_700_000_000);
+      assert.equal(delegation.bump, 255);
+      assert.equal(delegation.version, 7);
+      assert.equal(delegation.expiresAt, null);
+      assert.equal(delegation.label, null);
+      assert.equal(delegation.authPubkey, null);
+      assert.equal(delegation.previousHomeserver, null);
+      assert.equal(delegation.frozen, false);
+      assert.equal(delegation.countedIn, null);
+
+      const [event] = await eventsFrom(signature);
+      assert.equal(event.name, "delegationUpgraded");
+      assert.equal(event.data.owner.toBase58(), fixtureOwner.toBase58());
+      assert.equal(event.data.previousVersion, 0);
+      assert.equal(event.data.version, 7);
+    });
+
+    test("leaves an up-to-date delegation alone", async () => {
+      const original = await provider.connection.getAccountInfo(fixtureAddress);
+      const signature = await migrateAccount(fixtureAddress);
+
+      const upgraded = await provider.connection.getAccountInfo(fixtureAddress);
+      assert.deepEqual(upgraded.data, original.data);
+      assert.equal(upgraded.lamports, original.lamports);
+      assert.deepEqual(await eventsFrom(signature), []);
+    });
+
+    test("only accepts delegations of this program", async () => {
+      await assert.rejects(migrateAccount(Keypair.generate().publicKey), /ConstraintOwner/);
+    });
   });
 });